@@ -0,0 +1,15 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// Datagrams of one sender, each after its length in a byte, so chunks of the same message meet
+fuzz_target!(|data: &[u8]| {
+    let mut datagrams = Vec::new();
+    let mut rest = data;
+    while let Some((&len, tail)) = rest.split_first() {
+        let (datagram, tail) = tail.split_at(usize::from(len).min(tail.len()));
+        datagrams.push(datagram);
+        rest = tail;
+    }
+    let _ = sqlx_logger::fuzz::decode_all(datagrams);
+});
@@ -0,0 +1,239 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+
+use crate::{
+    json,
+    pipeline::{Entry, Sink},
+    writer::{Param, Writer},
+};
+
+/// What entries are counted by
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct AggregateKey {
+    pub window_start: SystemTime,
+    /// The `host` field of JSON entries, or the sender's address without one
+    pub host: String,
+    pub level: Option<u64>,
+}
+
+/// Counts entries per window, host and level
+#[derive(Debug)]
+pub struct Aggregator {
+    window: Duration,
+    counts: HashMap<AggregateKey, u64>,
+}
+
+impl Aggregator {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            counts: Default::default(),
+        }
+    }
+
+    pub fn add(&mut self, entry: &Entry) {
+        let at = entry.received.map_or_else(SystemTime::now, |v| v.at);
+        let host = json::get_string(entry, "host")
+            .or_else(|| entry.sender.map(|v| v.ip().to_string()))
+            .unwrap_or_default();
+        let key = AggregateKey {
+            window_start: self.window_start(at),
+            host,
+            level: json::get_u64(entry, "level"),
+        };
+        *self.counts.entry(key).or_default() += 1;
+    }
+
+    /// Take the counts of windows that are over by `now`
+    pub fn take_closed(&mut self, now: SystemTime) -> Vec<(AggregateKey, u64)> {
+        let current = self.window_start(now);
+        let closed: Vec<_> = self
+            .counts
+            .keys()
+            .filter(|key| key.window_start < current)
+            .cloned()
+            .collect();
+        closed
+            .into_iter()
+            .map(|key| {
+                let count = self.counts.remove(&key).unwrap_or_default();
+                (key, count)
+            })
+            .collect()
+    }
+
+    pub fn take_all(&mut self) -> Vec<(AggregateKey, u64)> {
+        self.counts.drain().collect()
+    }
+
+    /// Count again what couldn't be written out
+    pub fn restore(&mut self, counts: Vec<(AggregateKey, u64)>) {
+        for (key, count) in counts {
+            *self.counts.entry(key).or_default() += count;
+        }
+    }
+
+    fn window_start(&self, at: SystemTime) -> SystemTime {
+        let since_epoch = at.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let window = self.window.as_micros().max(1);
+        let start = since_epoch.as_micros() / window * window;
+        UNIX_EPOCH + Duration::from_micros(start as u64)
+    }
+}
+
+/// Counts entries with an [`Aggregator`], upserting the counts of each window once it is over,
+/// whether or not more entries come in, and on shutdown the counts so far. Entries are handed on
+/// to `inner` as well, if any, to keep the raw rows too. Counts that fail to be written are kept
+/// for the next tick rather than failing the entries, which `inner` has written by then.
+///
+/// Counts are written with `sql`, given the start of the window in seconds since the epoch, the
+/// host, the level and the count.
+pub struct AggregateSink {
+    pub inner: Option<Box<dyn Sink>>,
+    pub aggregator: Aggregator,
+    pub writer: Writer,
+    pub sql: String,
+}
+
+impl AggregateSink {
+    async fn write_counts(&mut self, counts: Vec<(AggregateKey, u64)>) -> anyhow::Result<()> {
+        if counts.is_empty() {
+            return Ok(());
+        }
+
+        let rows: Vec<_> = counts
+            .iter()
+            .map(|(key, count)| {
+                let window_start = key.window_start.duration_since(UNIX_EPOCH);
+                vec![
+                    Param::Int(window_start.ok().map(|v| v.as_secs() as i64)),
+                    Param::Text(Some(key.host.clone())),
+                    Param::Int(key.level.map(|v| v as i64)),
+                    Param::Int(Some(*count as i64)),
+                ]
+            })
+            .collect();
+
+        match self.writer.write_rows(&self.sql, &rows).await {
+            Ok(_) => {
+                log::info!("Wrote {} aggregates", rows.len());
+                Ok(())
+            }
+            Err(e) => {
+                self.aggregator.restore(counts);
+                Err(e)
+            }
+        }
+    }
+
+    /// Write the counts of the windows that are over, leaving them to the next tick if that fails
+    async fn write_closed(&mut self) {
+        let closed = self.aggregator.take_closed(SystemTime::now());
+        if let Err(e) = self.write_counts(closed).await {
+            log::error!("Writing aggregates, trying again on the next tick: {e:#}");
+        }
+    }
+}
+
+#[async_trait]
+impl Sink for AggregateSink {
+    async fn write(&mut self, entries: &[Entry]) -> anyhow::Result<()> {
+        if let Some(inner) = &mut self.inner {
+            inner.write(entries).await?;
+        }
+
+        for entry in entries {
+            self.aggregator.add(entry);
+        }
+
+        self.write_closed().await;
+        Ok(())
+    }
+
+    async fn tick(&mut self) -> anyhow::Result<()> {
+        if let Some(inner) = &mut self.inner {
+            inner.tick().await?;
+        }
+
+        self.write_closed().await;
+        Ok(())
+    }
+
+    async fn close(&mut self) -> anyhow::Result<()> {
+        if let Some(inner) = &mut self.inner {
+            inner.close().await?;
+        }
+
+        let counts = self.aggregator.take_all();
+        self.write_counts(counts).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pipeline::Received;
+
+    use super::*;
+
+    fn entry(body: &str, at: SystemTime) -> Entry {
+        Entry {
+            sender: Some("10.0.0.1:1234".parse().unwrap()),
+            received: Some(Received { at, seq: 0 }),
+            body: body.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn counts_per_window() {
+        let mut aggregator = Aggregator::new(Duration::from_secs(60));
+        let start = UNIX_EPOCH + Duration::from_secs(1_700_000_040);
+
+        aggregator.add(&entry(r#"{"host":"web","level":3}"#, start));
+        aggregator.add(&entry(
+            r#"{"host":"web","level":3}"#,
+            start + Duration::from_secs(59),
+        ));
+        aggregator.add(&entry(r#"{"level":6}"#, start + Duration::from_secs(1)));
+        aggregator.add(&entry(
+            r#"{"host":"web","level":3}"#,
+            start + Duration::from_secs(60),
+        ));
+
+        assert!(aggregator
+            .take_closed(start + Duration::from_secs(59))
+            .is_empty());
+
+        let mut closed = aggregator.take_closed(start + Duration::from_secs(60));
+        closed.sort_by(|a, b| a.0.host.cmp(&b.0.host));
+        assert_eq!(
+            vec![
+                (
+                    AggregateKey {
+                        window_start: start,
+                        host: "10.0.0.1".to_string(),
+                        level: Some(6),
+                    },
+                    1
+                ),
+                (
+                    AggregateKey {
+                        window_start: start,
+                        host: "web".to_string(),
+                        level: Some(3),
+                    },
+                    2
+                ),
+            ],
+            closed
+        );
+
+        aggregator.restore(closed);
+        assert_eq!(3, aggregator.take_all().len());
+        assert!(aggregator.take_all().is_empty());
+    }
+}
@@ -0,0 +1,347 @@
+use std::{
+    borrow::Cow,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use regex::Regex;
+use tokio::spawn;
+
+use crate::{
+    email::Mailer,
+    json,
+    pipeline::{Entry, Transform},
+    retry::Retries,
+    severity::parse_level,
+};
+
+/// What an entry has to match to fire an alert, either of them if both are given
+#[derive(Debug, Clone, Default)]
+pub struct Condition {
+    /// Fire on entries with a syslog level up to this one, e.g. 2 for critical and worse
+    pub max_level: Option<u64>,
+    /// Fire on entries matching this
+    pub pattern: Option<Regex>,
+}
+
+impl Condition {
+    pub fn matches(&self, entry: &Entry) -> bool {
+        let level_matches = self.max_level.is_some_and(|max| {
+            let level = json::get_u64(entry, "level")
+                .or_else(|| parse_level(&json::get_string(entry, "level")?));
+            level.is_some_and(|v| v <= max)
+        });
+        level_matches
+            || self
+                .pattern
+                .as_ref()
+                .is_some_and(|v| v.is_match(&entry.body))
+    }
+}
+
+/// The payload to send, with `{name}` replaced by the value of `name`: `body` for the entry
+/// itself, `sender`, `suppressed` for the number of alerts held back since the last one, a field
+/// added along the way, or else a top-level field of JSON entries. Values are escaped to go in
+/// JSON strings, unless rendered as text.
+#[derive(Debug, Clone)]
+pub struct Template(String);
+
+impl Template {
+    pub fn new(template: impl Into<String>) -> Self {
+        Self(template.into())
+    }
+
+    pub fn render(&self, entry: &Entry, suppressed: u64) -> String {
+        self.render_with(entry, suppressed, escape)
+    }
+
+    /// As [`Template::render`], with values as they are, e.g. for the subject of an email
+    pub fn render_text(&self, entry: &Entry, suppressed: u64) -> String {
+        self.render_with(entry, suppressed, str::to_string)
+    }
+
+    fn render_with(
+        &self,
+        entry: &Entry,
+        suppressed: u64,
+        escape: impl Fn(&str) -> String,
+    ) -> String {
+        let mut rendered = String::with_capacity(self.0.len());
+        let mut rest = self.0.as_str();
+        while let Some(start) = rest.find('{') {
+            rendered.push_str(&rest[..start]);
+            rest = &rest[start..];
+
+            let name = rest[1..]
+                .find('}')
+                .map(|end| &rest[1..end + 1])
+                .filter(|name| Self::is_name(name));
+            match name {
+                Some(name) => {
+                    let value = Self::value(name, entry, suppressed).unwrap_or_default();
+                    rendered.push_str(&escape(&value));
+                    rest = &rest[name.len() + 2..];
+                }
+                None => {
+                    rendered.push('{');
+                    rest = &rest[1..];
+                }
+            }
+        }
+        rendered.push_str(rest);
+        rendered
+    }
+
+    /// So that braces of a JSON template are left alone
+    fn is_name(name: &str) -> bool {
+        !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.' | '@'))
+    }
+
+    fn value<'a>(name: &str, entry: &'a Entry, suppressed: u64) -> Option<Cow<'a, str>> {
+        let value = match name {
+            "body" => entry.body.as_str().into(),
+            "sender" => entry.sender?.to_string().into(),
+            "suppressed" => suppressed.to_string().into(),
+            name => match entry.fields.get(name) {
+                Some(v) => v.as_str().into(),
+                None => json::get_string(entry, name)
+                    .or_else(|| json::get_f64(entry, name).map(|v| v.to_string()))?
+                    .into(),
+            },
+        };
+        Some(value)
+    }
+}
+
+fn escape(value: &str) -> String {
+    let quoted = serde_json::Value::from(value).to_string();
+    quoted[1..quoted.len() - 1].to_string()
+}
+
+/// Fires at most once per interval, counting what is held back in between
+#[derive(Debug)]
+pub struct RateLimit {
+    interval: Duration,
+    last: Option<Instant>,
+    suppressed: u64,
+}
+
+impl RateLimit {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last: None,
+            suppressed: 0,
+        }
+    }
+
+    /// The number of times held back before this one, if it may fire
+    pub fn fire(&mut self, now: Instant) -> Option<u64> {
+        if self
+            .last
+            .is_some_and(|last| now.duration_since(last) < self.interval)
+        {
+            self.suppressed += 1;
+            return None;
+        }
+        self.last = Some(now);
+        Some(std::mem::take(&mut self.suppressed))
+    }
+}
+
+/// Posts to a webhook when an entry matches a [`Condition`], leaving entries as they are.
+///
+/// Webhooks are posted in the background, so a slow or broken endpoint never holds up entries.
+pub struct Webhook {
+    client: reqwest::Client,
+    url: String,
+    condition: Condition,
+    template: Template,
+    rate_limit: RateLimit,
+    retries: Retries,
+}
+
+impl Webhook {
+    pub fn new(url: String, condition: Condition, template: Template, interval: Duration) -> Self {
+        Self {
+            client: Default::default(),
+            url,
+            condition,
+            template,
+            rate_limit: RateLimit::new(interval),
+            retries: Default::default(),
+        }
+    }
+
+    /// Retry failed alerts by `retries`
+    pub fn with_retries(mut self, retries: Retries) -> Self {
+        self.retries = retries;
+        self
+    }
+}
+
+impl Transform for Webhook {
+    fn apply(&mut self, entry: Entry) -> Option<Entry> {
+        if !self.condition.matches(&entry) {
+            return Some(entry);
+        }
+
+        let Some(suppressed) = self.rate_limit.fire(Instant::now()) else {
+            log::debug!("Alert held back by the rate limit");
+            return Some(entry);
+        };
+
+        let request = self
+            .client
+            .post(&self.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(self.template.render(&entry, suppressed));
+        let mut attempts = self.retries.attempts("Sending an alert");
+        spawn(async move {
+            loop {
+                let Some(request) = request.try_clone() else {
+                    break;
+                };
+                let rs = request.send().await.and_then(|v| v.error_for_status());
+                match rs {
+                    Ok(_) => {
+                        log::info!("Alert sent");
+                        break;
+                    }
+                    Err(e) => {
+                        if let Err(e) = attempts.failed(e.into()).await {
+                            log::warn!("Error sending alert: {e}");
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+
+        Some(entry)
+    }
+}
+
+/// Emails when an entry matches a [`Condition`], leaving entries as they are. Like [`Webhook`],
+/// in the background.
+pub struct EmailAlert {
+    mailer: Arc<Mailer>,
+    condition: Condition,
+    subject: Template,
+    body: Template,
+    rate_limit: RateLimit,
+    retries: Retries,
+}
+
+impl EmailAlert {
+    pub fn new(
+        mailer: Mailer,
+        condition: Condition,
+        subject: Template,
+        body: Template,
+        interval: Duration,
+    ) -> Self {
+        Self {
+            mailer: Arc::new(mailer),
+            condition,
+            subject,
+            body,
+            rate_limit: RateLimit::new(interval),
+            retries: Default::default(),
+        }
+    }
+
+    /// Retry failed alerts by `retries`
+    pub fn with_retries(mut self, retries: Retries) -> Self {
+        self.retries = retries;
+        self
+    }
+}
+
+impl Transform for EmailAlert {
+    fn apply(&mut self, entry: Entry) -> Option<Entry> {
+        if !self.condition.matches(&entry) {
+            return Some(entry);
+        }
+
+        let Some(suppressed) = self.rate_limit.fire(Instant::now()) else {
+            log::debug!("Alert email held back by the rate limit");
+            return Some(entry);
+        };
+
+        let mailer = self.mailer.clone();
+        let subject = self.subject.render_text(&entry, suppressed);
+        let body = self.body.render_text(&entry, suppressed);
+        let mut attempts = self.retries.attempts("Emailing an alert");
+        spawn(async move {
+            loop {
+                match mailer.send(&subject, &body).await {
+                    Ok(()) => {
+                        log::info!("Alert emailed to {}", mailer.to.join(", "));
+                        break;
+                    }
+                    Err(e) => {
+                        if let Err(e) = attempts.failed(e).await {
+                            log::warn!("Error emailing alert: {e:#}");
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+
+        Some(entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_templates() {
+        let mut entry = Entry {
+            sender: Some("10.0.0.1:1234".parse().unwrap()),
+            body: r#"{"short_message":"thread \"main\" panicked","level":2}"#.to_string(),
+            ..Default::default()
+        };
+        entry.fields.insert("env".to_string(), "prod".to_string());
+
+        let template = Template::new(
+            r#"{"text": "{env}/{sender}: {short_message} ({level}, {suppressed} more) {missing}{}"}"#,
+        );
+        assert_eq!(
+            r#"{"text": "prod/10.0.0.1:1234: thread \"main\" panicked (2, 3 more) {}"}"#,
+            template.render(&entry, 3)
+        );
+        assert_eq!(
+            r#"thread "main" panicked"#,
+            Template::new("{short_message}").render_text(&entry, 0)
+        );
+
+        let condition = Condition {
+            max_level: Some(2),
+            pattern: None,
+        };
+        assert!(condition.matches(&entry));
+        entry.set_body(r#"{"level":"warn"}"#.to_string());
+        assert!(!condition.matches(&entry));
+    }
+
+    #[test]
+    fn rate_limits() {
+        let mut limit = RateLimit {
+            interval: Duration::from_secs(60),
+            last: None,
+            suppressed: 0,
+        };
+        let now = Instant::now();
+        assert_eq!(Some(0), limit.fire(now));
+        assert_eq!(None, limit.fire(now + Duration::from_secs(1)));
+        assert_eq!(None, limit.fire(now + Duration::from_secs(59)));
+        assert_eq!(Some(2), limit.fire(now + Duration::from_secs(60)));
+    }
+}
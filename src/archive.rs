@@ -0,0 +1,207 @@
+//! Every entry written also kept in Parquet files, one folder per hour, for querying archives
+//! without touching the database
+
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use async_trait::async_trait;
+
+use crate::{
+    identity,
+    parquet::{self, Codec},
+    pipeline::{Entry, PendingCommit, Sink},
+    s3::Bucket,
+};
+
+const HOUR: u64 = 3600;
+
+/// Where the files go
+pub enum Store {
+    Dir(PathBuf),
+    S3 { bucket: Bucket, prefix: String },
+}
+
+impl Store {
+    async fn put(&self, key: &str, file: Vec<u8>) -> anyhow::Result<()> {
+        match self {
+            Self::Dir(dir) => {
+                let path = dir.join(key);
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)
+                        .with_context(|| format!("Creating {}", parent.display()))?;
+                }
+                // Renamed once complete, so readers never see half a file
+                let partial = path.with_extension("parquet.partial");
+                std::fs::write(&partial, file)
+                    .with_context(|| format!("Writing {}", partial.display()))?;
+                std::fs::rename(&partial, &path)
+                    .with_context(|| format!("Renaming {}", partial.display()))
+            }
+            Self::S3 { bucket, prefix } => {
+                let key = match prefix.trim_matches('/') {
+                    "" => key.to_string(),
+                    prefix => format!("{prefix}/{key}"),
+                };
+                bucket.put(&key, file).await
+            }
+        }
+    }
+}
+
+/// The hive-style partition of an hour since the epoch, e.g. `date=2024-01-02/hour=03`
+fn partition(hour: u64) -> String {
+    let at = humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(hour * HOUR));
+    let at = at.to_string();
+    format!("date={}/hour={}", &at[..10], &at[11..13])
+}
+
+fn hour_of(entry: &Entry) -> u64 {
+    let at = entry.received.map_or_else(SystemTime::now, |v| v.at);
+    at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / HOUR
+}
+
+/// Writes every batch to `inner`, if any, then holds on to the entries to write them to a
+/// Parquet file for their hour, once the hour is over, `max_rows` are held for it, or on closing
+pub struct ArchiveSink {
+    pub inner: Option<Box<dyn Sink>>,
+    store: Store,
+    codec: Codec,
+    max_rows: usize,
+    held: BTreeMap<u64, Vec<Entry>>,
+    files: u64,
+}
+
+impl ArchiveSink {
+    pub fn new(inner: Option<Box<dyn Sink>>, store: Store, codec: Codec, max_rows: usize) -> Self {
+        Self {
+            inner,
+            store,
+            codec,
+            max_rows: max_rows.max(1),
+            held: Default::default(),
+            files: 0,
+        }
+    }
+
+    fn hold(&mut self, entries: &[Entry]) {
+        for entry in entries {
+            self.held
+                .entry(hour_of(entry))
+                .or_default()
+                .push(entry.clone());
+        }
+    }
+
+    /// The hours due to be written, before `now`, or with `max_rows` held
+    fn due(&self, now: SystemTime) -> Vec<u64> {
+        let current = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / HOUR;
+        self.held
+            .iter()
+            .filter(|(hour, entries)| **hour < current || entries.len() >= self.max_rows)
+            .map(|(hour, _)| *hour)
+            .collect()
+    }
+
+    /// Write what is held for `hours`, holding on to what couldn't be, to try again
+    async fn write_hours(&mut self, hours: Vec<u64>) -> anyhow::Result<()> {
+        for hour in hours {
+            let Some(mut entries) = self.held.remove(&hour) else {
+                continue;
+            };
+            while !entries.is_empty() {
+                let rows = entries.len().min(self.max_rows);
+                let file = parquet::encode(&entries[..rows], self.codec)?;
+                let written = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis();
+                let key = format!(
+                    "{}/{}-{written}-{}.parquet",
+                    partition(hour),
+                    identity::get().instance_id,
+                    self.files
+                );
+                if let Err(e) = self.store.put(&key, file).await {
+                    self.held.insert(hour, entries);
+                    return Err(e);
+                }
+                self.files += 1;
+                entries.drain(..rows);
+                log::info!("Archived {rows} entries to {key}");
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Sink for ArchiveSink {
+    async fn write(&mut self, entries: &[Entry]) -> anyhow::Result<()> {
+        self.write_pending(entries).await?.await
+    }
+
+    async fn write_pending(&mut self, entries: &[Entry]) -> anyhow::Result<PendingCommit> {
+        let commit = match &mut self.inner {
+            Some(inner) => inner.write_pending(entries).await?,
+            None => Box::pin(async { Ok(()) }),
+        };
+
+        // Tried again with the next batch, rather than holding up the database
+        self.hold(entries);
+        let due = self.due(SystemTime::now());
+        if let Err(e) = self.write_hours(due).await {
+            log::warn!("Error archiving entries: {e:#}");
+        }
+        Ok(commit)
+    }
+
+    async fn tick(&mut self) -> anyhow::Result<()> {
+        match &mut self.inner {
+            Some(inner) => inner.tick().await,
+            None => Ok(()),
+        }
+    }
+
+    async fn close(&mut self) -> anyhow::Result<()> {
+        if let Some(inner) = &mut self.inner {
+            inner.close().await?;
+        }
+
+        let hours = self.held.keys().copied().collect();
+        self.write_hours(hours).await.context("Archiving entries")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pipeline::Received;
+
+    use super::*;
+
+    fn entry(secs: u64) -> Entry {
+        Entry {
+            received: Some(Received {
+                at: UNIX_EPOCH + Duration::from_secs(secs),
+                seq: 0,
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn partitions_by_hour() {
+        assert_eq!("date=1970-01-02/hour=03", partition(27));
+
+        let mut sink = ArchiveSink::new(None, Store::Dir(PathBuf::new()), Codec::None, 2);
+        sink.hold(&[entry(10), entry(HOUR + 10)]);
+        let now = UNIX_EPOCH + Duration::from_secs(HOUR + 20);
+        assert_eq!(vec![0], sink.due(now));
+
+        sink.hold(&[entry(HOUR + 30)]);
+        assert_eq!(vec![0, 1], sink.due(now));
+    }
+}
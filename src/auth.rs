@@ -0,0 +1,61 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Length of the HMAC-SHA256 trailer
+pub const TRAILER_LEN: usize = 32;
+
+/// Datagrams signed with a shared key: every datagram, including each chunk of a chunked
+/// message, ends with the HMAC-SHA256 of everything before it.
+#[derive(Clone)]
+pub struct DatagramAuth {
+    mac: HmacSha256,
+}
+
+impl DatagramAuth {
+    pub fn new(key: &[u8]) -> Self {
+        Self {
+            mac: HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length"),
+        }
+    }
+
+    /// The payload of a datagram whose trailer checks out, without the trailer
+    pub fn verify<'a>(&self, datagram: &'a [u8]) -> Option<&'a [u8]> {
+        let split = datagram.len().checked_sub(TRAILER_LEN)?;
+        let (payload, trailer) = datagram.split_at(split);
+
+        let mut mac = self.mac.clone();
+        mac.update(payload);
+        mac.verify_slice(trailer).ok().map(|_| payload)
+    }
+
+    /// The trailer to append to `payload`, as a sender would
+    pub fn sign(&self, payload: &[u8]) -> [u8; TRAILER_LEN] {
+        let mut mac = self.mac.clone();
+        mac.update(payload);
+        mac.finalize().into_bytes().into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signed_datagrams() {
+        let auth = DatagramAuth::new(b"secret");
+        let payload = br#"{"short_message":"hello"}"#;
+
+        let mut datagram = payload.to_vec();
+        datagram.extend_from_slice(&auth.sign(payload));
+        assert_eq!(Some(&payload[..]), auth.verify(&datagram));
+
+        assert_eq!(None, DatagramAuth::new(b"other").verify(&datagram));
+        assert_eq!(None, auth.verify(payload));
+        assert_eq!(None, auth.verify(&datagram[1..]));
+
+        datagram[0] ^= 1;
+        assert_eq!(None, auth.verify(&datagram));
+    }
+}
@@ -0,0 +1,210 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+/// When to stop listening to a sender for a while
+#[derive(Debug, Clone, Copy)]
+pub struct BanPolicy {
+    /// Undecodable datagrams allowed per window
+    pub max_errors: Option<u32>,
+    /// Datagrams allowed per window
+    pub max_datagrams: Option<u32>,
+    pub window: Duration,
+    pub ban_duration: Duration,
+}
+
+#[derive(Debug)]
+struct Activity {
+    window_started: Instant,
+    datagrams: u32,
+    errors: u32,
+    banned_until: Option<Instant>,
+}
+
+/// Senders that went over the thresholds of the [`BanPolicy`], and are ignored until their ban
+/// runs out
+#[derive(Debug)]
+pub struct BanList {
+    policy: BanPolicy,
+    senders: HashMap<IpAddr, Activity>,
+    num_bans: u64,
+    num_dropped: u64,
+}
+
+impl BanList {
+    pub fn new(policy: BanPolicy) -> Self {
+        Self {
+            policy,
+            senders: Default::default(),
+            num_bans: 0,
+            num_dropped: 0,
+        }
+    }
+
+    /// Count a datagram from `sender`, returning whether to go on with it
+    pub fn on_datagram(&mut self, sender: IpAddr, now: Instant) -> bool {
+        let max_datagrams = self.policy.max_datagrams;
+        let activity = self.activity(sender, now);
+        if activity.banned_until.is_some() {
+            self.num_dropped += 1;
+            return false;
+        }
+
+        activity.datagrams += 1;
+        if max_datagrams.is_some_and(|max| activity.datagrams > max) {
+            self.ban(sender, now, "datagrams");
+            self.num_dropped += 1;
+            return false;
+        }
+
+        true
+    }
+
+    /// Count a datagram from `sender` that couldn't be decoded
+    pub fn on_error(&mut self, sender: IpAddr, now: Instant) {
+        let max_errors = self.policy.max_errors;
+        let activity = self.activity(sender, now);
+        if activity.banned_until.is_some() {
+            return;
+        }
+
+        activity.errors += 1;
+        if max_errors.is_some_and(|max| activity.errors > max) {
+            self.ban(sender, now, "errors");
+        }
+    }
+
+    /// Forget about senders that are neither banned nor active in the current window
+    pub fn clean_up(&mut self, now: Instant) {
+        let window = self.policy.window;
+        self.senders
+            .retain(|_, activity| match activity.banned_until {
+                Some(until) => until > now,
+                None => now.duration_since(activity.window_started) < window,
+            });
+    }
+
+    pub fn is_banned(&self, sender: IpAddr, now: Instant) -> bool {
+        self.senders
+            .get(&sender)
+            .and_then(|v| v.banned_until)
+            .is_some_and(|until| until > now)
+    }
+
+    /// How many bans there have been so far
+    pub fn num_bans(&self) -> u64 {
+        self.num_bans
+    }
+
+    /// How many datagrams have been ignored for coming from banned senders so far
+    pub fn num_dropped(&self) -> u64 {
+        self.num_dropped
+    }
+
+    /// The activity of `sender` in the current window, starting a new one when the last one or
+    /// the ban is over
+    fn activity(&mut self, sender: IpAddr, now: Instant) -> &mut Activity {
+        let window = self.policy.window;
+        let activity = self.senders.entry(sender).or_insert(Activity {
+            window_started: now,
+            datagrams: 0,
+            errors: 0,
+            banned_until: None,
+        });
+
+        let over = match activity.banned_until {
+            Some(until) => until <= now,
+            None => now.duration_since(activity.window_started) >= window,
+        };
+        if over {
+            *activity = Activity {
+                window_started: now,
+                datagrams: 0,
+                errors: 0,
+                banned_until: None,
+            };
+        }
+
+        activity
+    }
+
+    fn ban(&mut self, sender: IpAddr, now: Instant, reason: &str) {
+        let duration = self.policy.ban_duration;
+        if let Some(activity) = self.senders.get_mut(&sender) {
+            activity.banned_until = Some(now + duration);
+        }
+
+        self.num_bans += 1;
+        log::warn!(
+            "Banned {sender} for {} after too many {reason} ({} bans so far)",
+            humantime::format_duration(duration),
+            self.num_bans
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> BanPolicy {
+        BanPolicy {
+            max_errors: Some(2),
+            max_datagrams: Some(5),
+            window: Duration::from_secs(10),
+            ban_duration: Duration::from_secs(60),
+        }
+    }
+
+    #[test]
+    fn bans_on_errors() {
+        let mut bans = BanList::new(policy());
+        let sender: IpAddr = "10.0.0.1".parse().unwrap();
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+        let now = Instant::now();
+
+        for _ in 0..2 {
+            assert!(bans.on_datagram(sender, now));
+            bans.on_error(sender, now);
+        }
+        assert!(!bans.is_banned(sender, now));
+
+        assert!(bans.on_datagram(sender, now));
+        bans.on_error(sender, now);
+        assert!(bans.is_banned(sender, now));
+        assert!(!bans.on_datagram(sender, now));
+        assert!(bans.on_datagram(other, now));
+        assert_eq!(1, bans.num_bans());
+        assert_eq!(1, bans.num_dropped());
+
+        let later = now + Duration::from_secs(61);
+        assert!(!bans.is_banned(sender, later));
+        assert!(bans.on_datagram(sender, later));
+    }
+
+    #[test]
+    fn bans_on_rate() {
+        let mut bans = BanList::new(policy());
+        let sender: IpAddr = "10.0.0.1".parse().unwrap();
+        let now = Instant::now();
+
+        for _ in 0..5 {
+            assert!(bans.on_datagram(sender, now));
+        }
+
+        // A new window starts afresh
+        let next_window = now + Duration::from_secs(10);
+        for _ in 0..5 {
+            assert!(bans.on_datagram(sender, next_window));
+        }
+        assert!(!bans.on_datagram(sender, next_window));
+        assert!(bans.is_banned(sender, next_window));
+
+        bans.clean_up(next_window + Duration::from_secs(30));
+        assert!(bans.is_banned(sender, next_window + Duration::from_secs(30)));
+        bans.clean_up(next_window + Duration::from_secs(60));
+        assert!(bans.senders.is_empty());
+    }
+}
@@ -0,0 +1,125 @@
+use std::time::{Duration, Instant};
+
+/// Decides how many entries go into each transaction.
+///
+/// With equal bounds this is just the static `--db-batch`. Otherwise the size doubles while
+/// committing takes at least as long as filling the batch did (the database is the bottleneck and
+/// packets pile up in the socket meanwhile) or a batch's worth of entries was already queued up
+/// behind it (a backlog to work through), and halves when filling the batch takes longer than
+/// `max_latency` (we are mostly idle and entries just sit around waiting).
+pub struct AdaptiveBatchSize {
+    min: usize,
+    max: usize,
+    current: usize,
+    max_latency: Duration,
+}
+
+impl AdaptiveBatchSize {
+    pub fn new(min: usize, max: usize, max_latency: Duration) -> Self {
+        let min = min.max(1);
+        let max = max.max(min);
+        Self {
+            min,
+            max,
+            current: min,
+            max_latency,
+        }
+    }
+
+    pub fn is_adaptive(&self) -> bool {
+        self.min < self.max
+    }
+
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    /// When a batch started at `started` should be flushed even if it isn't full yet.
+    pub fn deadline(&self, started: Instant) -> Option<Instant> {
+        self.is_adaptive().then(|| started + self.max_latency)
+    }
+
+    /// Feed back how long the last batch took to fill up and to commit, and how many entries were
+    /// queued up behind it when it was handed over.
+    pub fn on_commit(&mut self, fill: Duration, commit: Duration, queued: usize) {
+        let next = if commit >= fill || queued >= self.current {
+            (self.current * 2).min(self.max)
+        } else if fill > self.max_latency {
+            (self.current / 2).max(self.min)
+        } else {
+            self.current
+        };
+
+        if next != self.current {
+            log::debug!(
+                "Batch size {} -> {next} (fill = {fill:?}, commit = {commit:?}, queued = {queued})",
+                self.current
+            );
+            self.current = next;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    #[test]
+    fn static_size() {
+        let mut size = AdaptiveBatchSize::new(10, 10, 1000 * MS);
+        size.on_commit(MS, 100 * MS, 1000);
+        assert_eq!(10, size.current());
+        assert!(size.deadline(Instant::now()).is_none());
+    }
+
+    #[test]
+    fn grows_when_database_is_slow() {
+        let mut size = AdaptiveBatchSize::new(10, 35, 1000 * MS);
+        size.on_commit(10 * MS, 20 * MS, 0);
+        assert_eq!(20, size.current());
+        size.on_commit(10 * MS, 20 * MS, 0);
+        assert_eq!(35, size.current());
+        size.on_commit(10 * MS, 20 * MS, 0);
+        assert_eq!(35, size.current());
+    }
+
+    #[test]
+    fn grows_while_backing_up() {
+        let mut size = AdaptiveBatchSize::new(10, 80, 1000 * MS);
+        // Committing quickly, but with more than a batch waiting
+        size.on_commit(10 * MS, MS, 10);
+        assert_eq!(20, size.current());
+        size.on_commit(10 * MS, MS, 100);
+        assert_eq!(40, size.current());
+
+        // Less than a batch waiting, as the larger batches keep up
+        size.on_commit(10 * MS, MS, 39);
+        assert_eq!(40, size.current());
+        // Even when slow to fill up, for the backlog
+        size.on_commit(2000 * MS, MS, 40);
+        assert_eq!(80, size.current());
+    }
+
+    #[test]
+    fn shrinks_when_idle() {
+        let mut size = AdaptiveBatchSize::new(5, 40, 1000 * MS);
+        size.on_commit(MS, MS, 0);
+        size.on_commit(MS, MS, 0);
+        assert_eq!(20, size.current());
+
+        size.on_commit(2000 * MS, MS, 0);
+        assert_eq!(10, size.current());
+        size.on_commit(2000 * MS, MS, 0);
+        size.on_commit(2000 * MS, MS, 0);
+        assert_eq!(5, size.current());
+    }
+
+    #[test]
+    fn steady_within_latency() {
+        let mut size = AdaptiveBatchSize::new(5, 40, 1000 * MS);
+        size.on_commit(500 * MS, 10 * MS, 0);
+        assert_eq!(5, size.current());
+    }
+}
@@ -0,0 +1,140 @@
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use tokio::time::sleep_until;
+
+use crate::pipeline::{Entry, Sink};
+
+/// Stops trying a failing database for a while once writes have failed too many times in a row
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    max_failures: u32,
+    min_backoff: Duration,
+    max_backoff: Duration,
+    failures: u32,
+    /// Doubles every time a probe fails, up to `max_backoff`
+    backoff: Duration,
+    open_until: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub fn new(max_failures: u32, min_backoff: Duration, max_backoff: Duration) -> Self {
+        Self {
+            max_failures: max_failures.max(1),
+            min_backoff,
+            max_backoff,
+            failures: 0,
+            backoff: min_backoff,
+            open_until: None,
+        }
+    }
+
+    /// Until when writes shouldn't be tried, if the circuit is open
+    pub fn open_until(&self, now: Instant) -> Option<Instant> {
+        self.open_until.filter(|until| *until > now)
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.open_until.is_some()
+    }
+
+    pub fn on_success(&mut self) {
+        self.failures = 0;
+        self.backoff = self.min_backoff;
+        self.open_until = None;
+    }
+
+    /// Returns how long the circuit is open for, if this failure opened it
+    pub fn on_failure(&mut self, now: Instant) -> Option<Duration> {
+        self.failures += 1;
+        if self.failures < self.max_failures {
+            return None;
+        }
+
+        if self.is_open() {
+            // A probe failed, so the outage goes on
+            self.backoff = (self.backoff * 2).min(self.max_backoff);
+        }
+        self.open_until = Some(now + self.backoff);
+        Some(self.backoff)
+    }
+}
+
+/// Writes through a [`CircuitBreaker`]. Failed batches are tried again, but not while the
+/// circuit is open: they wait, or go to `divert` if there is one, until the next probe.
+pub struct BreakerSink {
+    pub inner: Box<dyn Sink>,
+    pub breaker: CircuitBreaker,
+    /// Where batches go while the circuit is open, e.g. a dead letter file
+    pub divert: Option<Box<dyn Sink>>,
+}
+
+#[async_trait]
+impl Sink for BreakerSink {
+    async fn write(&mut self, entries: &[Entry]) -> anyhow::Result<()> {
+        loop {
+            if let Some(until) = self.breaker.open_until(Instant::now()) {
+                match &mut self.divert {
+                    Some(divert) => {
+                        log::warn!("Circuit open, diverting {} entries", entries.len());
+                        return divert.write(entries).await;
+                    }
+                    None => sleep_until(until.into()).await,
+                }
+            }
+
+            match self.inner.write(entries).await {
+                Ok(()) => {
+                    if self.breaker.is_open() {
+                        log::info!("Writes are working again, closing circuit");
+                    }
+                    self.breaker.on_success();
+                    return Ok(());
+                }
+                Err(e) => {
+                    log::warn!("Error writing {} entries: {e:#}", entries.len());
+                    if let Some(backoff) = self.breaker.on_failure(Instant::now()) {
+                        log::warn!(
+                            "Circuit open, not writing for {}",
+                            humantime::format_duration(backoff)
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    async fn close(&mut self) -> anyhow::Result<()> {
+        if let Some(divert) = &mut self.divert {
+            divert.close().await?;
+        }
+        self.inner.close().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opens_and_backs_off() {
+        let mut breaker = CircuitBreaker::new(3, Duration::from_secs(1), Duration::from_secs(3));
+        let now = Instant::now();
+        let secs = Duration::from_secs;
+
+        assert_eq!(None, breaker.on_failure(now));
+        assert_eq!(None, breaker.on_failure(now));
+        assert_eq!(Some(secs(1)), breaker.on_failure(now));
+        assert_eq!(Some(now + secs(1)), breaker.open_until(now));
+        assert_eq!(None, breaker.open_until(now + secs(1)));
+
+        // Failed probes
+        assert_eq!(Some(secs(2)), breaker.on_failure(now + secs(1)));
+        assert_eq!(Some(secs(3)), breaker.on_failure(now + secs(3)));
+        assert_eq!(Some(secs(3)), breaker.on_failure(now + secs(6)));
+
+        breaker.on_success();
+        assert!(!breaker.is_open());
+        assert_eq!(None, breaker.on_failure(now + secs(8)));
+    }
+}
@@ -0,0 +1,129 @@
+//! Sending a share of the entries to other statements, e.g. into a new schema, to try them under
+//! real load while the rest goes on as before
+
+use anyhow::{bail, Context};
+use async_trait::async_trait;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    json,
+    pipeline::{Entry, PendingCommit, Sink},
+};
+
+/// Which entries go to the canary: `percent` of them, picked by the hash of a top-level field of
+/// JSON entries if given, so every entry of the same trace or request goes the same way, and
+/// else by the order they were received in
+#[derive(Debug, Clone)]
+pub struct Split {
+    /// Hashes below this go to the canary
+    threshold: u64,
+    field: Option<String>,
+}
+
+impl Split {
+    pub fn new(percent: f64, field: Option<String>) -> anyhow::Result<Self> {
+        if !(0.0..=100.0).contains(&percent) {
+            bail!("Canary percentage must be between 0 and 100, got {percent}");
+        }
+        Ok(Self {
+            threshold: (percent / 100.0 * u64::MAX as f64) as u64,
+            field,
+        })
+    }
+
+    /// Whether `entry` goes to the canary
+    pub fn is_canary(&self, entry: &Entry) -> bool {
+        let key = match &self.field {
+            Some(field) => json::get_string(entry, field),
+            None => None,
+        };
+        let key = key.unwrap_or_else(|| entry.received.map_or(0, |v| v.seq).to_string());
+        let digest = Sha256::digest(key.as_bytes());
+        let hash = u64::from_be_bytes(digest[..8].try_into().unwrap());
+        self.threshold == u64::MAX || hash < self.threshold
+    }
+}
+
+/// Writes the entries of [`Split`] to `canary` and the rest to `primary`, both as part of the
+/// same batch, so a failing canary fails the batch like a failing primary would
+pub struct CanarySink {
+    pub primary: Box<dyn Sink>,
+    pub canary: Box<dyn Sink>,
+    pub split: Split,
+}
+
+#[async_trait]
+impl Sink for CanarySink {
+    async fn write(&mut self, entries: &[Entry]) -> anyhow::Result<()> {
+        self.write_pending(entries).await?.await
+    }
+
+    async fn write_pending(&mut self, entries: &[Entry]) -> anyhow::Result<PendingCommit> {
+        let (canary, primary): (Vec<_>, Vec<_>) = entries
+            .iter()
+            .cloned()
+            .partition(|v| self.split.is_canary(v));
+
+        let mut pending = Vec::new();
+        if !primary.is_empty() {
+            pending.push(self.primary.write_pending(&primary).await?);
+        }
+        if !canary.is_empty() {
+            pending.push(
+                self.canary
+                    .write_pending(&canary)
+                    .await
+                    .context("Writing to the canary")?,
+            );
+        }
+        Ok(Box::pin(async move {
+            futures::future::try_join_all(pending).await?;
+            Ok(())
+        }))
+    }
+
+    async fn close(&mut self) -> anyhow::Result<()> {
+        self.canary.close().await?;
+        self.primary.close().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pipeline::Received;
+
+    use super::*;
+
+    fn entry(seq: u64, body: &str) -> Entry {
+        Entry {
+            received: Some(Received {
+                seq,
+                ..Received::now()
+            }),
+            body: body.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn splits() {
+        let split = Split::new(10.0, None).unwrap();
+        let canary = (0..1000)
+            .filter(|i| split.is_canary(&entry(*i, "")))
+            .count();
+        assert!((70..130).contains(&canary), "canary {canary}");
+
+        let split = Split::new(50.0, Some("trace_id".to_string())).unwrap();
+        for i in 0..100 {
+            let body = format!(r#"{{"trace_id":"t{i}"}}"#);
+            assert_eq!(
+                split.is_canary(&entry(0, &body)),
+                split.is_canary(&entry(i + 1, &body))
+            );
+        }
+
+        assert!(!Split::new(0.0, None).unwrap().is_canary(&entry(1, "")));
+        assert!(Split::new(100.0, None).unwrap().is_canary(&entry(1, "")));
+        assert!(Split::new(101.0, None).is_err());
+    }
+}
@@ -0,0 +1,326 @@
+use std::{
+    io::{self, BufRead, Write},
+    net::{TcpStream, UdpSocket},
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use clap::ValueEnum;
+use serde_json::{json, Value};
+use sqlx_logger::identity;
+
+/// Most chunks GELF allows a message to be split into
+const MAX_CHUNKS: usize = 128;
+
+#[derive(Debug, ValueEnum, Clone, Copy)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zlib,
+    Zstd,
+}
+
+/// Where and how to send messages to
+#[derive(Debug, clap::Args)]
+pub struct Target {
+    /// The address the daemon listens on
+    #[arg(long, default_value = "127.0.0.1:9000")]
+    to: String,
+
+    /// Send over TCP, terminating every message with a null byte, instead of UDP
+    #[arg(long)]
+    tcp: bool,
+
+    /// Compress every message first. UDP only
+    #[arg(long, default_value = "none", conflicts_with = "tcp")]
+    compress: Compression,
+
+    /// Split UDP messages longer than this into chunks
+    #[arg(long, default_value = "8192")]
+    chunk_size: usize,
+
+    /// Wait for the reply to every datagram and print it, from a daemon run with --udp-ack
+    #[arg(long, conflicts_with = "tcp")]
+    ack: bool,
+}
+
+/// How long to wait for the reply to a datagram with --ack
+const ACK_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, clap::Args)]
+pub struct SendArgs {
+    #[command(flatten)]
+    target: Target,
+
+    /// The host of messages that aren't GELF already. Defaults to ours
+    #[arg(long)]
+    host: Option<String>,
+
+    /// The syslog level of messages that aren't GELF already
+    #[arg(long)]
+    level: Option<u8>,
+
+    /// The messages to send. A JSON object is sent as is, anything else as the short message of
+    /// one. Read one per line from stdin if none are given
+    messages: Vec<String>,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct BenchArgs {
+    #[command(flatten)]
+    target: Target,
+
+    /// How many messages to send
+    #[arg(long, default_value = "100000")]
+    count: u64,
+
+    /// Messages per second to send at. As fast as possible if not given
+    #[arg(long)]
+    rate: Option<f64>,
+
+    /// Roughly how long each message is, in bytes
+    #[arg(long, default_value = "200")]
+    size: usize,
+}
+
+enum Connection {
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+}
+
+struct Sender {
+    connection: Connection,
+    compress: Compression,
+    chunk_size: usize,
+    ack: bool,
+}
+
+impl Sender {
+    fn connect(target: &Target) -> anyhow::Result<Self> {
+        let connection = if target.tcp {
+            let stream = TcpStream::connect(&target.to)
+                .with_context(|| format!("Connecting to tcp://{}", target.to))?;
+            Connection::Tcp(stream)
+        } else {
+            let socket = UdpSocket::bind("0.0.0.0:0").context("Binding UDP socket")?;
+            socket
+                .connect(&target.to)
+                .with_context(|| format!("Connecting to udp://{}", target.to))?;
+            socket.set_read_timeout(Some(ACK_TIMEOUT))?;
+            Connection::Udp(socket)
+        };
+
+        // Room for the chunk header, and for at least a byte of the message each
+        if target.chunk_size <= 12 {
+            bail!(
+                "--chunk-size must be more than 12, got {}",
+                target.chunk_size
+            );
+        }
+
+        Ok(Self {
+            connection,
+            compress: target.compress,
+            chunk_size: target.chunk_size,
+            ack: target.ack,
+        })
+    }
+
+    fn send(&mut self, message: &Value) -> anyhow::Result<()> {
+        let payload = serde_json::to_vec(message).context("Encoding message")?;
+        match &mut self.connection {
+            Connection::Tcp(stream) => {
+                stream.write_all(&payload).context("Sending message")?;
+                stream.write_all(b"\0").context("Sending message")
+            }
+            Connection::Udp(socket) => {
+                let payload = compress(self.compress, payload)?;
+                for datagram in chunks(&payload, self.chunk_size)? {
+                    socket.send(&datagram).context("Sending message")?;
+                    if self.ack {
+                        let mut reply = [0u8; 1024];
+                        match socket.recv(&mut reply) {
+                            Ok(n) => print!("{}", String::from_utf8_lossy(&reply[..n])),
+                            Err(e) => println!("No reply: {e}"),
+                        }
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        match &mut self.connection {
+            Connection::Tcp(stream) => stream.flush().context("Sending messages"),
+            Connection::Udp(_) => Ok(()),
+        }
+    }
+}
+
+fn compress(compression: Compression, payload: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    use flate2::{write::GzEncoder, write::ZlibEncoder};
+
+    let compressed = match compression {
+        Compression::None => return Ok(payload),
+        Compression::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(&payload)?;
+            encoder.finish()?
+        }
+        Compression::Zlib => {
+            let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(&payload)?;
+            encoder.finish()?
+        }
+        Compression::Zstd => zstd::encode_all(payload.as_slice(), 0)?,
+    };
+    Ok(compressed)
+}
+
+/// The datagrams to send `payload` as, chunked as GELF does if it's longer than `chunk_size`
+fn chunks(payload: &[u8], chunk_size: usize) -> anyhow::Result<Vec<Vec<u8>>> {
+    if payload.len() <= chunk_size {
+        return Ok(vec![payload.to_vec()]);
+    }
+
+    let parts: Vec<_> = payload.chunks(chunk_size - 12).collect();
+    if parts.len() > MAX_CHUNKS {
+        bail!(
+            "Message of {} bytes needs {} chunks, more than {MAX_CHUNKS}",
+            payload.len(),
+            parts.len()
+        );
+    }
+
+    let id = OsRng.next_u64().to_be_bytes();
+    Ok(parts
+        .iter()
+        .enumerate()
+        .map(|(seq, part)| {
+            let mut datagram = vec![0x1e, 0x0f];
+            datagram.extend_from_slice(&id);
+            datagram.extend_from_slice(&[seq as u8, parts.len() as u8]);
+            datagram.extend_from_slice(part);
+            datagram
+        })
+        .collect())
+}
+
+/// `text` as a GELF message, unless it's one already
+fn to_gelf(text: &str, host: &str, level: Option<u8>) -> Value {
+    if let Ok(message @ Value::Object(_)) = serde_json::from_str(text) {
+        return message;
+    }
+
+    let mut message = json!({
+        "version": "1.1",
+        "host": host,
+        "short_message": text,
+    });
+    if let Some(level) = level {
+        message["level"] = level.into();
+    }
+    message
+}
+
+pub fn send(
+    SendArgs {
+        target,
+        host,
+        level,
+        messages,
+    }: SendArgs,
+) -> anyhow::Result<()> {
+    let host = host.unwrap_or_else(|| identity::init(None).hostname.clone());
+    let mut sender = Sender::connect(&target)?;
+
+    let mut sent = 0;
+    if messages.is_empty() {
+        for line in io::stdin().lock().lines() {
+            let line = line.context("Reading stdin")?;
+            if !line.trim().is_empty() {
+                sender.send(&to_gelf(&line, &host, level))?;
+                sent += 1;
+            }
+        }
+    } else {
+        for text in &messages {
+            sender.send(&to_gelf(text, &host, level))?;
+            sent += 1;
+        }
+    }
+    sender.flush()?;
+
+    log::info!("Sent {sent} messages to {}", target.to);
+    Ok(())
+}
+
+pub fn bench(
+    BenchArgs {
+        target,
+        count,
+        rate,
+        size,
+    }: BenchArgs,
+) -> anyhow::Result<()> {
+    if let Some(rate) = rate.filter(|v| *v <= 0.0) {
+        bail!("--rate must be positive, got {rate}");
+    }
+
+    let mut sender = Sender::connect(&target)?;
+    let padding = "x".repeat(size.saturating_sub(100));
+    let started = Instant::now();
+    for seq in 0..count {
+        if let Some(rate) = rate {
+            let due = started + Duration::from_secs_f64(seq as f64 / rate);
+            if let Some(wait) = due.checked_duration_since(Instant::now()) {
+                sleep(wait);
+            }
+        }
+
+        sender.send(&json!({
+            "version": "1.1",
+            "host": "sqlx_logger-bench",
+            "short_message": format!("Bench message {seq} {padding}"),
+            "level": 6,
+            "_seq": seq,
+        }))?;
+    }
+    sender.flush()?;
+
+    let elapsed = started.elapsed();
+    println!(
+        "Sent {count} messages to {} in {}, {:.0} messages/s",
+        target.to,
+        humantime::format_duration(Duration::from_millis(elapsed.as_millis() as u64)),
+        count as f64 / elapsed.as_secs_f64().max(f64::EPSILON)
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_long_messages() {
+        assert_eq!(vec![b"short".to_vec()], chunks(b"short", 13).unwrap());
+
+        let datagrams = chunks(b"abcdefghijklmnop", 15).unwrap();
+        assert_eq!(6, datagrams.len());
+        let mut reassembled = Vec::new();
+        for (seq, datagram) in datagrams.iter().enumerate() {
+            assert!(datagram.len() <= 15);
+            assert_eq!([0x1e, 0x0f], datagram[..2]);
+            assert_eq!(datagrams[0][2..10], datagram[2..10]);
+            assert_eq!([seq as u8, 6], datagram[10..12]);
+            reassembled.extend_from_slice(&datagram[12..]);
+        }
+        assert_eq!(b"abcdefghijklmnop", reassembled.as_slice());
+
+        assert!(chunks(&[0; 129], 13).is_err());
+    }
+}
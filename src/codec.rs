@@ -0,0 +1,173 @@
+use bytes::{BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::gelf::GelfError;
+
+/// Longest message accepted by default, as GELF over streams has no limit of its own
+const DEFAULT_MAX_LENGTH: usize = 1 << 20;
+
+/// GELF over stream transports such as TCP, where every message is terminated by a null byte.
+///
+/// Empty frames are skipped, and so are frames that aren't valid UTF-8, after logging them.
+/// A frame longer than the maximum length is an error, as we can't tell where the next one starts
+/// without reading the whole thing.
+pub struct GelfCodec {
+    max_length: usize,
+    /// Where to continue looking for the null byte, so a long frame isn't scanned over and over
+    next_index: usize,
+}
+
+impl Default for GelfCodec {
+    fn default() -> Self {
+        Self::with_max_length(DEFAULT_MAX_LENGTH)
+    }
+}
+
+impl GelfCodec {
+    pub fn with_max_length(max_length: usize) -> Self {
+        Self {
+            max_length,
+            next_index: 0,
+        }
+    }
+
+    fn frame_to_string(frame: &[u8]) -> Option<String> {
+        if frame.is_empty() {
+            return None;
+        }
+
+        match std::str::from_utf8(frame) {
+            Ok(v) => Some(v.to_string()),
+            Err(e) => {
+                log::error!("Error handling incoming data: {e:?}");
+                None
+            }
+        }
+    }
+}
+
+impl Decoder for GelfCodec {
+    type Item = String;
+    type Error = GelfError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<String>, GelfError> {
+        loop {
+            let end = match src[self.next_index..].iter().position(|b| *b == 0) {
+                Some(pos) => self.next_index + pos,
+                None if src.len() > self.max_length => {
+                    return Err(GelfError::MessageTooLong {
+                        max_length: self.max_length,
+                    })
+                }
+                None => {
+                    self.next_index = src.len();
+                    return Ok(None);
+                }
+            };
+
+            self.next_index = 0;
+            if end > self.max_length {
+                return Err(GelfError::MessageTooLong {
+                    max_length: self.max_length,
+                });
+            }
+
+            let frame = src.split_to(end + 1);
+            if let Some(v) = Self::frame_to_string(&frame[..end]) {
+                return Ok(Some(v));
+            }
+        }
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<String>, GelfError> {
+        if let Some(v) = self.decode(src)? {
+            return Ok(Some(v));
+        }
+
+        // The last message may go without its null byte when the connection is closed
+        self.next_index = 0;
+        let frame = src.split();
+        Ok(Self::frame_to_string(&frame))
+    }
+}
+
+impl<T: AsRef<str>> Encoder<T> for GelfCodec {
+    type Error = GelfError;
+
+    fn encode(&mut self, item: T, dst: &mut BytesMut) -> Result<(), GelfError> {
+        let item = item.as_ref().as_bytes();
+        if item.len() > self.max_length {
+            return Err(GelfError::MessageTooLong {
+                max_length: self.max_length,
+            });
+        }
+
+        if item.contains(&0) {
+            return Err(GelfError::NullInMessage);
+        }
+
+        dst.reserve(item.len() + 1);
+        dst.put_slice(item);
+        dst.put_u8(0);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_frames() {
+        let mut codec = GelfCodec::default();
+        let mut buf = BytesMut::from(&b"{\"a\":1}\0\0\xff\xfe\0{\"b\""[..]);
+
+        assert_eq!(
+            Some("{\"a\":1}".to_string()),
+            codec.decode(&mut buf).unwrap()
+        );
+        assert_eq!(None, codec.decode(&mut buf).unwrap());
+
+        buf.extend_from_slice(b":2}\0{\"c\":3}");
+        assert_eq!(
+            Some("{\"b\":2}".to_string()),
+            codec.decode(&mut buf).unwrap()
+        );
+        assert_eq!(None, codec.decode(&mut buf).unwrap());
+        assert_eq!(
+            Some("{\"c\":3}".to_string()),
+            codec.decode_eof(&mut buf).unwrap()
+        );
+        assert_eq!(None, codec.decode_eof(&mut buf).unwrap());
+    }
+
+    #[test]
+    fn decode_too_long() {
+        let mut codec = GelfCodec::with_max_length(4);
+        let mut buf = BytesMut::from(&b"1234\0"[..]);
+        assert_eq!(Some("1234".to_string()), codec.decode(&mut buf).unwrap());
+
+        buf.extend_from_slice(b"12345");
+        assert!(matches!(
+            codec.decode(&mut buf),
+            Err(GelfError::MessageTooLong { max_length: 4 })
+        ));
+    }
+
+    #[test]
+    fn encode_round_trip() {
+        let mut codec = GelfCodec::default();
+        let mut buf = BytesMut::new();
+        codec.encode("hello", &mut buf).unwrap();
+        codec.encode("world".to_string(), &mut buf).unwrap();
+        assert_eq!(&b"hello\0world\0"[..], &buf[..]);
+
+        assert_eq!(Some("hello".to_string()), codec.decode(&mut buf).unwrap());
+        assert_eq!(Some("world".to_string()), codec.decode(&mut buf).unwrap());
+
+        assert!(matches!(
+            codec.encode("a\0b", &mut buf),
+            Err(GelfError::NullInMessage)
+        ));
+    }
+}
@@ -0,0 +1,109 @@
+use std::{borrow::Cow, io::Read};
+
+use flate2::read::{GzDecoder, ZlibDecoder};
+
+use crate::gelf::GelfError;
+
+const GZIP_MAGIC_BYTES: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC_BYTES: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// Far beyond any real message, but a payload of 128 chunks can't blow up into gigabytes
+pub const MAX_DECOMPRESSED_LEN: usize = 64 << 20;
+
+/// GELF senders may compress a payload with GZIP or ZLIB, or with Zstandard outside of the spec,
+/// which is told apart by its first bytes. Anything else is passed through as it is. Payloads
+/// decompressing to more than `max_length` bytes are refused.
+pub fn decompress(data: Cow<[u8]>, max_length: usize) -> Result<Cow<[u8]>, GelfError> {
+    let output = if data.starts_with(GZIP_MAGIC_BYTES) {
+        read_limited(GzDecoder::new(data.as_ref()), max_length)?
+    } else if data.starts_with(ZSTD_MAGIC_BYTES) {
+        let decoder = zstd::Decoder::new(data.as_ref()).map_err(GelfError::Decompress)?;
+        read_limited(decoder, max_length)?
+    } else if is_zlib(&data) {
+        read_limited(ZlibDecoder::new(data.as_ref()), max_length)?
+    } else {
+        return Ok(data);
+    };
+
+    Ok(Cow::Owned(output))
+}
+
+fn read_limited(decoder: impl Read, max_length: usize) -> Result<Vec<u8>, GelfError> {
+    let mut output = vec![];
+    decoder
+        .take(max_length as u64 + 1)
+        .read_to_end(&mut output)
+        .map_err(GelfError::Decompress)?;
+    if output.len() > max_length {
+        return Err(GelfError::DecompressedTooLong { max_length });
+    }
+    Ok(output)
+}
+
+/// A ZLIB header says "deflate" in its low bits and is a multiple of 31 as a whole
+fn is_zlib(data: &[u8]) -> bool {
+    match data {
+        [cmf, flg, ..] => cmf & 0x0f == 8 && (u16::from(*cmf) << 8 | u16::from(*flg)) % 31 == 0,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{
+        write::{GzEncoder, ZlibEncoder},
+        Compression,
+    };
+
+    use super::*;
+
+    const MESSAGE: &str = r#"{"version":"1.1","short_message":"hello"}"#;
+
+    #[test]
+    fn plain_payload() {
+        let actual = decompress(Cow::Borrowed(MESSAGE.as_bytes()), MAX_DECOMPRESSED_LEN).unwrap();
+        assert!(matches!(actual, Cow::Borrowed(_)));
+        assert_eq!(MESSAGE.as_bytes(), actual.as_ref());
+    }
+
+    #[test]
+    fn compressed_payloads() {
+        let mut gzip = GzEncoder::new(vec![], Compression::default());
+        gzip.write_all(MESSAGE.as_bytes()).unwrap();
+        let gzip = gzip.finish().unwrap();
+
+        let mut zlib = ZlibEncoder::new(vec![], Compression::default());
+        zlib.write_all(MESSAGE.as_bytes()).unwrap();
+        let zlib = zlib.finish().unwrap();
+
+        let zstd = zstd::encode_all(MESSAGE.as_bytes(), 0).unwrap();
+
+        for input in [gzip, zlib, zstd] {
+            let actual = decompress(Cow::Owned(input), MAX_DECOMPRESSED_LEN).unwrap();
+            assert_eq!(MESSAGE.as_bytes(), actual.as_ref());
+        }
+
+        assert!(matches!(
+            decompress(Cow::Borrowed(&[0x1f, 0x8b, 0, 0]), MAX_DECOMPRESSED_LEN),
+            Err(GelfError::Decompress(_))
+        ));
+    }
+
+    #[test]
+    fn compression_bombs() {
+        let zeros = vec![0u8; MAX_DECOMPRESSED_LEN + 1];
+        let bomb = zstd::encode_all(zeros.as_slice(), 3).unwrap();
+        assert!(matches!(
+            decompress(Cow::Owned(bomb), MAX_DECOMPRESSED_LEN),
+            Err(GelfError::DecompressedTooLong { .. })
+        ));
+
+        let zstd = zstd::encode_all(MESSAGE.as_bytes(), 0).unwrap();
+        assert!(matches!(
+            decompress(Cow::Owned(zstd), MESSAGE.len() - 1),
+            Err(GelfError::DecompressedTooLong { max_length }) if max_length == MESSAGE.len() - 1
+        ));
+    }
+}
@@ -0,0 +1,196 @@
+//! Making inserts safe to repeat, for retries and duplicate deliveries, by adding what each
+//! database needs to skip or replace rows already there
+
+use anyhow::bail;
+use clap::ValueEnum;
+use sqlx::any::AnyKind;
+
+use crate::placeholders;
+
+/// What an insert does with a row already there, by its primary key or a unique constraint
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OnConflict {
+    /// Fail, as the SQL is given
+    Error,
+    /// Keep the row already there
+    Ignore,
+    /// Replace the row already there
+    Replace,
+}
+
+/// Where each of `keywords` is in the code of `sql`, as a word of its own, ignoring case
+fn find_keywords(sql: &str, keywords: &[&str]) -> Vec<usize> {
+    let mut found = Vec::new();
+    placeholders::replace_in_code(sql, |rest| {
+        let at = sql.len() - rest.len();
+        let starts_word = !sql[..at].ends_with(|v: char| v.is_alphanumeric() || v == '_');
+        let matched = keywords.iter().any(|keyword| {
+            rest.get(..keyword.len())
+                .is_some_and(|v| v.eq_ignore_ascii_case(keyword))
+                && !rest[keyword.len()..].starts_with(|v: char| v.is_alphanumeric() || v == '_')
+        });
+        if starts_word && matched {
+            found.push(at);
+        }
+        None
+    });
+    found
+}
+
+/// The columns inserted into by `INSERT INTO table (a, b, ...)`, if listed
+fn inserted_columns(sql: &str) -> Option<Vec<String>> {
+    let into = *find_keywords(sql, &["INTO"]).first()?;
+    let after = &sql[into + 4..];
+    let open = after.find('(')?;
+    // No VALUES or SELECT between the table and the list
+    if !find_keywords(&after[..open], &["VALUES", "SELECT"]).is_empty() {
+        return None;
+    }
+    let close = open + after[open..].find(')')?;
+    Some(
+        after[open + 1..close]
+            .split(',')
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+            .collect(),
+    )
+}
+
+/// `sql` rewritten to do `on_conflict` on a database of `kind`, with `key` the columns a
+/// replaced row is found by, which Postgres needs spelled out. Statements other than inserts,
+/// and inserts already saying what to do, are left as they are.
+pub fn rewrite(
+    sql: &str,
+    on_conflict: OnConflict,
+    kind: AnyKind,
+    key: &[String],
+) -> anyhow::Result<String> {
+    let trimmed = sql.trim().trim_end_matches(';').trim_end();
+    let is_insert = trimmed
+        .get(..6)
+        .is_some_and(|v| v.eq_ignore_ascii_case("INSERT"));
+    if on_conflict == OnConflict::Error || !is_insert {
+        return Ok(sql.to_string());
+    }
+    let rest = &trimmed[6..];
+    let first = rest.split_whitespace().next().unwrap_or_default();
+    let handled = ["OR", "IGNORE"]
+        .iter()
+        .any(|v| first.eq_ignore_ascii_case(v))
+        || !find_keywords(trimmed, &["CONFLICT", "DUPLICATE"]).is_empty();
+    if handled {
+        return Ok(sql.to_string());
+    }
+
+    Ok(match (on_conflict, kind) {
+        (OnConflict::Ignore, AnyKind::Sqlite) => format!("INSERT OR IGNORE{rest}"),
+        (OnConflict::Replace, AnyKind::Sqlite) => format!("INSERT OR REPLACE{rest}"),
+        (OnConflict::Ignore, AnyKind::MySql) => format!("INSERT IGNORE{rest}"),
+        (OnConflict::Replace, AnyKind::MySql) => format!("REPLACE{rest}"),
+        (_, AnyKind::Postgres) => {
+            let clause = match on_conflict {
+                OnConflict::Replace => {
+                    if key.is_empty() {
+                        bail!("Replacing rows on Postgres needs --conflict-key: {sql}");
+                    }
+                    let Some(columns) = inserted_columns(trimmed) else {
+                        bail!("Replacing rows needs the columns inserted into listed: {sql}");
+                    };
+                    let set: Vec<_> = columns
+                        .iter()
+                        .filter(|v| !key.contains(v))
+                        .map(|v| format!("{v} = EXCLUDED.{v}"))
+                        .collect();
+                    match set.is_empty() {
+                        true => format!("ON CONFLICT ({}) DO NOTHING", key.join(", ")),
+                        false => format!(
+                            "ON CONFLICT ({}) DO UPDATE SET {}",
+                            key.join(", "),
+                            set.join(", ")
+                        ),
+                    }
+                }
+                _ => "ON CONFLICT DO NOTHING".to_string(),
+            };
+            // Before what it returns, if anything
+            match find_keywords(trimmed, &["RETURNING"]).last() {
+                Some(at) => format!("{} {clause} {}", trimmed[..*at].trim_end(), &trimmed[*at..]),
+                None => format!("{trimmed} {clause}"),
+            }
+        }
+        (_, kind) => bail!("--on-conflict isn't supported on {kind:?}: {sql}"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INSERT: &str = "INSERT INTO logs(id, body) VALUES ($1, $2)";
+
+    fn rewritten(on_conflict: OnConflict, kind: AnyKind) -> String {
+        rewrite(INSERT, on_conflict, kind, &["id".to_string()]).unwrap()
+    }
+
+    #[test]
+    fn per_database() {
+        assert_eq!(INSERT, rewritten(OnConflict::Error, AnyKind::Postgres));
+        assert_eq!(
+            "INSERT INTO logs(id, body) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+            rewritten(OnConflict::Ignore, AnyKind::Postgres)
+        );
+        assert_eq!(
+            "INSERT INTO logs(id, body) VALUES ($1, $2) ON CONFLICT (id) DO UPDATE SET body = EXCLUDED.body",
+            rewritten(OnConflict::Replace, AnyKind::Postgres)
+        );
+        assert_eq!(
+            "INSERT OR IGNORE INTO logs(id, body) VALUES ($1, $2)",
+            rewritten(OnConflict::Ignore, AnyKind::Sqlite)
+        );
+        assert_eq!(
+            "INSERT OR REPLACE INTO logs(id, body) VALUES ($1, $2)",
+            rewritten(OnConflict::Replace, AnyKind::Sqlite)
+        );
+        assert_eq!(
+            "INSERT IGNORE INTO logs(id, body) VALUES ($1, $2)",
+            rewritten(OnConflict::Ignore, AnyKind::MySql)
+        );
+        assert_eq!(
+            "REPLACE INTO logs(id, body) VALUES ($1, $2)",
+            rewritten(OnConflict::Replace, AnyKind::MySql)
+        );
+        assert!(rewrite(INSERT, OnConflict::Ignore, AnyKind::Mssql, &[]).is_err());
+        assert!(rewrite(INSERT, OnConflict::Replace, AnyKind::Postgres, &[]).is_err());
+    }
+
+    #[test]
+    fn leaves_what_is_not_a_plain_insert() {
+        let returning = "INSERT INTO logs(body) VALUES ($1) RETURNING id;";
+        assert_eq!(
+            "INSERT INTO logs(body) VALUES ($1) ON CONFLICT DO NOTHING RETURNING id",
+            rewrite(returning, OnConflict::Ignore, AnyKind::Postgres, &[]).unwrap()
+        );
+
+        for sql in [
+            "UPDATE hosts SET seen = $1",
+            "INSERT INTO logs(body) VALUES ($1) ON CONFLICT DO NOTHING",
+            "insert or ignore into logs(body) values ($1)",
+            "INSERT IGNORE INTO logs(body) VALUES ($1)",
+        ] {
+            assert_eq!(
+                sql,
+                rewrite(sql, OnConflict::Ignore, AnyKind::Sqlite, &[]).unwrap()
+            );
+        }
+        assert_eq!(
+            "INSERT OR IGNORE INTO logs(body) VALUES ('or ignore')",
+            rewrite(
+                "INSERT INTO logs(body) VALUES ('or ignore')",
+                OnConflict::Ignore,
+                AnyKind::Sqlite,
+                &[]
+            )
+            .unwrap()
+        );
+    }
+}
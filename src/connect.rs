@@ -0,0 +1,84 @@
+//! Connecting pools to the database, the same way wherever it's done: on startup, when failing
+//! over and when credentials rotate
+
+use anyhow::Context;
+use sqlx::{any::AnyPoolOptions, AnyConnection, AnyPool, Connection, Executor};
+
+use crate::tls::DbTls;
+
+/// How to connect to the database, whatever its URL
+#[derive(Debug, Clone, Default)]
+pub struct Connector {
+    pub tls: DbTls,
+    /// Run on every new connection before it's used, e.g. `SET synchronous_commit = off`
+    pub init_sql: Vec<String>,
+}
+
+impl Connector {
+    /// Connect a pool to `url`
+    pub async fn connect(&self, url: &str) -> anyhow::Result<AnyPool> {
+        self.connect_with(AnyPoolOptions::new(), url).await
+    }
+
+    /// Connect a pool of `pool` options to `url`
+    pub async fn connect_with(&self, pool: AnyPoolOptions, url: &str) -> anyhow::Result<AnyPool> {
+        let options = self.tls.options(url)?;
+        let pool = match self.init_sql.as_slice() {
+            [] => pool,
+            init_sql => {
+                let init_sql = init_sql.to_vec();
+                pool.after_connect(move |conn, _| {
+                    let init_sql = init_sql.clone();
+                    Box::pin(async move {
+                        for sql in &init_sql {
+                            conn.execute(sql.as_str()).await?;
+                        }
+                        Ok(())
+                    })
+                })
+            }
+        };
+        if self.init_sql.is_empty() {
+            return Ok(pool.connect_with(options).await?);
+        }
+
+        // Tried on a connection of its own first: failing within the pool, it'd only be retried
+        // until the pool times out, with the error lost
+        let mut conn = AnyConnection::connect_with(&options).await?;
+        for sql in &self.init_sql {
+            conn.execute(sql.as_str())
+                .await
+                .with_context(|| format!("Executing SQL on connecting: {sql}"))?;
+        }
+        let _ = conn.close().await;
+        Ok(pool.connect_with(options).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::Row;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn runs_init_sql_on_every_connection() {
+        let connector = Connector {
+            init_sql: vec!["PRAGMA user_version = 7".to_string()],
+            ..Default::default()
+        };
+        let pool = connector.connect("sqlite::memory:").await.unwrap();
+        let version: i32 = sqlx::query("PRAGMA user_version")
+            .fetch_one(&pool)
+            .await
+            .unwrap()
+            .get(0);
+        assert_eq!(7, version);
+
+        let failing = Connector {
+            init_sql: vec!["SET nothing".to_string()],
+            ..Default::default()
+        };
+        assert!(failing.connect("sqlite::memory:").await.is_err());
+    }
+}
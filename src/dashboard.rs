@@ -0,0 +1,248 @@
+//! A live view of the daemon in the terminal, redrawn every second, for a quick look at what it's
+//! doing on a box over SSH
+
+use std::{
+    collections::VecDeque,
+    fmt::Write as _,
+    net::IpAddr,
+    sync::{atomic::Ordering, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use tokio::{
+    io::AsyncWriteExt,
+    time::{interval, MissedTickBehavior},
+};
+
+use crate::{
+    health::{Event, Health},
+    memory, metrics,
+    overload::Overload,
+    source_stats::SourceStats,
+    timestamp,
+};
+
+/// How many of the latest log lines are shown
+const RECENT_LINES: usize = 8;
+/// How many senders are shown
+const TOP_SENDERS: usize = 5;
+/// Longer log lines are cut, so each takes one line of the terminal
+const MAX_LINE: usize = 120;
+
+static RECENT: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+
+/// Keeps what [`env_logger`] lets through for the dashboard, instead of writing it over it
+struct Logger(env_logger::Logger);
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.0.matches(record) {
+            return;
+        }
+        let at = humantime::format_rfc3339_seconds(SystemTime::now());
+        let mut line = format!("{at} {:<5} {}", record.level(), record.args());
+        if let Some((cut, _)) = line.char_indices().nth(MAX_LINE) {
+            line.truncate(cut);
+        }
+
+        let mut recent = RECENT.lock().unwrap();
+        if recent.len() == RECENT_LINES {
+            recent.pop_front();
+        }
+        recent.push_back(line);
+    }
+
+    fn flush(&self) {}
+}
+
+/// Log to the dashboard rather than to stderr, warnings and errors unless `RUST_LOG` says
+/// otherwise
+pub fn init_logger() -> anyhow::Result<()> {
+    let logger =
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).build();
+    log::set_max_level(logger.filter());
+    log::set_boxed_logger(Box::new(Logger(logger)))?;
+    Ok(())
+}
+
+/// What one frame shows
+#[derive(Debug, Clone, Default)]
+struct View {
+    uptime: Duration,
+    /// Entries a second, over the last frame
+    received_rate: f64,
+    committed_rate: f64,
+    received: u64,
+    committed: u64,
+    queue_depth: u64,
+    batches_in_flight: u64,
+    lag: Duration,
+    memory_bytes: u64,
+    /// What entries were dropped for, and how many were
+    drops: Vec<(&'static str, u64)>,
+    failed_writes: u64,
+    top_senders: Vec<(IpAddr, u64, u64)>,
+    recent: Vec<String>,
+}
+
+impl View {
+    fn now(uptime: Duration, source_stats: &SourceStats, health: &Health) -> Self {
+        let overload = [
+            Overload::DropNewest,
+            Overload::DropOldest,
+            Overload::DropLowestSeverity,
+        ]
+        .map(Overload::dropped)
+        .iter()
+        .sum();
+        Self {
+            uptime,
+            received: metrics::ENTRIES_RECEIVED.load(Ordering::Relaxed),
+            committed: metrics::ENTRIES_COMMITTED.load(Ordering::Relaxed),
+            queue_depth: metrics::QUEUE_DEPTH.load(Ordering::Relaxed),
+            batches_in_flight: metrics::BATCHES_IN_FLIGHT.load(Ordering::Relaxed),
+            lag: metrics::lag(SystemTime::now()),
+            memory_bytes: memory::used(),
+            drops: vec![
+                ("decode errors", health.count(Event::DecodeError)),
+                ("filter and transforms", health.count(Event::Dropped)),
+                ("queue full", overload),
+                ("memory limit", memory::SHED.load(Ordering::Relaxed)),
+                ("too old", timestamp::TOO_OLD.load(Ordering::Relaxed)),
+            ],
+            failed_writes: health.count(Event::DbError),
+            top_senders: source_stats.top(TOP_SENDERS),
+            recent: RECENT.lock().unwrap().iter().cloned().collect(),
+            ..Default::default()
+        }
+    }
+
+    /// The frame, drawn from the top left of a cleared screen
+    fn render(&self) -> String {
+        let mut text = String::from("\x1b[H\x1b[2J");
+        let uptime = Duration::from_secs(self.uptime.as_secs());
+        let _ = writeln!(
+            text,
+            "sqlx_logger, up {}, Ctrl-C to stop\n",
+            humantime::format_duration(uptime)
+        );
+        let _ = writeln!(
+            text,
+            "Throughput  {:.0}/s received, {:.0}/s committed ({} and {} so far)",
+            self.received_rate, self.committed_rate, self.received, self.committed
+        );
+        let _ = writeln!(
+            text,
+            "Queue       {} entries, {} batches in flight, {:.1}s behind, {} KiB in memory",
+            self.queue_depth,
+            self.batches_in_flight,
+            self.lag.as_secs_f64(),
+            self.memory_bytes / 1024
+        );
+        let drops: Vec<_> = self
+            .drops
+            .iter()
+            .map(|(reason, count)| format!("{count} {reason}"))
+            .collect();
+        let _ = writeln!(
+            text,
+            "Dropped     {}\nFailed      {} writes\n",
+            drops.join(", "),
+            self.failed_writes
+        );
+
+        let _ = writeln!(text, "Top senders");
+        if self.top_senders.is_empty() {
+            let _ = writeln!(text, "  (none yet)");
+        }
+        for (sender, messages, dropped) in &self.top_senders {
+            let _ = writeln!(
+                text,
+                "  {:<40} {messages:>10} messages {dropped:>8} dropped",
+                sender.to_string()
+            );
+        }
+
+        let _ = writeln!(text, "\nRecent log");
+        if self.recent.is_empty() {
+            let _ = writeln!(text, "  (nothing yet)");
+        }
+        for line in &self.recent {
+            let _ = writeln!(text, "  {line}");
+        }
+        text
+    }
+}
+
+/// Back to the screen as it was, however the dashboard ends
+struct Restore;
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        use std::io::Write;
+        let mut stdout = std::io::stdout();
+        let _ = stdout.write_all(b"\x1b[?25h\x1b[?1049l");
+        let _ = stdout.flush();
+    }
+}
+
+/// Draw the dashboard on stdout every second for good, from the alternate screen so what was on
+/// the terminal is back afterwards. The top senders are those of `source_stats`, and the drops
+/// and failed writes those of `health`. Meant to be spawned.
+pub async fn run(source_stats: SourceStats, health: Health) {
+    let mut stdout = tokio::io::stdout();
+    let _ = stdout.write_all(b"\x1b[?1049h\x1b[?25l").await;
+    let _restore = Restore;
+
+    let started = Instant::now();
+    let mut last: Option<(Instant, View)> = None;
+    let mut ticks = interval(Duration::from_secs(1));
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticks.tick().await;
+        let mut view = View::now(started.elapsed(), &source_stats, &health);
+        if let Some((at, last)) = &last {
+            let secs = at.elapsed().as_secs_f64().max(0.001);
+            view.received_rate = view.received.saturating_sub(last.received) as f64 / secs;
+            view.committed_rate = view.committed.saturating_sub(last.committed) as f64 / secs;
+        }
+        let _ = stdout.write_all(view.render().as_bytes()).await;
+        let _ = stdout.flush().await;
+        last = Some((Instant::now(), view));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders() {
+        let view = View {
+            uptime: Duration::from_millis(61_500),
+            received_rate: 120.4,
+            committed_rate: 99.6,
+            received: 1000,
+            committed: 900,
+            queue_depth: 12,
+            drops: vec![("decode errors", 2), ("queue full", 0)],
+            failed_writes: 1,
+            top_senders: vec![("10.0.0.1".parse().unwrap(), 700, 3)],
+            ..Default::default()
+        };
+        let text = view.render();
+        assert!(
+            text.starts_with("\x1b[H\x1b[2Jsqlx_logger, up 1m 1s,"),
+            "{text}"
+        );
+        assert!(text.contains("Throughput  120/s received, 100/s committed (1000 and 900 so far)"));
+        assert!(text.contains("Dropped     2 decode errors, 0 queue full\nFailed      1 writes"));
+        assert!(text.contains("  10.0.0.1 "));
+        assert!(text.contains("700 messages        3 dropped"));
+        assert!(text.ends_with("Recent log\n  (nothing yet)\n"));
+    }
+}
@@ -0,0 +1,366 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, bail, Context};
+use async_shutdown::Shutdown;
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+
+use sqlx_logger::{
+    encryption::RecordCipher,
+    gelf::ExpiredMessage,
+    health::{Event, Health},
+    pipeline::{Entry, Received, Sink, Source},
+};
+
+fn open_append(path: &Path) -> anyhow::Result<BufWriter<File>> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Opening {}", path.display()))?;
+    Ok(BufWriter::new(file))
+}
+
+fn write_line(
+    writer: &mut impl Write,
+    cipher: Option<&RecordCipher>,
+    line: &serde_json::Value,
+) -> anyhow::Result<()> {
+    match cipher {
+        Some(cipher) => {
+            let line = serde_json::to_vec(line).context("Writing dead letter")?;
+            writer
+                .write_all(cipher.encrypt(&line).as_bytes())
+                .context("Writing dead letter")?;
+        }
+        None => serde_json::to_writer(&mut *writer, line).context("Writing dead letter")?,
+    }
+    writer.write_all(b"\n").context("Writing dead letter")
+}
+
+/// How many clean-ups' worth of incomplete messages may wait to be written
+const FRAGMENT_QUEUE_SIZE: usize = 64;
+
+/// Appends incomplete messages given up on to a file, one JSON object per line, so we can tell
+/// which sender is fragmenting badly. With a cipher, every line is encrypted on its own. Writing
+/// blocks, so it's done on a thread of its own, keeping it off the way of the datagrams.
+pub struct FragmentDeadLetter {
+    lines: mpsc::Sender<Vec<Value>>,
+}
+
+impl FragmentDeadLetter {
+    pub fn open(path: &Path, cipher: Option<RecordCipher>) -> anyhow::Result<Self> {
+        let mut writer = open_append(path)?;
+        let path = path.to_path_buf();
+        let (lines, mut received) = mpsc::channel::<Vec<Value>>(FRAGMENT_QUEUE_SIZE);
+        tokio::task::spawn_blocking(move || {
+            while let Some(lines) = received.blocking_recv() {
+                let rs = lines
+                    .iter()
+                    .try_for_each(|line| write_line(&mut writer, cipher.as_ref(), line))
+                    .and_then(|_| writer.flush().context("Flushing dead letters"));
+                if let Err(e) = rs {
+                    log::error!("Error writing dead letters to {}: {e:?}", path.display());
+                }
+            }
+        });
+        Ok(Self { lines })
+    }
+
+    /// Queue `messages` up to be written, or let them go if too many are waiting already
+    pub fn write(&mut self, now: Instant, messages: &[ExpiredMessage]) -> anyhow::Result<()> {
+        let expired_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+
+        let mut lines = Vec::with_capacity(messages.len());
+        for message in messages {
+            let chunks: Vec<_> = message
+                .chunks
+                .iter()
+                .map(|(seq, data)| json!({ "seq": seq, "data": to_hex(data) }))
+                .collect();
+
+            lines.push(json!({
+                "expired_at": expired_at,
+                "sender": message.sender.to_string(),
+                "id": to_hex(&message.id),
+                "total_seq": message.total_seq,
+                "age_secs": (now - message.first_arrived).as_secs_f64(),
+                "chunks": chunks,
+            }));
+        }
+
+        self.lines.try_send(lines).map_err(|_| {
+            anyhow!(
+                "Too many dead letters waiting to be written, letting {} go",
+                messages.len()
+            )
+        })
+    }
+}
+
+/// The zstd level to compress dead letters at, fast enough to keep up with an outage
+const ZSTD_LEVEL: i32 = 3;
+/// How a zstd frame starts, to tell compressed files from plain ones
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Appends entries that couldn't be written to the database to a file, one JSON object per
+/// line, to load later. With a cipher, every line is encrypted on its own. Compressed, each
+/// batch is a zstd frame of its own, so a file cut short by a crash only loses the last one.
+pub struct EntryDeadLetter {
+    writer: BufWriter<File>,
+    cipher: Option<RecordCipher>,
+    compress: bool,
+}
+
+impl EntryDeadLetter {
+    /// Append to `path`, compressed with zstd if `compress`. A file already there has to be
+    /// compressed the same way, as it's read back as a whole.
+    pub fn open(path: &Path, cipher: Option<RecordCipher>, compress: bool) -> anyhow::Result<Self> {
+        let writer = open_append(path)?;
+        if writer.get_ref().metadata()?.len() > 0 {
+            let mut start = Vec::new();
+            File::open(path)
+                .and_then(|v| v.take(ZSTD_MAGIC.len() as u64).read_to_end(&mut start))
+                .with_context(|| format!("Reading {}", path.display()))?;
+            if (start == ZSTD_MAGIC) != compress {
+                bail!(
+                    "{} is {}compressed already, start another file",
+                    path.display(),
+                    if compress { "not " } else { "" }
+                );
+            }
+        }
+
+        Ok(Self {
+            writer,
+            cipher,
+            compress,
+        })
+    }
+}
+
+fn write_entries(
+    writer: &mut impl Write,
+    cipher: Option<&RecordCipher>,
+    entries: &[Entry],
+) -> anyhow::Result<()> {
+    for entry in entries {
+        let line = json!({
+            "received_at": entry
+                .received
+                .map(|v| humantime::format_rfc3339_micros(v.at).to_string()),
+            "sender": entry.sender.map(|v| v.to_string()),
+            "client_cn": entry.client_cn,
+            "fields": entry.fields,
+            "body": entry.body,
+        });
+        write_line(writer, cipher, &line)?;
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl Sink for EntryDeadLetter {
+    async fn write(&mut self, entries: &[Entry]) -> anyhow::Result<()> {
+        if self.compress {
+            let mut encoder = zstd::Encoder::new(&mut self.writer, ZSTD_LEVEL)
+                .context("Compressing dead letters")?;
+            write_entries(&mut encoder, self.cipher.as_ref(), entries)?;
+            encoder.finish().context("Compressing dead letters")?;
+        } else {
+            write_entries(&mut self.writer, self.cipher.as_ref(), entries)?;
+        }
+
+        self.writer.flush().context("Flushing dead letters")
+    }
+}
+
+/// The lines of `file`, decompressed if it starts like zstd
+fn read_lines(file: File) -> anyhow::Result<Box<dyn BufRead + Send>> {
+    let mut reader = BufReader::new(file);
+    if reader.fill_buf()?.starts_with(&ZSTD_MAGIC) {
+        Ok(Box::new(BufReader::new(zstd::Decoder::with_buffer(
+            reader,
+        )?)))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+/// An entry from a line written by [`EntryDeadLetter`]
+fn parse_entry(line: &str, cipher: Option<&RecordCipher>) -> anyhow::Result<Entry> {
+    let line: Value = match cipher {
+        Some(cipher) => serde_json::from_slice(&cipher.decrypt(line)?)?,
+        None => serde_json::from_str(line)?,
+    };
+
+    let received = match line["received_at"].as_str() {
+        Some(at) => Some(Received {
+            at: humantime::parse_rfc3339(at).context("Parsing received_at")?,
+            ..Received::now()
+        }),
+        None => None,
+    };
+    let sender = match line["sender"].as_str() {
+        Some(sender) => Some(sender.parse().context("Parsing sender")?),
+        None => None,
+    };
+    let fields = match line["fields"].as_object() {
+        Some(fields) => fields
+            .iter()
+            .filter_map(|(k, v)| Some((k.clone(), v.as_str()?.to_string())))
+            .collect(),
+        None => Default::default(),
+    };
+
+    Ok(Entry {
+        sender,
+        client_cn: line["client_cn"].as_str().map(str::to_string),
+        received,
+        fields,
+        body: line["body"].as_str().context("No body")?.to_string(),
+        ..Default::default()
+    })
+}
+
+/// Reads back the entries an [`EntryDeadLetter`] wrote, keeping when and from whom they were
+/// received, compressed or not. Lines that can't be read are logged and skipped.
+pub struct DeadLetterSource {
+    path: PathBuf,
+    cipher: Option<RecordCipher>,
+    read_whole: Arc<AtomicBool>,
+    health: Health,
+}
+
+impl DeadLetterSource {
+    pub fn new(path: PathBuf, cipher: Option<RecordCipher>) -> Self {
+        Self {
+            path,
+            cipher,
+            read_whole: Default::default(),
+            health: Default::default(),
+        }
+    }
+
+    /// Count what can't be read into `health`
+    pub fn with_health(mut self, health: Health) -> Self {
+        self.health = health;
+        self
+    }
+
+    /// Set once every line has been handed on, with none skipped and without being shut down
+    /// halfway through
+    pub fn read_whole(&self) -> Arc<AtomicBool> {
+        self.read_whole.clone()
+    }
+}
+
+const REPLAYED_EXTENSION: &str = "replayed";
+
+/// The dead letter files in `dir` not replayed yet, oldest first by name
+pub fn pending_files(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir).with_context(|| format!("Reading {}", dir.display()))? {
+        let path = entry?.path();
+        let replayed = path.extension().is_some_and(|v| v == REPLAYED_EXTENSION);
+        let hidden = path
+            .file_name()
+            .is_some_and(|v| v.to_string_lossy().starts_with('.'));
+        if path.is_file() && !replayed && !hidden {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Rename `path` so [`pending_files`] leaves it out from now on
+pub fn mark_replayed(path: &Path) -> anyhow::Result<PathBuf> {
+    let mut marked = path.as_os_str().to_owned();
+    marked.push(".");
+    marked.push(REPLAYED_EXTENSION);
+    let marked = PathBuf::from(marked);
+    std::fs::rename(path, &marked)
+        .with_context(|| format!("Renaming {} to {}", path.display(), marked.display()))?;
+    Ok(marked)
+}
+
+#[async_trait]
+impl Source for DeadLetterSource {
+    async fn run(
+        self: Box<Self>,
+        entries: mpsc::Sender<Entry>,
+        shutdown: Shutdown,
+    ) -> anyhow::Result<()> {
+        let file =
+            File::open(&self.path).with_context(|| format!("Opening {}", self.path.display()))?;
+        let reader =
+            read_lines(file).with_context(|| format!("Reading {}", self.path.display()))?;
+
+        // Decompressing blocks, so lines are read on a thread of their own, until we stop asking
+        let (tx, mut lines) = mpsc::channel(64);
+        tokio::task::spawn_blocking(move || {
+            for line in reader.lines() {
+                let failed = line.is_err();
+                if tx.blocking_send(line).is_err() || failed {
+                    break;
+                }
+            }
+        });
+
+        let (mut replayed, mut skipped, mut line_no) = (0u64, 0u64, 0u64);
+        let mut at_end = false;
+        while let Some(line) = shutdown.wrap_cancel(lines.recv()).await {
+            let Some(line) = line
+                .transpose()
+                .with_context(|| format!("Reading {}", self.path.display()))?
+            else {
+                at_end = true;
+                break;
+            };
+            line_no += 1;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            match parse_entry(&line, self.cipher.as_ref()) {
+                Ok(entry) => {
+                    if entries.send(entry).await.is_err() {
+                        break;
+                    }
+                    replayed += 1;
+                }
+                Err(e) => {
+                    log::error!("Skipping line {line_no} of {}: {e:#}", self.path.display());
+                    self.health.record(Event::DecodeError);
+                    skipped += 1;
+                }
+            }
+        }
+
+        log::info!(
+            "Replayed {replayed} entries from {}, skipped {skipped}",
+            self.path.display()
+        );
+        self.read_whole
+            .store(at_end && skipped == 0, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02x}")).collect()
+}
@@ -0,0 +1,279 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime},
+};
+
+use async_trait::async_trait;
+use serde_json::Value;
+
+use crate::pipeline::{Entry, PendingCommit, Sink};
+
+/// How many times the entry was seen within its window, added by [`Dedup`]
+pub const OCCURRENCES: &str = "occurrences";
+
+/// A run of the same entry is written out once it has gone on for this many windows, even if it
+/// goes on, so a steady stream of one error still shows up
+const MAX_RUN_WINDOWS: u32 = 10;
+
+/// The first of a run of the same entry, waiting for its window to end
+#[derive(Debug)]
+struct Pending {
+    entry: Entry,
+    first_seen: SystemTime,
+    last_seen: SystemTime,
+    occurrences: u64,
+}
+
+/// Keeps only the first of the entries with the same `fields` (top-level fields of JSON entries)
+/// for as long as each comes within `window` of the one before, counting how many there were. A
+/// run is over once `window` goes by without another, or after [`MAX_RUN_WINDOWS`] windows.
+#[derive(Debug)]
+pub struct Dedup {
+    fields: Vec<String>,
+    window: Duration,
+    pending: HashMap<Vec<Option<String>>, Pending>,
+}
+
+impl Dedup {
+    pub fn new(fields: Vec<String>, window: Duration) -> Self {
+        Self {
+            fields,
+            window,
+            pending: Default::default(),
+        }
+    }
+
+    fn key(&self, entry: &Entry) -> Vec<Option<String>> {
+        self.fields
+            .iter()
+            .map(|field| match entry.json()?.get(field)? {
+                Value::String(v) => Some(v.clone()),
+                Value::Null => None,
+                v => Some(v.to_string()),
+            })
+            .collect()
+    }
+
+    /// Whether the run of `pending` is over by `now`
+    fn is_over(&self, pending: &Pending, now: SystemTime) -> bool {
+        pending.last_seen + self.window <= now
+            || pending.first_seen + self.window * MAX_RUN_WINDOWS <= now
+    }
+
+    /// Returns the entry of the same key whose run this one is past, if any
+    pub fn add(&mut self, entry: &Entry) -> Option<Entry> {
+        let key = self.key(entry);
+        let at = entry.received.map_or_else(SystemTime::now, |v| v.at);
+        if let Some(pending) = self.pending.get(&key) {
+            if !self.is_over(pending, at) {
+                let pending = self.pending.get_mut(&key).unwrap();
+                pending.occurrences += 1;
+                pending.last_seen = pending.last_seen.max(at);
+                return None;
+            }
+        }
+
+        let pending = Pending {
+            first_seen: at,
+            last_seen: at,
+            entry: entry.clone(),
+            occurrences: 1,
+        };
+        let closed = self.pending.insert(key, pending)?;
+        Self::finish([closed].into_iter()).pop()
+    }
+
+    /// Take the entries whose run is over by `now`, in the order they were received
+    pub fn take_closed(&mut self, now: SystemTime) -> Vec<Entry> {
+        let closed: Vec<_> = self
+            .pending
+            .iter()
+            .filter(|(_, v)| self.is_over(v, now))
+            .map(|(key, _)| key.clone())
+            .collect();
+        let closed = closed
+            .into_iter()
+            .filter_map(|key| self.pending.remove(&key));
+        Self::finish(closed)
+    }
+
+    pub fn take_all(&mut self) -> Vec<Entry> {
+        Self::finish(self.pending.drain().map(|(_, v)| v))
+    }
+
+    fn finish(pending: impl Iterator<Item = Pending>) -> Vec<Entry> {
+        let mut pending: Vec<_> = pending.collect();
+        pending.sort_by_key(|v| (v.first_seen, v.entry.received.map(|v| v.seq)));
+        pending
+            .into_iter()
+            .map(
+                |Pending {
+                     mut entry,
+                     occurrences,
+                     ..
+                 }| {
+                    entry
+                        .fields
+                        .insert(OCCURRENCES.to_string(), occurrences.to_string());
+                    entry
+                },
+            )
+            .collect()
+    }
+}
+
+/// Hands entries on to `inner` with a [`Dedup`], each once its run is over, whether or not more
+/// entries come in, and the rest on shutdown.
+///
+/// The entries held back count as committed with the batch they came in, though they are only in
+/// memory until their run is over: a crash loses up to [`MAX_RUN_WINDOWS`] windows of them, and
+/// durable sources resume past them all the same. Waiting for the runs instead would hold up the
+/// commits of every batch behind them.
+pub struct DedupSink {
+    pub inner: Box<dyn Sink>,
+    pub dedup: Dedup,
+}
+
+#[async_trait]
+impl Sink for DedupSink {
+    async fn write(&mut self, entries: &[Entry]) -> anyhow::Result<()> {
+        self.write_pending(entries).await?.await
+    }
+
+    async fn write_pending(&mut self, entries: &[Entry]) -> anyhow::Result<PendingCommit> {
+        let mut closed: Vec<_> = entries
+            .iter()
+            .filter_map(|entry| self.dedup.add(entry))
+            .collect();
+        closed.extend(self.dedup.take_closed(SystemTime::now()));
+        if closed.is_empty() {
+            return Ok(Box::pin(async { Ok(()) }));
+        }
+        self.inner.write_pending(&closed).await
+    }
+
+    async fn tick(&mut self) -> anyhow::Result<()> {
+        let closed = self.dedup.take_closed(SystemTime::now());
+        if !closed.is_empty() {
+            self.inner.write(&closed).await?;
+        }
+        self.inner.tick().await
+    }
+
+    async fn close(&mut self) -> anyhow::Result<()> {
+        let rest = self.dedup.take_all();
+        if !rest.is_empty() {
+            self.inner.write(&rest).await?;
+        }
+        self.inner.close().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::UNIX_EPOCH;
+
+    use crate::pipeline::Received;
+
+    use super::*;
+
+    #[test]
+    fn counts_within_sliding_window() {
+        let start = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let secs = Duration::from_secs;
+        let entry = |body: &str, at, seq| Entry {
+            received: Some(Received { at, seq }),
+            body: body.to_string(),
+            ..Default::default()
+        };
+        let occurrences = |entries: Vec<Entry>| -> Vec<_> {
+            entries
+                .iter()
+                .map(|v| (v.body.clone(), v.fields[OCCURRENCES].clone()))
+                .collect()
+        };
+
+        let mut dedup = Dedup::new(
+            vec!["host".to_string(), "short_message".to_string()],
+            secs(60),
+        );
+        let a = r#"{"host":"a","short_message":"boom","_n":1}"#;
+        let b = r#"{"host":"b","short_message":"boom"}"#;
+        assert!(dedup.add(&entry(a, start, 0)).is_none());
+        dedup.add(&entry(
+            r#"{"host":"a","short_message":"boom","_n":2}"#,
+            start + secs(1),
+            1,
+        ));
+        dedup.add(&entry(b, start + secs(2), 2));
+        dedup.add(&entry(a, start + secs(59), 3));
+
+        // Each one seen keeps the run going for another window
+        assert!(dedup.take_closed(start + secs(61)).is_empty());
+        assert_eq!(
+            vec![(b.to_string(), "1".to_string())],
+            occurrences(dedup.take_closed(start + secs(62)))
+        );
+        dedup.add(&entry(a, start + secs(118), 4));
+        assert!(dedup.take_closed(start + secs(177)).is_empty());
+        assert_eq!(
+            vec![(a.to_string(), "4".to_string())],
+            occurrences(dedup.take_closed(start + secs(178)))
+        );
+
+        // A new run for the same key, closing the one before
+        dedup.add(&entry(b, start + secs(200), 5));
+        let closed = dedup.add(&entry(b, start + secs(300), 6));
+        assert_eq!(
+            vec![(b.to_string(), "1".to_string())],
+            occurrences(closed.into_iter().collect())
+        );
+
+        // A run that never stops is written out all the same
+        let at = start + secs(400);
+        for i in 0..MAX_RUN_WINDOWS as u64 * 2 {
+            dedup.add(&entry(a, at + secs(i * 30), 7 + i));
+        }
+        assert_eq!(
+            vec![
+                (b.to_string(), "1".to_string()),
+                (a.to_string(), "20".to_string())
+            ],
+            occurrences(dedup.take_closed(at + secs(600)))
+        );
+        assert!(dedup.take_all().is_empty());
+    }
+
+    #[derive(Clone, Default)]
+    struct Written(std::sync::Arc<std::sync::Mutex<Vec<Entry>>>);
+
+    #[async_trait]
+    impl Sink for Written {
+        async fn write(&mut self, entries: &[Entry]) -> anyhow::Result<()> {
+            self.0.lock().unwrap().extend_from_slice(entries);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn writes_out_on_ticks() {
+        let written = Written::default();
+        let mut sink = DedupSink {
+            inner: Box::new(written.clone()),
+            dedup: Dedup::new(vec!["host".to_string()], Duration::from_millis(10)),
+        };
+        let entry = Entry {
+            received: Some(Received::now()),
+            body: r#"{"host":"a"}"#.to_string(),
+            ..Default::default()
+        };
+        sink.write(&[entry.clone(), entry]).await.unwrap();
+        assert!(written.0.lock().unwrap().is_empty());
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        sink.tick().await.unwrap();
+        let written = written.0.lock().unwrap();
+        assert_eq!(1, written.len());
+        assert_eq!("2", written[0].fields[OCCURRENCES]);
+    }
+}
@@ -0,0 +1,248 @@
+//! Just enough SMTP to send a plain text email, upgrading to TLS whenever the server offers
+//! STARTTLS
+
+use std::{
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+use tokio_rustls::{
+    rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName},
+    TlsConnector,
+};
+
+use crate::identity;
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// Sends emails through an SMTP server, e.g. `smtp.example.com:587`
+#[derive(Debug, Clone)]
+pub struct Mailer {
+    pub server: String,
+    pub from: String,
+    pub to: Vec<String>,
+    /// The user and password to authenticate with, only ever over TLS
+    pub credentials: Option<(String, String)>,
+}
+
+struct Session {
+    stream: BufReader<Box<dyn Stream>>,
+}
+
+impl Session {
+    /// The reply to what was last sent, failing unless its code is `expected`
+    async fn reply(&mut self, expected: u16) -> anyhow::Result<Vec<String>> {
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            if self.stream.read_line(&mut line).await? == 0 {
+                bail!("Connection closed by the SMTP server");
+            }
+            let line = line.trim_end().to_string();
+            let last = line.as_bytes().get(3) != Some(&b'-');
+            lines.push(line);
+            if last {
+                break;
+            }
+        }
+
+        let code = lines.last().and_then(|v| v.get(..3)?.parse::<u16>().ok());
+        if code != Some(expected) {
+            bail!("SMTP server replied {}", lines.join(" / "));
+        }
+        Ok(lines)
+    }
+
+    async fn command(&mut self, command: &str, expected: u16) -> anyhow::Result<Vec<String>> {
+        let stream = self.stream.get_mut();
+        stream.write_all(command.as_bytes()).await?;
+        stream.write_all(b"\r\n").await?;
+        stream.flush().await?;
+        let verb = command.split(' ').next().unwrap_or_default();
+        self.reply(expected)
+            .await
+            .with_context(|| format!("SMTP {verb}"))
+    }
+
+    /// The extensions the server takes, from its reply to EHLO, in upper case
+    async fn ehlo(&mut self) -> anyhow::Result<Vec<String>> {
+        let lines = self.command(&format!("EHLO {}", ehlo_name()), 250).await?;
+        Ok(lines
+            .iter()
+            .skip(1)
+            .filter_map(|v| Some(v.get(4..)?.to_ascii_uppercase()))
+            .collect())
+    }
+}
+
+fn ehlo_name() -> String {
+    match identity::get().hostname.as_str() {
+        "" => "localhost".to_string(),
+        name => name.to_string(),
+    }
+}
+
+fn tls_connector() -> TlsConnector {
+    let mut roots = RootCertStore::empty();
+    roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|v| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(v.subject, v.spki, v.name_constraints)
+    }));
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    TlsConnector::from(Arc::new(config))
+}
+
+impl Mailer {
+    pub async fn send(&self, subject: &str, body: &str) -> anyhow::Result<()> {
+        let host = self
+            .server
+            .rsplit_once(':')
+            .map_or(self.server.as_str(), |v| v.0);
+        let tcp = TcpStream::connect(&self.server)
+            .await
+            .with_context(|| format!("Connecting to {}", self.server))?;
+        let mut session = Session {
+            stream: BufReader::new(Box::new(tcp)),
+        };
+        session.reply(220).await.context("SMTP greeting")?;
+
+        let mut extensions = session.ehlo().await?;
+        let mut tls = false;
+        if extensions.iter().any(|v| v == "STARTTLS") {
+            session.command("STARTTLS", 220).await?;
+            let name = ServerName::try_from(host)
+                .with_context(|| format!("Not a name to check TLS against: {host}"))?;
+            let stream = tls_connector()
+                .connect(name, session.stream.into_inner())
+                .await
+                .context("SMTP STARTTLS")?;
+            session = Session {
+                stream: BufReader::new(Box::new(stream)),
+            };
+            extensions = session.ehlo().await?;
+            tls = true;
+        }
+
+        if let Some((user, password)) = &self.credentials {
+            if !tls {
+                bail!(
+                    "{} doesn't offer STARTTLS, not sending the password",
+                    self.server
+                );
+            }
+            if !extensions
+                .iter()
+                .any(|v| v.starts_with("AUTH") && v.contains("PLAIN"))
+            {
+                bail!("{} doesn't take AUTH PLAIN", self.server);
+            }
+            let token = base64::encode(format!("\0{user}\0{password}"));
+            session.command(&format!("AUTH PLAIN {token}"), 235).await?;
+        }
+
+        session
+            .command(&format!("MAIL FROM:<{}>", self.from), 250)
+            .await?;
+        for to in &self.to {
+            session.command(&format!("RCPT TO:<{to}>"), 250).await?;
+        }
+        session.command("DATA", 354).await?;
+        let message = self.message(subject, body, SystemTime::now());
+        session.command(&format!("{message}\r\n."), 250).await?;
+        let _ = session.command("QUIT", 221).await;
+        Ok(())
+    }
+
+    /// The message as sent after DATA, without the final dot
+    fn message(&self, subject: &str, body: &str, at: SystemTime) -> String {
+        let headers = [
+            format!("From: <{}>", self.from),
+            format!(
+                "To: {}",
+                self.to
+                    .iter()
+                    .map(|v| format!("<{v}>"))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            format!("Subject: {}", encode_header(subject)),
+            format!("Date: {}", rfc5322_date(at)),
+            "MIME-Version: 1.0".to_string(),
+            "Content-Type: text/plain; charset=utf-8".to_string(),
+            "Content-Transfer-Encoding: base64".to_string(),
+        ];
+
+        // Base64 lines never start with a dot, so need no stuffing
+        let encoded = base64::encode(body);
+        let lines: Vec<_> = encoded
+            .as_bytes()
+            .chunks(76)
+            .map(|v| String::from_utf8_lossy(v).into_owned())
+            .collect();
+        format!("{}\r\n\r\n{}", headers.join("\r\n"), lines.join("\r\n"))
+    }
+}
+
+/// `value` as is if it's printable ASCII on one line, else as an RFC 2047 encoded word
+fn encode_header(value: &str) -> String {
+    if value.bytes().all(|b| (0x20..0x7f).contains(&b)) {
+        return value.to_string();
+    }
+    let value: String = value.chars().filter(|c| !c.is_control()).collect();
+    format!("=?UTF-8?B?{}?=", base64::encode(value))
+}
+
+/// e.g. `Tue, 02 Jan 2024 03:04:05 +0000`
+fn rfc5322_date(at: SystemTime) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let secs = at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let days = secs / 86400;
+    // e.g. 2024-01-02T03:04:05Z
+    let rfc3339 = humantime::format_rfc3339_seconds(at).to_string();
+    let month: usize = rfc3339[5..7].parse().unwrap_or(1);
+    format!(
+        "{}, {} {} {} {} +0000",
+        DAYS[(days % 7) as usize],
+        &rfc3339[8..10],
+        MONTHS[month - 1],
+        &rfc3339[..4],
+        &rfc3339[11..19]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn writes_messages() {
+        let at = UNIX_EPOCH + Duration::from_secs(1_704_164_645);
+        assert_eq!("Tue, 02 Jan 2024 03:04:05 +0000", rfc5322_date(at));
+        assert_eq!("web: disk full", encode_header("web: disk full"));
+        assert_eq!("=?UTF-8?B?w6k=?=", encode_header("é\n"));
+
+        let mailer = Mailer {
+            server: "smtp:25".to_string(),
+            from: "logs@example.com".to_string(),
+            to: vec!["a@example.com".to_string(), "b@example.com".to_string()],
+            credentials: None,
+        };
+        let message = mailer.message("Alert", ".hi", at);
+        assert!(message.starts_with("From: <logs@example.com>\r\nTo: <a@example.com>, <b@example.com>\r\nSubject: Alert\r\n"));
+        assert!(message.ends_with("\r\n\r\nLmhp"));
+    }
+}
@@ -0,0 +1,86 @@
+use anyhow::{bail, Context};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    ChaCha20Poly1305, Nonce,
+};
+
+const NONCE_LEN: usize = 12;
+
+/// Encrypts records written to disk, such as dead letters, one line at a time.
+///
+/// Every record is sealed with ChaCha20-Poly1305 under a fresh random nonce and written as the
+/// base64 of the nonce followed by the ciphertext, so files stay line-oriented and appendable.
+#[derive(Clone)]
+pub struct RecordCipher {
+    cipher: ChaCha20Poly1305,
+}
+
+impl RecordCipher {
+    /// From a 256-bit key spelled out as 64 hex digits, e.g. the output of `openssl rand -hex 32`
+    pub fn from_hex_key(key: &str) -> anyhow::Result<Self> {
+        let key = key.trim();
+        if key.len() != 64 || !key.bytes().all(|b| b.is_ascii_hexdigit()) {
+            bail!("Encryption key must be 64 hex digits");
+        }
+
+        let key: Vec<u8> = (0..key.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&key[i..i + 2], 16))
+            .collect::<Result<_, _>>()
+            .context("Parsing encryption key")?;
+
+        Ok(Self {
+            cipher: ChaCha20Poly1305::new_from_slice(&key).context("Invalid encryption key")?,
+        })
+    }
+
+    pub fn encrypt(&self, record: &[u8]) -> String {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, record)
+            .expect("Encrypting into a Vec doesn't fail");
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        base64::encode(sealed)
+    }
+
+    pub fn decrypt(&self, line: &str) -> anyhow::Result<Vec<u8>> {
+        let sealed = base64::decode(line.trim_end()).context("Decoding encrypted record")?;
+        if sealed.len() < NONCE_LEN {
+            bail!("Encrypted record is too short");
+        }
+
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        self.cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow::anyhow!("Encrypted record can't be decrypted with this key"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    #[test]
+    fn round_trip() {
+        let cipher = RecordCipher::from_hex_key(KEY).unwrap();
+        let line = cipher.encrypt(b"{\"id\":1}");
+        assert_ne!(line, cipher.encrypt(b"{\"id\":1}"));
+        assert_eq!(b"{\"id\":1}".to_vec(), cipher.decrypt(&line).unwrap());
+
+        let other = RecordCipher::from_hex_key(&KEY.replace('0', "f")).unwrap();
+        assert!(other.decrypt(&line).is_err());
+        assert!(cipher.decrypt("AAAA").is_err());
+    }
+
+    #[test]
+    fn invalid_keys() {
+        assert!(RecordCipher::from_hex_key("abcd").is_err());
+        assert!(RecordCipher::from_hex_key(&KEY.replace('a', "z")).is_err());
+        assert!(RecordCipher::from_hex_key(&format!("{KEY}\n")).is_ok());
+    }
+}
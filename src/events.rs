@@ -0,0 +1,280 @@
+//! Writing entries as structured JSON events, one per line, for Vector, Fluent Bit and the like to
+//! pick up, so this daemon can decode GELF in front of a larger pipeline
+
+use std::{
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context};
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+};
+
+use crate::{
+    pipeline::{Entry, PendingCommit, Sink},
+    retry::Retries,
+    timestamp::TIMESTAMP,
+};
+
+/// Where to write events to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    /// `-`
+    Stdout,
+    /// `tcp://host:port`, one event per line
+    Tcp(String),
+    /// `udp://host:port`, one event per datagram
+    Udp(String),
+}
+
+impl FromStr for Target {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        if s == "-" {
+            return Ok(Self::Stdout);
+        }
+        match s.split_once("://") {
+            Some(("tcp", addr)) => Ok(Self::Tcp(addr.to_string())),
+            Some(("udp", addr)) => Ok(Self::Udp(addr.to_string())),
+            _ => bail!("Expecting -, tcp://host:port or udp://host:port, got {s}"),
+        }
+    }
+}
+
+/// The event of `entry`: when it happened, by its own timestamp or else when it was received,
+/// where it came from, its message, the fields added along the way, the GELF document it is, if
+/// it's JSON, and the body as received
+pub fn event(entry: &Entry) -> Value {
+    let json = entry.json();
+    let at = entry
+        .fields
+        .get(TIMESTAMP)
+        .and_then(|v| humantime::parse_rfc3339(v).ok())
+        .or(entry.received.map(|v| v.at))
+        .unwrap_or_else(SystemTime::now);
+    let host = json
+        .and_then(|v| v["host"].as_str())
+        .map(str::to_string)
+        .or(entry.sender.map(|v| v.ip().to_string()));
+    let message = json
+        .and_then(|v| v["short_message"].as_str())
+        .unwrap_or(&entry.body);
+
+    json!({
+        "timestamp": humantime::format_rfc3339_micros(at.max(UNIX_EPOCH)).to_string(),
+        "host": host,
+        "message": message,
+        "source": {
+            "type": "gelf",
+            "sender": entry.sender.map(|v| v.to_string()),
+            "client_cn": entry.client_cn,
+        },
+        "fields": entry.fields,
+        "gelf": json,
+        "raw": entry.body,
+    })
+}
+
+enum Output {
+    Stdout(tokio::io::Stdout),
+    /// Connected once there is something to write, and again after failing to
+    Tcp(String, Option<TcpStream>),
+    Udp(String, Option<UdpSocket>),
+}
+
+async fn write_all(writer: &mut (impl AsyncWrite + Unpin), data: &[u8]) -> std::io::Result<()> {
+    writer.write_all(data).await?;
+    writer.flush().await
+}
+
+impl Output {
+    async fn write(&mut self, events: &[Value]) -> anyhow::Result<()> {
+        let mut lines = Vec::new();
+        for event in events {
+            serde_json::to_writer(&mut lines, event)?;
+            lines.push(b'\n');
+        }
+
+        match self {
+            Self::Stdout(stdout) => write_all(stdout, &lines)
+                .await
+                .context("Writing events to stdout"),
+            Self::Tcp(addr, stream) => {
+                let connected = match stream {
+                    Some(v) => v,
+                    None => stream.insert(
+                        TcpStream::connect(&*addr)
+                            .await
+                            .with_context(|| format!("Connecting to {addr}"))?,
+                    ),
+                };
+                let rs = write_all(connected, &lines).await;
+                if rs.is_err() {
+                    *stream = None;
+                }
+                rs.with_context(|| format!("Writing events to {addr}"))
+            }
+            Self::Udp(addr, socket) => {
+                let connected = match socket {
+                    Some(v) => v,
+                    None => {
+                        let bound = UdpSocket::bind(("0.0.0.0", 0)).await?;
+                        bound
+                            .connect(&*addr)
+                            .await
+                            .with_context(|| format!("Resolving {addr}"))?;
+                        socket.insert(bound)
+                    }
+                };
+                for line in lines.split(|v| *v == b'\n').filter(|v| !v.is_empty()) {
+                    connected
+                        .send(line)
+                        .await
+                        .with_context(|| format!("Sending events to {addr}"))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Writes every batch to `inner`, if any, then as events to the target
+pub struct EventSink {
+    pub inner: Option<Box<dyn Sink>>,
+    output: Output,
+    retries: Retries,
+}
+
+impl EventSink {
+    pub fn new(inner: Option<Box<dyn Sink>>, target: Target) -> Self {
+        let output = match target {
+            Target::Stdout => Output::Stdout(tokio::io::stdout()),
+            Target::Tcp(addr) => Output::Tcp(addr, None),
+            Target::Udp(addr) => Output::Udp(addr, None),
+        };
+        Self {
+            inner,
+            output,
+            retries: Default::default(),
+        }
+    }
+
+    /// Retry failed writes by `retries`
+    pub fn with_retries(mut self, retries: Retries) -> Self {
+        self.retries = retries;
+        self
+    }
+}
+
+#[async_trait]
+impl Sink for EventSink {
+    async fn write(&mut self, entries: &[Entry]) -> anyhow::Result<()> {
+        self.write_pending(entries).await?.await
+    }
+
+    async fn write_pending(&mut self, entries: &[Entry]) -> anyhow::Result<PendingCommit> {
+        let commit = match &mut self.inner {
+            Some(inner) => inner.write_pending(entries).await?,
+            None => Box::pin(async { Ok(()) }),
+        };
+        let events: Vec<_> = entries.iter().map(event).collect();
+        let mut attempts = self.retries.attempts("Writing events");
+        while let Err(e) = self.output.write(&events).await {
+            attempts.failed(e).await?;
+        }
+        Ok(commit)
+    }
+
+    async fn tick(&mut self) -> anyhow::Result<()> {
+        match &mut self.inner {
+            Some(inner) => inner.tick().await,
+            None => Ok(()),
+        }
+    }
+
+    async fn close(&mut self) -> anyhow::Result<()> {
+        match &mut self.inner {
+            Some(inner) => inner.close().await,
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{io::AsyncBufReadExt, net::TcpListener};
+
+    use crate::pipeline::Received;
+
+    use super::*;
+
+    #[test]
+    fn builds_events() {
+        let mut entry = Entry {
+            sender: Some("10.0.0.1:1234".parse().unwrap()),
+            received: Some(Received {
+                at: UNIX_EPOCH + Duration::from_millis(1500),
+                seq: 0,
+            }),
+            ..Default::default()
+        };
+        entry.fields.insert("env".to_string(), "prod".to_string());
+        entry.set_body(r#"{"host":"web","short_message":"hi"}"#.to_string());
+        assert_eq!(
+            json!({
+                "timestamp": "1970-01-01T00:00:01.500000Z",
+                "host": "web",
+                "message": "hi",
+                "source": {"type": "gelf", "sender": "10.0.0.1:1234", "client_cn": null},
+                "fields": {"env": "prod"},
+                "gelf": {"host": "web", "short_message": "hi"},
+                "raw": r#"{"host":"web","short_message":"hi"}"#,
+            }),
+            event(&entry)
+        );
+
+        entry.set_body("not json".to_string());
+        let plain = event(&entry);
+        assert_eq!(json!("10.0.0.1"), plain["host"]);
+        assert_eq!(json!("not json"), plain["message"]);
+        assert_eq!(Value::Null, plain["gelf"]);
+    }
+
+    #[test]
+    fn targets() {
+        assert_eq!(Target::Stdout, "-".parse().unwrap());
+        assert_eq!(
+            Target::Tcp("vector:9000".to_string()),
+            "tcp://vector:9000".parse().unwrap()
+        );
+        assert!("vector:9000".parse::<Target>().is_err());
+    }
+
+    #[tokio::test]
+    async fn writes_lines_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = Target::Tcp(listener.local_addr().unwrap().to_string());
+        let mut sink = EventSink::new(None, target);
+
+        let entries = ["a", "b"].map(|v| Entry {
+            body: v.to_string(),
+            ..Default::default()
+        });
+        sink.write(&entries).await.unwrap();
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut lines = tokio::io::BufReader::new(stream).lines();
+        for body in ["a", "b"] {
+            let line: Value =
+                serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+            assert_eq!(json!(body), line["raw"]);
+        }
+    }
+}
@@ -0,0 +1,171 @@
+use std::{borrow::Cow, fmt::Write};
+
+use anyhow::Context;
+use sqlx_logger::{
+    canary::Split,
+    compression, gelf,
+    pipeline::{Dropped, Entry, Received},
+    service::ServiceBuilder,
+    writer::BindField,
+};
+
+/// What entries the pipeline keeps are written with
+pub struct Statements {
+    pub sql: Vec<String>,
+    /// And the statements of the entries it picks instead
+    pub canary: Option<(Split, Vec<String>)>,
+    pub binds: Vec<BindField>,
+    /// Whether the entry itself is given first, before the binds
+    pub takes_entry: bool,
+}
+
+fn quoted(value: Option<&str>) -> String {
+    match value {
+        Some(v) => format!("'{}'", v.replace('\'', "''")),
+        None => "NULL".to_string(),
+    }
+}
+
+/// The fields `after` has that `before` doesn't, or has changed
+fn changed_fields(before: &Entry, after: &Entry) -> Vec<String> {
+    after
+        .fields
+        .iter()
+        .filter(|(k, v)| before.fields.get(*k) != Some(v))
+        .map(|(k, v)| format!("{k}={v}"))
+        .chain(
+            before
+                .fields
+                .keys()
+                .filter(|k| !after.fields.contains_key(*k))
+                .map(|k| format!("{k} removed")),
+        )
+        .collect()
+}
+
+/// What the pipeline of `service` would do with `payload` as received over UDP, step by step:
+/// decoding it, each transform, the fields it ends up with, and the statements it would be
+/// written with and what they would be given. Nothing is written.
+pub fn explain(
+    payload: &[u8],
+    service: &mut ServiceBuilder,
+    statements: &Statements,
+) -> anyhow::Result<String> {
+    let mut text = String::new();
+    let decoded =
+        compression::decompress(Cow::Borrowed(payload), compression::MAX_DECOMPRESSED_LEN)
+            .and_then(gelf::data_to_str)
+            .context("Decoding the message")?;
+    let entry = Entry {
+        received: Some(Received::now()),
+        body: decoded.into_owned(),
+        ..Default::default()
+    };
+    let _ = writeln!(text, "Decoded:\n  {}", entry.body.trim_end());
+    if entry.json().is_none() {
+        let _ = writeln!(text, "  (not JSON)");
+    }
+
+    let _ = writeln!(text, "Transforms:");
+    let mut last = entry;
+    for (name, outcome) in service.transform_steps(last.clone()) {
+        let step = match &outcome {
+            Err(Dropped::Rejected) => "rejected, as it would be counted and quarantined".into(),
+            Err(Dropped::LetGo) => "let go, on purpose".into(),
+            Ok(entry) => {
+                let mut changes = Vec::new();
+                if entry.body != last.body {
+                    changes.push(format!("body now {}", entry.body.trim_end()));
+                }
+                let fields = changed_fields(&last, entry);
+                if !fields.is_empty() {
+                    changes.push(format!("fields {}", fields.join(", ")));
+                }
+                if changes.is_empty() {
+                    "kept as it is".to_string()
+                } else {
+                    format!("kept, {}", changes.join("; "))
+                }
+            }
+        };
+        let _ = writeln!(text, "  {name}: {step}");
+        match outcome {
+            Ok(entry) => last = entry,
+            Err(_) => {
+                let _ = writeln!(text, "Not written");
+                return Ok(text);
+            }
+        }
+    }
+
+    let _ = writeln!(text, "Fields:");
+    if last.fields.is_empty() {
+        let _ = writeln!(text, "  (none)");
+    }
+    for (k, v) in &last.fields {
+        let _ = writeln!(text, "  {k} = {v}");
+    }
+
+    let mut sql = &statements.sql;
+    if let Some((split, canary)) = &statements.canary {
+        if split.is_canary(&last) {
+            let _ = writeln!(text, "Picked for the canary");
+            sql = canary;
+        }
+    }
+    let _ = writeln!(text, "Statements:");
+    for statement in sql {
+        let _ = writeln!(text, "  {statement}");
+    }
+
+    let _ = writeln!(text, "Parameters:");
+    let mut n = 0;
+    if statements.takes_entry {
+        n += 1;
+        let _ = writeln!(text, "  ${n} = {}", quoted(Some(&last.body)));
+    }
+    for bind in &statements.binds {
+        n += 1;
+        let value = match bind {
+            BindField::Returned(_) => "(what an earlier statement returns)".to_string(),
+            bind => quoted(bind.value(&last).as_deref()),
+        };
+        let _ = writeln!(text, "  ${n} = {value}  -- {}", bind.name());
+    }
+    Ok(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx_logger::Service;
+
+    use super::*;
+
+    #[test]
+    fn explains_steps() {
+        let mut service = Service::builder().filter(|v| v.body.contains("keep"));
+        let statements = Statements {
+            sql: vec!["INSERT INTO logs(body, host) VALUES ($1, $2)".to_string()],
+            canary: None,
+            binds: vec![BindField::Json("host".to_string()), BindField::Sender],
+            takes_entry: true,
+        };
+
+        let text = explain(
+            br#"{"host":"web","short_message":"keep it's"}"#,
+            &mut service,
+            &statements,
+        )
+        .unwrap();
+        assert!(text.contains("  Filter: kept as it is\n"), "{text}");
+        assert!(text.contains("  INSERT INTO logs(body, host) VALUES ($1, $2)\n"));
+        assert!(text.contains(r#"  $1 = '{"host":"web","short_message":"keep it''s"}'"#));
+        assert!(text.contains("  $2 = 'web'  -- host\n"));
+        assert!(text.contains("  $3 = NULL  -- sender\n"));
+
+        let text = explain(b"drop", &mut service, &statements).unwrap();
+        assert!(text.contains("  (not JSON)\n"));
+        assert!(text.contains("  Filter: rejected"));
+        assert!(text.ends_with("Not written\n"));
+    }
+}
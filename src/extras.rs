@@ -0,0 +1,153 @@
+use std::time::{Duration, UNIX_EPOCH};
+
+use serde_json::{Map, Value};
+
+use crate::{
+    pipeline::{Entry, Transform},
+    writer::BindField,
+};
+
+/// The additional fields of a GELF entry, as a JSON object
+pub const GELF_EXTRAS: &str = "gelf-extras";
+
+/// The standard GELF fields added by [`GelfColumns`], in the order they are bound, followed by
+/// [`GELF_EXTRAS`]
+pub const GELF_COLUMNS: [&str; 5] = [
+    "gelf-host",
+    "gelf-timestamp",
+    "gelf-level",
+    "gelf-short-message",
+    "gelf-full-message",
+];
+
+/// Collects the `_`-prefixed additional fields of GELF entries into one JSON object, with the
+/// underscore stripped, so they can go into a JSON column without keeping the whole message
+#[derive(Debug, Clone, Default)]
+pub struct GelfExtras;
+
+/// The additional fields of `fields`
+fn extras(fields: &Map<String, Value>) -> String {
+    let extras: Map<String, Value> = fields
+        .iter()
+        .filter_map(|(key, value)| Some((key.strip_prefix('_')?.to_string(), value.clone())))
+        .filter(|(key, _)| !key.is_empty())
+        .collect();
+    Value::Object(extras).to_string()
+}
+
+fn parse(entry: &Entry) -> Option<&Map<String, Value>> {
+    entry.json()?.as_object()
+}
+
+impl Transform for GelfExtras {
+    fn apply(&mut self, mut entry: Entry) -> Option<Entry> {
+        if let Some(extras) = parse(&entry).map(extras) {
+            entry.fields.insert(GELF_EXTRAS.to_string(), extras);
+        }
+        Some(entry)
+    }
+}
+
+/// Adds the standard GELF fields of each entry as [`GELF_COLUMNS`], along with [`GELF_EXTRAS`],
+/// parsing the entry once for all of them. Entries without a timestamp get the time they were
+/// received, as GELF says.
+#[derive(Debug, Clone, Default)]
+pub struct GelfColumns;
+
+impl GelfColumns {
+    /// What to bind for an INSERT of host, timestamp, level, short message, full message and
+    /// extras
+    pub fn binds() -> Vec<BindField> {
+        GELF_COLUMNS
+            .iter()
+            .chain([&GELF_EXTRAS])
+            .map(|v| BindField::Field(v.to_string()))
+            .collect()
+    }
+}
+
+impl Transform for GelfColumns {
+    fn apply(&mut self, mut entry: Entry) -> Option<Entry> {
+        let Some(fields) = parse(&entry) else {
+            return Some(entry);
+        };
+
+        let text = |key| match fields.get(key) {
+            Some(Value::String(v)) => Some(v.clone()),
+            Some(Value::Null) | None => None,
+            Some(v) => Some(v.to_string()),
+        };
+        let timestamp = fields
+            .get("timestamp")
+            .and_then(Value::as_f64)
+            .and_then(|v| Duration::try_from_secs_f64(v).ok())
+            .and_then(|v| UNIX_EPOCH.checked_add(v))
+            .or(entry.received.map(|v| v.at))
+            .map(|v| humantime::format_rfc3339_micros(v).to_string());
+
+        let values = [
+            text("host"),
+            timestamp,
+            text("level"),
+            text("short_message"),
+            text("full_message"),
+        ];
+        let extras = extras(fields);
+        for (key, value) in GELF_COLUMNS.iter().zip(values) {
+            if let Some(value) = value {
+                entry.fields.insert(key.to_string(), value);
+            }
+        }
+        entry.fields.insert(GELF_EXTRAS.to_string(), extras);
+        Some(entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_underscores() {
+        let extras = |body: &str| {
+            let entry = Entry {
+                body: body.to_string(),
+                ..Default::default()
+            };
+            parse(&entry).map(extras)
+        };
+        assert_eq!(
+            Some(r#"{"app":"x","user_id":{"n":1}}"#.to_string()),
+            extras(r#"{"version":"1.1","short_message":"hi","_app":"x","_user_id":{"n":1},"_":2}"#)
+        );
+        assert_eq!(Some("{}".to_string()), extras(r#"{"short_message":"hi"}"#));
+        assert_eq!(None, extras("plain text"));
+        assert_eq!(None, extras("[1]"));
+    }
+
+    #[test]
+    fn columns() {
+        let entry = GelfColumns
+            .apply(Entry {
+                body: r#"{"host":"h1","short_message":"hi","timestamp":1700000000.5,"level":3,"_app":"x"}"#
+                    .to_string(),
+                ..Default::default()
+            })
+            .unwrap();
+        let values: Vec<_> = GelfColumns::binds()
+            .iter()
+            .map(|v| v.value(&entry))
+            .collect();
+        assert_eq!(
+            vec![
+                Some("h1".to_string()),
+                Some("2023-11-14T22:13:20.500000Z".to_string()),
+                Some("3".to_string()),
+                Some("hi".to_string()),
+                None,
+                Some(r#"{"app":"x"}"#.to_string()),
+            ],
+            values
+        );
+    }
+}
@@ -0,0 +1,203 @@
+//! Moving writers between databases, e.g. a primary and its replicas, as they go down and come
+//! back
+
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use sqlx::{
+    any::{AnyKind, AnyPoolOptions},
+    AnyPool, Row,
+};
+use tokio::{
+    sync::watch,
+    time::{interval, timeout, MissedTickBehavior},
+};
+
+use crate::{
+    connect::Connector,
+    credentials,
+    health::{Event, Health},
+};
+
+/// Probe intervals with failed writes in a row before failing over, even if the probe passes
+const FAIL_OVER_AFTER: u32 = 2;
+/// Passed probes in a row before failing back to a preferred database
+const FAIL_BACK_AFTER: u32 = 3;
+
+/// Which of the databases to write to, from how they were probed
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Choice {
+    current: usize,
+    /// Probe intervals in a row with failed writes to the current one
+    failing: u32,
+    /// Probes in a row passed by each
+    passed: Vec<u32>,
+}
+
+impl Choice {
+    fn new(current: usize, num_urls: usize) -> Self {
+        Self {
+            current,
+            failing: 0,
+            passed: vec![0; num_urls],
+        }
+    }
+
+    /// The database to switch to, if any, given whether each passed its probe this time, and
+    /// whether writes to the current one failed since the last time
+    fn next(&mut self, healthy: &[bool], write_errors: bool) -> Option<usize> {
+        for (passed, healthy) in self.passed.iter_mut().zip(healthy) {
+            *passed = if *healthy { *passed + 1 } else { 0 };
+        }
+        self.failing = if write_errors { self.failing + 1 } else { 0 };
+
+        let next = if !healthy[self.current] || self.failing >= FAIL_OVER_AFTER {
+            // The next one down the list that's up, trying the preferred ones again last
+            (1..healthy.len())
+                .map(|i| (self.current + i) % healthy.len())
+                .find(|i| healthy[*i])?
+        } else {
+            (0..self.current).find(|i| self.passed[*i] >= FAIL_BACK_AFTER)?
+        };
+
+        self.current = next;
+        self.failing = 0;
+        Some(next)
+    }
+}
+
+/// Connect to the first of `urls` that is up, in order. Returns which it was, and its pool.
+pub async fn connect_first(
+    urls: &[String],
+    connector: &Connector,
+) -> anyhow::Result<(usize, AnyPool)> {
+    for (i, url) in urls.iter().enumerate() {
+        match connector.connect(url).await {
+            Ok(pool) => return Ok((i, pool)),
+            Err(e) if i + 1 < urls.len() => {
+                log::warn!("Error connecting to {}: {e:#}", credentials::redacted(url));
+            }
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Connecting to {}", credentials::redacted(url)))
+            }
+        }
+    }
+    bail!("No database URL")
+}
+
+/// Whether the database takes writes. Postgres replicas answer, but are read-only, so they don't.
+async fn probe(pool: &AnyPool) -> anyhow::Result<()> {
+    if pool.any_kind() == AnyKind::Postgres {
+        let in_recovery: bool = sqlx::query("SELECT pg_is_in_recovery()")
+            .fetch_one(pool)
+            .await?
+            .try_get(0)?;
+        if in_recovery {
+            bail!("Read-only, in recovery");
+        }
+    } else {
+        sqlx::query("SELECT 1").execute(pool).await?;
+    }
+    Ok(())
+}
+
+/// Probe every one of `urls` every `every`, sending a new pool to `pools` whenever the writers
+/// should move to another database, or when writes failed, as counted in `health`. Starts off
+/// writing to `current`. Meant to be spawned.
+pub async fn run(
+    urls: Vec<String>,
+    connector: Connector,
+    current: usize,
+    every: Duration,
+    pools: watch::Sender<AnyPool>,
+    health: Health,
+) {
+    let mut choice = Choice::new(current, urls.len());
+    // Of a single connection each, only to probe with
+    let mut probes: Vec<Option<AnyPool>> = vec![None; urls.len()];
+    let mut db_errors = health.count(Event::DbError);
+
+    let mut ticks = interval(every);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    ticks.tick().await;
+
+    loop {
+        ticks.tick().await;
+
+        let mut healthy = Vec::with_capacity(urls.len());
+        for (url, pool) in urls.iter().zip(&mut probes) {
+            let rs = timeout(every, async {
+                if pool.is_none() {
+                    let options = AnyPoolOptions::new().max_connections(1);
+                    *pool = Some(connector.connect_with(options, url).await?);
+                }
+                probe(pool.as_ref().unwrap()).await
+            })
+            .await
+            .unwrap_or_else(|_| Err(anyhow::anyhow!("Timed out")));
+
+            if let Err(e) = &rs {
+                log::debug!("Probing {} failed: {e:#}", credentials::redacted(url));
+            }
+            healthy.push(rs.is_ok());
+        }
+
+        let errors = health.count(Event::DbError);
+        let write_errors = errors > db_errors;
+        db_errors = errors;
+
+        let from = choice.current;
+        let Some(to) = choice.next(&healthy, write_errors) else {
+            continue;
+        };
+        let url = &urls[to];
+        match connector.connect(url).await {
+            Ok(pool) => {
+                if to < from {
+                    log::info!("Failing back to {}", credentials::redacted(url));
+                } else {
+                    log::warn!(
+                        "Failing over from {} to {}",
+                        credentials::redacted(&urls[from]),
+                        credentials::redacted(url)
+                    );
+                }
+                pools.send_replace(pool);
+            }
+            Err(e) => {
+                log::warn!("Error connecting to {}: {e:#}", credentials::redacted(url));
+                choice = Choice::new(from, urls.len());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fails_over_and_back() {
+        let mut choice = Choice::new(0, 3);
+        assert_eq!(None, choice.next(&[true, true, true], false));
+
+        // Down, so the next one that's up
+        assert_eq!(Some(2), choice.next(&[false, false, true], false));
+
+        // Back, but only after passing enough probes in a row
+        assert_eq!(None, choice.next(&[true, true, true], false));
+        assert_eq!(None, choice.next(&[false, true, true], false));
+        assert_eq!(Some(1), choice.next(&[true, true, true], false));
+        assert_eq!(None, choice.next(&[true, true, true], false));
+        assert_eq!(Some(0), choice.next(&[true, true, true], false));
+
+        // Up, but writes keep failing
+        assert_eq!(None, choice.next(&[true, true, true], true));
+        assert_eq!(Some(1), choice.next(&[true, true, true], true));
+
+        // Nowhere to go
+        assert_eq!(None, choice.next(&[false, false, false], true));
+        assert_eq!(1, choice.current);
+    }
+}
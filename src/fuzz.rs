@@ -0,0 +1,63 @@
+//! Entry points for fuzzing the decoder, with the `fuzzing` feature: everything a datagram goes
+//! through on its way to becoming a message, none of which may panic whatever comes in
+
+use std::{borrow::Cow, net::SocketAddr};
+
+use bytes::Bytes;
+
+use crate::{
+    compression,
+    gelf::{data_to_str, GELFState, GelfError},
+};
+
+/// Decode one datagram as the UDP source does, on a state of its own: taken as it is, or from a
+/// chunk that is the whole message, then decompressed and checked to be UTF-8. `None` for a chunk
+/// of a longer message.
+pub fn decode(data: &[u8]) -> Result<Option<String>, GelfError> {
+    decode_all([data]).pop().unwrap_or(Ok(None))
+}
+
+/// Decode the datagrams of one sender in turn, as [`decode`] does each, but on the same state so
+/// chunks are put back together. One result for each datagram.
+pub fn decode_all<'a>(
+    datagrams: impl IntoIterator<Item = &'a [u8]>,
+) -> Vec<Result<Option<String>, GelfError>> {
+    let sender = SocketAddr::from(([127, 0, 0, 1], 12201));
+    let mut state = GELFState::default();
+    datagrams
+        .into_iter()
+        .map(|datagram| {
+            let datagram = Bytes::copy_from_slice(datagram);
+            let Some(payload) = state.on_payload(sender, &datagram)? else {
+                return Ok(None);
+            };
+            let payload = compression::decompress(
+                Cow::Owned(payload.into_owned()),
+                compression::MAX_DECOMPRESSED_LEN,
+            )?;
+            Ok(Some(data_to_str(payload)?.into_owned()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_datagrams() {
+        assert_eq!(Some("hi".to_string()), decode(b"hi").unwrap());
+
+        let chunk =
+            |seq: u8, data: &[u8]| [&[0x1e, 0x0f, 1, 2, 3, 4, 5, 6, 7, 8, seq, 2], data].concat();
+        let (first, second) = (chunk(1, b"lo"), chunk(0, b"hel"));
+        let decoded = decode_all([first.as_slice(), second.as_slice()]);
+        assert!(matches!(decoded[0], Ok(None)));
+        assert_eq!(Some("hello".to_string()), *decoded[1].as_ref().unwrap());
+
+        assert!(matches!(
+            decode(&[0x1e, 0x0f, 1]),
+            Err(GelfError::TooShortHeader { len: 3 })
+        ));
+    }
+}
@@ -2,94 +2,493 @@ use std::{
     borrow::Cow,
     cmp::Ordering,
     collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::atomic,
     time::{Duration, Instant},
 };
 
-use anyhow::{bail, Context};
-use bytes::Buf;
+use bytes::{Buf, Bytes, BytesMut};
+use derive_more::{Display, Error};
+
+use crate::metrics;
 
 const CHUNKED_MAGIC_BYTES: &[u8] = &[0x1e, 0x0f];
+const CHUNKED_HEADER_LEN: usize = 12;
+/// GELF doesn't allow a message to be split into more chunks than this
+const MAX_CHUNKS: ChunkSeq = 128;
 const MAX_CHUNKED_MESSAGE_DURATION: Duration = Duration::from_secs(120);
 
-type MessageID = [u8; 8];
-type ChunkSeq = u8;
+pub type MessageID = [u8; 8];
+pub type ChunkSeq = u8;
+
+/// Memory packets are received into back to back. A chunk sliced out of one of them keeps all of
+/// it alive, which is what the memory budget is charged for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ReceiveBlock {
+    start: usize,
+    len: usize,
+}
+
+impl ReceiveBlock {
+    /// The block `buf` starts, up to its capacity
+    pub fn of(buf: &BytesMut) -> Self {
+        Self {
+            start: buf.as_ptr() as usize,
+            len: buf.capacity(),
+        }
+    }
+
+    /// Whether `buf` is somewhere in the block
+    pub fn holds(&self, buf: &[u8]) -> bool {
+        (self.start..self.start + self.len).contains(&(buf.as_ptr() as usize))
+    }
+
+    /// Just the memory of `chunk`, for packets that came in on their own
+    fn around(chunk: &[u8]) -> Self {
+        Self {
+            start: chunk.as_ptr() as usize,
+            len: chunk.len(),
+        }
+    }
+}
 
 struct MergeChunk {
     start: ChunkSeq,
     end: ChunkSeq,
-    data: Vec<u8>,
+    /// Slices of the received packets, only copied together once the message is complete
+    data: Vec<Bytes>,
 }
 
 struct MessageState {
+    sender: SocketAddr,
     first_arrived: Instant,
     total_seq: ChunkSeq,
     sorted_chunks: Vec<MergeChunk>,
+    num_bytes: usize,
+    /// The block each chunk was sliced out of
+    blocks: Vec<ReceiveBlock>,
+}
+
+/// Limits on what a single host may keep in the reassembly map. `None` means unlimited.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SourceQuota {
+    pub max_messages: Option<usize>,
+    pub max_bytes: Option<usize>,
 }
 
 #[derive(Default)]
+struct SourceUsage {
+    messages: usize,
+    bytes: usize,
+}
+
+impl SourceQuota {
+    fn is_exceeded(&self, usage: &SourceUsage) -> bool {
+        self.max_messages.is_some_and(|max| usage.messages > max)
+            || self.max_bytes.is_some_and(|max| usage.bytes > max)
+    }
+}
+
 pub struct GELFState {
+    /// How long an incomplete message is waited on before giving up
+    max_duration: Duration,
+    quota: SourceQuota,
     messages: HashMap<MessageID, MessageState>,
+    /// Incomplete messages per host. Ports are left out as some clients use a socket per message.
+    sources: HashMap<IpAddr, SourceUsage>,
+    /// How many chunks keep each receive block alive
+    pinned: HashMap<ReceiveBlock, usize>,
+    buffered_bytes: usize,
+    num_conflicts: u64,
+    num_over_quota: u64,
 }
 
-fn data_to_str(data: Cow<[u8]>) -> anyhow::Result<Option<Cow<str>>> {
+#[derive(Debug, Display, Error)]
+pub enum GelfError {
+    #[display(
+        fmt = "Invalid chunked header. Expecting header to be at least {} but got: {}",
+        CHUNKED_HEADER_LEN,
+        len
+    )]
+    TooShortHeader { len: usize },
+
+    #[display(fmt = "Total seq is 0")]
+    ZeroTotalSeq,
+
+    #[display(fmt = "Total seq {} exceeds the limit of {}", total_seq, MAX_CHUNKS)]
+    TooManyChunks { total_seq: ChunkSeq },
+
+    #[display(fmt = "Chunk seq = {} is out of range of total seq {}", seq, total_seq)]
+    SeqOutOfRange { seq: ChunkSeq, total_seq: ChunkSeq },
+
+    #[display(
+        fmt = "Chunk seq = {} differs from the one received before, discarding message {:02x?} ({} conflicts so far)",
+        seq,
+        id,
+        num_conflicts
+    )]
+    ConflictingChunk {
+        id: MessageID,
+        seq: ChunkSeq,
+        num_conflicts: u64,
+    },
+
+    #[display(
+        fmt = "Total seq = {} differs from {} received before, discarding message {:02x?}",
+        total_seq,
+        expected,
+        id
+    )]
+    InconsistentTotalSeq {
+        id: MessageID,
+        total_seq: ChunkSeq,
+        expected: ChunkSeq,
+    },
+
+    #[display(
+        fmt = "Sender {} exceeded its reassembly quota, discarding message {:02x?} ({} discarded so far)",
+        sender,
+        id,
+        num_over_quota
+    )]
+    SourceQuotaExceeded {
+        sender: IpAddr,
+        id: MessageID,
+        num_over_quota: u64,
+    },
+
+    #[display(
+        fmt = "Datagram failed HMAC verification ({} dropped so far)",
+        num_failed
+    )]
+    Unauthenticated { num_failed: u64 },
+
+    #[display(fmt = "Converting data into UTF-8 string: {}", _0)]
+    Utf8(std::str::Utf8Error),
+
+    #[display(fmt = "Decompressing payload: {}", _0)]
+    Decompress(std::io::Error),
+
+    #[display(fmt = "Payload decompresses to more than {} bytes", max_length)]
+    DecompressedTooLong { max_length: usize },
+
+    #[display(fmt = "Message exceeds {} bytes", max_length)]
+    MessageTooLong { max_length: usize },
+
+    #[display(fmt = "Message contains a null byte, which terminates messages on streams")]
+    NullInMessage,
+
+    #[display(fmt = "Transport error: {}", _0)]
+    Io(std::io::Error),
+}
+
+/// Codecs have to be able to report errors of the transport underneath
+impl From<std::io::Error> for GelfError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+/// An incomplete chunked message given up on by [`GELFState::clean_up`]
+pub struct ExpiredMessage {
+    pub id: MessageID,
+    pub sender: SocketAddr,
+    pub total_seq: ChunkSeq,
+    pub first_arrived: Instant,
+    /// The chunks received so far, in order of seq
+    pub chunks: Vec<(ChunkSeq, Bytes)>,
+}
+
+pub fn data_to_str(data: Cow<[u8]>) -> Result<Cow<str>, GelfError> {
     match data {
         Cow::Borrowed(v) => std::str::from_utf8(v)
-            .context("Converting data into UTF-8 string")
-            .map(|v| Some(Cow::Borrowed(v))),
+            .map_err(GelfError::Utf8)
+            .map(Cow::Borrowed),
         Cow::Owned(v) => String::from_utf8(v)
-            .context("Converting data into UTF-8 string")
-            .map(|v| Some(Cow::Owned(v))),
+            .map_err(|e| GelfError::Utf8(e.utf8_error()))
+            .map(Cow::Owned),
+    }
+}
+
+impl Drop for GELFState {
+    fn drop(&mut self) {
+        metrics::REASSEMBLY_BYTES.fetch_sub(self.buffered_bytes as u64, atomic::Ordering::Relaxed);
+    }
+}
+
+impl Default for GELFState {
+    fn default() -> Self {
+        Self::new(MAX_CHUNKED_MESSAGE_DURATION, Default::default())
     }
 }
 
 impl GELFState {
-    pub fn on_data<'a>(&mut self, mut data: &'a [u8]) -> anyhow::Result<Option<Cow<'a, str>>> {
+    pub fn new(max_duration: Duration, quota: SourceQuota) -> Self {
+        Self {
+            max_duration,
+            quota,
+            messages: Default::default(),
+            sources: Default::default(),
+            pinned: Default::default(),
+            buffered_bytes: 0,
+            num_conflicts: 0,
+            num_over_quota: 0,
+        }
+    }
+
+    pub fn on_data<'a>(
+        &mut self,
+        sender: SocketAddr,
+        data: &'a Bytes,
+    ) -> Result<Option<Cow<'a, str>>, GelfError> {
+        self.on_data_at(sender, data, Instant::now())
+    }
+
+    /// Like [`GELFState::on_data`], with the data arriving at `now` rather than right now, e.g. on
+    /// a simulated clock, which [`GELFState::clean_up`] then goes by as well
+    pub fn on_data_at<'a>(
+        &mut self,
+        sender: SocketAddr,
+        data: &'a Bytes,
+        now: Instant,
+    ) -> Result<Option<Cow<'a, str>>, GelfError> {
+        self.on_payload_at(sender, data, now)?
+            .map(data_to_str)
+            .transpose()
+    }
+
+    /// Like [`GELFState::on_data`], but leaves the complete payload as it is, for when it still
+    /// has to be decompressed.
+    pub fn on_payload<'a>(
+        &mut self,
+        sender: SocketAddr,
+        data: &'a Bytes,
+    ) -> Result<Option<Cow<'a, [u8]>>, GelfError> {
+        self.on_payload_at(sender, data, Instant::now())
+    }
+
+    /// Like [`GELFState::on_payload`], at `now` as [`GELFState::on_data_at`] is
+    pub fn on_payload_at<'a>(
+        &mut self,
+        sender: SocketAddr,
+        data: &'a Bytes,
+        now: Instant,
+    ) -> Result<Option<Cow<'a, [u8]>>, GelfError> {
+        self.receive(sender, data, None, now)
+    }
+
+    /// Like [`GELFState::on_payload`], for `data` received into `block` along with other
+    /// packets, which is then counted in [`GELFState::buffered_bytes`] in full for as long as a
+    /// chunk of it is waited on. The others only count the chunks themselves.
+    pub fn on_payload_in<'a>(
+        &mut self,
+        sender: SocketAddr,
+        data: &'a Bytes,
+        block: ReceiveBlock,
+    ) -> Result<Option<Cow<'a, [u8]>>, GelfError> {
+        self.receive(sender, data, Some(block), Instant::now())
+    }
+
+    fn receive<'a>(
+        &mut self,
+        sender: SocketAddr,
+        data: &'a Bytes,
+        block: Option<ReceiveBlock>,
+        now: Instant,
+    ) -> Result<Option<Cow<'a, [u8]>>, GelfError> {
         if data.starts_with(CHUNKED_MAGIC_BYTES) {
-            if data.len() < 12 {
-                bail!(
-                    "Invalid chunked header. Expecting header to be at least 12 but got: {}",
-                    data.len()
-                );
+            if data.len() < CHUNKED_HEADER_LEN {
+                return Err(GelfError::TooShortHeader { len: data.len() });
             }
 
-            data.advance(CHUNKED_MAGIC_BYTES.len()); // The magic bytes
+            let mut header = &data[CHUNKED_MAGIC_BYTES.len()..CHUNKED_HEADER_LEN];
 
             let mut id: MessageID = Default::default();
-            data.copy_to_slice(&mut id);
+            header.copy_to_slice(&mut id);
 
-            let seq: ChunkSeq = data.get_u8();
-            let total_seq: ChunkSeq = data.get_u8();
+            let seq: ChunkSeq = header.get_u8();
+            let total_seq: ChunkSeq = header.get_u8();
 
             match total_seq {
-                0 => bail!("Total seq is 0"),
-                1 => return data_to_str(Cow::Borrowed(data)),
+                0 => return Err(GelfError::ZeroTotalSeq),
+                1 => return Ok(Some(Cow::Borrowed(&data[CHUNKED_HEADER_LEN..]))),
+                n if n > MAX_CHUNKS => return Err(GelfError::TooManyChunks { total_seq }),
+                n if seq >= n => return Err(GelfError::SeqOutOfRange { seq, total_seq }),
                 _ => {}
             }
 
-            let state = self
-                .messages
-                .entry(id.clone())
-                .or_insert_with(|| MessageState {
-                    first_arrived: Instant::now(),
+            let sources = &mut self.sources;
+            let state = self.messages.entry(id).or_insert_with(|| {
+                sources.entry(sender.ip()).or_default().messages += 1;
+                MessageState {
+                    sender,
+                    first_arrived: now,
                     total_seq,
                     sorted_chunks: Default::default(),
+                    num_bytes: 0,
+                    blocks: Default::default(),
+                }
+            });
+
+            if state.total_seq != total_seq {
+                let expected = state.total_seq;
+                self.remove(&id);
+                return Err(GelfError::InconsistentTotalSeq {
+                    id,
+                    total_seq,
+                    expected,
                 });
+            }
 
-            let rs = state.try_merge(seq, data);
+            let chunk = data.slice(CHUNKED_HEADER_LEN..);
+            let block = block.unwrap_or_else(|| ReceiveBlock::around(&chunk));
+            if state.conflicts_with(seq, &chunk) {
+                // Most likely two senders picked the same message ID: neither can be trusted now
+                self.remove(&id);
+                self.num_conflicts += 1;
+                return Err(GelfError::ConflictingChunk {
+                    id,
+                    seq,
+                    num_conflicts: self.num_conflicts,
+                });
+            }
 
-            if matches!(&rs, Ok(Some(_))) {
-                self.messages.remove(&id);
+            let num_bytes_before = state.num_bytes;
+            let source = state.sender.ip();
+            let rs = state.try_merge(seq, chunk);
+            let num_added = state.num_bytes - num_bytes_before;
+            if num_added > 0 {
+                state.blocks.push(block);
+                self.pin(block);
+            }
+            if let Some(usage) = self.sources.get_mut(&source) {
+                usage.bytes += num_added;
             }
 
-            rs
+            match rs {
+                Some(data) => {
+                    self.remove(&id);
+                    Ok(Some(Cow::Owned(data)))
+                }
+                None if !self.enforce_quota(source, &id) => Err(GelfError::SourceQuotaExceeded {
+                    sender: source,
+                    id,
+                    num_over_quota: self.num_over_quota,
+                }),
+                None => Ok(None),
+            }
         } else {
-            data_to_str(Cow::Borrowed(data))
+            Ok(Some(Cow::Borrowed(data)))
+        }
+    }
+
+    /// Give up on the messages that have been incomplete for too long, returning them
+    pub fn clean_up(&mut self, now: Instant) -> Vec<ExpiredMessage> {
+        let expired: Vec<MessageID> = self
+            .messages
+            .iter()
+            .filter(|(_, item)| now - item.first_arrived >= self.max_duration)
+            .map(|(id, _)| *id)
+            .collect();
+
+        expired
+            .into_iter()
+            .filter_map(|id| {
+                let item = self.remove(&id)?;
+                Some(ExpiredMessage {
+                    id,
+                    sender: item.sender,
+                    total_seq: item.total_seq,
+                    first_arrived: item.first_arrived,
+                    chunks: item
+                        .sorted_chunks
+                        .into_iter()
+                        .flat_map(|chunk| (chunk.start..).zip(chunk.data))
+                        .collect(),
+                })
+            })
+            .collect()
+    }
+
+    /// Drop the oldest messages from `source` until it is within its quota again. Returns false
+    /// if the message `current` had to go as well.
+    fn enforce_quota(&mut self, source: IpAddr, current: &MessageID) -> bool {
+        let mut kept = true;
+        while self
+            .sources
+            .get(&source)
+            .is_some_and(|usage| self.quota.is_exceeded(usage))
+        {
+            let oldest = self
+                .messages
+                .iter()
+                .filter(|(_, item)| item.sender.ip() == source)
+                .min_by_key(|(_, item)| item.first_arrived)
+                .map(|(id, _)| *id);
+
+            let Some(oldest) = oldest else { break };
+            self.remove(&oldest);
+            self.num_over_quota += 1;
+            kept &= oldest != *current;
+        }
+        kept
+    }
+
+    fn remove(&mut self, id: &MessageID) -> Option<MessageState> {
+        let item = self.messages.remove(id)?;
+        for block in &item.blocks {
+            self.unpin(block);
+        }
+
+        let source = item.sender.ip();
+        if let Some(usage) = self.sources.get_mut(&source) {
+            usage.messages -= 1;
+            usage.bytes -= item.num_bytes;
+            if usage.messages == 0 {
+                self.sources.remove(&source);
+            }
+        }
+        Some(item)
+    }
+
+    /// Count `block` as kept alive by one more chunk
+    fn pin(&mut self, block: ReceiveBlock) {
+        let refs = self.pinned.entry(block).or_default();
+        if *refs == 0 {
+            self.buffered_bytes += block.len;
+            metrics::REASSEMBLY_BYTES.fetch_add(block.len as u64, atomic::Ordering::Relaxed);
         }
+        *refs += 1;
     }
 
-    pub fn clean_up(&mut self, now: Instant) {
-        self.messages
-            .retain(|_, item| now - item.first_arrived < MAX_CHUNKED_MESSAGE_DURATION);
+    fn unpin(&mut self, block: &ReceiveBlock) {
+        let Some(refs) = self.pinned.get_mut(block) else {
+            return;
+        };
+        *refs -= 1;
+        if *refs == 0 {
+            self.pinned.remove(block);
+            self.buffered_bytes -= block.len;
+            metrics::REASSEMBLY_BYTES.fetch_sub(block.len as u64, atomic::Ordering::Relaxed);
+        }
+    }
+
+    /// Number of bytes kept alive by incomplete chunked messages: every receive block a chunk of
+    /// theirs is in, or just the chunks for packets that came in on their own
+    pub fn buffered_bytes(&self) -> usize {
+        self.buffered_bytes
+    }
+
+    /// Give up on the incomplete message that started arriving first. Returns false when there is
+    /// nothing left to give up on.
+    pub fn shed_oldest(&mut self) -> bool {
+        let oldest = self
+            .messages
+            .iter()
+            .min_by_key(|(_, item)| item.first_arrived)
+            .map(|(id, _)| *id);
+
+        oldest.and_then(|id| self.remove(&id)).is_some()
     }
 }
 
@@ -104,11 +503,24 @@ fn compare_chunk(chunk: &MergeChunk, seq: ChunkSeq) -> Ordering {
 }
 
 impl MessageState {
-    fn try_merge<'a>(
-        &mut self,
-        seq: ChunkSeq,
-        data: &'a [u8],
-    ) -> anyhow::Result<Option<Cow<'a, str>>> {
+    /// Whether we already have a chunk with this seq, but with different content
+    fn conflicts_with(&self, seq: ChunkSeq, data: &Bytes) -> bool {
+        match self
+            .sorted_chunks
+            .binary_search_by(|probe| compare_chunk(probe, seq))
+        {
+            Ok(index) => {
+                let chunk = &self.sorted_chunks[index];
+                chunk
+                    .data
+                    .get(usize::from(seq.saturating_sub(chunk.start)))
+                    .is_some_and(|v| v != data)
+            }
+            Err(_) => false,
+        }
+    }
+
+    fn try_merge(&mut self, seq: ChunkSeq, data: Bytes) -> Option<Vec<u8>> {
         // Find the closest chunk
         match self
             .sorted_chunks
@@ -117,33 +529,28 @@ impl MessageState {
             Ok(_) => {
                 // This indicates this seq is already in one of our chunk: ignoring...
                 log::debug!("Ignoring chunk seq = {seq}: already in the merge chunk");
-                return Ok(None);
+                return None;
             }
             Err(index) => {
-                if index == 0 {
-                    // There's no place to insert our data into, just create a new merge chunk
-                    self.sorted_chunks.insert(
-                        0,
-                        MergeChunk {
-                            start: seq,
-                            end: seq,
-                            data: data.to_vec(),
-                        },
-                    );
-                } else {
-                    let last_chunk = self.sorted_chunks.get_mut(index - 1).unwrap();
-                    if last_chunk.end + 1 == seq {
+                self.num_bytes += data.len();
+                let before = index
+                    .checked_sub(1)
+                    .and_then(|v| self.sorted_chunks.get_mut(v));
+                match before {
+                    Some(last_chunk) if last_chunk.end.checked_add(1) == Some(seq) => {
                         // This means we can just merge the data into last chunk instead of creating a new one
-                        last_chunk.data.extend_from_slice(data);
-                        last_chunk.end += 1;
-                    } else {
-                        // This means this data has to create its own chunk
+                        last_chunk.data.push(data);
+                        last_chunk.end = seq;
+                    }
+                    _ => {
+                        // Either there's no chunk before, or it doesn't end right before this
+                        // one: this data has to create its own chunk
                         self.sorted_chunks.insert(
                             index,
                             MergeChunk {
                                 start: seq,
                                 end: seq,
-                                data: data.to_vec(),
+                                data: vec![data],
                             },
                         );
                     }
@@ -152,24 +559,29 @@ impl MessageState {
         }
 
         // Check if we have full data
-        if self.sorted_chunks.last().unwrap().end == self.total_seq - 1
-            && self.sorted_chunks.first().unwrap().start == 0
+        let (Some(first), Some(last)) = (self.sorted_chunks.first(), self.sorted_chunks.last())
+        else {
+            return None;
+        };
+        if first.start == 0
+            && Some(last.end) == self.total_seq.checked_sub(1)
             && self.are_chunks_continuous()
         {
             let num_total_bytes = self
                 .sorted_chunks
                 .iter()
-                .fold(0usize, |acc, item| acc + item.data.len());
+                .flat_map(|item| &item.data)
+                .fold(0usize, |acc, item| acc + item.len());
 
             let mut data = Vec::with_capacity(num_total_bytes);
-            for chunk in &self.sorted_chunks {
-                data.extend_from_slice(&chunk.data);
+            for slice in self.sorted_chunks.iter().flat_map(|item| &item.data) {
+                data.extend_from_slice(slice);
             }
 
             self.sorted_chunks.clear();
-            data_to_str(Cow::Owned(data))
+            Some(data)
         } else {
-            Ok(None)
+            None
         }
     }
 
@@ -177,7 +589,7 @@ impl MessageState {
         let mut last: Option<&MergeChunk> = None;
         for chunk in &self.sorted_chunks {
             match &last {
-                Some(v) if chunk.start - v.end > 1 => return false,
+                Some(v) if chunk.start.saturating_sub(v.end) > 1 => return false,
                 _ => {}
             }
 
@@ -192,25 +604,30 @@ impl MessageState {
 mod tests {
     use super::*;
 
+    fn sender() -> SocketAddr {
+        "127.0.0.1:12201".parse().unwrap()
+    }
+
     #[test]
     fn unchunked_data() {
         let mut state = GELFState::default();
         let expect = "hello, world";
+        let input = Bytes::from_static(expect.as_bytes());
         let actual = state
-            .on_data(expect.as_bytes())
+            .on_data(sender(), &input)
             .expect("No error")
             .expect("Some message");
         assert_eq!(expect, actual.as_ref());
     }
 
-    fn new_chunk_message(id: &MessageID, seq: u8, total: u8, msg: impl AsRef<[u8]>) -> Vec<u8> {
+    fn new_chunk_message(id: &MessageID, seq: u8, total: u8, msg: impl AsRef<[u8]>) -> Bytes {
         let mut input = vec![];
         input.extend_from_slice(CHUNKED_MAGIC_BYTES);
         input.extend_from_slice(id);
         input.push(seq);
         input.push(total);
         input.extend_from_slice(msg.as_ref());
-        input
+        input.into()
     }
 
     #[test]
@@ -221,7 +638,7 @@ mod tests {
         let input = new_chunk_message(&Default::default(), 0, 1, expect);
 
         let actual = state
-            .on_data(&input)
+            .on_data(sender(), &input)
             .expect("No error")
             .expect("Some message");
         assert_eq!(expect, actual.as_ref());
@@ -234,16 +651,16 @@ mod tests {
 
         for (seq, data) in chunks.iter().take(chunks.len() - 1) {
             let input = new_chunk_message(&id, *seq, num_total, data);
-            let rs = state.on_data(&input);
+            let rs = state.on_data(sender(), &input);
 
             assert!(matches!(rs, Ok(None)));
         }
 
         let last_chunk = chunks.last().unwrap();
 
-        let input = new_chunk_message(&id, last_chunk.0, chunks.len() as u8, last_chunk.1);
+        let input = new_chunk_message(&id, last_chunk.0, num_total, last_chunk.1);
         let actual = state
-            .on_data(&input)
+            .on_data(sender(), &input)
             .expect("No error")
             .expect("Some message");
         assert_eq!(input_message, actual.as_ref());
@@ -299,6 +716,307 @@ mod tests {
         );
     }
 
+    #[test]
+    fn invalid_chunk_headers() {
+        let mut state = GELFState::default();
+        let id: MessageID = Default::default();
+
+        let input = Bytes::from_static(&[0x1e, 0x0f, 0, 0]);
+        assert!(matches!(
+            state.on_data(sender(), &input),
+            Err(GelfError::TooShortHeader { len: 4 })
+        ));
+
+        let input = new_chunk_message(&id, 0, 0, "1234");
+        assert!(matches!(
+            state.on_data(sender(), &input),
+            Err(GelfError::ZeroTotalSeq)
+        ));
+
+        let input = new_chunk_message(&id, 0, 129, "1234");
+        assert!(matches!(
+            state.on_data(sender(), &input),
+            Err(GelfError::TooManyChunks { total_seq: 129 })
+        ));
+
+        let input = new_chunk_message(&id, 2, 2, "1234");
+        assert!(matches!(
+            state.on_data(sender(), &input),
+            Err(GelfError::SeqOutOfRange {
+                seq: 2,
+                total_seq: 2
+            })
+        ));
+
+        let input = Bytes::from_static(&[0xff, 0xfe]);
+        assert!(matches!(
+            state.on_data(sender(), &input),
+            Err(GelfError::Utf8(_))
+        ));
+    }
+
+    #[test]
+    fn chunked_with_conflicting_duplications() {
+        let mut state = GELFState::default();
+        let id: MessageID = Default::default();
+
+        let input = new_chunk_message(&id, 0, 2, "1234");
+        assert!(matches!(state.on_data(sender(), &input), Ok(None)));
+
+        let input = new_chunk_message(&id, 0, 2, "abcd");
+        assert!(matches!(
+            state.on_data(sender(), &input),
+            Err(GelfError::ConflictingChunk { seq: 0, .. })
+        ));
+        assert_eq!(0, state.buffered_bytes());
+
+        // The message has been discarded, so the last chunk alone doesn't complete it
+        let input = new_chunk_message(&id, 1, 2, "5678");
+        assert!(matches!(state.on_data(sender(), &input), Ok(None)));
+    }
+
+    #[test]
+    fn chunked_with_inconsistent_total_seq() {
+        let mut state = GELFState::default();
+        let id: MessageID = Default::default();
+
+        let input = new_chunk_message(&id, 0, 2, "1234");
+        assert!(matches!(state.on_data(sender(), &input), Ok(None)));
+
+        let input = new_chunk_message(&id, 3, 4, "5678");
+        assert!(matches!(
+            state.on_data(sender(), &input),
+            Err(GelfError::InconsistentTotalSeq {
+                total_seq: 4,
+                expected: 2,
+                ..
+            })
+        ));
+        assert_eq!(0, state.buffered_bytes());
+    }
+
+    #[test]
+    fn never_panics_on_mangled_chunks() {
+        // Every header length, then a deterministic spread of seq, total seq and id bytes
+        let mut state = GELFState::default();
+        let message = new_chunk_message(&[1; 8], 0, 2, "1234");
+        for len in 0..message.len() {
+            let _ = state.on_data(sender(), &message.slice(..len));
+        }
+
+        let mut x = 0x2545_f491_4f6c_dd1du64;
+        for _ in 0..20_000 {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            let [a, b, c, d, ..] = x.to_le_bytes();
+            let id: MessageID = [a % 4; 8];
+            let input = new_chunk_message(&id, b % 8, c % 8, [d; 3]);
+            let _ = state.on_payload(sender(), &input);
+        }
+    }
+
+    #[test]
+    fn chunked_with_custom_timeout() {
+        let mut state = GELFState::new(Duration::from_secs(5), Default::default());
+        let id: MessageID = Default::default();
+
+        let input = new_chunk_message(&id, 0, 2, "1234");
+        assert!(matches!(state.on_data(sender(), &input), Ok(None)));
+
+        let now = Instant::now();
+        assert!(state.clean_up(now + Duration::from_secs(4)).is_empty());
+        assert_eq!(1, state.clean_up(now + Duration::from_secs(6)).len());
+    }
+
+    #[test]
+    fn chunked_on_a_given_clock() {
+        let mut state = GELFState::new(Duration::from_secs(5), Default::default());
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        let first = new_chunk_message(&[1; 8], 0, 2, "1234");
+        let second = new_chunk_message(&[2; 8], 0, 2, "abcd");
+        assert!(matches!(
+            state.on_data_at(sender(), &first, at(0)),
+            Ok(None)
+        ));
+        assert!(matches!(
+            state.on_data_at(sender(), &second, at(3)),
+            Ok(None)
+        ));
+
+        assert!(state.clean_up(at(4)).is_empty());
+        let expired = state.clean_up(at(5));
+        assert_eq!(1, expired.len());
+        assert_eq!([1; 8], expired[0].id);
+        assert_eq!(at(0), expired[0].first_arrived);
+
+        let rest = new_chunk_message(&[2; 8], 1, 2, "efgh");
+        let output = state.on_data_at(sender(), &rest, at(7)).unwrap();
+        assert_eq!(Some("abcdefgh"), output.as_deref());
+        assert!(state.clean_up(at(100)).is_empty());
+    }
+
+    #[test]
+    fn per_source_quota() {
+        let quota = SourceQuota {
+            max_messages: Some(2),
+            max_bytes: Some(10),
+        };
+        let mut state = GELFState::new(MAX_CHUNKED_MESSAGE_DURATION, quota);
+        let other: SocketAddr = "127.0.0.2:12201".parse().unwrap();
+
+        let chunk = |id: u8, seq, data| {
+            let id: MessageID = [id; 8];
+            new_chunk_message(&id, seq, 4, data)
+        };
+        // Every chunk arrives a millisecond after the one before
+        let arrived = std::cell::Cell::new(Instant::now());
+        let next = || {
+            arrived.set(arrived.get() + Duration::from_millis(1));
+            arrived.get()
+        };
+
+        assert!(matches!(
+            state.on_data_at(other, &chunk(9, 0, "abc"), next()),
+            Ok(None)
+        ));
+        assert!(matches!(
+            state.on_data_at(sender(), &chunk(1, 0, "123"), next()),
+            Ok(None)
+        ));
+        assert!(matches!(
+            state.on_data_at(sender(), &chunk(2, 0, "456"), next()),
+            Ok(None)
+        ));
+
+        // A third message from the same host pushes its oldest one out
+        assert!(matches!(
+            state.on_data_at(sender(), &chunk(3, 0, "789"), next()),
+            Ok(None)
+        ));
+        assert_eq!(9, state.buffered_bytes());
+
+        // Too many bytes from one host drops its oldest message first...
+        assert!(matches!(
+            state.on_data_at(sender(), &chunk(3, 1, "0123456"), next()),
+            Ok(None)
+        ));
+        assert_eq!(13, state.buffered_bytes());
+
+        // ...until the message growing past the quota is the oldest one
+        assert!(matches!(
+            state.on_data_at(sender(), &chunk(3, 2, "x"), next()),
+            Err(GelfError::SourceQuotaExceeded { .. })
+        ));
+        assert_eq!(3, state.buffered_bytes());
+
+        // The other host is left alone
+        assert!(matches!(
+            state.on_data_at(other, &chunk(9, 1, "def"), next()),
+            Ok(None)
+        ));
+        assert!(matches!(
+            state.on_data_at(other, &chunk(9, 2, "ghi"), next()),
+            Ok(None)
+        ));
+        let input = chunk(9, 3, "jkl");
+        let actual = state
+            .on_data_at(other, &input, next())
+            .expect("No error")
+            .expect("Some message");
+        assert_eq!("abcdefghijkl", actual.as_ref());
+    }
+
+    #[test]
+    fn buffered_bytes_and_shedding() {
+        let mut state = GELFState::default();
+
+        let first: MessageID = [1; 8];
+        let second: MessageID = [2; 8];
+
+        assert!(matches!(
+            state.on_data(sender(), &new_chunk_message(&first, 0, 2, "1234")),
+            Ok(None)
+        ));
+        assert!(matches!(
+            state.on_data(sender(), &new_chunk_message(&first, 0, 2, "1234")),
+            Ok(None)
+        ));
+        assert!(matches!(
+            state.on_data(sender(), &new_chunk_message(&second, 1, 2, "56")),
+            Ok(None)
+        ));
+        assert_eq!(6, state.buffered_bytes());
+
+        assert!(state.shed_oldest());
+        assert_eq!(2, state.buffered_bytes());
+
+        let input = new_chunk_message(&second, 0, 2, "1234");
+        let actual = state
+            .on_data(sender(), &input)
+            .expect("No error")
+            .expect("Some message");
+        assert_eq!("123456", actual.as_ref());
+        assert_eq!(0, state.buffered_bytes());
+        assert!(!state.shed_oldest());
+    }
+
+    #[test]
+    fn partials_charge_their_receive_blocks() {
+        const BLOCK_SIZE: usize = 128 << 10;
+        let mut state = GELFState::default();
+        let budget = crate::memory::MemoryBudget::new(1 << 20);
+
+        for i in 0..1000u32 {
+            let mut id: MessageID = Default::default();
+            id[..4].copy_from_slice(&i.to_be_bytes());
+            let mut buf = BytesMut::with_capacity(BLOCK_SIZE);
+            let block = ReceiveBlock::of(&buf);
+            buf.extend_from_slice(&new_chunk_message(&id, 0, 2, [b'x'; 100]));
+
+            let packet = buf.split().freeze();
+            assert!(block.holds(&packet));
+            assert!(matches!(
+                state.on_payload_in(sender(), &packet, block),
+                Ok(None)
+            ));
+            budget.shed_partials(&mut state);
+        }
+
+        // Every partial kept pins a block of its own
+        assert_eq!(8 * BLOCK_SIZE, state.buffered_bytes());
+        assert_eq!(8, state.messages.len());
+    }
+
+    #[test]
+    fn chunks_sharing_a_block_charge_it_once() {
+        let mut state = GELFState::default();
+        let mut buf = BytesMut::with_capacity(1024);
+        let block = ReceiveBlock::of(&buf);
+
+        let mut packets = Vec::new();
+        for id in [[1; 8], [1; 8], [2; 8]] {
+            let seq = packets.len() as u8 % 2;
+            buf.extend_from_slice(&new_chunk_message(&id, seq, 3, "12"));
+            packets.push(buf.split().freeze());
+        }
+        for packet in &packets {
+            assert!(block.holds(packet));
+            assert!(matches!(
+                state.on_payload_in(sender(), packet, block),
+                Ok(None)
+            ));
+        }
+        assert_eq!(1024, state.buffered_bytes());
+
+        assert!(state.shed_oldest());
+        assert_eq!(1024, state.buffered_bytes());
+        assert!(state.shed_oldest());
+        assert_eq!(0, state.buffered_bytes());
+    }
+
     #[test]
     fn chunked_with_timeout() {
         let mut state = GELFState::default();
@@ -307,13 +1025,18 @@ mod tests {
         let id: MessageID = Default::default();
 
         let input = new_chunk_message(&id, 0, 2, &message[..4]);
-        let output = state.on_data(&input);
+        let output = state.on_data(sender(), &input);
         assert!(matches!(output, Ok(None)));
 
-        state.clean_up(Instant::now() + MAX_CHUNKED_MESSAGE_DURATION + Duration::from_secs(1));
+        let expired =
+            state.clean_up(Instant::now() + MAX_CHUNKED_MESSAGE_DURATION + Duration::from_secs(1));
+        assert_eq!(1, expired.len());
+        assert_eq!(sender(), expired[0].sender);
+        assert_eq!(2, expired[0].total_seq);
+        assert_eq!(vec![(0, Bytes::from(&message[..4]))], expired[0].chunks);
 
         let input = new_chunk_message(&id, 1, 2, &message[4..]);
-        let output = state.on_data(&input);
+        let output = state.on_data(sender(), &input);
         assert!(matches!(output, Ok(None)));
     }
 }
@@ -0,0 +1,112 @@
+use std::{net::IpAddr, path::Path};
+
+use anyhow::Context;
+use maxminddb::{geoip2, Reader};
+
+use crate::{
+    json,
+    pipeline::{Entry, Transform},
+};
+
+/// ISO code of the country, e.g. `NZ`
+pub const COUNTRY: &str = "geo-country";
+/// English name of the city
+pub const CITY: &str = "geo-city";
+/// Number of the autonomous system
+pub const ASN: &str = "geo-asn";
+/// Organization of the autonomous system
+pub const AS_ORG: &str = "geo-as-org";
+
+enum Kind {
+    City,
+    Asn,
+}
+
+struct Database {
+    reader: Reader<Vec<u8>>,
+    kind: Kind,
+}
+
+/// Adds where an IP address is to entries, looked up in MaxMind databases: country and city from
+/// a GeoIP2/GeoLite2 City or Country database, the autonomous system from an ASN or ISP one.
+pub struct GeoIp {
+    databases: Vec<Database>,
+    ip_field: Option<String>,
+}
+
+impl GeoIp {
+    /// Look up the sender's address, or if `ip_field` is given, the address in that top-level
+    /// field of JSON entries
+    pub fn open(paths: &[impl AsRef<Path>], ip_field: Option<String>) -> anyhow::Result<Self> {
+        let databases = paths
+            .iter()
+            .map(|path| {
+                let path = path.as_ref();
+                let reader = Reader::open_readfile(path)
+                    .with_context(|| format!("Opening GeoIP database {}", path.display()))?;
+                let database_type = &reader.metadata().database_type;
+                let kind = if database_type.contains("ASN") || database_type.contains("ISP") {
+                    Kind::Asn
+                } else {
+                    Kind::City
+                };
+                log::info!("Loaded {database_type} database from {}", path.display());
+                Ok(Database { reader, kind })
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Self {
+            databases,
+            ip_field,
+        })
+    }
+
+    fn lookup(&self, ip: IpAddr, entry: &mut Entry) -> anyhow::Result<()> {
+        for db in &self.databases {
+            let result = db.reader.lookup(ip)?;
+            match db.kind {
+                Kind::City => {
+                    let Some(city) = result.decode::<geoip2::City>()? else {
+                        continue;
+                    };
+                    if let Some(v) = city.country.iso_code {
+                        entry.fields.insert(COUNTRY.to_string(), v.to_string());
+                    }
+                    if let Some(v) = city.city.names.english {
+                        entry.fields.insert(CITY.to_string(), v.to_string());
+                    }
+                }
+                Kind::Asn => {
+                    let Some(asn) = result.decode::<geoip2::Asn>()? else {
+                        continue;
+                    };
+                    if let Some(v) = asn.autonomous_system_number {
+                        entry.fields.insert(ASN.to_string(), v.to_string());
+                    }
+                    if let Some(v) = asn.autonomous_system_organization {
+                        entry.fields.insert(AS_ORG.to_string(), v.to_string());
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Transform for GeoIp {
+    fn apply(&mut self, mut entry: Entry) -> Option<Entry> {
+        let ip = match &self.ip_field {
+            Some(field) => json::get_string(&entry, field).and_then(|v| v.parse().ok()),
+            None => entry.sender.map(|v| v.ip()),
+        };
+
+        if let Some(ip) = ip {
+            if let Err(e) = self.lookup(ip, &mut entry) {
+                log::debug!("Error looking up {ip}: {e:?}");
+            }
+        }
+
+        Some(entry)
+    }
+}
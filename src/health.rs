@@ -0,0 +1,259 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use derive_more::Display;
+use tokio::time::{interval, MissedTickBehavior};
+
+use crate::writer::{Param, Writer};
+
+/// Something going wrong in the pipeline, counted to tell when it happens too often
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    #[display(fmt = "decode errors")]
+    DecodeError,
+    #[display(fmt = "dropped entries")]
+    Dropped,
+    #[display(fmt = "database errors")]
+    DbError,
+}
+
+impl Event {
+    const ALL: [Event; 3] = [Event::DecodeError, Event::Dropped, Event::DbError];
+
+    /// How the rate is named in rows of alerts
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::DecodeError => "decode-errors",
+            Self::Dropped => "dropped",
+            Self::DbError => "db-errors",
+        }
+    }
+}
+
+/// The number of each [`Event`] so far. Clones count into the same counts, so hand the same one
+/// to the service, the sources and the writers, and to what watches the rates.
+#[derive(Debug, Clone, Default)]
+pub struct Health(Arc<[AtomicU64; 3]>);
+
+impl Health {
+    /// Count an event, from wherever it happens
+    pub fn record(&self, event: Event) {
+        self.0[event as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The number of `event`s so far
+    pub fn count(&self, event: Event) -> u64 {
+        self.0[event as usize].load(Ordering::Relaxed)
+    }
+
+    /// The number of each event so far, by [`Event`]
+    fn counts(&self) -> [u64; 3] {
+        self.0.each_ref().map(|v| v.load(Ordering::Relaxed))
+    }
+}
+
+/// A rate going over its threshold, or back under it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Crossing {
+    pub event: Event,
+    /// Within the last window
+    pub count: u64,
+    pub threshold: u64,
+    pub over: bool,
+}
+
+/// How many of each event are fine within one window
+#[derive(Debug, Clone, Default)]
+pub struct Thresholds {
+    pub decode_errors: Option<u64>,
+    pub dropped: Option<u64>,
+    pub db_errors: Option<u64>,
+}
+
+impl Thresholds {
+    pub fn is_empty(&self) -> bool {
+        self.get(Event::DecodeError).is_none()
+            && self.get(Event::Dropped).is_none()
+            && self.get(Event::DbError).is_none()
+    }
+
+    fn get(&self, event: Event) -> Option<u64> {
+        match event {
+            Event::DecodeError => self.decode_errors,
+            Event::Dropped => self.dropped,
+            Event::DbError => self.db_errors,
+        }
+    }
+}
+
+/// Compares the counts of each window against the thresholds, only reporting changes so a rate
+/// staying high doesn't alert again every window
+#[derive(Debug)]
+struct RateCheck {
+    thresholds: Thresholds,
+    last_counts: [u64; 3],
+    over: [bool; 3],
+}
+
+impl RateCheck {
+    fn new(thresholds: Thresholds, counts: [u64; 3]) -> Self {
+        Self {
+            thresholds,
+            last_counts: counts,
+            over: [false; 3],
+        }
+    }
+
+    fn check(&mut self, counts: [u64; 3]) -> Vec<Crossing> {
+        let mut crossings = Vec::new();
+        for event in Event::ALL {
+            let i = event as usize;
+            let count = counts[i] - self.last_counts[i];
+            let Some(threshold) = self.thresholds.get(event) else {
+                continue;
+            };
+
+            let over = count > threshold;
+            if over != self.over[i] {
+                self.over[i] = over;
+                crossings.push(Crossing {
+                    event,
+                    count,
+                    threshold,
+                    over,
+                });
+            }
+        }
+        self.last_counts = counts;
+        crossings
+    }
+}
+
+/// Where to report crossings, besides the log
+#[derive(Default)]
+pub struct AlertTargets {
+    /// Posted `{"text": "..."}`
+    pub webhook: Option<String>,
+    /// Run with the name of the rate, the count within the window, the threshold and whether
+    /// it's over (1) or back under (0)
+    pub sql: Option<(Writer, String)>,
+}
+
+/// Check the rates of the [`Event`]s of `health` every `window` for good. Meant to be spawned.
+pub async fn watch(
+    health: Health,
+    thresholds: Thresholds,
+    window: Duration,
+    mut targets: AlertTargets,
+) {
+    let client = reqwest::Client::new();
+    let mut check = RateCheck::new(thresholds, health.counts());
+    let mut ticks = interval(window);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    ticks.tick().await;
+
+    loop {
+        ticks.tick().await;
+        for crossing in check.check(health.counts()) {
+            let Crossing {
+                event,
+                count,
+                threshold,
+                over,
+            } = &crossing;
+            let message = if *over {
+                format!(
+                    "{count} {event} in the last {}, more than {threshold}",
+                    humantime::format_duration(window)
+                )
+            } else {
+                format!(
+                    "Back to {count} {event} in the last {}",
+                    humantime::format_duration(window)
+                )
+            };
+
+            if *over {
+                log::warn!("{message}");
+            } else {
+                log::info!("{message}");
+            }
+
+            if let Some(url) = &targets.webhook {
+                let rs = client
+                    .post(url)
+                    .json(&serde_json::json!({ "text": message }))
+                    .send()
+                    .await
+                    .and_then(|v| v.error_for_status());
+                if let Err(e) = rs {
+                    log::warn!("Error sending alert: {e}");
+                }
+            }
+
+            if let Some((writer, sql)) = &mut targets.sql {
+                let row = vec![
+                    Param::Text(Some(event.name().to_string())),
+                    Param::Int(Some(*count as i64)),
+                    Param::Int(Some(*threshold as i64)),
+                    Param::Int(Some(*over as i64)),
+                ];
+                if let Err(e) = writer.write_rows(sql, &[row]).await {
+                    log::warn!("Error writing alert: {e:#}");
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_of_their_own() {
+        let health = Health::default();
+        health.clone().record(Event::DbError);
+        health.record(Event::Dropped);
+        Health::default().record(Event::DbError);
+        assert_eq!([0, 1, 1], health.counts());
+        assert_eq!(1, health.count(Event::DbError));
+    }
+
+    #[test]
+    fn reports_crossings_once() {
+        let mut check = RateCheck::new(
+            Thresholds {
+                decode_errors: Some(10),
+                dropped: None,
+                db_errors: Some(0),
+            },
+            [5, 0, 0],
+        );
+
+        assert!(check.check([15, 100, 0]).is_empty());
+
+        let crossing = |event, count, threshold, over| Crossing {
+            event,
+            count,
+            threshold,
+            over,
+        };
+        assert_eq!(
+            vec![
+                crossing(Event::DecodeError, 11, 10, true),
+                crossing(Event::DbError, 1, 0, true),
+            ],
+            check.check([26, 100, 1])
+        );
+        assert_eq!(
+            vec![crossing(Event::DbError, 0, 0, false)],
+            check.check([40, 100, 1])
+        );
+    }
+}
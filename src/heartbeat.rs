@@ -0,0 +1,34 @@
+//! Rows written for as long as we're up, so a collector that died can be told from SQL alone
+
+use std::time::{Duration, SystemTime};
+
+use tokio::time::{interval, MissedTickBehavior};
+
+use crate::{
+    identity,
+    writer::{Param, Writer},
+};
+
+/// Run `sql` every `every` for good, starting right away, given our hostname, instance ID and
+/// version, and the time in RFC 3339. Meant to be spawned.
+pub async fn run(mut writer: Writer, sql: String, every: Duration) {
+    let identity = identity::get();
+    let mut ticks = interval(every);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        ticks.tick().await;
+        let row = vec![
+            Param::Text(Some(identity.hostname.clone())),
+            Param::Text(Some(identity.instance_id.clone())),
+            Param::Text(Some(identity.version.to_string())),
+            Param::Text(Some(
+                humantime::format_rfc3339_micros(SystemTime::now()).to_string(),
+            )),
+        ];
+        match writer.write_rows(&sql, &[row]).await {
+            Ok(_) => log::debug!("Wrote heartbeat"),
+            Err(e) => log::warn!("Error writing heartbeat: {e:#}"),
+        }
+    }
+}
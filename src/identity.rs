@@ -0,0 +1,36 @@
+//! Who is writing the entries, for deployments with more than one of us
+
+use std::sync::OnceLock;
+
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+
+#[derive(Debug, Clone)]
+pub struct Identity {
+    pub hostname: String,
+    /// Random unless configured, so restarts on the same host can be told apart
+    pub instance_id: String,
+    pub version: &'static str,
+}
+
+static IDENTITY: OnceLock<Identity> = OnceLock::new();
+
+/// Set up the identity with a configured instance ID, before anything gets it. Returns the
+/// identity in use, which is the earlier one if there is
+pub fn init(instance_id: Option<String>) -> &'static Identity {
+    IDENTITY.get_or_init(|| {
+        let hostname = dns_lookup::get_hostname().unwrap_or_else(|e| {
+            log::warn!("Error getting hostname: {e}");
+            String::new()
+        });
+
+        Identity {
+            hostname,
+            instance_id: instance_id.unwrap_or_else(|| format!("{:016x}", OsRng.next_u64())),
+            version: env!("CARGO_PKG_VERSION"),
+        }
+    })
+}
+
+pub fn get() -> &'static Identity {
+    init(None)
+}
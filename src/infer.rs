@@ -0,0 +1,414 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    fs::File,
+    io::{BufRead, BufReader},
+    net::SocketAddr,
+    path::PathBuf,
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use async_shutdown::Shutdown;
+use clap::ValueEnum;
+use serde_json::Value;
+use sqlx_logger::{pipeline::Source, GelfSource};
+use tokio::{sync::mpsc, time::sleep};
+
+/// The fields GELF defines, suggested as columns in this order before any others
+const GELF_FIELDS: [&str; 8] = [
+    "host",
+    "short_message",
+    "full_message",
+    "timestamp",
+    "level",
+    "facility",
+    "file",
+    "line",
+];
+
+/// Strings longer than this get TEXT on MySQL rather than a VARCHAR
+const MAX_VARCHAR: usize = 255;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Dialect {
+    Postgres,
+    Mysql,
+    Sqlite,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct InferArgs {
+    /// A file of sample messages, one GELF JSON object per line, or how long to listen for them
+    /// instead, e.g. 30s
+    #[arg(long)]
+    samples: String,
+
+    /// Where to listen for samples, over UDP
+    #[arg(long, default_value = "0.0.0.0:12201")]
+    listen: SocketAddr,
+
+    /// Stop after this many samples
+    #[arg(long, default_value_t = 10000)]
+    max_samples: usize,
+
+    /// The table to suggest
+    #[arg(long, default_value = "logs")]
+    table: String,
+
+    /// The database to suggest SQL for
+    #[arg(long, value_enum, default_value_t = Dialect::Postgres)]
+    dialect: Dialect,
+}
+
+/// What the samples held in a field
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct Field {
+    /// Samples with it, not null
+    present: usize,
+    integers: usize,
+    floats: usize,
+    bools: usize,
+    strings: usize,
+    /// Objects and arrays
+    documents: usize,
+    max_len: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Integer,
+    Float,
+    Bool,
+    Text,
+    Document,
+}
+
+impl Field {
+    fn add(&mut self, value: &Value) {
+        match value {
+            Value::Null => return,
+            Value::Bool(_) => {
+                self.bools += 1;
+                self.max_len = self.max_len.max("false".len());
+            }
+            Value::Number(v) if v.is_i64() || v.is_u64() => self.integers += 1,
+            Value::Number(_) => self.floats += 1,
+            Value::String(v) => {
+                self.strings += 1;
+                self.max_len = self.max_len.max(v.chars().count());
+            }
+            Value::Array(_) | Value::Object(_) => self.documents += 1,
+        }
+        self.present += 1;
+    }
+
+    /// The one type every value fits, text if they are of more than one
+    fn kind(&self) -> Kind {
+        let numbers = self.integers + self.floats;
+        if self.integers == self.present {
+            Kind::Integer
+        } else if numbers == self.present {
+            Kind::Float
+        } else if self.bools == self.present {
+            Kind::Bool
+        } else if self.documents == self.present {
+            Kind::Document
+        } else {
+            Kind::Text
+        }
+    }
+}
+
+/// The top-level fields of the samples, and how many there were
+#[derive(Debug, Default)]
+struct Samples {
+    count: usize,
+    fields: BTreeMap<String, Field>,
+}
+
+impl Samples {
+    /// Count in a sample if it's a JSON object
+    fn add(&mut self, body: &str) -> bool {
+        let Ok(Value::Object(object)) = serde_json::from_str(body) else {
+            return false;
+        };
+        self.count += 1;
+        for (name, value) in &object {
+            self.fields.entry(name.clone()).or_default().add(value);
+        }
+        true
+    }
+
+    /// The fields to suggest columns for, GELF's own first
+    fn columns(&self) -> Vec<(&str, &Field)> {
+        let order = |name: &str| GELF_FIELDS.iter().position(|v| *v == name);
+        let mut columns: Vec<_> = self
+            .fields
+            .iter()
+            .map(|(name, field)| (name.as_str(), field))
+            .filter(|(name, field)| *name != "version" && field.present > 0)
+            .collect();
+        columns.sort_by_key(|(name, _)| (order(name).unwrap_or(GELF_FIELDS.len()), *name));
+        columns
+    }
+}
+
+/// Whether `field` can be bound as a placeholder of its name
+fn is_bindable(field: &str) -> bool {
+    field.starts_with(|v: char| v.is_ascii_alphabetic() || v == '_')
+        && field.chars().all(|v| v.is_ascii_alphanumeric() || v == '_')
+}
+
+/// A column named after `field`, without the underscore GELF puts before additional fields,
+/// and anything but letters, digits and underscores replaced
+fn column_name(field: &str, taken: &[String]) -> String {
+    let name: String = field
+        .trim_start_matches('_')
+        .chars()
+        .map(|v| match v {
+            'a'..='z' | '0'..='9' | '_' => v,
+            'A'..='Z' => v.to_ascii_lowercase(),
+            _ => '_',
+        })
+        .collect();
+    let name = match name.chars().next() {
+        None => "field".to_string(),
+        Some(v) if v.is_ascii_digit() => format!("f{name}"),
+        Some(_) => name,
+    };
+    if taken.contains(&name) {
+        format!("{name}_{}", taken.len())
+    } else {
+        name
+    }
+}
+
+/// The column type for `field`, and how to turn the text bound at `placeholder` into it
+fn column_type(name: &str, field: &Field, dialect: Dialect, placeholder: &str) -> (String, String) {
+    let typed = |sql_type: &str, value: String| (sql_type.to_string(), value);
+    let as_is = placeholder.to_string();
+    let kind = field.kind();
+    let is_time = name == "timestamp" && matches!(kind, Kind::Integer | Kind::Float);
+    match dialect {
+        Dialect::Postgres if is_time => typed(
+            "TIMESTAMPTZ",
+            format!("to_timestamp({placeholder}::text::double precision)"),
+        ),
+        Dialect::Postgres => match kind {
+            Kind::Integer => typed("BIGINT", format!("{placeholder}::text::bigint")),
+            Kind::Float => typed(
+                "DOUBLE PRECISION",
+                format!("{placeholder}::text::double precision"),
+            ),
+            Kind::Bool => typed("BOOLEAN", format!("{placeholder}::text::boolean")),
+            Kind::Document => typed("JSONB", format!("{placeholder}::text::jsonb")),
+            Kind::Text => typed("TEXT", as_is),
+        },
+        Dialect::Mysql if is_time => typed("DATETIME(6)", format!("FROM_UNIXTIME({placeholder})")),
+        Dialect::Mysql => match kind {
+            Kind::Integer => typed("BIGINT", as_is),
+            Kind::Float => typed("DOUBLE", as_is),
+            Kind::Document => typed("JSON", as_is),
+            Kind::Bool | Kind::Text if field.max_len <= MAX_VARCHAR => {
+                typed(&format!("VARCHAR({})", field.max_len.max(1)), as_is)
+            }
+            Kind::Bool | Kind::Text => typed("TEXT", as_is),
+        },
+        Dialect::Sqlite => match kind {
+            Kind::Integer => typed("INTEGER", as_is),
+            Kind::Float => typed("REAL", as_is),
+            Kind::Bool | Kind::Text | Kind::Document => typed("TEXT", as_is),
+        },
+    }
+}
+
+/// A CREATE TABLE for the samples and the INSERT to go with it, binding each field by name
+fn suggest(samples: &Samples, table: &str, dialect: Dialect) -> String {
+    let (received_type, received_at) = match dialect {
+        Dialect::Postgres => ("TIMESTAMPTZ", ":@received_at::text::timestamptz"),
+        Dialect::Mysql => ("VARCHAR(40)", ":@received_at"),
+        Dialect::Sqlite => ("TEXT", ":@received_at"),
+    };
+
+    let mut names = vec!["received_at".to_string()];
+    let mut definitions = vec![format!("received_at {received_type} NOT NULL")];
+    let mut values = vec![received_at.to_string()];
+    let mut unbindable = Vec::new();
+    for (field, stats) in samples.columns() {
+        if !is_bindable(field) {
+            unbindable.push(field);
+            continue;
+        }
+        let name = column_name(field, &names);
+        let (sql_type, value) = column_type(field, stats, dialect, &format!(":{field}"));
+        let not_null = if stats.present == samples.count {
+            " NOT NULL"
+        } else {
+            ""
+        };
+        definitions.push(format!("{name} {sql_type}{not_null}"));
+        values.push(value);
+        names.push(name);
+    }
+
+    let mut sql = String::new();
+    let _ = writeln!(sql, "-- Inferred from {} samples", samples.count);
+    if !unbindable.is_empty() {
+        let _ = writeln!(
+            sql,
+            "-- Left out, as they can't be bound by name: {}",
+            unbindable.join(", ")
+        );
+    }
+    let _ = writeln!(
+        sql,
+        "CREATE TABLE {table} (\n    {}\n);\n",
+        definitions.join(",\n    ")
+    );
+    let _ = writeln!(
+        sql,
+        "INSERT INTO {table}({}) VALUES ({})",
+        names.join(", "),
+        values.join(", ")
+    );
+    sql
+}
+
+fn read_samples(path: &PathBuf, max: usize) -> anyhow::Result<Samples> {
+    let file = File::open(path).with_context(|| format!("Opening {}", path.display()))?;
+    let mut samples = Samples::default();
+    let mut skipped = 0;
+    for line in BufReader::new(file).lines() {
+        let line = line.with_context(|| format!("Reading {}", path.display()))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        if !samples.add(&line) {
+            skipped += 1;
+        }
+        if samples.count >= max {
+            break;
+        }
+    }
+    if skipped > 0 {
+        log::warn!("Skipped {skipped} lines that aren't JSON objects");
+    }
+    Ok(samples)
+}
+
+async fn listen_for_samples(
+    addr: SocketAddr,
+    duration: Duration,
+    max: usize,
+) -> anyhow::Result<Samples> {
+    let source = GelfSource::bind(addr).await?;
+    let shutdown = Shutdown::new();
+    let (sender, mut entries) = mpsc::channel(1024);
+    let running = tokio::spawn(Box::new(source).run(sender, shutdown.clone()));
+    eprintln!(
+        "Listening on {addr} for {}",
+        humantime::format_duration(duration)
+    );
+
+    let mut samples = Samples::default();
+    let stop = sleep(duration);
+    tokio::pin!(stop);
+    loop {
+        tokio::select! {
+            entry = entries.recv() => match entry {
+                Some(entry) => {
+                    samples.add(&entry.body);
+                    if samples.count >= max {
+                        break;
+                    }
+                }
+                None => break,
+            },
+            _ = &mut stop => break,
+        }
+    }
+
+    shutdown.shutdown();
+    drop(entries);
+    running.await??;
+    Ok(samples)
+}
+
+pub fn infer(
+    InferArgs {
+        samples,
+        listen,
+        max_samples,
+        table,
+        dialect,
+    }: InferArgs,
+) -> anyhow::Result<()> {
+    let samples = match humantime::parse_duration(&samples) {
+        Ok(duration) => tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .context("Building runtime")?
+            .block_on(listen_for_samples(listen, duration, max_samples))?,
+        Err(_) => read_samples(&samples.into(), max_samples)?,
+    };
+    if samples.count == 0 {
+        bail!("No samples to infer from");
+    }
+
+    print!("{}", suggest(&samples, &table, dialect));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn samples(lines: &[&str]) -> Samples {
+        let mut samples = Samples::default();
+        for line in lines {
+            samples.add(line);
+        }
+        samples
+    }
+
+    #[test]
+    fn suggests_tables() {
+        let samples = samples(&[
+            r#"{"version":"1.1","host":"web","short_message":"hi","timestamp":1.5,"level":6,"_user_id":7,"_tags":["a"]}"#,
+            r#"{"version":"1.1","host":"web","short_message":"ho","timestamp":2,"level":3,"_took":0.5,"_ok":true,"_trace-id":"x"}"#,
+            "not json",
+        ]);
+        assert_eq!(
+            "-- Inferred from 2 samples
+-- Left out, as they can't be bound by name: _trace-id
+CREATE TABLE logs (
+    received_at TIMESTAMPTZ NOT NULL,
+    host TEXT NOT NULL,
+    short_message TEXT NOT NULL,
+    timestamp TIMESTAMPTZ NOT NULL,
+    level BIGINT NOT NULL,
+    ok BOOLEAN,
+    tags JSONB,
+    took DOUBLE PRECISION,
+    user_id BIGINT
+);
+
+INSERT INTO logs(received_at, host, short_message, timestamp, level, ok, tags, took, user_id) \
+VALUES (:@received_at::text::timestamptz, :host, :short_message, \
+to_timestamp(:timestamp::text::double precision), :level::text::bigint, :_ok::text::boolean, \
+:_tags::text::jsonb, :_took::text::double precision, :_user_id::text::bigint)
+",
+            suggest(&samples, "logs", Dialect::Postgres)
+        );
+    }
+
+    #[test]
+    fn names_columns() {
+        assert_eq!("user_id", column_name("_user_id", &[]));
+        assert_eq!("a_b", column_name("_A.b", &[]));
+        assert!(!is_bindable("_trace-id"));
+        assert_eq!("f1", column_name("_1", &[]));
+        assert_eq!("host_1", column_name("_host", &["host".to_string()]));
+    }
+}
@@ -0,0 +1,286 @@
+//! Writing entries to InfluxDB as points in line protocol, through its v2 HTTP API
+
+use std::{convert::Infallible, str::FromStr, time::UNIX_EPOCH};
+
+use anyhow::Context;
+use async_trait::async_trait;
+use serde_json::Value;
+
+use crate::{
+    pipeline::{Entry, PendingCommit, Sink},
+    retry::Retries,
+    timestamp::TIMESTAMP,
+    writer::BindField,
+};
+
+/// A tag or field of the points, named `name` and taken from `source`: the top-level field of
+/// JSON entries by that name, or else what would be bound by it, e.g. `sender` or `geo-country`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mapping {
+    pub name: String,
+    pub source: String,
+}
+
+impl FromStr for Mapping {
+    type Err = Infallible;
+
+    /// `name=source`, or just `name` when taken from the field of the same name
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, source) = s.split_once('=').unwrap_or((s, s));
+        Ok(Self {
+            name: name.to_string(),
+            source: source.to_string(),
+        })
+    }
+}
+
+impl Mapping {
+    fn value(&self, entry: &Entry) -> Option<Value> {
+        match entry.json().and_then(|v| v.get(&self.source)) {
+            Some(Value::Null) | None => {}
+            Some(v) => return Some(v.clone()),
+        }
+        let bound = BindField::from_str(&self.source).unwrap_or_else(|e| match e {});
+        bound.value(entry).map(Value::String)
+    }
+}
+
+/// Backslash-escapes `special` characters, as line protocol wants them in names and tags
+fn escape(s: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if c == '\\' || special.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn tag_value(value: &Value) -> String {
+    match value {
+        Value::String(v) => v.clone(),
+        v => v.to_string(),
+    }
+}
+
+fn field_value(value: &Value) -> String {
+    match value {
+        Value::Number(v) => match v.as_i64() {
+            Some(v) => format!("{v}i"),
+            None => v.to_string(),
+        },
+        Value::Bool(v) => v.to_string(),
+        Value::String(v) => format!("\"{}\"", escape(v, &['"'])),
+        v => format!("\"{}\"", escape(&v.to_string(), &['"'])),
+    }
+}
+
+/// The points of entries, as lines of the measurement with tags and fields mapped from them
+#[derive(Debug, Clone)]
+pub struct Points {
+    pub measurement: String,
+    pub tags: Vec<Mapping>,
+    pub fields: Vec<Mapping>,
+}
+
+impl Points {
+    /// The line of `entry`, at its own timestamp or else when it was received, in nanoseconds.
+    /// None if it has none of the fields, as a point needs at least one
+    fn line(&self, entry: &Entry) -> Option<String> {
+        let fields: Vec<_> = self
+            .fields
+            .iter()
+            .filter_map(|m| {
+                let value = m.value(entry)?;
+                Some(format!(
+                    "{}={}",
+                    escape(&m.name, &[',', '=', ' ']),
+                    field_value(&value)
+                ))
+            })
+            .collect();
+        if fields.is_empty() {
+            return None;
+        }
+
+        let mut line = escape(&self.measurement, &[',', ' ']);
+        for tag in &self.tags {
+            let Some(value) = tag.value(entry).map(|v| tag_value(&v)) else {
+                continue;
+            };
+            // Empty tag values aren't allowed
+            if !value.is_empty() {
+                line.push(',');
+                line.push_str(&escape(&tag.name, &[',', '=', ' ']));
+                line.push('=');
+                line.push_str(&escape(&value, &[',', '=', ' ']));
+            }
+        }
+        line.push(' ');
+        line.push_str(&fields.join(","));
+
+        let at = entry
+            .fields
+            .get(TIMESTAMP)
+            .and_then(|v| humantime::parse_rfc3339(v).ok())
+            .or(entry.received.map(|v| v.at));
+        if let Some(at) = at.and_then(|v| v.duration_since(UNIX_EPOCH).ok()) {
+            line.push(' ');
+            line.push_str(&at.as_nanos().to_string());
+        }
+        Some(line)
+    }
+
+    /// The lines of `entries`, a line each
+    pub fn lines(&self, entries: &[Entry]) -> String {
+        let mut lines = String::new();
+        for line in entries.iter().filter_map(|v| self.line(v)) {
+            lines.push_str(&line);
+            lines.push('\n');
+        }
+        lines
+    }
+}
+
+/// Writes every batch to `inner`, if any, then as points to an InfluxDB bucket
+pub struct InfluxSink {
+    pub inner: Option<Box<dyn Sink>>,
+    client: reqwest::Client,
+    write_url: String,
+    token: Option<String>,
+    points: Points,
+    retries: Retries,
+}
+
+impl InfluxSink {
+    pub fn new(
+        inner: Option<Box<dyn Sink>>,
+        url: &str,
+        org: &str,
+        bucket: &str,
+        token: Option<String>,
+        points: Points,
+    ) -> anyhow::Result<Self> {
+        let write_url = url::Url::parse_with_params(
+            &format!("{}/api/v2/write", url.trim_end_matches('/')),
+            [("org", org), ("bucket", bucket), ("precision", "ns")],
+        )
+        .with_context(|| format!("Parsing InfluxDB URL {url}"))?;
+
+        Ok(Self {
+            inner,
+            client: reqwest::Client::new(),
+            write_url: write_url.into(),
+            token,
+            points,
+            retries: Default::default(),
+        })
+    }
+
+    /// Retry failed writes by `retries`
+    pub fn with_retries(mut self, retries: Retries) -> Self {
+        self.retries = retries;
+        self
+    }
+}
+
+#[async_trait]
+impl Sink for InfluxSink {
+    async fn write(&mut self, entries: &[Entry]) -> anyhow::Result<()> {
+        self.write_pending(entries).await?.await
+    }
+
+    async fn write_pending(&mut self, entries: &[Entry]) -> anyhow::Result<PendingCommit> {
+        let commit = match &mut self.inner {
+            Some(inner) => inner.write_pending(entries).await?,
+            None => Box::pin(async { Ok(()) }),
+        };
+
+        let lines = self.points.lines(entries);
+        if !lines.is_empty() {
+            let mut attempts = self.retries.attempts("Writing points to InfluxDB");
+            loop {
+                let mut request = self
+                    .client
+                    .post(&self.write_url)
+                    .header(reqwest::header::CONTENT_TYPE, "text/plain; charset=utf-8")
+                    .body(lines.clone());
+                if let Some(token) = &self.token {
+                    request =
+                        request.header(reqwest::header::AUTHORIZATION, format!("Token {token}"));
+                }
+                match request
+                    .send()
+                    .await
+                    .and_then(|v| v.error_for_status())
+                    .context("Writing points to InfluxDB")
+                {
+                    Ok(_) => break,
+                    Err(e) => attempts.failed(e).await?,
+                }
+            }
+        }
+        Ok(commit)
+    }
+
+    async fn tick(&mut self) -> anyhow::Result<()> {
+        match &mut self.inner {
+            Some(inner) => inner.tick().await,
+            None => Ok(()),
+        }
+    }
+
+    async fn close(&mut self) -> anyhow::Result<()> {
+        match &mut self.inner {
+            Some(inner) => inner.close().await,
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::pipeline::Received;
+
+    use super::*;
+
+    #[test]
+    fn writes_line_protocol() {
+        let points = Points {
+            measurement: "gelf logs".to_string(),
+            tags: ["host", "from=sender", "app=_app"]
+                .iter()
+                .map(|v| v.parse().unwrap())
+                .collect(),
+            fields: ["level", "message=short_message", "ok=_ok", "ratio=_ratio"]
+                .iter()
+                .map(|v| v.parse().unwrap())
+                .collect(),
+        };
+        let mut entry = Entry {
+            sender: Some("10.0.0.1:1234".parse().unwrap()),
+            received: Some(Received {
+                at: UNIX_EPOCH + Duration::from_millis(1500),
+                seq: 0,
+            }),
+            ..Default::default()
+        };
+        entry.set_body(
+            r#"{"host":"web 1,a","short_message":"say \"hi\"","level":3,"_ok":true,"_ratio":0.5}"#
+                .to_string(),
+        );
+
+        assert_eq!(
+            "gelf\\ logs,host=web\\ 1\\,a,from=10.0.0.1:1234 \
+             level=3i,message=\"say \\\"hi\\\"\",ok=true,ratio=0.5 1500000000\n",
+            points.lines(&[entry])
+        );
+
+        let mut entry = Entry::default();
+        entry.set_body(r#"{"host":"a"}"#.to_string());
+        assert_eq!("", points.lines(&[entry]));
+    }
+}
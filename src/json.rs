@@ -0,0 +1,103 @@
+//! JSON handling for the entries, backed by simd-json when the `simd-json` feature is enabled.
+//!
+//! Entries are parsed once, by the first stage that needs it, see [`Entry::json`], and the
+//! fields are read from that.
+
+use serde_json::Value;
+
+use crate::pipeline::Entry;
+
+#[cfg(not(feature = "simd-json"))]
+pub fn parse(entry: &str) -> Option<Value> {
+    serde_json::from_str(entry).ok()
+}
+
+#[cfg(feature = "simd-json")]
+pub fn parse(entry: &str) -> Option<Value> {
+    // simd-json parses in place, so it needs a scratch copy of the entry
+    let mut data = entry.as_bytes().to_vec();
+    simd_json::serde::from_slice(&mut data).ok()
+}
+
+pub fn is_valid(entry: &Entry) -> bool {
+    entry.json().is_some()
+}
+
+/// Read a top-level unsigned integer field
+pub fn get_u64(entry: &Entry, field: &str) -> Option<u64> {
+    entry.json()?.get(field)?.as_u64()
+}
+
+/// Read a top-level number field
+pub fn get_f64(entry: &Entry, field: &str) -> Option<f64> {
+    entry.json()?.get(field)?.as_f64()
+}
+
+/// Read a top-level string field
+pub fn get_string(entry: &Entry, field: &str) -> Option<String> {
+    entry.json()?.get(field)?.as_str().map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(body: &str) -> Entry {
+        Entry {
+            body: body.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn validity() {
+        assert!(is_valid(&entry(
+            r#"{"short_message": "hello", "level": 1}"#
+        )));
+        assert!(is_valid(&entry("[1, 2, 3]")));
+        assert!(!is_valid(&entry(r#"{"short_message": "hello""#)));
+        assert!(!is_valid(&entry("hello, world")));
+    }
+
+    #[test]
+    fn u64_fields() {
+        assert_eq!(None, get_u64(&entry("hello, world"), "level"));
+        let entry = entry(r#"{"short_message": "hello", "level": 3, "pid": -1}"#);
+        assert_eq!(Some(3), get_u64(&entry, "level"));
+        assert_eq!(None, get_u64(&entry, "pid"));
+        assert_eq!(None, get_u64(&entry, "short_message"));
+        assert_eq!(None, get_u64(&entry, "missing"));
+    }
+
+    #[test]
+    fn f64_fields() {
+        let entry = entry(r#"{"timestamp": 1700000000.5, "level": 3, "host": "h"}"#);
+        assert_eq!(Some(1700000000.5), get_f64(&entry, "timestamp"));
+        assert_eq!(Some(3.0), get_f64(&entry, "level"));
+        assert_eq!(None, get_f64(&entry, "host"));
+    }
+
+    #[test]
+    fn string_fields() {
+        let entry = entry(r#"{"short_message": "hello", "level": 3}"#);
+        assert_eq!(
+            Some("hello".to_string()),
+            get_string(&entry, "short_message")
+        );
+        assert_eq!(None, get_string(&entry, "level"));
+        assert_eq!(None, get_string(&entry, "missing"));
+    }
+
+    #[test]
+    fn parsed_once() {
+        let mut entry = entry(r#"{"level": 3}"#);
+        assert_eq!(Some(3), get_u64(&entry, "level"));
+
+        entry.set_body(r#"{"level": 4}"#.to_string());
+        assert_eq!(Some(4), get_u64(&entry, "level"));
+
+        entry.set_json(serde_json::json!({ "level": 5 }));
+        assert_eq!(r#"{"level":5}"#, entry.body);
+        assert_eq!(Some(5), get_u64(&entry, "level"));
+    }
+}
@@ -0,0 +1,372 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use async_shutdown::Shutdown;
+use futures::StreamExt;
+use serde_json::Value;
+use tokio::{spawn, time::sleep};
+
+use crate::{
+    json,
+    pipeline::{Entry, Transform},
+};
+
+pub const POD: &str = "k8s-pod";
+pub const NAMESPACE: &str = "k8s-namespace";
+pub const NODE: &str = "k8s-node";
+/// Followed by the name of the label, e.g. `k8s-label-app`
+pub const LABEL_PREFIX: &str = "k8s-label-";
+
+const SERVICE_ACCOUNT_DIR: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+
+/// How long to wait before listing pods again after an error
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long the API server may keep a watch open, before it's picked up again from where it left
+const WATCH_TIMEOUT_SECS: u32 = 300;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Pod {
+    name: String,
+    namespace: String,
+    node: Option<String>,
+    /// Unless the pod shares the node's network, whose address tells nothing about the pod
+    ip: Option<String>,
+    labels: BTreeMap<String, String>,
+}
+
+impl Pod {
+    fn from_json(value: &Value) -> Option<Self> {
+        let metadata = value.get("metadata")?;
+        let spec = value.get("spec");
+        let host_network = spec
+            .and_then(|v| v.get("hostNetwork"))
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        let str_field = |v: Option<&Value>, field: &str| {
+            v.and_then(|v| v.get(field))
+                .and_then(Value::as_str)
+                .map(str::to_string)
+        };
+
+        Some(Self {
+            name: str_field(Some(metadata), "name")?,
+            namespace: str_field(Some(metadata), "namespace")?,
+            node: str_field(spec, "nodeName"),
+            ip: str_field(value.get("status"), "podIP").filter(|_| !host_network),
+            labels: metadata
+                .get("labels")
+                .and_then(Value::as_object)
+                .map(|labels| {
+                    labels
+                        .iter()
+                        .filter_map(|(k, v)| Some((k.clone(), v.as_str()?.to_string())))
+                        .collect()
+                })
+                .unwrap_or_default(),
+        })
+    }
+}
+
+/// The pods we know about, by address and by name
+#[derive(Debug, Default)]
+struct PodIndex {
+    pods: HashMap<(String, String), Arc<Pod>>,
+    by_ip: HashMap<String, Arc<Pod>>,
+    by_name: HashMap<String, Arc<Pod>>,
+}
+
+impl PodIndex {
+    fn insert(&mut self, pod: Pod) {
+        self.remove(&pod.namespace, &pod.name);
+
+        let pod = Arc::new(pod);
+        if let Some(ip) = &pod.ip {
+            self.by_ip.insert(ip.clone(), pod.clone());
+        }
+        self.by_name.insert(pod.name.clone(), pod.clone());
+        self.pods
+            .insert((pod.namespace.clone(), pod.name.clone()), pod);
+    }
+
+    fn remove(&mut self, namespace: &str, name: &str) {
+        let Some(pod) = self.pods.remove(&(namespace.to_string(), name.to_string())) else {
+            return;
+        };
+
+        // Addresses and names may have been taken over by another pod since
+        if let Some(ip) = &pod.ip {
+            if self.by_ip.get(ip).is_some_and(|v| Arc::ptr_eq(v, &pod)) {
+                self.by_ip.remove(ip);
+            }
+        }
+        if self
+            .by_name
+            .get(&pod.name)
+            .is_some_and(|v| Arc::ptr_eq(v, &pod))
+        {
+            self.by_name.remove(&pod.name);
+        }
+    }
+
+    /// The pod with the address or name `key`
+    fn get(&self, key: &str) -> Option<Arc<Pod>> {
+        self.by_ip
+            .get(key)
+            .or_else(|| self.by_name.get(key))
+            .cloned()
+    }
+}
+
+/// Talks to the API server with the service account the daemon runs as
+struct ApiClient {
+    client: reqwest::Client,
+    base_url: String,
+}
+
+impl ApiClient {
+    fn in_cluster() -> anyhow::Result<Self> {
+        let host = std::env::var("KUBERNETES_SERVICE_HOST")
+            .context("KUBERNETES_SERVICE_HOST isn't set, not running in a cluster?")?;
+        let port = std::env::var("KUBERNETES_SERVICE_PORT").unwrap_or_else(|_| "443".into());
+        let host = if host.contains(':') {
+            format!("[{host}]")
+        } else {
+            host
+        };
+
+        let ca_path = format!("{SERVICE_ACCOUNT_DIR}/ca.crt");
+        let ca = std::fs::read(&ca_path).with_context(|| format!("Reading {ca_path}"))?;
+        let client = reqwest::Client::builder()
+            .add_root_certificate(reqwest::Certificate::from_pem(&ca)?)
+            .build()
+            .context("Creating Kubernetes API client")?;
+
+        Ok(Self {
+            client,
+            base_url: format!("https://{host}:{port}"),
+        })
+    }
+
+    /// Read every time, as the token is rotated
+    fn token() -> anyhow::Result<String> {
+        let path = format!("{SERVICE_ACCOUNT_DIR}/token");
+        let token = std::fs::read_to_string(&path).with_context(|| format!("Reading {path}"))?;
+        Ok(token.trim().to_string())
+    }
+
+    async fn get(&self, query: &[(&str, &str)]) -> anyhow::Result<reqwest::Response> {
+        let response = self
+            .client
+            .get(format!("{}/api/v1/pods", self.base_url))
+            .bearer_auth(Self::token()?)
+            .query(query)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(response)
+    }
+
+    /// All pods, and the version to watch for changes from
+    async fn list(&self) -> anyhow::Result<(Vec<Pod>, String)> {
+        let list: Value = self.get(&[]).await?.json().await?;
+        let version = list
+            .pointer("/metadata/resourceVersion")
+            .and_then(Value::as_str)
+            .context("No resourceVersion in pod list")?
+            .to_string();
+        let pods = list
+            .get("items")
+            .and_then(Value::as_array)
+            .map(|items| items.iter().filter_map(Pod::from_json).collect())
+            .unwrap_or_default();
+        Ok((pods, version))
+    }
+
+    /// Apply changes to `index` until the API server ends the watch, returning the version to
+    /// carry on from
+    async fn watch(&self, mut version: String, index: &RwLock<PodIndex>) -> anyhow::Result<String> {
+        let timeout = WATCH_TIMEOUT_SECS.to_string();
+        let response = self
+            .get(&[
+                ("watch", "1"),
+                ("allowWatchBookmarks", "true"),
+                ("resourceVersion", &version),
+                ("timeoutSeconds", &timeout),
+            ])
+            .await?;
+
+        let mut events = response.bytes_stream();
+        let mut buf = Vec::new();
+        while let Some(data) = events.next().await {
+            buf.extend_from_slice(&data?);
+            while let Some(end) = buf.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = buf.drain(..=end).collect();
+                let event: Value = serde_json::from_slice(&line).context("Parsing watch event")?;
+                let object = event.get("object").context("No object in watch event")?;
+
+                let kind = event.get("type").and_then(Value::as_str);
+                if kind == Some("ERROR") {
+                    bail!("Watch failed: {object}");
+                }
+
+                if let Some(v) = object
+                    .pointer("/metadata/resourceVersion")
+                    .and_then(Value::as_str)
+                {
+                    version = v.to_string();
+                }
+
+                let Some(pod) = Pod::from_json(object) else {
+                    continue;
+                };
+                let mut index = index.write().unwrap();
+                match kind {
+                    Some("ADDED" | "MODIFIED") => index.insert(pod),
+                    Some("DELETED") => index.remove(&pod.namespace, &pod.name),
+                    _ => {}
+                }
+            }
+        }
+
+        Ok(version)
+    }
+}
+
+async fn keep_up_to_date(client: ApiClient, index: Arc<RwLock<PodIndex>>, mut version: String) {
+    loop {
+        match client.watch(version.clone(), &index).await {
+            Ok(v) => {
+                version = v;
+                continue;
+            }
+            Err(e) => log::warn!("Error watching pods, listing them again: {e:#}"),
+        }
+
+        loop {
+            sleep(RETRY_INTERVAL).await;
+            match client.list().await {
+                Ok((pods, v)) => {
+                    replace(&index, pods);
+                    version = v;
+                    break;
+                }
+                Err(e) => log::warn!("Error listing pods: {e:#}"),
+            }
+        }
+    }
+}
+
+fn replace(index: &RwLock<PodIndex>, pods: Vec<Pod>) {
+    let mut new_index = PodIndex::default();
+    for pod in pods {
+        new_index.insert(pod);
+    }
+    *index.write().unwrap() = new_index;
+}
+
+/// Adds the namespace, node and labels of the pod an entry came from.
+///
+/// Pods are looked up by the sender's address, or the address or pod name in a top-level field
+/// of JSON entries. They are kept up to date by watching the API server in the background, which
+/// needs permission to list and watch pods.
+pub struct KubeMetadata {
+    index: Arc<RwLock<PodIndex>>,
+    field: Option<String>,
+}
+
+impl KubeMetadata {
+    /// Watch pods with the service account of the pod we run in, until `shutdown`
+    pub async fn in_cluster(field: Option<String>, shutdown: &Shutdown) -> anyhow::Result<Self> {
+        let client = ApiClient::in_cluster()?;
+        let (pods, version) = client.list().await.context("Listing pods")?;
+        log::info!("Found {} pods", pods.len());
+
+        let index = Arc::new(RwLock::new(PodIndex::default()));
+        replace(&index, pods);
+        spawn(shutdown.wrap_cancel(keep_up_to_date(client, index.clone(), version)));
+
+        Ok(Self { index, field })
+    }
+}
+
+impl Transform for KubeMetadata {
+    fn apply(&mut self, mut entry: Entry) -> Option<Entry> {
+        let key = match &self.field {
+            Some(field) => json::get_string(&entry, field),
+            None => entry.sender.map(|v| v.ip().to_string()),
+        };
+
+        let pod = key.and_then(|key| self.index.read().unwrap().get(&key));
+        if let Some(pod) = pod {
+            entry.fields.insert(POD.to_string(), pod.name.clone());
+            entry
+                .fields
+                .insert(NAMESPACE.to_string(), pod.namespace.clone());
+            if let Some(node) = &pod.node {
+                entry.fields.insert(NODE.to_string(), node.clone());
+            }
+            for (k, v) in &pod.labels {
+                entry.fields.insert(format!("{LABEL_PREFIX}{k}"), v.clone());
+            }
+        }
+
+        Some(entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pod(name: &str, ip: &str) -> Value {
+        serde_json::json!({
+            "metadata": {"name": name, "namespace": "default", "labels": {"app": "web"}},
+            "spec": {"nodeName": "node-1"},
+            "status": {"podIP": ip},
+        })
+    }
+
+    #[test]
+    fn parses_pods() {
+        let parsed = Pod::from_json(&pod("web-1", "10.1.0.5")).unwrap();
+        assert_eq!(
+            Pod {
+                name: "web-1".into(),
+                namespace: "default".into(),
+                node: Some("node-1".into()),
+                ip: Some("10.1.0.5".into()),
+                labels: [("app".to_string(), "web".to_string())].into(),
+            },
+            parsed
+        );
+
+        let mut host_network = pod("proxy", "192.168.0.1");
+        host_network["spec"]["hostNetwork"] = true.into();
+        assert_eq!(None, Pod::from_json(&host_network).unwrap().ip);
+
+        assert_eq!(None, Pod::from_json(&serde_json::json!({"metadata": {}})));
+    }
+
+    #[test]
+    fn index_follows_reused_addresses() {
+        let mut index = PodIndex::default();
+        index.insert(Pod::from_json(&pod("web-1", "10.1.0.5")).unwrap());
+        assert_eq!("web-1", index.get("10.1.0.5").unwrap().name);
+        assert_eq!("web-1", index.get("web-1").unwrap().name);
+
+        // The address goes to a new pod before the old one is gone
+        index.insert(Pod::from_json(&pod("web-2", "10.1.0.5")).unwrap());
+        index.remove("default", "web-1");
+        assert_eq!("web-2", index.get("10.1.0.5").unwrap().name);
+        assert!(index.get("web-1").is_none());
+
+        index.remove("default", "web-2");
+        assert!(index.get("10.1.0.5").is_none());
+        assert!(index.pods.is_empty());
+    }
+}
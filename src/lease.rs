@@ -0,0 +1,339 @@
+//! Running collectors in pairs, or more, with only one of them writing at a time: whichever holds
+//! a lease in the database, renewing it for as long as it's up, and another taking it over once
+//! it's no longer renewed
+
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context};
+use async_trait::async_trait;
+use sqlx::any::AnyKind;
+use tokio::time::{interval, MissedTickBehavior};
+
+use crate::{
+    alert::RateLimit,
+    conflict::{self, OnConflict},
+    pipeline::{Entry, PendingCommit, Sink},
+    writer::{Param, Writer},
+};
+
+/// Leases held by this process, and so writing
+pub static LEASES_HELD: AtomicU64 = AtomicU64::new(0);
+/// Entries let go while on standby
+pub static STANDBY_DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// At most this many entries are held while standing by, however recent
+const MAX_HELD: usize = 100_000;
+/// How often letting entries go on standby is logged
+const LOG_EVERY: Duration = Duration::from_secs(10);
+
+/// Whether a [`Lease`] is held, as [`Lease::run`] last found, shared with the [`StandbySink`]
+#[derive(Debug, Clone, Default)]
+pub struct Holding(Arc<AtomicBool>);
+
+impl Holding {
+    pub fn get(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Whether it was held before
+    fn set(&self, holding: bool) -> bool {
+        self.0.swap(holding, Ordering::Relaxed)
+    }
+}
+
+fn unix_millis(at: SystemTime) -> i64 {
+    at.duration_since(UNIX_EPOCH)
+        .map_or(0, |v| v.as_millis() as i64)
+}
+
+/// A lease named `name`, held by `holder` for `ttl` at a time, as a row of `table`, which has to have the
+/// columns `name` (the primary key), `holder` (text) and `expires_at` (a big integer, of
+/// milliseconds since the epoch). It's renewed three times within `ttl`, and given up as
+/// soon as it can't be, so whoever takes it next, going by their own clock, finds it stopped
+/// being renewed in time. Clocks should be within a fraction of `ttl` of each other.
+#[derive(Debug, Clone)]
+pub struct Lease {
+    pub table: String,
+    pub name: String,
+    /// Who we are, e.g. our instance ID
+    pub holder: String,
+    pub ttl: Duration,
+}
+
+impl Lease {
+    /// Takes the lease, or renews it, given the holder, when it expires, the name and now
+    fn renew_sql(&self) -> String {
+        format!(
+            "UPDATE {} SET holder = $1, expires_at = $2 WHERE name = $3 AND (holder = $1 OR expires_at < $4)",
+            self.table
+        )
+    }
+
+    /// Takes the lease the first time anyone does, given the same as [`Lease::renew_sql`] but now
+    fn insert_sql(&self, kind: AnyKind) -> anyhow::Result<String> {
+        let sql = format!(
+            "INSERT INTO {}(name, holder, expires_at) VALUES ($3, $1, $2)",
+            self.table
+        );
+        conflict::rewrite(&sql, OnConflict::Ignore, kind, &["name".to_string()])
+            .context("Taking leases on this database")
+    }
+
+    fn params(&self, now: SystemTime) -> Vec<Param> {
+        vec![
+            Param::Text(Some(self.holder.clone())),
+            Param::Int(Some(unix_millis(now + self.ttl))),
+            Param::Text(Some(self.name.clone())),
+            Param::Int(Some(unix_millis(now))),
+        ]
+    }
+
+    /// Prepare the statements on `writer`, mostly to check the table is there
+    pub async fn prepare(&self, writer: &mut Writer, kind: AnyKind) -> anyhow::Result<()> {
+        let given = ["holder", "expires at", "name", "now"];
+        for (sql, given) in [
+            (self.renew_sql(), &given[..]),
+            (self.insert_sql(kind)?, &given[..3]),
+        ] {
+            writer
+                .prepare(&sql, given)
+                .await
+                .with_context(|| format!("Checking SQL: {sql}"))?;
+        }
+        Ok(())
+    }
+
+    /// Whether we hold the lease now, having taken or renewed it
+    pub async fn try_renew(&self, writer: &mut Writer, kind: AnyKind) -> anyhow::Result<bool> {
+        let mut params = self.params(SystemTime::now());
+        if writer
+            .write_rows(&self.renew_sql(), std::slice::from_ref(&params))
+            .await?
+            > 0
+        {
+            return Ok(true);
+        }
+        // Not given now, as Postgres takes no more parameters than a statement uses
+        params.truncate(3);
+        Ok(writer
+            .write_rows(&self.insert_sql(kind)?, &[params])
+            .await?
+            > 0)
+    }
+
+    /// Take the lease whenever it's free and keep renewing it, for good, telling `holding`
+    /// whether it's ours. Meant to be spawned.
+    pub async fn run(self, mut writer: Writer, kind: AnyKind, holding: Holding) {
+        let mut ticks = interval(self.ttl / 3);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut renewed: Option<Instant> = None;
+
+        loop {
+            ticks.tick().await;
+            let held = match self.try_renew(&mut writer, kind).await {
+                Ok(true) => {
+                    renewed = Some(Instant::now());
+                    true
+                }
+                Ok(false) => false,
+                Err(e) => {
+                    log::warn!("Error renewing the lease {}: {e:#}", self.name);
+                    // Until another can take it, less one renewal for our clocks to differ by
+                    renewed.is_some_and(|v| v.elapsed() < self.ttl - self.ttl / 3)
+                }
+            };
+            if !held {
+                renewed = None;
+            }
+
+            match (holding.set(held), held) {
+                (false, true) => {
+                    LEASES_HELD.fetch_add(1, Ordering::Relaxed);
+                    log::warn!("Took the lease {}, writing from now on", self.name)
+                }
+                (true, false) => {
+                    LEASES_HELD.fetch_sub(1, Ordering::Relaxed);
+                    log::warn!("Lost the lease {}, standing by from now on", self.name)
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Writes to `inner` while we hold the lease. On standby, entries are held for as long as
+/// another collector may have stopped writing without our knowing, the TTL of the lease and one
+/// renewal more, and written first on taking the lease over, as they may be what was sent its way
+/// after it died. Older ones are let go. Entries of durable sources are refused rather than
+/// reported as written, failing the batch.
+pub struct StandbySink {
+    inner: Box<dyn Sink>,
+    holding: Holding,
+    hold_for: Duration,
+    held: VecDeque<(Instant, Entry)>,
+    log: RateLimit,
+    /// Since it was last logged
+    let_go: u64,
+}
+
+impl StandbySink {
+    pub fn new(inner: Box<dyn Sink>, lease: &Lease, holding: Holding) -> Self {
+        Self {
+            inner,
+            holding,
+            hold_for: lease.ttl + lease.ttl / 3,
+            held: Default::default(),
+            log: RateLimit::new(LOG_EVERY),
+            let_go: 0,
+        }
+    }
+
+    /// Let go of what has been held for too long by `now`
+    fn let_go(&mut self, now: Instant) {
+        let before = self.held.len();
+        while self.held.len() > MAX_HELD
+            || self
+                .held
+                .front()
+                .is_some_and(|(at, _)| now.duration_since(*at) > self.hold_for)
+        {
+            self.held.pop_front();
+        }
+        let let_go = (before - self.held.len()) as u64;
+        STANDBY_DROPPED.fetch_add(let_go, Ordering::Relaxed);
+        self.let_go += let_go;
+        if self.let_go > 0 && self.log.fire(now).is_some() {
+            log::warn!(
+                "Standing by for the lease, let {} entries go",
+                std::mem::take(&mut self.let_go)
+            );
+        }
+    }
+
+    fn take_held(&mut self) -> Vec<Entry> {
+        if !self.held.is_empty() {
+            log::info!("Writing {} entries held while standing by", self.held.len());
+        }
+        self.held.drain(..).map(|(_, v)| v).collect()
+    }
+}
+
+#[async_trait]
+impl Sink for StandbySink {
+    async fn write(&mut self, entries: &[Entry]) -> anyhow::Result<()> {
+        self.write_pending(entries).await?.await
+    }
+
+    async fn write_pending(&mut self, entries: &[Entry]) -> anyhow::Result<PendingCommit> {
+        if !self.holding.get() {
+            if entries.iter().any(|v| v.position.is_some()) {
+                bail!("Standing by for the lease, so entries of durable sources can't be written");
+            }
+            let now = Instant::now();
+            self.held.extend(entries.iter().map(|v| (now, v.clone())));
+            self.let_go(now);
+            return Ok(Box::pin(async { Ok(()) }));
+        }
+        if self.held.is_empty() {
+            return self.inner.write_pending(entries).await;
+        }
+        let mut held = self.take_held();
+        held.extend_from_slice(entries);
+        self.inner.write_pending(&held).await
+    }
+
+    /// Even on standby, as what's held back came in while holding the lease
+    async fn tick(&mut self) -> anyhow::Result<()> {
+        if !self.holding.get() {
+            self.let_go(Instant::now());
+        } else if !self.held.is_empty() {
+            let held = self.take_held();
+            self.inner.write(&held).await?;
+        }
+        self.inner.tick().await
+    }
+
+    async fn close(&mut self) -> anyhow::Result<()> {
+        if self.holding.get() {
+            let held = self.take_held();
+            if !held.is_empty() {
+                self.inner.write(&held).await?;
+            }
+        } else if !self.held.is_empty() {
+            STANDBY_DROPPED.fetch_add(self.held.len() as u64, Ordering::Relaxed);
+            log::warn!(
+                "Standing by for the lease, letting {} held entries go",
+                self.held.len()
+            );
+        }
+        self.inner.close().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use crate::pipeline::Position;
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Written(Arc<Mutex<Vec<Entry>>>);
+
+    #[async_trait]
+    impl Sink for Written {
+        async fn write(&mut self, entries: &[Entry]) -> anyhow::Result<()> {
+            self.0.lock().unwrap().extend_from_slice(entries);
+            Ok(())
+        }
+    }
+
+    fn entries(bodies: &[&str]) -> Vec<Entry> {
+        bodies
+            .iter()
+            .map(|v| Entry {
+                body: v.to_string(),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn holds_entries_while_standing_by() {
+        let written = Written::default();
+        let lease = Lease {
+            table: "leases".to_string(),
+            name: "collector".to_string(),
+            holder: "a".to_string(),
+            ttl: Duration::from_secs(3),
+        };
+        let holding = Holding::default();
+        let mut sink = StandbySink::new(Box::new(written.clone()), &lease, holding.clone());
+
+        sink.write(&entries(&["a", "b"])).await.unwrap();
+        sink.let_go(Instant::now() + Duration::from_secs(5));
+        sink.write(&entries(&["c"])).await.unwrap();
+        let durable = Entry {
+            position: Some(Position {
+                source: "spool".to_string(),
+                offset: 1,
+            }),
+            ..Default::default()
+        };
+        assert!(sink.write(&[durable]).await.is_err());
+        assert!(written.0.lock().unwrap().is_empty());
+
+        holding.set(true);
+        sink.write(&entries(&["d"])).await.unwrap();
+        sink.write(&entries(&["e"])).await.unwrap();
+        assert_eq!(entries(&["c", "d", "e"]), *written.0.lock().unwrap());
+    }
+}
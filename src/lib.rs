@@ -0,0 +1,75 @@
+//! Receiving and decoding GELF messages, for services that want them without the SQL part, and
+//! the pipeline moving them from sources to sinks, for embedding the whole daemon.
+
+pub mod aggregate;
+pub mod alert;
+pub mod anonymize;
+pub mod archive;
+pub mod auth;
+pub mod ban;
+pub mod batch;
+pub mod breaker;
+pub mod canary;
+pub mod codec;
+pub mod compression;
+pub mod conflict;
+pub mod connect;
+pub mod credentials;
+pub mod dashboard;
+pub mod dedup;
+pub mod email;
+pub mod encryption;
+pub mod events;
+pub mod extras;
+pub mod failover;
+#[cfg(feature = "fuzzing")]
+pub mod fuzz;
+pub mod gelf;
+pub mod geoip;
+pub mod health;
+pub mod heartbeat;
+pub mod identity;
+pub mod influx;
+pub mod json;
+pub mod k8s;
+pub mod lease;
+pub mod maintenance;
+pub mod memory;
+pub mod metrics;
+pub mod migrations;
+pub mod mirror;
+pub mod overload;
+pub mod parquet;
+pub mod pipeline;
+pub mod placeholders;
+pub mod poison;
+pub mod quarantine;
+pub mod rdns;
+pub mod redact;
+pub mod retry;
+pub mod s3;
+pub mod sample;
+pub mod script;
+pub mod self_test;
+pub mod sequence;
+pub mod service;
+pub mod severity;
+pub mod shard;
+pub mod source;
+pub mod source_stats;
+pub mod splunk;
+pub mod status;
+pub mod stop;
+pub mod tags;
+pub mod tcp;
+pub mod tenant;
+pub mod throttle;
+pub mod timescale;
+pub mod timestamp;
+pub mod tls;
+pub mod trace;
+pub mod writer;
+
+pub use codec::GelfCodec;
+pub use service::Service;
+pub use source::{DecodedMessage, GelfSource};
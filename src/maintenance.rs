@@ -0,0 +1,27 @@
+//! Statements run on a schedule to keep the database in shape, e.g. `VACUUM` and `ANALYZE` on
+//! Postgres, or `PRAGMA optimize` on SQLite
+
+use std::time::{Duration, Instant};
+
+use tokio::time::{interval, MissedTickBehavior};
+
+use crate::writer::Writer;
+
+/// Run each of `sql` in turn every `every`, starting after the first interval, each between the
+/// transactions of the writers sharing the transaction lock of `writer`. Meant to be spawned.
+pub async fn run(mut writer: Writer, sql: Vec<String>, every: Duration) {
+    let mut ticks = interval(every);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    ticks.tick().await;
+
+    loop {
+        ticks.tick().await;
+        for sql in &sql {
+            let started = Instant::now();
+            match writer.run_between_transactions(sql).await {
+                Ok(()) => log::info!("Ran {sql} in {:?}", started.elapsed()),
+                Err(e) => log::warn!("Error running maintenance statement: {e:#}"),
+            }
+        }
+    }
+}
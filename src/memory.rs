@@ -0,0 +1,280 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use anyhow::{bail, Context};
+
+use crate::{
+    gelf::GELFState,
+    json,
+    metrics::{IN_FLIGHT_BYTES, PENDING_BYTES, REASSEMBLY_BYTES},
+    pipeline::Entry,
+};
+
+/// GELF assumes ALERT when an entry doesn't say
+const DEFAULT_LEVEL: u64 = 1;
+
+/// An approximate cap on the memory held by incomplete chunked messages and pending entries.
+///
+/// Once exceeded, the oldest incomplete messages are given up on first, then the least severe
+/// pending entries (highest GELF `level`) are dropped until we are back under the budget.
+///
+/// The budget is shared between the sources holding incomplete messages and the pipeline holding
+/// pending entries: the pipeline publishes how much is pending, sources shed incomplete messages
+/// to make room for it.
+pub struct MemoryBudget {
+    limit: usize,
+    pending_bytes: AtomicUsize,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            pending_bytes: AtomicUsize::new(0),
+        }
+    }
+
+    /// Give up on incomplete messages until they fit next to the pending entries
+    pub fn shed_partials(&self, state: &mut GELFState) {
+        let pending = self.pending_bytes.load(Ordering::Relaxed);
+        let mut num_partials = 0usize;
+        while state.buffered_bytes() + pending > self.limit && state.shed_oldest() {
+            num_partials += 1;
+        }
+
+        if num_partials > 0 {
+            log::warn!(
+                "Memory budget of {} bytes exceeded: dropped {num_partials} incomplete messages",
+                self.limit
+            );
+        }
+    }
+
+    /// Drop the least severe pending entries if they alone exceed the budget, as incomplete
+    /// messages can always make room otherwise.
+    pub fn enforce(&self, batch: &mut Vec<Entry>) {
+        let mut used = entry_bytes(batch);
+        let mut dropped = vec![false; batch.len()];
+        if used > self.limit {
+            let mut candidates: Vec<(u64, usize)> = batch
+                .iter()
+                .enumerate()
+                .map(|(i, entry)| {
+                    let level = json::get_u64(entry, "level").unwrap_or(DEFAULT_LEVEL);
+                    (level, i)
+                })
+                .collect();
+            candidates.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+
+            for (_, i) in candidates {
+                if used <= self.limit {
+                    break;
+                }
+                used -= batch[i].body.len();
+                dropped[i] = true;
+            }
+        }
+        self.pending_bytes.store(used, Ordering::Relaxed);
+
+        let num_entries = dropped.iter().filter(|v| **v).count();
+        if num_entries == 0 {
+            return;
+        }
+
+        let mut dropped = dropped.into_iter();
+        batch.retain(|_| !dropped.next().unwrap());
+
+        log::warn!(
+            "Memory budget of {} bytes exceeded: dropped {num_entries} entries",
+            self.limit
+        );
+    }
+
+    /// The pending entries have been handed over to the sink
+    pub fn clear_pending(&self) {
+        self.pending_bytes.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Entries and incomplete messages dropped for being over the limit
+pub static SHED: AtomicU64 = AtomicU64::new(0);
+
+/// Approximate bytes held by `entries`
+pub fn entry_bytes(entries: &[Entry]) -> usize {
+    entries.iter().map(|entry| entry.body.len()).sum()
+}
+
+/// Approximate memory in use: by incomplete chunked messages, the entries being batched up and
+/// the batches waiting to be written or committed, across the process
+pub fn used() -> u64 {
+    [&REASSEMBLY_BYTES, &PENDING_BYTES, &IN_FLIGHT_BYTES]
+        .iter()
+        .map(|v| v.load(Ordering::Relaxed))
+        .sum()
+}
+
+/// A hard cap on the memory [`used`]: beyond it, sources give up on incomplete messages and new
+/// entries are dropped, rather than growing until the OOM killer steps in.
+///
+/// It's the whole process's memory that's counted, as it's the process that runs out. Share the
+/// same limit between the pipeline and the [`GelfSource`](crate::GelfSource).
+#[derive(Debug)]
+pub struct MemoryLimit {
+    limit: usize,
+    /// Whether [`used`] memory was beyond the limit when last looked at
+    over: AtomicBool,
+}
+
+impl MemoryLimit {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            over: AtomicBool::new(false),
+        }
+    }
+
+    /// Whether memory [`used`] is beyond the limit, logging loudly once it goes beyond it and
+    /// once it's back under 90% of it
+    pub fn is_over(&self) -> bool {
+        self.is_over_at(used())
+    }
+
+    fn is_over_at(&self, used: u64) -> bool {
+        let limit = self.limit;
+        // Once over, until well under, so it doesn't flip back and forth with every entry
+        let was_over = self.over.load(Ordering::Relaxed);
+        let over = used > if was_over { limit / 10 * 9 } else { limit } as u64;
+        if was_over != over {
+            self.over.store(over, Ordering::Relaxed);
+            if over {
+                log::error!(
+                    "Memory limit of {limit} bytes exceeded, dropping entries and incomplete \
+                     messages until back under it: {used} bytes in use, {} in incomplete \
+                     messages, {} being batched up, {} waiting to be written",
+                    REASSEMBLY_BYTES.load(Ordering::Relaxed),
+                    PENDING_BYTES.load(Ordering::Relaxed),
+                    IN_FLIGHT_BYTES.load(Ordering::Relaxed),
+                );
+            } else {
+                log::warn!(
+                    "Back under the memory limit of {limit} bytes, after dropping {} entries and \
+                     incomplete messages so far",
+                    SHED.load(Ordering::Relaxed)
+                );
+            }
+        }
+        over
+    }
+
+    /// Give up on every incomplete message of `state` if over the limit
+    pub fn shed(&self, state: &mut GELFState) {
+        if !self.is_over() {
+            return;
+        }
+        while state.shed_oldest() {
+            SHED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Parse a size such as `1048576`, `512K` or `64MiB`
+pub fn parse_size(input: &str) -> anyhow::Result<usize> {
+    let input = input.trim();
+    let split = input
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(input.len());
+    let (number, unit) = input.split_at(split);
+    let number: usize = number
+        .parse()
+        .with_context(|| format!("Invalid size: {input}"))?;
+
+    let multiplier: usize = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        _ => bail!("Unknown size unit: {unit}"),
+    };
+
+    number
+        .checked_mul(multiplier)
+        .with_context(|| format!("Size too large: {input}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(1048576, parse_size("1048576").unwrap());
+        assert_eq!(512 << 10, parse_size("512K").unwrap());
+        assert_eq!(64 << 20, parse_size("64MiB").unwrap());
+        assert_eq!(2 << 30, parse_size("2 gb").unwrap());
+        assert!(parse_size("MiB").is_err());
+        assert!(parse_size("12 parsecs").is_err());
+    }
+
+    fn entry(body: &str) -> Entry {
+        Entry {
+            body: body.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn drops_least_severe_entries_first() {
+        let mut batch = vec![
+            entry(r#"{"level":7,"short_message":"a"}"#),
+            entry(r#"{"level":3,"short_message":"b"}"#),
+            entry(r#"{"level":7,"short_message":"c"}"#),
+            entry(r#"{"level":6,"short_message":"d"}"#),
+        ];
+
+        let budget = MemoryBudget::new(batch[0].body.len() * 2);
+        budget.enforce(&mut batch);
+
+        assert_eq!(
+            vec![
+                r#"{"level":3,"short_message":"b"}"#,
+                r#"{"level":6,"short_message":"d"}"#,
+            ],
+            batch.iter().map(|e| e.body.as_str()).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn sheds_partials_for_pending_entries() {
+        let mut state = GELFState::default();
+        let chunk: bytes::Bytes = [&[0x1e, 0x0f][..], &[1; 8], &[0, 2], b"12345"]
+            .concat()
+            .into();
+        state
+            .on_data("127.0.0.1:12201".parse().unwrap(), &chunk)
+            .unwrap();
+
+        let budget = MemoryBudget::new(8);
+        budget.shed_partials(&mut state);
+        assert_eq!(5, state.buffered_bytes());
+
+        budget.enforce(&mut vec![entry("hello")]);
+        budget.shed_partials(&mut state);
+        assert_eq!(0, state.buffered_bytes());
+    }
+
+    #[test]
+    fn over_the_limit_until_well_under() {
+        let limit = MemoryLimit::new(100);
+        assert!(!limit.is_over_at(100));
+        assert!(limit.is_over_at(101));
+        assert!(limit.is_over_at(91));
+        assert!(!limit.is_over_at(90));
+        assert!(!MemoryLimit::new(100).is_over_at(91));
+    }
+
+    #[test]
+    fn within_budget() {
+        let mut batch = vec![entry("hello")];
+        MemoryBudget::new(5).enforce(&mut batch);
+        assert_eq!(1, batch.len());
+    }
+}
@@ -0,0 +1,585 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+    spawn,
+    time::{interval, MissedTickBehavior},
+};
+
+use crate::{
+    health::{Event, Health},
+    lease, memory, mirror,
+    overload::Overload,
+    poison,
+    quarantine::Quarantine,
+    retry, sequence, status, timestamp,
+};
+
+/// Entries received from the sources so far
+pub static ENTRIES_RECEIVED: AtomicU64 = AtomicU64::new(0);
+/// Entries committed to the sink so far
+pub static ENTRIES_COMMITTED: AtomicU64 = AtomicU64::new(0);
+/// Entries received but not batched up yet
+pub static QUEUE_DEPTH: AtomicU64 = AtomicU64::new(0);
+/// Full batches waiting for, or being written by, the sink
+pub static BATCHES_IN_FLIGHT: AtomicU64 = AtomicU64::new(0);
+/// Bytes held by incomplete chunked messages, across sources
+pub static REASSEMBLY_BYTES: AtomicU64 = AtomicU64::new(0);
+/// Bytes of the entries being batched up
+pub static PENDING_BYTES: AtomicU64 = AtomicU64::new(0);
+/// Bytes of the batches waiting for, or being written by, the sink, until committed
+pub static IN_FLIGHT_BYTES: AtomicU64 = AtomicU64::new(0);
+/// When a batch was last committed, in micros since the epoch, or 0
+pub static LAST_COMMITTED: AtomicU64 = AtomicU64::new(0);
+/// When the oldest entry not committed yet was received, in micros since the epoch, or 0
+pub static OLDEST_UNCOMMITTED: AtomicU64 = AtomicU64::new(0);
+
+/// One pipeline's part of a gauge such as [`QUEUE_DEPTH`], which is the process's, summed over
+/// every pipeline running in it. Taken back out when dropped.
+#[derive(Debug)]
+pub struct Share {
+    gauge: &'static AtomicU64,
+    value: u64,
+}
+
+impl Share {
+    pub fn new(gauge: &'static AtomicU64) -> Self {
+        Self { gauge, value: 0 }
+    }
+
+    pub fn set(&mut self, value: u64) {
+        if value > self.value {
+            self.gauge.fetch_add(value - self.value, Ordering::Relaxed);
+        } else {
+            self.gauge.fetch_sub(self.value - value, Ordering::Relaxed);
+        }
+        self.value = value;
+    }
+
+    pub fn add(&mut self, n: u64) {
+        self.set(self.value + n);
+    }
+}
+
+impl Drop for Share {
+    fn drop(&mut self) {
+        self.set(0);
+    }
+}
+
+/// How long each statement took to execute
+pub static STATEMENT_SECONDS: Histogram = Histogram::new();
+/// How long each transaction took to commit
+pub static COMMIT_SECONDS: Histogram = Histogram::new();
+/// From receiving the oldest entry of each batch to committing the batch
+pub static BATCH_LATENCY_SECONDS: Histogram = Histogram::new();
+
+/// Upper bounds of the buckets of every [`Histogram`], in seconds
+const BUCKETS: [f64; 15] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0,
+];
+
+/// Counts durations into [`BUCKETS`]
+#[derive(Debug)]
+pub struct Histogram {
+    /// Not cumulative, the last one for everything beyond the buckets
+    counts: [AtomicU64; BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub const fn new() -> Self {
+        Self {
+            counts: [const { AtomicU64::new(0) }; BUCKETS.len() + 1],
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        let bucket = BUCKETS
+            .iter()
+            .position(|le| secs <= *le)
+            .unwrap_or(BUCKETS.len());
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Estimate the `q`th quantile, 0 to 1, by where it falls within its bucket, as Prometheus'
+    /// `histogram_quantile` does
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        let counts: Vec<u64> = self
+            .counts
+            .iter()
+            .map(|v| v.load(Ordering::Relaxed))
+            .collect();
+        let total: u64 = counts.iter().sum();
+        if total == 0 {
+            return None;
+        }
+
+        let rank = q.clamp(0.0, 1.0) * total as f64;
+        let mut below = 0;
+        for (i, count) in counts.iter().enumerate() {
+            if *count > 0 && (below + count) as f64 >= rank {
+                let Some(upper) = BUCKETS.get(i) else {
+                    return BUCKETS.last().copied();
+                };
+                let lower = if i == 0 { 0.0 } else { BUCKETS[i - 1] };
+                return Some(lower + (upper - lower) * (rank - below as f64) / *count as f64);
+            }
+            below += count;
+        }
+        BUCKETS.last().copied()
+    }
+
+    fn render(&self, name: &str, help: &str, text: &mut String) {
+        let _ = writeln!(text, "# HELP {name} {help}\n# TYPE {name} histogram");
+        let mut cumulative = 0;
+        for (i, count) in self.counts.iter().enumerate() {
+            cumulative += count.load(Ordering::Relaxed);
+            let _ = match BUCKETS.get(i) {
+                Some(le) => writeln!(text, "{name}_bucket{{le=\"{le}\"}} {cumulative}"),
+                None => writeln!(text, "{name}_bucket{{le=\"+Inf\"}} {cumulative}"),
+            };
+        }
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(text, "{name}_sum {sum}\n{name}_count {cumulative}");
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The oldest uncommitted entry of each pipeline, by [`Oldest::id`], in micros since the epoch
+static OLDEST_BY_PIPELINE: Mutex<BTreeMap<u64, u64>> = Mutex::new(BTreeMap::new());
+static NEXT_PIPELINE: AtomicU64 = AtomicU64::new(0);
+
+/// One pipeline's oldest uncommitted entry, [`OLDEST_UNCOMMITTED`] being the oldest of every
+/// pipeline's. Taken back out when dropped.
+#[derive(Debug)]
+pub struct Oldest {
+    id: u64,
+}
+
+impl Default for Oldest {
+    fn default() -> Self {
+        Self {
+            id: NEXT_PIPELINE.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+}
+
+impl Oldest {
+    pub fn set(&self, at: Option<SystemTime>) {
+        let micros = at
+            .and_then(|v| v.duration_since(UNIX_EPOCH).ok())
+            .map(|v| v.as_micros() as u64);
+        let mut by_pipeline = OLDEST_BY_PIPELINE.lock().unwrap();
+        match micros {
+            Some(micros) => by_pipeline.insert(self.id, micros),
+            None => by_pipeline.remove(&self.id),
+        };
+        let oldest = by_pipeline.values().min().copied().unwrap_or(0);
+        OLDEST_UNCOMMITTED.store(oldest, Ordering::Relaxed);
+    }
+}
+
+impl Drop for Oldest {
+    fn drop(&mut self) {
+        self.set(None);
+    }
+}
+
+pub fn set_last_committed(at: SystemTime) {
+    let micros = at.duration_since(UNIX_EPOCH).unwrap_or_default();
+    LAST_COMMITTED.store(micros.as_micros() as u64, Ordering::Relaxed);
+}
+
+/// How long ago a batch was last committed, if one was
+pub(crate) fn since_last_commit(now: SystemTime) -> Option<Duration> {
+    match LAST_COMMITTED.load(Ordering::Relaxed) {
+        0 => None,
+        micros => Some(
+            now.duration_since(UNIX_EPOCH + Duration::from_micros(micros))
+                .unwrap_or_default(),
+        ),
+    }
+}
+
+/// How long the oldest uncommitted entry has been waiting
+pub(crate) fn lag(now: SystemTime) -> Duration {
+    match OLDEST_UNCOMMITTED.load(Ordering::Relaxed) {
+        0 => Duration::ZERO,
+        micros => now
+            .duration_since(UNIX_EPOCH + Duration::from_micros(micros))
+            .unwrap_or_default(),
+    }
+}
+
+pub(crate) struct Metric {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub kind: &'static str,
+    pub value: f64,
+}
+
+/// The metrics, with the counts of `health`
+pub(crate) fn snapshot(health: &Health) -> Vec<Metric> {
+    let gauge = |name, help, value| Metric {
+        name,
+        help,
+        kind: "gauge",
+        value,
+    };
+    let counter = |name, help, event| Metric {
+        name,
+        help,
+        kind: "counter",
+        value: health.count(event) as f64,
+    };
+
+    vec![
+        Metric {
+            name: "sqlx_logger_entries_received_total",
+            help: "Entries received from the sources",
+            kind: "counter",
+            value: ENTRIES_RECEIVED.load(Ordering::Relaxed) as f64,
+        },
+        Metric {
+            name: "sqlx_logger_entries_committed_total",
+            help: "Entries committed to the database, or whatever is written to instead",
+            kind: "counter",
+            value: ENTRIES_COMMITTED.load(Ordering::Relaxed) as f64,
+        },
+        gauge(
+            "sqlx_logger_queue_depth",
+            "Entries received but not batched up yet",
+            QUEUE_DEPTH.load(Ordering::Relaxed) as f64,
+        ),
+        gauge(
+            "sqlx_logger_batches_in_flight",
+            "Full batches waiting to be written",
+            BATCHES_IN_FLIGHT.load(Ordering::Relaxed) as f64,
+        ),
+        gauge(
+            "sqlx_logger_reassembly_bytes",
+            "Bytes held by incomplete chunked messages",
+            REASSEMBLY_BYTES.load(Ordering::Relaxed) as f64,
+        ),
+        gauge(
+            "sqlx_logger_pending_bytes",
+            "Bytes of the entries being batched up",
+            PENDING_BYTES.load(Ordering::Relaxed) as f64,
+        ),
+        gauge(
+            "sqlx_logger_in_flight_bytes",
+            "Bytes of the batches not committed yet",
+            IN_FLIGHT_BYTES.load(Ordering::Relaxed) as f64,
+        ),
+        gauge(
+            "sqlx_logger_memory_bytes",
+            "Approximate memory in use, all of the above",
+            memory::used() as f64,
+        ),
+        Metric {
+            name: "sqlx_logger_memory_shed_total",
+            help: "Entries and incomplete messages dropped over the memory limit",
+            kind: "counter",
+            value: memory::SHED.load(Ordering::Relaxed) as f64,
+        },
+        Metric {
+            name: "sqlx_logger_overload_dropped_newest_total",
+            help: "Entries dropped on arrival for the queue being full",
+            kind: "counter",
+            value: Overload::DropNewest.dropped() as f64,
+        },
+        Metric {
+            name: "sqlx_logger_overload_dropped_oldest_total",
+            help: "Entries that waited longest, dropped for the queue being full",
+            kind: "counter",
+            value: Overload::DropOldest.dropped() as f64,
+        },
+        Metric {
+            name: "sqlx_logger_overload_dropped_lowest_severity_total",
+            help: "Least severe entries dropped for the queue being full",
+            kind: "counter",
+            value: Overload::DropLowestSeverity.dropped() as f64,
+        },
+        Metric {
+            name: "sqlx_logger_mirror_written_total",
+            help: "Entries written to the mirror database as well",
+            kind: "counter",
+            value: mirror::MIRROR_WRITTEN.load(Ordering::Relaxed) as f64,
+        },
+        Metric {
+            name: "sqlx_logger_mirror_spooled_total",
+            help: "Entries spooled while the mirror database was down",
+            kind: "counter",
+            value: mirror::MIRROR_SPOOLED.load(Ordering::Relaxed) as f64,
+        },
+        Metric {
+            name: "sqlx_logger_mirror_failed_total",
+            help: "Entries that failed to go to the mirror database",
+            kind: "counter",
+            value: mirror::MIRROR_FAILED.load(Ordering::Relaxed) as f64,
+        },
+        gauge(
+            "sqlx_logger_mirror_divergence_entries",
+            "Entries written to the database but not to the mirror",
+            mirror::divergence() as f64,
+        ),
+        Metric {
+            name: "sqlx_logger_retries_total",
+            help: "Writes and alerts tried again after failing",
+            kind: "counter",
+            value: retry::RETRIED.load(Ordering::Relaxed) as f64,
+        },
+        Metric {
+            name: "sqlx_logger_retries_over_budget_total",
+            help: "Retries not made for the retry budget having run out",
+            kind: "counter",
+            value: retry::OVER_BUDGET.load(Ordering::Relaxed) as f64,
+        },
+        gauge(
+            "sqlx_logger_lease_held",
+            "1 while holding the lease of --ha-lease-table, and so writing",
+            lease::LEASES_HELD.load(Ordering::Relaxed) as f64,
+        ),
+        Metric {
+            name: "sqlx_logger_standby_dropped_entries_total",
+            help: "Entries let go while standing by for the lease",
+            kind: "counter",
+            value: lease::STANDBY_DROPPED.load(Ordering::Relaxed) as f64,
+        },
+        Metric {
+            name: "sqlx_logger_sequence_gaps_total",
+            help: "Gaps in the sequence numbers of senders, with --sequence-field",
+            kind: "counter",
+            value: sequence::GAPS.load(Ordering::Relaxed) as f64,
+        },
+        Metric {
+            name: "sqlx_logger_sequence_missing_entries_total",
+            help: "Entries missing in those gaps, as far as the sequence numbers tell",
+            kind: "counter",
+            value: sequence::MISSING.load(Ordering::Relaxed) as f64,
+        },
+        Metric {
+            name: "sqlx_logger_sequence_late_entries_total",
+            help:
+                "Entries behind the latest sequence number of their sender, arriving late or again",
+            kind: "counter",
+            value: sequence::LATE.load(Ordering::Relaxed) as f64,
+        },
+        Metric {
+            name: "sqlx_logger_too_old_entries_total",
+            help: "Entries dropped by --drop-older-than",
+            kind: "counter",
+            value: timestamp::TOO_OLD.load(Ordering::Relaxed) as f64,
+        },
+        Metric {
+            name: "sqlx_logger_poison_entries_total",
+            help: "Entries the database kept refusing, sent to --poison-dead-letter",
+            kind: "counter",
+            value: poison::POISONED.load(Ordering::Relaxed) as f64,
+        },
+        gauge(
+            "sqlx_logger_lag_seconds",
+            "How long the oldest entry not committed yet has been waiting",
+            lag(SystemTime::now()).as_secs_f64(),
+        ),
+        counter(
+            "sqlx_logger_decode_errors_total",
+            "Messages that failed to decode",
+            Event::DecodeError,
+        ),
+        counter(
+            "sqlx_logger_dropped_total",
+            "Entries dropped by the filter or transforms",
+            Event::Dropped,
+        ),
+        counter(
+            "sqlx_logger_db_errors_total",
+            "Failed writes",
+            Event::DbError,
+        ),
+    ]
+}
+
+/// Every metric in the Prometheus text format, with the counts of `health`
+pub fn render(health: &Health) -> String {
+    let mut text = String::new();
+    for Metric {
+        name,
+        help,
+        kind,
+        value,
+    } in snapshot(health)
+    {
+        let _ = writeln!(
+            text,
+            "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}"
+        );
+    }
+    STATEMENT_SECONDS.render(
+        "sqlx_logger_statement_seconds",
+        "How long each statement took to execute",
+        &mut text,
+    );
+    COMMIT_SECONDS.render(
+        "sqlx_logger_commit_seconds",
+        "How long each transaction took to commit",
+        &mut text,
+    );
+    BATCH_LATENCY_SECONDS.render(
+        "sqlx_logger_batch_latency_seconds",
+        "From receiving the oldest entry of each batch to committing the batch",
+        &mut text,
+    );
+    text
+}
+
+/// Every metric on one line, for the log, with percentiles of the batch latency
+pub fn stats_line(health: &Health) -> String {
+    let mut stats: Vec<_> = snapshot(health)
+        .iter()
+        .map(|v| format!("{}={}", v.name.trim_start_matches("sqlx_logger_"), v.value))
+        .collect();
+    for (name, q) in [("p50", 0.5), ("p90", 0.9), ("p99", 0.99)] {
+        if let Some(v) = BATCH_LATENCY_SECONDS.quantile(q) {
+            stats.push(format!("batch_latency_{name}_seconds={v:.3}"));
+        }
+    }
+    stats.join(" ")
+}
+
+/// Log [`stats_line`] of `health` every `interval` for good. Meant to be spawned.
+pub async fn log_stats(every: Duration, health: Health) {
+    let mut ticks = interval(every);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    ticks.tick().await;
+    loop {
+        ticks.tick().await;
+        log::info!("Stats: {}", stats_line(&health));
+    }
+}
+
+/// Answer requests for `/status` with [`status::page`] of `settings`, `quarantine` and `health`,
+/// and every other with [`render`], whatever the path, for good. Meant to be spawned.
+pub async fn serve(
+    listener: TcpListener,
+    settings: Vec<(&'static str, String)>,
+    quarantine: Quarantine,
+    health: Health,
+) -> anyhow::Result<()> {
+    let settings = Arc::new(settings);
+    loop {
+        let (mut stream, addr) = listener.accept().await?;
+        let settings = settings.clone();
+        let quarantine = quarantine.clone();
+        let health = health.clone();
+        spawn(async move {
+            let mut request = [0u8; 1024];
+            let read = stream.read(&mut request).await.unwrap_or_default();
+            let path = std::str::from_utf8(&request[..read])
+                .ok()
+                .and_then(|v| v.split(' ').nth(1));
+
+            let (content_type, body) = match path {
+                Some("/status") => (
+                    "text/html; charset=utf-8",
+                    status::page(&settings, &quarantine, &health),
+                ),
+                _ => ("text/plain; version=0.0.4", render(&health)),
+            };
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            if let Err(e) = stream.write_all(response.as_bytes()).await {
+                log::debug!("Error serving metrics to {addr}: {e}");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lag_of_oldest_entry() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let oldest = Oldest::default();
+        let newer = Oldest::default();
+        oldest.set(Some(now - Duration::from_secs(3)));
+        newer.set(Some(now - Duration::from_secs(1)));
+        assert_eq!(Duration::from_secs(3), lag(now));
+        drop(oldest);
+        assert_eq!(Duration::from_secs(1), lag(now));
+        newer.set(None);
+        assert_eq!(Duration::ZERO, lag(now));
+
+        assert!(render(&Health::default())
+            .contains("# TYPE sqlx_logger_lag_seconds gauge\nsqlx_logger_lag_seconds 0\n"));
+    }
+
+    #[test]
+    fn histogram() {
+        let histogram = Histogram::new();
+        histogram.observe(Duration::from_micros(300));
+        histogram.observe(Duration::from_millis(20));
+        histogram.observe(Duration::from_secs(100));
+
+        let mut text = String::new();
+        histogram.render("t", "Test", &mut text);
+        assert!(text.contains("t_bucket{le=\"0.0005\"} 1\n"));
+        assert!(text.contains("t_bucket{le=\"0.01\"} 1\nt_bucket{le=\"0.025\"} 2\n"));
+        assert!(text.contains("t_bucket{le=\"60\"} 2\nt_bucket{le=\"+Inf\"} 3\n"));
+        assert!(text.ends_with("t_sum 100.0203\nt_count 3\n"));
+    }
+
+    #[test]
+    fn quantiles() {
+        let histogram = Histogram::new();
+        assert_eq!(None, histogram.quantile(0.5));
+
+        for _ in 0..50 {
+            histogram.observe(Duration::from_millis(200));
+        }
+        for _ in 0..50 {
+            histogram.observe(Duration::from_millis(700));
+        }
+        // Halfway through (0.1, 0.25], and 80% through (0.5, 1]
+        assert_eq!(Some(0.175), histogram.quantile(0.25));
+        assert_eq!(Some(0.9), histogram.quantile(0.9));
+        assert_eq!(Some(1.0), histogram.quantile(1.0));
+    }
+
+    #[test]
+    fn shares_of_a_gauge() {
+        static GAUGE: AtomicU64 = AtomicU64::new(0);
+        let mut one = Share::new(&GAUGE);
+        let mut other = Share::new(&GAUGE);
+        one.set(5);
+        other.add(3);
+        other.add(3);
+        one.set(2);
+        assert_eq!(8, GAUGE.load(Ordering::Relaxed));
+        drop(other);
+        assert_eq!(2, GAUGE.load(Ordering::Relaxed));
+    }
+}
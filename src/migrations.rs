@@ -0,0 +1,31 @@
+//! Keeping the schema written to versioned along with the daemon, with sqlx migrations
+
+use std::path::Path;
+
+use anyhow::Context;
+use sqlx::{migrate::Migrator, AnyPool};
+
+/// Apply the migrations in `dir` not applied yet, `<version>_<description>.sql` as `sqlx migrate`
+/// has them. The database is locked meanwhile where it can be, on Postgres and MySQL, so
+/// collectors starting together take turns rather than race, and the ones after the first find
+/// nothing left to do.
+pub async fn apply(pool: &AnyPool, dir: &Path) -> anyhow::Result<()> {
+    let migrator = Migrator::new(dir)
+        .await
+        .with_context(|| format!("Reading migrations from {}", dir.display()))?;
+    migrator
+        .run(pool)
+        .await
+        .with_context(|| format!("Applying migrations from {}", dir.display()))?;
+
+    match migrator.iter().last() {
+        Some(latest) => log::info!(
+            "Schema is at version {} ({}) of {}",
+            latest.version,
+            latest.description,
+            dir.display()
+        ),
+        None => log::warn!("No migrations in {}", dir.display()),
+    }
+    Ok(())
+}
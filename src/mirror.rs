@@ -0,0 +1,146 @@
+//! Writing every batch to a second database as well, e.g. the new cluster during a migration,
+//! keeping count of where the two part ways
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use async_trait::async_trait;
+
+use crate::{
+    health::{Event, Health},
+    pipeline::{Entry, PendingCommit, Sink},
+};
+
+/// Entries written to the primary database by a [`MirrorSink`]
+pub static PRIMARY_WRITTEN: AtomicU64 = AtomicU64::new(0);
+/// Entries written to the mirror as well
+pub static MIRROR_WRITTEN: AtomicU64 = AtomicU64::new(0);
+/// Entries spooled while the mirror was down, to replay into it later
+pub static MIRROR_SPOOLED: AtomicU64 = AtomicU64::new(0);
+/// Entries that failed to go to the mirror and weren't spooled
+pub static MIRROR_FAILED: AtomicU64 = AtomicU64::new(0);
+
+/// Entries written to the primary database but not to the mirror, spooled or lost
+pub fn divergence() -> u64 {
+    let primary = PRIMARY_WRITTEN.load(Ordering::Relaxed);
+    primary.saturating_sub(MIRROR_WRITTEN.load(Ordering::Relaxed))
+}
+
+/// Writes every batch to `primary`, then to `mirror`. Only the primary failing fails the batch:
+/// the mirror failing is logged and counted, so a migration target can't hold up the database in
+/// use. Each retries, or spools, on its own, the mirror's spool counted with [`Spooled`].
+pub struct MirrorSink {
+    pub primary: Box<dyn Sink>,
+    pub mirror: Box<dyn Sink>,
+    /// Where failed writes to the mirror are counted
+    pub health: Health,
+}
+
+#[async_trait]
+impl Sink for MirrorSink {
+    async fn write(&mut self, entries: &[Entry]) -> anyhow::Result<()> {
+        self.write_pending(entries).await?.await
+    }
+
+    async fn write_pending(&mut self, entries: &[Entry]) -> anyhow::Result<PendingCommit> {
+        let commit = self.primary.write_pending(entries).await?;
+        let num_entries = entries.len() as u64;
+
+        let spooled = MIRROR_SPOOLED.load(Ordering::Relaxed);
+        match self.mirror.write(entries).await {
+            Ok(()) if MIRROR_SPOOLED.load(Ordering::Relaxed) == spooled => {
+                MIRROR_WRITTEN.fetch_add(num_entries, Ordering::Relaxed);
+            }
+            Ok(()) => {}
+            Err(e) => {
+                log::error!("Error writing {num_entries} entries to the mirror: {e:#}");
+                self.health.record(Event::DbError);
+                MIRROR_FAILED.fetch_add(num_entries, Ordering::Relaxed);
+            }
+        }
+
+        Ok(Box::pin(async move {
+            commit.await?;
+            PRIMARY_WRITTEN.fetch_add(num_entries, Ordering::Relaxed);
+            Ok(())
+        }))
+    }
+
+    async fn close(&mut self) -> anyhow::Result<()> {
+        self.mirror.close().await?;
+        self.primary.close().await?;
+        log::info!(
+            "Mirrored {} of {} entries, {} spooled, {} failed",
+            MIRROR_WRITTEN.load(Ordering::Relaxed),
+            PRIMARY_WRITTEN.load(Ordering::Relaxed),
+            MIRROR_SPOOLED.load(Ordering::Relaxed),
+            MIRROR_FAILED.load(Ordering::Relaxed),
+        );
+        Ok(())
+    }
+}
+
+/// Counts what the mirror spools into `inner` while it's down
+pub struct Spooled(pub Box<dyn Sink>);
+
+#[async_trait]
+impl Sink for Spooled {
+    async fn write(&mut self, entries: &[Entry]) -> anyhow::Result<()> {
+        self.0.write(entries).await?;
+        MIRROR_SPOOLED.fetch_add(entries.len() as u64, Ordering::Relaxed);
+        Ok(())
+    }
+
+    async fn close(&mut self) -> anyhow::Result<()> {
+        self.0.close().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::bail;
+
+    use super::*;
+
+    struct Failing;
+
+    #[async_trait]
+    impl Sink for Failing {
+        async fn write(&mut self, _: &[Entry]) -> anyhow::Result<()> {
+            bail!("Down")
+        }
+    }
+
+    struct Written;
+
+    #[async_trait]
+    impl Sink for Written {
+        async fn write(&mut self, _: &[Entry]) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn counts_divergence() {
+        let entries = vec![Entry::default(); 3];
+        let mut sink = MirrorSink {
+            primary: Box::new(Written),
+            mirror: Box::new(Written),
+            health: Default::default(),
+        };
+        sink.write(&entries).await.unwrap();
+        sink.mirror = Box::new(Failing);
+        sink.write(&entries).await.unwrap();
+        sink.mirror = Box::new(Spooled(Box::new(Written)));
+        sink.write(&entries).await.unwrap();
+
+        assert_eq!(9, PRIMARY_WRITTEN.load(Ordering::Relaxed));
+        assert_eq!(3, MIRROR_WRITTEN.load(Ordering::Relaxed));
+        assert_eq!(3, MIRROR_FAILED.load(Ordering::Relaxed));
+        assert_eq!(3, MIRROR_SPOOLED.load(Ordering::Relaxed));
+        assert_eq!(6, divergence());
+
+        sink.primary = Box::new(Failing);
+        assert!(sink.write(&entries).await.is_err());
+        assert_eq!(3, MIRROR_WRITTEN.load(Ordering::Relaxed));
+    }
+}
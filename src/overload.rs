@@ -0,0 +1,282 @@
+//! Choosing which entries to give up on when the queue between the sources and the batching is
+//! full, rather than pushing back on the sources until the kernel drops datagrams blindly
+
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use clap::ValueEnum;
+use tokio::sync::mpsc;
+
+use crate::{
+    health::{Event, Health},
+    json, metrics,
+    pipeline::{Entry, Received},
+    source_stats::SourceStats,
+};
+
+/// GELF assumes ALERT when an entry doesn't say
+const DEFAULT_LEVEL: u64 = 1;
+/// Room between the sources and the relay, which takes entries as fast as they come
+const RELAY_ROOM: usize = 64;
+
+/// What to do with an entry received while the queue is full
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Overload {
+    /// Stop taking entries from the sources until there is room, so sockets fill up and the
+    /// kernel drops whatever comes next
+    #[default]
+    Block,
+    /// Drop the entry just received
+    DropNewest,
+    /// Drop the entry that has waited longest
+    DropOldest,
+    /// Drop the least severe entry, by GELF `level`, the newest of them if several are as low
+    DropLowestSeverity,
+}
+
+/// Entries dropped by each of the policies that drop any
+static DROPPED: [AtomicU64; 3] = [const { AtomicU64::new(0) }; 3];
+
+impl Overload {
+    fn counter(self) -> Option<&'static AtomicU64> {
+        match self {
+            Self::Block => None,
+            Self::DropNewest => Some(&DROPPED[0]),
+            Self::DropOldest => Some(&DROPPED[1]),
+            Self::DropLowestSeverity => Some(&DROPPED[2]),
+        }
+    }
+
+    /// How many entries this policy dropped so far
+    pub fn dropped(self) -> u64 {
+        self.counter().map_or(0, |v| v.load(Ordering::Relaxed))
+    }
+}
+
+/// Entries waiting in the relay of one [`channel`], to count along with the queue
+#[derive(Debug, Clone, Default)]
+pub struct Queued(Arc<AtomicU64>);
+
+impl Queued {
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn set(&self, n: usize) {
+        self.0.store(n as u64, Ordering::Relaxed);
+    }
+}
+
+/// Up to `capacity` entries, making room by the policy
+struct Queue {
+    policy: Overload,
+    capacity: usize,
+    /// With the level of each entry, for [`Overload::DropLowestSeverity`]
+    entries: VecDeque<(u64, Entry)>,
+}
+
+impl Queue {
+    fn new(policy: Overload, capacity: usize) -> Self {
+        Self {
+            policy,
+            capacity: capacity.max(1),
+            entries: VecDeque::new(),
+        }
+    }
+
+    /// Add `entry`, returning the one dropped to make room, if it took that
+    fn push(&mut self, entry: Entry) -> Option<Entry> {
+        let level = match self.policy {
+            Overload::DropLowestSeverity => json::get_u64(&entry, "level").unwrap_or(DEFAULT_LEVEL),
+            _ => 0,
+        };
+        if self.entries.len() < self.capacity {
+            self.entries.push_back((level, entry));
+            return None;
+        }
+
+        let dropped = match self.policy {
+            Overload::Block | Overload::DropNewest => return Some(entry),
+            Overload::DropOldest => self.entries.pop_front(),
+            Overload::DropLowestSeverity => {
+                let lowest = self.entries.iter().map(|v| v.0).max().unwrap_or(0);
+                if level >= lowest {
+                    return Some(entry);
+                }
+                let i = self.entries.iter().rposition(|v| v.0 == lowest);
+                i.and_then(|i| self.entries.remove(i))
+            }
+        };
+        self.entries.push_back((level, entry));
+        dropped.map(|v| v.1)
+    }
+
+    fn pop(&mut self) -> Option<Entry> {
+        self.entries.pop_front().map(|v| v.1)
+    }
+}
+
+/// Take entries from `input` as fast as they come, holding up to `capacity` of them until
+/// `output` has room, and dropping one by `policy` whenever another comes with none left
+async fn relay(
+    mut input: mpsc::Receiver<Entry>,
+    output: mpsc::Sender<Entry>,
+    policy: Overload,
+    capacity: usize,
+    queued: Queued,
+    (source_stats, health): (SourceStats, Health),
+) {
+    let mut queue = Queue::new(policy, capacity);
+    let mut num_dropped = 0u64;
+    loop {
+        tokio::select! {
+            entry = input.recv() => {
+                let Some(mut entry) = entry else {
+                    break;
+                };
+                entry.received.get_or_insert_with(Received::now);
+                if let Some(dropped) = queue.push(entry) {
+                    if num_dropped == 0 {
+                        log::warn!("Queue full, dropping entries by {policy:?}");
+                    }
+                    num_dropped += 1;
+                    drop_entry(policy, &dropped, &source_stats, &health);
+                }
+            }
+            permit = output.reserve(), if !queue.entries.is_empty() => {
+                let Ok(permit) = permit else {
+                    return;
+                };
+                permit.send(queue.pop().expect("Not empty"));
+                if queue.entries.is_empty() && num_dropped > 0 {
+                    log::info!("Queue caught up after dropping {num_dropped} entries");
+                    num_dropped = 0;
+                }
+            }
+        }
+        queued.set(queue.entries.len());
+    }
+
+    // The sources have stopped, hand on what they produced until then
+    while let Some(entry) = queue.pop() {
+        if output.send(entry).await.is_err() {
+            break;
+        }
+        queued.set(queue.entries.len());
+    }
+    queued.set(0);
+}
+
+fn drop_entry(policy: Overload, entry: &Entry, source_stats: &SourceStats, health: &Health) {
+    if let Some(counter) = policy.counter() {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+    health.record(Event::Dropped);
+    source_stats.received(entry);
+    source_stats.dropped(entry.sender);
+    metrics::ENTRIES_RECEIVED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// The sending end for the sources and the receiving end for the batching of a queue of
+/// `capacity` entries. Unless blocking, entries are taken from the sources as fast as they come,
+/// and dropped by `policy` once the queue is full, counted by the [`Queued`] handed back, and
+/// those dropped against their senders and as [`Event::Dropped`] in `dropped`.
+pub fn channel(
+    policy: Overload,
+    capacity: usize,
+    dropped: (SourceStats, Health),
+) -> (mpsc::Sender<Entry>, mpsc::Receiver<Entry>, Queued) {
+    let queued = Queued::default();
+    if policy == Overload::Block {
+        let (entries, receiver) = mpsc::channel(capacity);
+        return (entries, receiver, queued);
+    }
+
+    let (entries, input) = mpsc::channel(RELAY_ROOM);
+    let (output, receiver) = mpsc::channel(1);
+    tokio::spawn(relay(
+        input,
+        output,
+        policy,
+        capacity,
+        queued.clone(),
+        dropped,
+    ));
+    (entries, receiver, queued)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(level: u64, body: &str) -> Entry {
+        Entry {
+            body: format!(r#"{{"level":{level},"short_message":"{body}"}}"#),
+            ..Default::default()
+        }
+    }
+
+    fn pushed(policy: Overload, entries: &[(u64, &str)]) -> (Vec<String>, Vec<String>) {
+        let mut queue = Queue::new(policy, 3);
+        let mut dropped = Vec::new();
+        for (level, body) in entries {
+            if let Some(v) = queue.push(entry(*level, body)) {
+                dropped.push(json::get_string(&v, "short_message").unwrap());
+            }
+        }
+        let mut kept = Vec::new();
+        while let Some(v) = queue.pop() {
+            kept.push(json::get_string(&v, "short_message").unwrap());
+        }
+        (kept, dropped)
+    }
+
+    #[test]
+    fn drops_by_policy() {
+        let entries = [(3, "a"), (7, "b"), (6, "c"), (7, "d"), (2, "e")];
+        assert_eq!(
+            (
+                vec!["a".into(), "b".into(), "c".into()],
+                vec!["d".into(), "e".into()]
+            ),
+            pushed(Overload::DropNewest, &entries)
+        );
+        assert_eq!(
+            (
+                vec!["c".into(), "d".into(), "e".into()],
+                vec!["a".into(), "b".into()]
+            ),
+            pushed(Overload::DropOldest, &entries)
+        );
+        assert_eq!(
+            (
+                vec!["a".into(), "c".into(), "e".into()],
+                vec!["d".into(), "b".into()]
+            ),
+            pushed(Overload::DropLowestSeverity, &entries)
+        );
+    }
+
+    #[tokio::test]
+    async fn relays_without_blocking() {
+        let (entries, mut receiver, queued) = channel(Overload::DropOldest, 2, Default::default());
+        for i in 0..10 {
+            entries.send(entry(1, &i.to_string())).await.unwrap();
+        }
+        drop(entries);
+
+        let mut kept = Vec::new();
+        while let Some(v) = receiver.recv().await {
+            kept.push(json::get_string(&v, "short_message").unwrap());
+        }
+        assert_eq!(kept.last().map(String::as_str), Some("9"));
+        assert!(kept.len() < 10);
+        assert!(Overload::DropOldest.dropped() > 0);
+        assert_eq!(0, queued.get());
+    }
+}
@@ -0,0 +1,419 @@
+//! Just enough of Parquet to write entries out for DuckDB, Athena and the like: a single row group
+//! of plain-encoded columns, one data page each
+
+use std::{io::Write, time::UNIX_EPOCH};
+
+use anyhow::Context;
+use clap::ValueEnum;
+use flate2::write::GzEncoder;
+
+use crate::{identity, pipeline::Entry};
+
+const MAGIC: &[u8] = b"PAR1";
+
+#[derive(Debug, ValueEnum, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Codec {
+    /// As Parquet numbers it
+    fn id(self) -> i32 {
+        match self {
+            Self::None => 0,
+            Self::Gzip => 2,
+            Self::Zstd => 6,
+        }
+    }
+
+    fn compress(self, data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        Ok(match self {
+            Self::None => data,
+            Self::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(&data)?;
+                encoder.finish()?
+            }
+            Self::Zstd => zstd::encode_all(data.as_slice(), 0)?,
+        })
+    }
+}
+
+/// Physical types, as Parquet numbers them
+const INT64: i32 = 2;
+const BYTE_ARRAY: i32 = 6;
+
+/// Converted types, as Parquet numbers them
+const UTF8: i32 = 0;
+const TIMESTAMP_MICROS: i32 = 10;
+
+/// Encodings, as Parquet numbers them
+const PLAIN: i32 = 0;
+const RLE: i32 = 3;
+
+enum Values {
+    Int64(Vec<Option<i64>>),
+    Text(Vec<Option<String>>),
+}
+
+struct Column {
+    name: &'static str,
+    converted_type: i32,
+    /// Whether it has no nulls, so no definition levels either
+    required: bool,
+    values: Values,
+}
+
+impl Column {
+    fn physical_type(&self) -> i32 {
+        match self.values {
+            Values::Int64(_) => INT64,
+            Values::Text(_) => BYTE_ARRAY,
+        }
+    }
+
+    fn len(&self) -> usize {
+        match &self.values {
+            Values::Int64(v) => v.len(),
+            Values::Text(v) => v.len(),
+        }
+    }
+
+    /// Definition levels then values, as a data page holds them before compression
+    fn page(&self) -> Vec<u8> {
+        let defined: Vec<bool> = match &self.values {
+            Values::Int64(v) => v.iter().map(Option::is_some).collect(),
+            Values::Text(v) => v.iter().map(Option::is_some).collect(),
+        };
+
+        let mut page = Vec::new();
+        if !self.required {
+            let levels = rle_levels(&defined);
+            page.extend_from_slice(&(levels.len() as u32).to_le_bytes());
+            page.extend_from_slice(&levels);
+        }
+        match &self.values {
+            Values::Int64(v) => {
+                for v in v.iter().flatten() {
+                    page.extend_from_slice(&v.to_le_bytes());
+                }
+            }
+            Values::Text(v) => {
+                for v in v.iter().flatten() {
+                    page.extend_from_slice(&(v.len() as u32).to_le_bytes());
+                    page.extend_from_slice(v.as_bytes());
+                }
+            }
+        }
+        page
+    }
+}
+
+/// Definition levels of a bit each, as runs of the RLE/bit-packing hybrid
+fn rle_levels(defined: &[bool]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut rest = defined;
+    while let Some(first) = rest.first() {
+        let run = rest.iter().take_while(|v| *v == first).count();
+        varint(&mut out, (run as u64) << 1);
+        out.push(*first as u8);
+        rest = &rest[run..];
+    }
+    out
+}
+
+fn varint(out: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        out.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+/// Thrift's compact protocol, which Parquet's metadata is written in
+#[derive(Default)]
+struct Compact {
+    out: Vec<u8>,
+    /// The last field ID of each struct being written, innermost last
+    last_ids: Vec<i16>,
+}
+
+const T_I32: u8 = 5;
+const T_I64: u8 = 6;
+const T_BINARY: u8 = 8;
+const T_LIST: u8 = 9;
+const T_STRUCT: u8 = 12;
+
+impl Compact {
+    fn field(&mut self, id: i16, ty: u8) {
+        let last = self.last_ids.last_mut().expect("Within a struct");
+        let delta = id - std::mem::replace(last, id);
+        if (1..=15).contains(&delta) {
+            self.out.push((delta as u8) << 4 | ty);
+        } else {
+            self.out.push(ty);
+            self.zigzag(id as i64);
+        }
+    }
+
+    fn zigzag(&mut self, v: i64) {
+        varint(&mut self.out, ((v << 1) ^ (v >> 63)) as u64);
+    }
+
+    fn i32(&mut self, id: i16, v: i32) {
+        self.field(id, T_I32);
+        self.zigzag(v as i64);
+    }
+
+    fn i64(&mut self, id: i16, v: i64) {
+        self.field(id, T_I64);
+        self.zigzag(v);
+    }
+
+    fn binary(&mut self, id: i16, v: &[u8]) {
+        self.field(id, T_BINARY);
+        self.raw_binary(v);
+    }
+
+    fn raw_binary(&mut self, v: &[u8]) {
+        varint(&mut self.out, v.len() as u64);
+        self.out.extend_from_slice(v);
+    }
+
+    fn list(&mut self, id: i16, elem_type: u8, len: usize) {
+        self.field(id, T_LIST);
+        if len < 15 {
+            self.out.push((len as u8) << 4 | elem_type);
+        } else {
+            self.out.push(0xf0 | elem_type);
+            varint(&mut self.out, len as u64);
+        }
+    }
+
+    /// A struct as a field, or as a list element without an `id`
+    fn begin(&mut self, id: Option<i16>) {
+        if let Some(id) = id {
+            self.field(id, T_STRUCT);
+        }
+        self.last_ids.push(0);
+    }
+
+    fn end(&mut self) {
+        self.out.push(0);
+        self.last_ids.pop();
+    }
+}
+
+fn page_header(uncompressed: usize, compressed: usize, num_values: usize) -> Vec<u8> {
+    let mut t = Compact::default();
+    t.begin(None);
+    t.i32(1, 0); // DATA_PAGE
+    t.i32(2, uncompressed as i32);
+    t.i32(3, compressed as i32);
+    t.begin(Some(5));
+    t.i32(1, num_values as i32);
+    t.i32(2, PLAIN);
+    t.i32(3, RLE);
+    t.i32(4, RLE);
+    t.end();
+    t.end();
+    t.out
+}
+
+/// Where a column chunk ended up within the file
+struct Chunk {
+    offset: usize,
+    uncompressed: usize,
+    compressed: usize,
+}
+
+fn file_metadata(columns: &[Column], chunks: &[Chunk], codec: Codec, num_rows: usize) -> Vec<u8> {
+    let mut t = Compact::default();
+    t.begin(None);
+    t.i32(1, 1);
+
+    t.list(2, T_STRUCT, columns.len() + 1);
+    t.begin(None);
+    t.binary(4, b"schema");
+    t.i32(5, columns.len() as i32);
+    t.end();
+    for column in columns {
+        t.begin(None);
+        t.i32(1, column.physical_type());
+        t.i32(3, if column.required { 0 } else { 1 });
+        t.binary(4, column.name.as_bytes());
+        t.i32(6, column.converted_type);
+        t.end();
+    }
+
+    t.i64(3, num_rows as i64);
+
+    t.list(4, T_STRUCT, 1);
+    t.begin(None);
+    t.list(1, T_STRUCT, columns.len());
+    for (column, chunk) in columns.iter().zip(chunks) {
+        t.begin(None);
+        t.i64(2, chunk.offset as i64);
+        t.begin(Some(3));
+        t.i32(1, column.physical_type());
+        t.list(2, T_I32, 2);
+        t.zigzag(PLAIN as i64);
+        t.zigzag(RLE as i64);
+        t.list(3, T_BINARY, 1);
+        t.raw_binary(column.name.as_bytes());
+        t.i32(4, codec.id());
+        t.i64(5, column.len() as i64);
+        t.i64(6, chunk.uncompressed as i64);
+        t.i64(7, chunk.compressed as i64);
+        t.i64(9, chunk.offset as i64);
+        t.end();
+        t.end();
+    }
+    t.i64(2, chunks.iter().map(|v| v.uncompressed as i64).sum());
+    t.i64(3, num_rows as i64);
+    t.end();
+
+    let created_by = format!("sqlx_logger version {}", identity::get().version);
+    t.binary(6, created_by.as_bytes());
+    t.end();
+    t.out
+}
+
+/// The entries as a Parquet file with columns `received_at` (a timestamp in microseconds),
+/// `sender` and `body`
+pub fn encode(entries: &[Entry], codec: Codec) -> anyhow::Result<Vec<u8>> {
+    let columns = [
+        Column {
+            name: "received_at",
+            converted_type: TIMESTAMP_MICROS,
+            required: false,
+            values: Values::Int64(
+                entries
+                    .iter()
+                    .map(|v| {
+                        let at = v.received?.at.duration_since(UNIX_EPOCH).ok()?;
+                        Some(at.as_micros() as i64)
+                    })
+                    .collect(),
+            ),
+        },
+        Column {
+            name: "sender",
+            converted_type: UTF8,
+            required: false,
+            values: Values::Text(
+                entries
+                    .iter()
+                    .map(|v| v.sender.map(|v| v.to_string()))
+                    .collect(),
+            ),
+        },
+        Column {
+            name: "body",
+            converted_type: UTF8,
+            required: true,
+            values: Values::Text(entries.iter().map(|v| Some(v.body.clone())).collect()),
+        },
+    ];
+
+    let mut file = MAGIC.to_vec();
+    let mut chunks = Vec::with_capacity(columns.len());
+    for column in &columns {
+        let page = column.page();
+        let uncompressed = page.len();
+        let page = codec
+            .compress(page)
+            .with_context(|| format!("Compressing {}", column.name))?;
+        let header = page_header(uncompressed, page.len(), column.len());
+
+        chunks.push(Chunk {
+            offset: file.len(),
+            uncompressed: header.len() + uncompressed,
+            compressed: header.len() + page.len(),
+        });
+        file.extend_from_slice(&header);
+        file.extend_from_slice(&page);
+    }
+
+    let metadata = file_metadata(&columns, &chunks, codec, entries.len());
+    file.extend_from_slice(&metadata);
+    file.extend_from_slice(&(metadata.len() as u32).to_le_bytes());
+    file.extend_from_slice(MAGIC);
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::pipeline::Received;
+
+    use super::*;
+
+    #[test]
+    fn encodes_thrift_and_levels() {
+        let mut t = Compact::default();
+        t.begin(None);
+        t.i32(1, -1);
+        t.i64(20, 300);
+        t.list(21, T_I32, 2);
+        t.zigzag(1);
+        t.zigzag(2);
+        t.end();
+        assert_eq!(
+            vec![0x15, 0x01, 0x06, 0x28, 0xd8, 0x04, 0x19, 0x25, 0x02, 0x04, 0x00],
+            t.out
+        );
+
+        assert_eq!(
+            vec![0x04, 0x01, 0x02, 0x00, 0x02, 0x01],
+            rle_levels(&[true, true, false, true])
+        );
+    }
+
+    #[test]
+    fn encodes_entries() {
+        let entries = vec![
+            Entry {
+                sender: Some("10.0.0.1:1234".parse().unwrap()),
+                received: Some(Received {
+                    at: UNIX_EPOCH + Duration::from_micros(1_500_000),
+                    seq: 0,
+                }),
+                body: "a".to_string(),
+                ..Default::default()
+            },
+            Entry {
+                body: "bc".to_string(),
+                ..Default::default()
+            },
+        ];
+        let file = encode(&entries, Codec::None).unwrap();
+        assert_eq!(MAGIC, &file[..4]);
+        assert_eq!(MAGIC, &file[file.len() - 4..]);
+
+        // Definition levels of received_at, then its one value
+        let page = Column {
+            name: "received_at",
+            converted_type: TIMESTAMP_MICROS,
+            required: false,
+            values: Values::Int64(vec![Some(1_500_000), None]),
+        }
+        .page();
+        assert_eq!(
+            [
+                &[4, 0, 0, 0, 0x02, 0x01, 0x02, 0x00][..],
+                &1_500_000i64.to_le_bytes()
+            ]
+            .concat(),
+            page
+        );
+        assert!(file.windows(page.len()).any(|v| v == page.as_slice()));
+
+        let footer = u32::from_le_bytes(file[file.len() - 8..file.len() - 4].try_into().unwrap());
+        assert!((footer as usize) < file.len());
+        assert!(encode(&entries, Codec::Zstd).is_ok());
+    }
+}
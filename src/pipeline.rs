@@ -0,0 +1,918 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    future::Future,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::anyhow;
+use async_shutdown::Shutdown;
+use async_trait::async_trait;
+use tokio::{
+    spawn,
+    sync::{mpsc, watch},
+    task::JoinHandle,
+    time::{interval, timeout_at, MissedTickBehavior},
+};
+
+use crate::{
+    batch::AdaptiveBatchSize,
+    health::{Event, Health},
+    json,
+    memory::{self, MemoryBudget, MemoryLimit},
+    metrics,
+    overload::{self, Overload, Queued},
+    quarantine::Quarantine,
+    source_stats::SourceStats,
+    trace::{self, Span},
+};
+
+/// How often sinks are given a [`Sink::tick`]
+pub const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A complete log entry on its way from a source to a sink
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Entry {
+    /// Where the entry came from, if the source has such a notion
+    pub sender: Option<SocketAddr>,
+    /// The common name of the client certificate the sender authenticated with
+    pub client_cn: Option<String>,
+    /// Set by the pipeline if the source doesn't
+    pub received: Option<Received>,
+    /// Values added along the way, e.g. by enrichment, which can be bound by name
+    pub fields: BTreeMap<String, String>,
+    /// Where a durable source read the entry from, to resume after it once it's written
+    pub position: Option<Position>,
+    /// Change with [`Entry::set_body`] or [`Entry::set_json`], so what [`Entry::json`] parsed
+    /// doesn't go stale
+    pub body: String,
+    pub parsed: ParsedBody,
+}
+
+impl Entry {
+    /// The body parsed as JSON, or `None` if it isn't. Only the first call parses it, so every
+    /// stage after that reads the same document.
+    pub fn json(&self) -> Option<&serde_json::Value> {
+        self.parsed
+            .0
+            .get_or_init(|| json::parse(&self.body))
+            .as_ref()
+    }
+
+    pub fn set_body(&mut self, body: String) {
+        self.body = body;
+        self.parsed = Default::default();
+    }
+
+    /// Replace the body with `value`, which stays parsed
+    pub fn set_json(&mut self, value: serde_json::Value) {
+        self.body = value.to_string();
+        self.parsed = ParsedBody(OnceLock::from(Some(value)));
+    }
+}
+
+/// The body of an [`Entry`] once parsed, a cache that's always equal to another so it doesn't
+/// matter when comparing entries
+#[derive(Debug, Clone, Default)]
+pub struct ParsedBody(OnceLock<Option<serde_json::Value>>);
+
+impl PartialEq for ParsedBody {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for ParsedBody {}
+
+/// How far a durable source, e.g. a spool or a queue, has got. Offsets only ever grow.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Position {
+    /// Which source, and which of its partitions or segments if it has more than one
+    pub source: String,
+    pub offset: i64,
+}
+
+impl Position {
+    /// The furthest position of each source within `entries`
+    pub fn latest(entries: &[Entry]) -> BTreeMap<&str, i64> {
+        let mut latest = BTreeMap::new();
+        for position in entries.iter().filter_map(|v| v.position.as_ref()) {
+            let offset = latest
+                .entry(position.source.as_str())
+                .or_insert(position.offset);
+            *offset = position.offset.max(*offset);
+        }
+        latest
+    }
+}
+
+/// When an entry was received, and in which order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Received {
+    /// The wall clock at process start moved forward by the monotonic clock, so it never goes
+    /// backwards when the system clock is stepped
+    pub at: SystemTime,
+    /// Counts up from 0 across all sources
+    pub seq: u64,
+}
+
+impl Received {
+    pub fn now() -> Self {
+        static SEQ: AtomicU64 = AtomicU64::new(0);
+
+        Self {
+            at: Self::clock(),
+            seq: SEQ.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    /// The clock `at` is taken from
+    pub fn clock() -> SystemTime {
+        static CLOCK: OnceLock<(SystemTime, Instant)> = OnceLock::new();
+
+        let (wall, monotonic) = CLOCK.get_or_init(|| (SystemTime::now(), Instant::now()));
+        *wall + monotonic.elapsed()
+    }
+}
+
+/// Produces entries, e.g. by listening on a socket.
+///
+/// Every source runs on its own task. Waiting for `entries` to have room is how a source gets
+/// pushed back on, when the sink can't keep up.
+#[async_trait]
+pub trait Source: Send + 'static {
+    /// Produce entries until shutdown is triggered, or there is nothing more to produce
+    async fn run(
+        self: Box<Self>,
+        entries: mpsc::Sender<Entry>,
+        shutdown: Shutdown,
+    ) -> anyhow::Result<()>;
+}
+
+/// Changes or drops entries before they are batched up
+pub trait Transform: Send {
+    /// Return `None` to drop the entry
+    fn apply(&mut self, entry: Entry) -> Option<Entry>;
+
+    /// Whether dropped entries were rejected, and so counted and quarantined, rather than let go
+    /// on purpose
+    fn rejects(&self) -> bool {
+        true
+    }
+
+    /// What to call the transform when explaining what it did
+    fn name(&self) -> &'static str {
+        let name = std::any::type_name::<Self>();
+        let name = name.split('<').next().unwrap_or(name);
+        name.rsplit("::").next().unwrap_or(name)
+    }
+}
+
+/// Committing a batch [`Sink::write_pending`] wrote, which goes on whether or not it's polled
+pub type PendingCommit = Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send>>;
+
+/// How a [`Transform`] dropped an entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dropped {
+    /// Counted and quarantined, e.g. by the filter
+    Rejected,
+    /// On purpose, e.g. sampled out
+    LetGo,
+}
+
+/// Run `entry` through every transform in turn, as the pipeline does
+pub fn transform(transforms: &mut [Box<dyn Transform>], entry: Entry) -> Result<Entry, Dropped> {
+    transforms.iter_mut().try_fold(entry, |entry, transform| {
+        let rejects = transform.rejects();
+        match transform.apply(entry) {
+            Some(entry) => Ok(entry),
+            None if rejects => Err(Dropped::Rejected),
+            None => Err(Dropped::LetGo),
+        }
+    })
+}
+
+/// Run `entry` through every transform in turn, as [`transform`] does, with what each one made
+/// of it, up to the one that dropped it if any
+pub fn transform_steps(
+    transforms: &mut [Box<dyn Transform>],
+    mut entry: Entry,
+) -> Vec<(&'static str, Result<Entry, Dropped>)> {
+    let mut steps = Vec::new();
+    for transform in transforms {
+        let rejects = transform.rejects();
+        let name = transform.name();
+        match transform.apply(entry) {
+            Some(v) => {
+                entry = v;
+                steps.push((name, Ok(entry.clone())));
+            }
+            None => {
+                let dropped = if rejects {
+                    Dropped::Rejected
+                } else {
+                    Dropped::LetGo
+                };
+                steps.push((name, Err(dropped)));
+                break;
+            }
+        }
+    }
+    steps
+}
+
+/// Writes batches of entries, e.g. to a database
+#[async_trait]
+pub trait Sink: Send + 'static {
+    async fn write(&mut self, entries: &[Entry]) -> anyhow::Result<()>;
+
+    /// Write the entries, but leave committing them to the returned future, so the next batch can
+    /// be written meanwhile. Sinks that can't tell the two apart have committed by the time it
+    /// returns.
+    async fn write_pending(&mut self, entries: &[Entry]) -> anyhow::Result<PendingCommit> {
+        self.write(entries).await?;
+        Ok(Box::pin(async { Ok(()) }))
+    }
+
+    /// Write out whatever the sink holds back that's due by now, e.g. windows that are over.
+    /// Called every [`TICK_INTERVAL`] whether batches come in or not, so nothing is held back for
+    /// long once they stop. Sinks wrapping others pass it on.
+    async fn tick(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Write out whatever the sink held back, once every batch has been written
+    async fn close(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Sink for Box<dyn Sink> {
+    async fn write(&mut self, entries: &[Entry]) -> anyhow::Result<()> {
+        (**self).write(entries).await
+    }
+
+    async fn write_pending(&mut self, entries: &[Entry]) -> anyhow::Result<PendingCommit> {
+        (**self).write_pending(entries).await
+    }
+
+    async fn tick(&mut self) -> anyhow::Result<()> {
+        (**self).tick().await
+    }
+
+    async fn close(&mut self) -> anyhow::Result<()> {
+        (**self).close().await
+    }
+}
+
+/// When the first entry of each batch not committed yet was received, oldest first
+#[derive(Debug, Default)]
+struct Uncommitted {
+    batches: Mutex<VecDeque<SystemTime>>,
+    oldest: metrics::Oldest,
+}
+
+impl Uncommitted {
+    fn on_batch_started(&self, first: &Entry) {
+        let mut batches = self.batches.lock().unwrap();
+        batches.push_back(first.received.map_or_else(SystemTime::now, |v| v.at));
+        self.oldest.set(batches.front().copied());
+    }
+
+    fn on_batch_committed(&self) {
+        let mut batches = self.batches.lock().unwrap();
+        batches.pop_front();
+        self.oldest.set(batches.front().copied());
+    }
+}
+
+/// A full batch on its way to the sink
+struct Batch {
+    entries: Vec<Entry>,
+    /// How long it took for the batch to fill up
+    fill: Duration,
+    /// When the batch was handed to the sink
+    flushed: SystemTime,
+    /// Entries dropped by the transforms while the batch filled up
+    num_dropped: usize,
+    /// Spent running transforms while the batch filled up
+    transform: Duration,
+    /// Entries queued up behind the batch when it was handed to the sink
+    queued: usize,
+}
+
+/// Moves entries from the sources, through the transforms, into batches for the sink.
+///
+/// Shutting down stops the sources. Whatever they produced until then is still written before
+/// [`Pipeline::run`] returns. A source that stops on its own, or fails, shuts everything down too.
+pub struct Pipeline {
+    pub sources: Vec<Box<dyn Source>>,
+    pub transforms: Vec<Box<dyn Transform>>,
+    pub sink: Box<dyn Sink>,
+    pub batch_size: AdaptiveBatchSize,
+    /// How many entries may wait between the sources and the batching
+    pub queue_size: usize,
+    /// What to do with entries received while the queue is full
+    pub overload: Overload,
+    /// How many full batches may wait while one is being written
+    pub max_in_flight: usize,
+    /// How many written batches may still be committing while the next one is written, with a
+    /// sink whose [`Sink::write_pending`] leaves committing for later
+    pub max_outstanding_commits: usize,
+    pub memory_budget: Option<Arc<MemoryBudget>>,
+    /// New entries are dropped beyond it
+    pub memory_limit: Option<Arc<MemoryLimit>>,
+    /// Where entries dropped by the transforms go
+    pub quarantine: Quarantine,
+    /// What each sender sent, and how much of it was dropped
+    pub source_stats: SourceStats,
+    /// Where dropped entries are counted
+    pub health: Health,
+    /// Told the latest [`Position`] of each source once its entries are committed, e.g. for the
+    /// source to commit offsets upstream only after the database has
+    pub committed: Option<watch::Sender<Committed>>,
+}
+
+/// The latest committed offset by source
+pub type Committed = BTreeMap<String, i64>;
+
+impl Pipeline {
+    pub async fn run(self, shutdown: Shutdown) -> anyhow::Result<()> {
+        let Self {
+            sources,
+            mut transforms,
+            sink,
+            batch_size,
+            queue_size,
+            overload,
+            max_in_flight,
+            max_outstanding_commits,
+            memory_budget,
+            memory_limit,
+            quarantine,
+            source_stats,
+            health,
+            committed,
+        } = self;
+
+        let dropped = (source_stats.clone(), health.clone());
+        let (entries, mut receiver, queued) = overload::channel(overload, queue_size, dropped);
+        let sources: Vec<_> = sources
+            .into_iter()
+            .map(|source| {
+                let run = source.run(entries.clone(), shutdown.clone());
+                spawn(shutdown.wrap_vital(run))
+            })
+            .collect();
+        drop(entries);
+
+        let batch_size = Arc::new(Mutex::new(batch_size));
+        let uncommitted = Arc::new(Uncommitted::default());
+        let (batches, sink_task) = spawn_sink(
+            sink,
+            batch_size.clone(),
+            max_in_flight,
+            max_outstanding_commits,
+            committed,
+            uncommitted.clone(),
+        );
+
+        let mut batch = Vec::new();
+        let rs = collect(
+            &mut receiver,
+            &queued,
+            Collecting {
+                transforms: &mut transforms,
+                batch_size: &batch_size,
+                memory_budget: memory_budget.as_deref(),
+                memory_limit: memory_limit.as_deref(),
+                quarantine: &quarantine,
+                source_stats: &source_stats,
+                health: &health,
+            },
+            &batches,
+            &uncommitted,
+            &mut batch,
+        )
+        .await;
+
+        // Either everything has stopped already, or the sink has and nothing else can go on
+        shutdown.shutdown();
+        drop(receiver);
+
+        if !batch.is_empty() {
+            log::info!("Committing pending transactions");
+            metrics::BATCHES_IN_FLIGHT.fetch_add(1, Ordering::Relaxed);
+            metrics::IN_FLIGHT_BYTES
+                .fetch_add(memory::entry_bytes(&batch) as u64, Ordering::Relaxed);
+            let _ = batches
+                .send(Batch {
+                    entries: batch,
+                    fill: Duration::ZERO,
+                    flushed: Received::clock(),
+                    num_dropped: 0,
+                    transform: Duration::ZERO,
+                    queued: 0,
+                })
+                .await;
+        }
+
+        drop(batches);
+        let written = sink_task
+            .await
+            .map_err(|e| anyhow!("Error joining sink: {e:?}"))
+            .and_then(|v| v);
+
+        let mut produced = Ok(());
+        for source in sources {
+            let rs = source
+                .await
+                .map_err(|e| anyhow!("Error joining source: {e:?}"))
+                .and_then(|v| v);
+            produced = produced.and(rs);
+        }
+
+        log::debug!(
+            "Pipeline result: {rs:?}, sink result: {written:?}, sources result: {produced:?}"
+        );
+
+        written.and(rs).and(produced)
+    }
+}
+
+/// What entries are collected into batches by
+struct Collecting<'a> {
+    transforms: &'a mut [Box<dyn Transform>],
+    batch_size: &'a Mutex<AdaptiveBatchSize>,
+    memory_budget: Option<&'a MemoryBudget>,
+    memory_limit: Option<&'a MemoryLimit>,
+    quarantine: &'a Quarantine,
+    source_stats: &'a SourceStats,
+    health: &'a Health,
+}
+
+async fn collect(
+    receiver: &mut mpsc::Receiver<Entry>,
+    queued: &Queued,
+    Collecting {
+        transforms,
+        batch_size,
+        memory_budget,
+        memory_limit,
+        quarantine,
+        source_stats,
+        health,
+    }: Collecting<'_>,
+    batches: &mpsc::Sender<Batch>,
+    uncommitted: &Uncommitted,
+    batch: &mut Vec<Entry>,
+) -> anyhow::Result<()> {
+    let mut batch_started: Option<Instant> = None;
+    let mut num_dropped = 0;
+    let mut transform = Duration::ZERO;
+    // This pipeline's, taken back out once it's done
+    let mut queue_depth = metrics::Share::new(&metrics::QUEUE_DEPTH);
+    let mut pending_bytes = metrics::Share::new(&metrics::PENDING_BYTES);
+    loop {
+        queue_depth.set(self::queue_depth(receiver, queued) as u64);
+
+        let deadline =
+            batch_started.and_then(|started| batch_size.lock().unwrap().deadline(started));
+        let received = match deadline {
+            Some(deadline) => timeout_at(deadline.into(), receiver.recv()).await.ok(),
+            None => Some(receiver.recv().await),
+        };
+
+        let mut entry = match received {
+            Some(Some(v)) => v,
+            Some(None) => break,
+            None => {
+                log::debug!("Batch is taking too long to fill up");
+                let started = batch_started.take().unwrap();
+                let stats = (
+                    std::mem::take(&mut num_dropped),
+                    std::mem::take(&mut transform),
+                    self::queue_depth(receiver, queued),
+                );
+                let budget = (memory_budget, &mut pending_bytes);
+                flush(batches, batch, started, stats, budget).await?;
+                continue;
+            }
+        };
+
+        entry.received.get_or_insert_with(Received::now);
+        source_stats.received(&entry);
+        metrics::ENTRIES_RECEIVED.fetch_add(1, Ordering::Relaxed);
+        if memory_limit.is_some_and(MemoryLimit::is_over) {
+            num_dropped += 1;
+            memory::SHED.fetch_add(1, Ordering::Relaxed);
+            health.record(Event::Dropped);
+            source_stats.dropped(entry.sender);
+            continue;
+        }
+
+        let sender = entry.sender;
+        let original = quarantine
+            .is_enabled()
+            .then(|| (entry.sender, entry.body.clone()));
+        let transform_started = Instant::now();
+        let transformed = self::transform(transforms, entry);
+        transform += transform_started.elapsed();
+        let entry = match transformed {
+            Ok(v) => v,
+            Err(dropped) => {
+                num_dropped += 1;
+                if dropped == Dropped::Rejected {
+                    health.record(Event::Dropped);
+                    source_stats.dropped(sender);
+                    if let Some((sender, body)) = original {
+                        quarantine.reject("Dropped by filter", sender, body.as_bytes());
+                    }
+                }
+                continue;
+            }
+        };
+
+        if batch_started.is_none() {
+            uncommitted.on_batch_started(&entry);
+        }
+        pending_bytes.add(entry.body.len() as u64);
+        batch.push(entry);
+        if let Some(budget) = memory_budget {
+            let before = batch.len();
+            budget.enforce(batch);
+            if batch.len() < before {
+                pending_bytes.set(memory::entry_bytes(batch) as u64);
+            }
+        }
+
+        let started = *batch_started.get_or_insert_with(Instant::now);
+        if batch.len() >= batch_size.lock().unwrap().current() {
+            batch_started = None;
+            let stats = (
+                std::mem::take(&mut num_dropped),
+                std::mem::take(&mut transform),
+                self::queue_depth(receiver, queued),
+            );
+            let budget = (memory_budget, &mut pending_bytes);
+            flush(batches, batch, started, stats, budget).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Entries waiting to be collected, in the channel and in the overload queue
+fn queue_depth(receiver: &mpsc::Receiver<Entry>, queued: &Queued) -> usize {
+    receiver.len() + queued.get() as usize
+}
+
+async fn flush(
+    batches: &mpsc::Sender<Batch>,
+    batch: &mut Vec<Entry>,
+    started: Instant,
+    (num_dropped, transform, queued): (usize, Duration, usize),
+    (memory_budget, pending_bytes): (Option<&MemoryBudget>, &mut metrics::Share),
+) -> anyhow::Result<()> {
+    let batch = Batch {
+        entries: std::mem::take(batch),
+        fill: started.elapsed(),
+        flushed: Received::clock(),
+        num_dropped,
+        transform,
+        queued,
+    };
+
+    if let Some(budget) = memory_budget {
+        budget.clear_pending();
+    }
+
+    metrics::BATCHES_IN_FLIGHT.fetch_add(1, Ordering::Relaxed);
+    pending_bytes.set(0);
+    metrics::IN_FLIGHT_BYTES.fetch_add(
+        memory::entry_bytes(&batch.entries) as u64,
+        Ordering::Relaxed,
+    );
+    batches
+        .send(batch)
+        .await
+        .map_err(|_| anyhow!("Sink has stopped"))
+}
+
+/// A batch written, but maybe not committed yet
+struct Written {
+    batch: Batch,
+    started: Instant,
+    write_started: SystemTime,
+    recorded: Vec<Span>,
+    commit: PendingCommit,
+}
+
+/// Run the sink on its own task, so writing one batch overlaps with collecting the next one.
+/// Up to `max_in_flight` full batches may wait while the previous one is being written, and up to
+/// `max_outstanding_commits` written ones may still be committing meanwhile.
+fn spawn_sink(
+    mut sink: Box<dyn Sink>,
+    batch_size: Arc<Mutex<AdaptiveBatchSize>>,
+    max_in_flight: usize,
+    max_outstanding_commits: usize,
+    committed: Option<watch::Sender<Committed>>,
+    uncommitted: Arc<Uncommitted>,
+) -> (mpsc::Sender<Batch>, JoinHandle<anyhow::Result<()>>) {
+    let (sender, mut receiver) = mpsc::channel::<Batch>(max_in_flight.max(1));
+
+    let task = spawn(async move {
+        // Oldest first. Batches only count as committed in the order they were written, even if
+        // a later one happens to finish committing first.
+        let mut written = VecDeque::<Written>::new();
+        let on_committed = |written: Written| {
+            on_batch_committed(written, &batch_size, committed.as_ref(), &uncommitted)
+        };
+
+        let mut ticks = interval(TICK_INTERVAL);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            let accepting = written.len() <= max_outstanding_commits;
+            let oldest = async {
+                match written.front_mut() {
+                    Some(written) => (&mut written.commit).await,
+                    None => std::future::pending().await,
+                }
+            };
+
+            tokio::select! {
+                rs = oldest => {
+                    rs?;
+                    on_committed(written.pop_front().unwrap());
+                }
+
+                _ = ticks.tick() => sink.tick().await?,
+
+                batch = receiver.recv(), if accepting => {
+                    let Some(batch) = batch else {
+                        break;
+                    };
+
+                    let started = Instant::now();
+                    let write_started = Received::clock();
+                    let (commit, recorded) = if trace::is_enabled() {
+                        let (rs, recorded) = trace::scope(sink.write_pending(&batch.entries)).await;
+                        (rs?, recorded)
+                    } else {
+                        (sink.write_pending(&batch.entries).await?, vec![])
+                    };
+                    written.push_back(Written {
+                        batch,
+                        started,
+                        write_started,
+                        recorded,
+                        commit,
+                    });
+                }
+            }
+        }
+
+        while let Some(mut oldest) = written.pop_front() {
+            (&mut oldest.commit).await?;
+            on_committed(oldest);
+        }
+        sink.close().await
+    });
+
+    (sender, task)
+}
+
+fn on_batch_committed(
+    Written {
+        batch,
+        started,
+        write_started,
+        recorded,
+        ..
+    }: Written,
+    batch_size: &Mutex<AdaptiveBatchSize>,
+    committed: Option<&watch::Sender<Committed>>,
+    uncommitted: &Uncommitted,
+) {
+    if trace::is_enabled() {
+        trace::export(batch_trace(&batch, write_started, recorded));
+    }
+
+    let Batch {
+        entries,
+        fill,
+        queued,
+        ..
+    } = batch;
+    metrics::BATCHES_IN_FLIGHT.fetch_sub(1, Ordering::Relaxed);
+    metrics::ENTRIES_COMMITTED.fetch_add(entries.len() as u64, Ordering::Relaxed);
+    metrics::set_last_committed(SystemTime::now());
+    metrics::IN_FLIGHT_BYTES.fetch_sub(memory::entry_bytes(&entries) as u64, Ordering::Relaxed);
+    uncommitted.on_batch_committed();
+    if let Some(oldest) = entries
+        .iter()
+        .filter_map(|v| v.received)
+        .map(|v| v.at)
+        .min()
+    {
+        let latency = Received::clock().duration_since(oldest).unwrap_or_default();
+        metrics::BATCH_LATENCY_SECONDS.observe(latency);
+    }
+    batch_size
+        .lock()
+        .unwrap()
+        .on_commit(fill, started.elapsed(), queued);
+
+    if let Some(committed) = committed {
+        let latest = Position::latest(&entries);
+        if !latest.is_empty() {
+            committed.send_modify(|committed| {
+                for (source, offset) in latest {
+                    let v = committed.entry(source.to_string()).or_insert(offset);
+                    *v = offset.max(*v);
+                }
+            });
+        }
+    }
+
+    log::info!("Committed {} transactions", entries.len());
+}
+
+/// The stages `batch` went through, with `recorded` while writing it
+fn batch_trace(batch: &Batch, write_started: SystemTime, recorded: Vec<Span>) -> Vec<Span> {
+    let now = Received::clock();
+    let oldest = batch
+        .entries
+        .iter()
+        .filter_map(|v| v.received)
+        .map(|v| v.at)
+        .min()
+        .unwrap_or(write_started);
+
+    let mut trace = vec![
+        Span::new("batch", oldest, now)
+            .attr("entries", batch.entries.len())
+            .attr("dropped", batch.num_dropped),
+        Span::new("collect", batch.flushed - batch.fill, batch.flushed)
+            .child_of(0)
+            .attr("entries", batch.entries.len())
+            .attr("dropped", batch.num_dropped)
+            .attr("transform_micros", batch.transform.as_micros() as i64),
+        Span::new("queued", batch.flushed, write_started).child_of(0),
+        Span::new("write", write_started, now).child_of(0),
+    ];
+    trace.extend(recorded.into_iter().map(|v| v.child_of(3)));
+    trace
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::oneshot;
+
+    use super::*;
+
+    #[test]
+    fn latest_positions() {
+        let entry = |source: &str, offset| Entry {
+            position: Some(Position {
+                source: source.to_string(),
+                offset,
+            }),
+            ..Default::default()
+        };
+        let entries = [
+            entry("a", 3),
+            entry("b", 10),
+            Entry::default(),
+            entry("a", 5),
+            entry("a", 4),
+        ];
+        assert_eq!(
+            BTreeMap::from([("a", 5), ("b", 10)]),
+            Position::latest(&entries)
+        );
+    }
+
+    /// Leaves committing each batch until told to
+    struct HeldCommits(mpsc::UnboundedSender<oneshot::Sender<()>>);
+
+    #[async_trait]
+    impl Sink for HeldCommits {
+        async fn write(&mut self, _: &[Entry]) -> anyhow::Result<()> {
+            unreachable!()
+        }
+
+        async fn write_pending(&mut self, _: &[Entry]) -> anyhow::Result<PendingCommit> {
+            let (commit, committed) = oneshot::channel();
+            self.0.send(commit)?;
+            Ok(Box::pin(async move { Ok(committed.await?) }))
+        }
+    }
+
+    #[tokio::test]
+    async fn commits_in_order() {
+        let (commits, mut held) = mpsc::unbounded_channel();
+        let (committed, mut latest) = watch::channel(Committed::new());
+        let (batches, task) = spawn_sink(
+            Box::new(HeldCommits(commits)),
+            Arc::new(Mutex::new(AdaptiveBatchSize::new(
+                1,
+                1,
+                Duration::from_secs(1),
+            ))),
+            1,
+            1,
+            Some(committed),
+            Default::default(),
+        );
+
+        for offset in 1..=2 {
+            let entry = Entry {
+                position: Some(Position {
+                    source: "a".to_string(),
+                    offset,
+                }),
+                ..Default::default()
+            };
+            batches
+                .send(Batch {
+                    entries: vec![entry],
+                    fill: Duration::ZERO,
+                    flushed: Received::clock(),
+                    num_dropped: 0,
+                    transform: Duration::ZERO,
+                    queued: 0,
+                })
+                .await
+                .unwrap();
+        }
+
+        // The second batch is written while the first is still committing
+        let first = held.recv().await.unwrap();
+        let second = held.recv().await.unwrap();
+        second.send(()).unwrap();
+        tokio::task::yield_now().await;
+        assert!(!latest.has_changed().unwrap());
+
+        first.send(()).unwrap();
+        latest.changed().await.unwrap();
+        drop(batches);
+        task.await.unwrap().unwrap();
+        assert_eq!(Some(&2), latest.borrow().get("a"));
+    }
+
+    struct Ticked(mpsc::UnboundedSender<()>);
+
+    #[async_trait]
+    impl Sink for Ticked {
+        async fn write(&mut self, _: &[Entry]) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        async fn tick(&mut self) -> anyhow::Result<()> {
+            Ok(self.0.send(())?)
+        }
+    }
+
+    #[tokio::test]
+    async fn ticks_while_idle() {
+        let (ticked, mut ticks) = mpsc::unbounded_channel();
+        let (batches, task) = spawn_sink(
+            Box::new(Ticked(ticked)),
+            Arc::new(Mutex::new(AdaptiveBatchSize::new(
+                1,
+                1,
+                Duration::from_secs(1),
+            ))),
+            1,
+            1,
+            None,
+            Default::default(),
+        );
+
+        // Without a batch ever coming in
+        for _ in 0..2 {
+            tokio::time::timeout(TICK_INTERVAL * 2, ticks.recv())
+                .await
+                .unwrap()
+                .unwrap();
+        }
+        drop(batches);
+        task.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn received_in_order() {
+        let first = Received::now();
+        let second = Received::now();
+        assert!(second.seq > first.seq);
+        assert!(second.at >= first.at);
+    }
+}
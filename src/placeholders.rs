@@ -0,0 +1,257 @@
+//! Placeholders in SQL: `$1`, `$2`, ... everywhere, translated into what the database takes when
+//! prepared, and named ones, e.g. `VALUES (:host, :short_message)`, binding fields of the entry
+//! by name
+
+use anyhow::bail;
+use sqlx::any::AnyKind;
+
+use crate::writer::BindField;
+
+/// `sql` with what `replace` returns put in, outside quoted strings and identifiers and
+/// comments. It's given the rest of the SQL from each character on, and returns how many bytes
+/// of it to replace and with what.
+pub(crate) fn replace_in_code(
+    sql: &str,
+    mut replace: impl FnMut(&str) -> Option<(usize, String)>,
+) -> String {
+    let mut replaced = String::with_capacity(sql.len());
+    let mut i = 0;
+    while let Some(c) = sql[i..].chars().next() {
+        let rest = &sql[i..];
+        let quoted = match c {
+            '\'' | '"' | '`' => Some((1, &rest[..1])),
+            '-' if rest.starts_with("--") => Some((2, "\n")),
+            '/' if rest.starts_with("/*") => Some((2, "*/")),
+            _ => None,
+        };
+        if let Some((open, close)) = quoted {
+            // To the closing one or the end. Doubled quotes end and reopen.
+            let end = rest[open..]
+                .find(close)
+                .map_or(rest.len(), |v| open + v + close.len());
+            replaced.push_str(&rest[..end]);
+            i += end;
+        } else if let Some((len, replacement)) = replace(rest) {
+            replaced.push_str(&replacement);
+            i += len;
+        } else {
+            replaced.push(c);
+            i += c.len_utf8();
+        }
+    }
+    replaced
+}
+
+/// The length and number of the `$n` placeholder `rest` starts with
+fn numbered(rest: &str) -> Option<(usize, usize)> {
+    let digits = rest.strip_prefix('$')?;
+    let len = digits.bytes().take_while(u8::is_ascii_digit).count();
+    let n = digits[..len].parse().ok().filter(|n| *n > 0)?;
+    Some((1 + len, n))
+}
+
+/// `sql` with its `$n` placeholders as a database of `kind` takes them: as they are for
+/// Postgres, `?n` for SQLite, `@pn` for MSSQL and `?` for MySQL. For MySQL, the placeholders
+/// only go in order, so along with which of the parameters given each one takes, the first
+/// being 0, if there are any.
+pub fn translate(sql: &str, kind: AnyKind) -> (String, Option<Vec<usize>>) {
+    let native = |n| match kind {
+        AnyKind::Sqlite => format!("?{n}"),
+        AnyKind::Mssql => format!("@p{n}"),
+        _ => "?".to_string(),
+    };
+    if kind == AnyKind::Postgres {
+        return (sql.to_string(), None);
+    }
+
+    let mut order = Vec::new();
+    let translated = replace_in_code(sql, |rest| {
+        let (len, n) = numbered(rest)?;
+        order.push(n - 1);
+        Some((len, native(n)))
+    });
+    let order = Some(order).filter(|v| kind == AnyKind::MySql && !v.is_empty());
+    (translated, order)
+}
+
+/// A statement rewritten by [`rewrite`], with the name of each parameter it takes, in order
+#[derive(Debug, PartialEq, Eq)]
+struct Rewritten {
+    sql: String,
+    names: Vec<String>,
+}
+
+/// Replace the named placeholders of `sql` with `$n`, numbered once per name. `::` casts are
+/// left as they are.
+fn rewrite_one(sql: &str) -> Rewritten {
+    let mut names: Vec<String> = Vec::new();
+    let sql = replace_in_code(sql, |rest| {
+        if rest.starts_with("::") {
+            return Some((2, "::".to_string()));
+        }
+        let name = rest.strip_prefix(':')?;
+        let len = name
+            .char_indices()
+            .take_while(|(i, v)| v.is_ascii_alphanumeric() || *v == '_' || (*i == 0 && *v == '@'))
+            .count();
+        let name = &name[..len];
+        let starts_well = name
+            .trim_start_matches('@')
+            .starts_with(|v: char| v.is_ascii_alphabetic() || v == '_');
+        if !starts_well {
+            return None;
+        }
+
+        let n = match names.iter().position(|v| v == name) {
+            Some(n) => n + 1,
+            None => {
+                names.push(name.to_string());
+                names.len()
+            }
+        };
+        Some((1 + len, format!("${n}")))
+    });
+
+    Rewritten { sql, names }
+}
+
+/// What to bind for a placeholder: `@`-names as `--bind` takes them, e.g. `@received_at`, and
+/// anything else a top-level field of the JSON entry
+fn bind_for(name: &str) -> anyhow::Result<BindField> {
+    if !name.starts_with('@') {
+        return Ok(BindField::Json(name.to_string()));
+    }
+    match name.parse().unwrap_or_else(|e| match e {}) {
+        BindField::Field(_) => bail!("No such placeholder :{name}"),
+        field => Ok(field),
+    }
+}
+
+/// Rewrite the named placeholders of every statement, or `None` if none has any. Statements are
+/// all given the same parameters, as many as each one takes, so each has to take the first ones
+/// of the statement taking the most.
+pub fn rewrite(sql: &[String]) -> anyhow::Result<Option<(Vec<String>, Vec<BindField>)>> {
+    let rewritten: Vec<_> = sql.iter().map(|v| rewrite_one(v)).collect();
+    let Some(most) = rewritten.iter().max_by_key(|v| v.names.len()) else {
+        return Ok(None);
+    };
+    if most.names.is_empty() {
+        return Ok(None);
+    }
+
+    for (sql, statement) in sql.iter().zip(&rewritten) {
+        if !most.names.starts_with(&statement.names) {
+            bail!(
+                "Takes :{} while other statements are given :{} first: {sql}",
+                statement.names.join(", :"),
+                most.names.join(", :"),
+            );
+        }
+    }
+
+    let binds = most
+        .names
+        .iter()
+        .map(|v| bind_for(v))
+        .collect::<anyhow::Result<_>>()?;
+    Ok(Some((
+        rewritten.into_iter().map(|v| v.sql).collect(),
+        binds,
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn translates_for_each_database() {
+        let sql = "INSERT INTO logs(body, sender, again) VALUES ($1, $2, $1)";
+        assert_eq!((sql.to_string(), None), translate(sql, AnyKind::Postgres));
+        assert_eq!(
+            (
+                "INSERT INTO logs(body, sender, again) VALUES (?1, ?2, ?1)".to_string(),
+                None
+            ),
+            translate(sql, AnyKind::Sqlite)
+        );
+        assert_eq!(
+            (
+                "INSERT INTO logs(body, sender, again) VALUES (@p1, @p2, @p1)".to_string(),
+                None
+            ),
+            translate(sql, AnyKind::Mssql)
+        );
+        assert_eq!(
+            (
+                "INSERT INTO logs(body, sender, again) VALUES (?, ?, ?)".to_string(),
+                Some(vec![0, 1, 0])
+            ),
+            translate(sql, AnyKind::MySql)
+        );
+
+        let native = "INSERT INTO logs(body) VALUES (?)";
+        assert_eq!(
+            (native.to_string(), None),
+            translate(native, AnyKind::MySql)
+        );
+        let quoted = "SELECT '$1', `$2`, \"$3\", $name, $0 -- $4";
+        assert_eq!(
+            (quoted.to_string(), None),
+            translate(quoted, AnyKind::MySql)
+        );
+    }
+
+    #[test]
+    fn rewrites_named() {
+        let sql = "INSERT INTO logs(host, msg, again) VALUES (:host, :short_message, :host)";
+        assert_eq!(
+            Rewritten {
+                sql: "INSERT INTO logs(host, msg, again) VALUES ($1, $2, $1)".to_string(),
+                names: names(&["host", "short_message"])
+            },
+            rewrite_one(sql)
+        );
+    }
+
+    #[test]
+    fn leaves_quotes_comments_and_casts() {
+        let sql = "SELECT ':a', \":b\", `:c` -- :d\n, /* :e */ :f::text, :@seq, ':it''s :g', 1:2";
+        assert_eq!(
+            Rewritten {
+                sql: "SELECT ':a', \":b\", `:c` -- :d\n, /* :e */ $1::text, $2, ':it''s :g', 1:2"
+                    .to_string(),
+                names: names(&["f", "@seq"])
+            },
+            rewrite_one(sql)
+        );
+    }
+
+    #[test]
+    fn binds() {
+        let sql = [
+            "INSERT INTO logs(host, at) VALUES (:host, :@received_at)".to_string(),
+            "INSERT INTO hosts(host) VALUES (:host)".to_string(),
+        ];
+        let (sql, binds) = rewrite(&sql).unwrap().unwrap();
+        assert_eq!("INSERT INTO hosts(host) VALUES ($1)", sql[1]);
+        assert_eq!(
+            vec![BindField::Json("host".to_string()), BindField::ReceivedAt],
+            binds
+        );
+
+        let positional = ["INSERT INTO logs(body) VALUES ($1)".to_string()];
+        assert_eq!(None, rewrite(&positional).unwrap());
+        let unknown = ["INSERT INTO logs(x) VALUES (:@nope)".to_string()];
+        assert!(rewrite(&unknown).is_err());
+        let out_of_order = [
+            "INSERT INTO logs(a, b) VALUES (:a, :b)".to_string(),
+            "INSERT INTO bs(b) VALUES (:b)".to_string(),
+        ];
+        assert!(rewrite(&out_of_order).is_err());
+    }
+}
@@ -0,0 +1,266 @@
+//! Skipping entries the database keeps refusing, e.g. a value too long for its column, rather than
+//! failing the batch they're in for good
+
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    future::Future,
+    hash::{Hash, Hasher},
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use async_trait::async_trait;
+use sqlx::{error::DatabaseError, sqlite::SqliteError};
+
+use crate::pipeline::{Entry, PendingCommit, Sink};
+
+/// Entries given up on and sent to the dead letters
+pub static POISONED: AtomicU64 = AtomicU64::new(0);
+
+/// Failures of entries not given up on yet are forgotten beyond this many
+const MAX_TRACKED: usize = 10_000;
+
+/// SQLite result codes of values it refuses: too big, breaking a constraint, or of the wrong type
+const SQLITE_DATA_ERRORS: [i32; 3] = [18, 19, 20];
+
+/// Whether `e` is the database refusing what the entries hold, e.g. a value too long for its
+/// column or breaking a constraint, rather than failing to run the statement at all, e.g. for the
+/// connection being lost, a deadlock or the database having become read-only, which says nothing
+/// about the entries and is left to retries and the circuit breaker
+pub fn is_refused(e: &anyhow::Error) -> bool {
+    e.chain().any(|v| match v.downcast_ref::<sqlx::Error>() {
+        Some(sqlx::Error::Database(e)) => is_data_error(e.as_ref()),
+        Some(sqlx::Error::ColumnDecode { .. }) => true,
+        _ => false,
+    })
+}
+
+fn is_data_error(e: &dyn DatabaseError) -> bool {
+    let code = e.code();
+    if e.try_downcast_ref::<SqliteError>().is_some() {
+        // Extended result codes keep the primary one in their lowest byte
+        return code
+            .and_then(|v| v.parse::<i32>().ok())
+            .is_some_and(|v| SQLITE_DATA_ERRORS.contains(&(v & 0xff)));
+    }
+    // The SQLSTATE classes of data exceptions and integrity constraint violations
+    code.is_some_and(|v| v.starts_with("22") || v.starts_with("23"))
+}
+
+fn key(entry: &Entry) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    entry.body.hash(&mut hasher);
+    entry.sender.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Writes to `inner`, and when the database refuses a batch, tells which of its entries it
+/// refuses by writing halves of it, then halves of the half that failed, down to single entries.
+/// Each of those is tried up to `max_attempts` times, counting the attempts at earlier batches
+/// too, then written to `dead_letters` instead, so the rest goes on. Should the database go away
+/// meanwhile, halves already written are written again when the batch is retried. Commits are
+/// waited on before the next batch is written, so those refused, e.g. by deferred constraints,
+/// are told apart too.
+pub struct PoisonSink {
+    pub inner: Box<dyn Sink>,
+    pub dead_letters: Box<dyn Sink>,
+    max_attempts: u32,
+    /// How many times each entry was refused on its own, by [`key`]
+    failures: HashMap<u64, u32>,
+}
+
+impl PoisonSink {
+    pub fn new(inner: Box<dyn Sink>, dead_letters: Box<dyn Sink>, max_attempts: u32) -> Self {
+        Self {
+            inner,
+            dead_letters,
+            max_attempts: max_attempts.max(1),
+            failures: Default::default(),
+        }
+    }
+
+    async fn write_alone(&mut self, entry: &Entry, mut e: anyhow::Error) -> anyhow::Result<()> {
+        let key = key(entry);
+        loop {
+            let failures = self.failures.entry(key).or_default();
+            *failures += 1;
+            if *failures >= self.max_attempts {
+                self.failures.remove(&key);
+                log::error!(
+                    "Giving up on an entry from {} refused {} times, to the dead letters: {e:#}",
+                    entry
+                        .sender
+                        .map_or_else(|| "nowhere".to_string(), |v| v.to_string()),
+                    self.max_attempts
+                );
+                POISONED.fetch_add(1, Ordering::Relaxed);
+                return self.dead_letters.write(std::slice::from_ref(entry)).await;
+            }
+
+            match self.inner.write(std::slice::from_ref(entry)).await {
+                Ok(()) => {
+                    self.failures.remove(&key);
+                    return Ok(());
+                }
+                Err(refused) if is_refused(&refused) => e = refused,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Write `entries`, which the database refused with `e`, in halves, or alone
+    async fn isolate(&mut self, entries: &[Entry], e: anyhow::Error) -> anyhow::Result<()> {
+        if self.failures.len() > MAX_TRACKED {
+            self.failures.clear();
+        }
+        if let [entry] = entries {
+            return self.write_alone(entry, e).await;
+        }
+
+        let (first, second) = entries.split_at(entries.len() / 2);
+        for half in [first, second] {
+            match self.inner.write(half).await {
+                Ok(()) => {}
+                Err(e) if is_refused(&e) => self.isolate_boxed(half, e).await?,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn isolate_boxed<'a>(
+        &'a mut self,
+        entries: &'a [Entry],
+        e: anyhow::Error,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + 'a>> {
+        Box::pin(self.isolate(entries, e))
+    }
+}
+
+#[async_trait]
+impl Sink for PoisonSink {
+    async fn write(&mut self, entries: &[Entry]) -> anyhow::Result<()> {
+        self.write_pending(entries).await?.await
+    }
+
+    async fn write_pending(&mut self, entries: &[Entry]) -> anyhow::Result<PendingCommit> {
+        let rs = match self.inner.write_pending(entries).await {
+            Ok(commit) => commit.await,
+            Err(e) => Err(e),
+        };
+        match rs {
+            Err(e) if is_refused(&e) => {
+                log::warn!(
+                    "Batch of {} entries refused, telling which: {e:#}",
+                    entries.len()
+                );
+                self.isolate(entries, e).await?;
+            }
+            rs => rs?,
+        }
+        Ok(Box::pin(async { Ok(()) }))
+    }
+
+    async fn close(&mut self) -> anyhow::Result<()> {
+        self.dead_letters.close().await?;
+        self.inner.close().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    /// An error of a database with SQLSTATE codes
+    #[derive(Debug)]
+    struct Refused(&'static str);
+
+    impl std::fmt::Display for Refused {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.write_str("refused")
+        }
+    }
+
+    impl std::error::Error for Refused {}
+
+    impl sqlx::error::DatabaseError for Refused {
+        fn message(&self) -> &str {
+            "refused"
+        }
+
+        fn code(&self) -> Option<std::borrow::Cow<'_, str>> {
+            Some(self.0.into())
+        }
+
+        fn as_error(&self) -> &(dyn std::error::Error + Send + Sync + 'static) {
+            self
+        }
+
+        fn as_error_mut(&mut self) -> &mut (dyn std::error::Error + Send + Sync + 'static) {
+            self
+        }
+
+        fn into_error(self: Box<Self>) -> Box<dyn std::error::Error + Send + Sync + 'static> {
+            self
+        }
+    }
+
+    /// Keeps what it's given, refusing batches with a body of "poison" in them if `refusing`
+    #[derive(Default, Clone)]
+    struct Kept {
+        bodies: Arc<Mutex<Vec<String>>>,
+        refusing: bool,
+    }
+
+    #[async_trait]
+    impl Sink for Kept {
+        async fn write(&mut self, entries: &[Entry]) -> anyhow::Result<()> {
+            if self.refusing && entries.iter().any(|v| v.body == "poison") {
+                return Err(sqlx::Error::Database(Box::new(Refused("22001"))).into());
+            }
+            self.bodies
+                .lock()
+                .unwrap()
+                .extend(entries.iter().map(|v| v.body.clone()));
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn sends_refused_entries_to_dead_letters() {
+        let kept = Kept {
+            refusing: true,
+            ..Default::default()
+        };
+        let dead = Kept::default();
+        let mut sink = PoisonSink::new(Box::new(kept.clone()), Box::new(dead.clone()), 2);
+
+        let entries: Vec<_> = ["a", "b", "poison", "c", "d"]
+            .map(|v| Entry {
+                body: v.to_string(),
+                ..Default::default()
+            })
+            .to_vec();
+        sink.write(&entries).await.unwrap();
+
+        assert_eq!(vec!["a", "b", "c", "d"], *kept.bodies.lock().unwrap());
+        assert_eq!(vec!["poison"], *dead.bodies.lock().unwrap());
+        assert_eq!(1, POISONED.load(Ordering::Relaxed));
+        assert!(sink.failures.is_empty());
+    }
+
+    #[test]
+    fn refuses_only_for_the_data() {
+        let error = |code| anyhow::Error::from(sqlx::Error::Database(Box::new(Refused(code))));
+        // Too long, a unique violation
+        assert!(is_refused(&error("22001")));
+        assert!(is_refused(&error("23505")));
+        // A serialization failure, too many connections, shutting down, read-only
+        for code in ["40001", "53300", "57P01", "25006"] {
+            assert!(!is_refused(&error(code)), "{code}");
+        }
+        assert!(!is_refused(&sqlx::Error::PoolTimedOut.into()));
+    }
+}
@@ -0,0 +1,153 @@
+use std::{
+    collections::VecDeque,
+    future::Future,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use tokio::sync::mpsc;
+
+use crate::writer::{Param, Writer};
+
+/// How many rejected messages may wait to be written before new ones are dropped
+const QUEUE_SIZE: usize = 1024;
+/// How many rejected messages are written within one transaction
+const BATCH_SIZE: usize = 100;
+/// How many of the latest rejected messages are kept as samples
+const SAMPLES: usize = 20;
+/// Longer payloads are cut in samples
+const MAX_SAMPLE_BYTES: usize = 1024;
+
+/// A message that never made it into an entry, or an entry that was dropped
+#[derive(Debug, Clone)]
+pub struct Rejected {
+    pub reason: String,
+    pub sender: Option<SocketAddr>,
+    pub payload: String,
+    pub at: SystemTime,
+}
+
+impl Rejected {
+    fn row(self) -> Vec<Param> {
+        vec![
+            Param::Text(Some(self.reason)),
+            Param::Text(Some(self.payload)),
+            Param::Text(self.sender.map(|v| v.to_string())),
+            Param::Text(Some(humantime::format_rfc3339_micros(self.at).to_string())),
+        ]
+    }
+}
+
+/// Where rejected messages go: written by [`Quarantine::writer`], kept as
+/// [`Quarantine::samples`], both, or neither by default. Clones share them, so one can be handed to
+/// each source and to the [`Service`](crate::Service).
+#[derive(Debug, Clone, Default)]
+pub struct Quarantine {
+    sender: Option<mpsc::Sender<Rejected>>,
+    recent: Option<Arc<Mutex<VecDeque<Rejected>>>>,
+}
+
+impl Quarantine {
+    /// Keep the latest rejected messages, cut short, for [`Quarantine::samples`], quarantined or
+    /// not
+    pub fn keep_samples(mut self) -> Self {
+        self.recent = Some(Default::default());
+        self
+    }
+
+    /// Start quarantining, returning what writes rejected messages with `sql`, given the reason,
+    /// the payload, the sender and when it was rejected. Meant to be spawned.
+    pub fn writer(&mut self, writer: Writer, sql: String) -> impl Future<Output = ()> {
+        let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+        self.sender = Some(sender);
+        run(writer, sql, receiver)
+    }
+
+    /// Whether rejected messages are quarantined or sampled, and so worth keeping to pass to
+    /// [`Quarantine::reject`]
+    pub fn is_enabled(&self) -> bool {
+        self.sender.is_some() || self.recent.is_some()
+    }
+
+    /// The latest rejected messages, newest first, if keeping them
+    pub fn samples(&self) -> Vec<Rejected> {
+        match &self.recent {
+            Some(recent) => recent.lock().unwrap().iter().rev().cloned().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Keep `payload` in quarantine with the reason it was rejected, if there is a quarantine,
+    /// and as a sample if keeping them. Not valid UTF-8 bits of the payload are replaced.
+    pub fn reject(&self, reason: impl ToString, sender: Option<SocketAddr>, payload: &[u8]) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let rejected = Rejected {
+            reason: reason.to_string(),
+            sender,
+            payload: String::from_utf8_lossy(payload).into_owned(),
+            at: SystemTime::now(),
+        };
+        if let Some(recent) = &self.recent {
+            let mut sample = rejected.clone();
+            if let Some((cut, _)) = sample
+                .payload
+                .char_indices()
+                .find(|(i, _)| *i >= MAX_SAMPLE_BYTES)
+            {
+                sample.payload.truncate(cut);
+            }
+            let mut recent = recent.lock().unwrap();
+            if recent.len() == SAMPLES {
+                recent.pop_front();
+            }
+            recent.push_back(sample);
+        }
+        let Some(quarantine) = &self.sender else {
+            return;
+        };
+        if quarantine.try_send(rejected).is_err() {
+            log::debug!("Too many rejected messages waiting, not quarantining one");
+        }
+    }
+}
+
+async fn run(mut writer: Writer, sql: String, mut receiver: mpsc::Receiver<Rejected>) {
+    let mut rejected = Vec::with_capacity(BATCH_SIZE);
+    while receiver.recv_many(&mut rejected, BATCH_SIZE).await > 0 {
+        let rows: Vec<_> = rejected.drain(..).map(Rejected::row).collect();
+        if let Err(e) = writer.write_rows(&sql, &rows).await {
+            log::warn!("Error quarantining {} messages: {e:#}", rows.len());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_of_its_own() {
+        let sampled = Quarantine::default().keep_samples();
+        let other = Quarantine::default().keep_samples();
+        assert!(!Quarantine::default().is_enabled());
+
+        sampled
+            .clone()
+            .reject("Not JSON", None, &[b'a'; MAX_SAMPLE_BYTES * 2]);
+        let samples = sampled.samples();
+        assert_eq!(1, samples.len());
+        assert_eq!(MAX_SAMPLE_BYTES, samples[0].payload.len());
+        assert!(other.samples().is_empty());
+
+        for i in 0..SAMPLES + 1 {
+            sampled.reject(i, None, b"");
+        }
+        let samples = sampled.samples();
+        assert_eq!(SAMPLES, samples.len());
+        assert_eq!(SAMPLES.to_string(), samples[0].reason);
+    }
+}
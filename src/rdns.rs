@@ -0,0 +1,144 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::{sync::Semaphore, task::spawn_blocking};
+
+use crate::pipeline::{Entry, Transform};
+
+/// The sender's hostname, from its PTR record
+pub const HOSTNAME: &str = "sender-hostname";
+
+/// How many lookups may be in flight at once. Senders beyond that are looked up for later entries
+const MAX_CONCURRENT_LOOKUPS: usize = 16;
+
+#[derive(Debug)]
+struct Cache {
+    capacity: usize,
+    resolved: HashMap<IpAddr, (Option<String>, Instant)>,
+    pending: HashSet<IpAddr>,
+}
+
+impl Cache {
+    /// Remember the hostname of `ip`, if any, making room by forgetting the oldest lookup
+    fn insert(&mut self, ip: IpAddr, hostname: Option<String>, now: Instant) {
+        self.pending.remove(&ip);
+        if self.resolved.len() >= self.capacity && !self.resolved.contains_key(&ip) {
+            let oldest = self
+                .resolved
+                .iter()
+                .min_by_key(|(_, (_, resolved_at))| *resolved_at)
+                .map(|(ip, _)| *ip);
+            if let Some(oldest) = oldest {
+                self.resolved.remove(&oldest);
+            }
+        }
+        self.resolved.insert(ip, (hostname, now));
+    }
+}
+
+/// Adds the sender's hostname to entries.
+///
+/// Lookups happen in the background so entries never wait for DNS: the first entries from a
+/// sender, and the ones arriving while too many lookups are in flight, go without a hostname.
+/// Hostnames are looked up again once they are older than `ttl`, the old one being used until then.
+pub struct ReverseDns {
+    cache: Arc<Mutex<Cache>>,
+    lookups: Arc<Semaphore>,
+    ttl: Duration,
+}
+
+impl ReverseDns {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            cache: Arc::new(Mutex::new(Cache {
+                capacity,
+                resolved: Default::default(),
+                pending: Default::default(),
+            })),
+            lookups: Arc::new(Semaphore::new(MAX_CONCURRENT_LOOKUPS)),
+            ttl,
+        }
+    }
+
+    fn hostname(&self, ip: IpAddr) -> Option<String> {
+        let mut cache = self.cache.lock().unwrap();
+        let (hostname, fresh) = match cache.resolved.get(&ip) {
+            Some((hostname, resolved_at)) => (hostname.clone(), resolved_at.elapsed() < self.ttl),
+            None => (None, false),
+        };
+
+        if !fresh && !cache.pending.contains(&ip) {
+            if let Ok(permit) = self.lookups.clone().try_acquire_owned() {
+                cache.pending.insert(ip);
+                let cache = self.cache.clone();
+                spawn_blocking(move || {
+                    let hostname = match dns_lookup::lookup_addr(&ip) {
+                        Ok(v) => Some(v),
+                        Err(e) => {
+                            log::debug!("No hostname for {ip}: {e}");
+                            None
+                        }
+                    };
+                    cache.lock().unwrap().insert(ip, hostname, Instant::now());
+                    drop(permit);
+                });
+            }
+        }
+
+        hostname
+    }
+}
+
+impl Transform for ReverseDns {
+    fn apply(&mut self, mut entry: Entry) -> Option<Entry> {
+        if let Some(hostname) = entry.sender.and_then(|v| self.hostname(v.ip())) {
+            entry.fields.insert(HOSTNAME.to_string(), hostname);
+        }
+        Some(entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cache_is_bounded() {
+        let mut cache = Cache {
+            capacity: 2,
+            resolved: Default::default(),
+            pending: Default::default(),
+        };
+        let now = Instant::now();
+        let ips: Vec<IpAddr> = ["10.0.0.1", "10.0.0.2", "10.0.0.3"]
+            .iter()
+            .map(|v| v.parse().unwrap())
+            .collect();
+
+        cache.pending.insert(ips[0]);
+        cache.insert(ips[0], Some("a.example".to_string()), now);
+        cache.insert(ips[1], None, now + Duration::from_secs(1));
+        assert!(cache.pending.is_empty());
+
+        // Looking up again doesn't evict anything
+        cache.insert(
+            ips[0],
+            Some("a.example".to_string()),
+            now + Duration::from_secs(2),
+        );
+        assert_eq!(2, cache.resolved.len());
+
+        cache.insert(
+            ips[2],
+            Some("c.example".to_string()),
+            now + Duration::from_secs(3),
+        );
+        assert_eq!(2, cache.resolved.len());
+        assert!(!cache.resolved.contains_key(&ips[1]));
+        assert!(cache.resolved.contains_key(&ips[0]));
+    }
+}
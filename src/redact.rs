@@ -0,0 +1,227 @@
+use std::{
+    borrow::Cow,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::Context;
+use clap::ValueEnum;
+use regex::{Captures, Regex};
+use serde_json::Value;
+
+use crate::pipeline::{Entry, Transform};
+
+const REDACTED: &str = "[REDACTED]";
+
+/// Patterns for the usual kinds of personal data
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Builtin {
+    Email,
+    /// Card numbers of 13 to 19 digits, optionally grouped by spaces or dashes, passing the Luhn
+    /// check
+    CreditCard,
+}
+
+impl Builtin {
+    fn pattern(self) -> Pattern {
+        let (regex, luhn) = match self {
+            Self::Email => (
+                r"[A-Za-z0-9._%+-]+@[A-Za-z0-9-]+(?:\.[A-Za-z0-9-]+)*\.[A-Za-z]{2,}",
+                false,
+            ),
+            Self::CreditCard => (r"\b\d(?:[ -]?\d){12,18}\b", true),
+        };
+        Pattern {
+            regex: Regex::new(regex).expect("Valid builtin pattern"),
+            luhn,
+        }
+    }
+}
+
+struct Pattern {
+    regex: Regex,
+    /// Only redact matches whose digits pass the Luhn check
+    luhn: bool,
+}
+
+/// Redacts personal data from entries before they are stored.
+///
+/// Patterns are matched against the whole entry, so custom ones shouldn't be able to match quotes
+/// or backslashes if entries have to stay valid JSON. Fields are the top-level fields of JSON
+/// entries, whose values are replaced as a whole.
+pub struct Redactor {
+    patterns: Vec<Pattern>,
+    fields: Vec<String>,
+    num_redactions: Arc<AtomicU64>,
+}
+
+impl Redactor {
+    pub fn new(
+        builtins: &[Builtin],
+        patterns: &[String],
+        fields: Vec<String>,
+    ) -> anyhow::Result<Self> {
+        let mut compiled: Vec<_> = builtins.iter().map(|v| v.pattern()).collect();
+        for pattern in patterns {
+            compiled.push(Pattern {
+                regex: Regex::new(pattern)
+                    .with_context(|| format!("Invalid redaction pattern: {pattern}"))?,
+                luhn: false,
+            });
+        }
+
+        Ok(Self {
+            patterns: compiled,
+            fields,
+            num_redactions: Default::default(),
+        })
+    }
+
+    /// How many values have been redacted so far, shared so it can be read while the redactor is
+    /// busy in a pipeline
+    pub fn num_redactions(&self) -> Arc<AtomicU64> {
+        self.num_redactions.clone()
+    }
+
+    /// The entry with personal data redacted, and how many values were
+    pub fn redact<'a>(&self, entry: &'a Entry) -> (Cow<'a, str>, usize) {
+        let mut body = Cow::Borrowed(entry.body.as_str());
+        let mut count = 0usize;
+
+        if !self.fields.is_empty() {
+            if let Some(mut object) = entry.json().and_then(Value::as_object).cloned() {
+                for field in &self.fields {
+                    match object.get_mut(field) {
+                        Some(Value::Null) | None => {}
+                        Some(value) => {
+                            *value = Value::String(REDACTED.to_string());
+                            count += 1;
+                        }
+                    }
+                }
+
+                if count > 0 {
+                    body = Cow::Owned(Value::Object(object).to_string());
+                }
+            }
+        }
+
+        for pattern in &self.patterns {
+            let replaced = pattern.regex.replace_all(&body, |caps: &Captures| {
+                if pattern.luhn && !passes_luhn(&caps[0]) {
+                    caps[0].to_string()
+                } else {
+                    count += 1;
+                    REDACTED.to_string()
+                }
+            });
+
+            if let Cow::Owned(v) = replaced {
+                body = Cow::Owned(v);
+            }
+        }
+
+        (body, count)
+    }
+}
+
+impl Transform for Redactor {
+    fn apply(&mut self, mut entry: Entry) -> Option<Entry> {
+        let (body, count) = self.redact(&entry);
+        if let Cow::Owned(body) = body {
+            log::debug!("Redacted {count} values");
+            entry.set_body(body);
+            self.num_redactions
+                .fetch_add(count as u64, Ordering::Relaxed);
+        }
+        Some(entry)
+    }
+}
+
+impl Drop for Redactor {
+    fn drop(&mut self) {
+        log::info!(
+            "Redacted {} values in total",
+            self.num_redactions.load(Ordering::Relaxed)
+        );
+    }
+}
+
+fn passes_luhn(number: &str) -> bool {
+    let sum: u32 = number
+        .bytes()
+        .filter(u8::is_ascii_digit)
+        .rev()
+        .enumerate()
+        .map(|(i, b)| {
+            let digit = u32::from(b - b'0');
+            match (i % 2 == 1, digit * 2) {
+                (true, v) if v > 9 => v - 9,
+                (true, v) => v,
+                (false, _) => digit,
+            }
+        })
+        .sum();
+    sum.is_multiple_of(10)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(body: &str) -> Entry {
+        Entry {
+            body: body.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn builtin_patterns() {
+        let redactor = Redactor::new(&[Builtin::Email, Builtin::CreditCard], &[], vec![]).unwrap();
+
+        let paid = entry(
+            r#"{"short_message":"Paid with 4111 1111 1111 1111 by jane.doe@example.co.uk, order 1234567890123"}"#,
+        );
+        let (body, count) = redactor.redact(&paid);
+        assert_eq!(
+            r#"{"short_message":"Paid with [REDACTED] by [REDACTED], order 1234567890123"}"#,
+            body
+        );
+        assert_eq!(2, count);
+
+        let nothing = entry("nothing to see");
+        let (body, count) = redactor.redact(&nothing);
+        assert!(matches!(body, Cow::Borrowed(_)));
+        assert_eq!(0, count);
+    }
+
+    #[test]
+    fn custom_patterns_and_fields() {
+        let redactor = Redactor::new(
+            &[],
+            &[r"token=\w+".to_string()],
+            vec!["_password".to_string(), "_missing".to_string()],
+        )
+        .unwrap();
+
+        let request = entry(r#"{"_password":"hunter2","short_message":"GET /?token=abc123"}"#);
+        let (body, count) = redactor.redact(&request);
+        assert_eq!(
+            r#"{"_password":"[REDACTED]","short_message":"GET /?[REDACTED]"}"#,
+            body
+        );
+        assert_eq!(2, count);
+
+        assert!(Redactor::new(&[], &["(".to_string()], vec![]).is_err());
+    }
+
+    #[test]
+    fn luhn() {
+        assert!(passes_luhn("4111-1111-1111-1111"));
+        assert!(passes_luhn("79927398713"));
+        assert!(!passes_luhn("4111 1111 1111 1112"));
+    }
+}
@@ -0,0 +1,273 @@
+//! How failed writes, sinks and alerts are tried again: how many times, how long to wait between
+//! attempts, and a budget shared by all of them, so retries can't pile onto a struggling database
+//! or endpoint when everything fails at once
+
+use std::{
+    fmt::Display,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+
+use crate::pipeline::{Entry, PendingCommit, Sink};
+
+/// Retries made so far
+pub static RETRIED: AtomicU64 = AtomicU64::new(0);
+/// Retries not made, for the budget having run out
+pub static OVER_BUDGET: AtomicU64 = AtomicU64::new(0);
+
+/// Unused retries of the budget are kept up to this many
+const MAX_SAVED: f64 = 100.0;
+
+/// How many times to try, and how long to wait in between
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Policy {
+    /// Including the first, so 1 never retries
+    pub max_attempts: u32,
+    /// Waited after the first attempt, doubling after each one after
+    pub base: Duration,
+    /// The longest wait
+    pub cap: Duration,
+    /// Up to this share of each wait, between 0 and 1, is taken off at random, so what failed
+    /// together doesn't retry together
+    pub jitter: f64,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 1,
+            base: Duration::from_millis(100),
+            cap: Duration::from_secs(10),
+            jitter: 0.5,
+        }
+    }
+}
+
+impl Policy {
+    /// How long to wait after `attempt`, counting from 1, with `random` between 0 and 1
+    fn delay(&self, attempt: u32, random: f64) -> Duration {
+        let doubled = self
+            .base
+            .saturating_mul(1 << attempt.saturating_sub(1).min(31));
+        doubled.min(self.cap).mul_f64(1.0 - self.jitter * random)
+    }
+}
+
+/// Retries earned by the attempts made, and over time
+#[derive(Debug)]
+pub struct Budget {
+    /// Earned by every first attempt
+    ratio: f64,
+    /// Earned every second whatever happens, so a quiet daemon still gets to retry
+    per_sec: f64,
+    saved: f64,
+    updated: Instant,
+}
+
+impl Budget {
+    pub fn new(ratio: f64, per_sec: f64) -> Self {
+        Self {
+            ratio,
+            per_sec,
+            saved: per_sec.min(MAX_SAVED),
+            updated: Instant::now(),
+        }
+    }
+
+    fn earn(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.saved = (self.saved + elapsed * self.per_sec).min(MAX_SAVED);
+        self.updated = now;
+    }
+
+    fn on_first_attempt(&mut self, now: Instant) {
+        self.earn(now);
+        self.saved = (self.saved + self.ratio).min(MAX_SAVED);
+    }
+
+    /// Whether there is a retry left, taking it if there is
+    fn take(&mut self, now: Instant) -> bool {
+        self.earn(now);
+        if self.saved < 1.0 {
+            return false;
+        }
+        self.saved -= 1.0;
+        true
+    }
+}
+
+/// A policy and the budget its retries share. Clones share the budget; with no policy given,
+/// nothing is retried.
+#[derive(Debug, Clone, Default)]
+pub struct Retries {
+    policy: Policy,
+    budget: Option<Arc<Mutex<Budget>>>,
+}
+
+impl Retries {
+    /// Retry by `policy`, within `budget` if there is one
+    pub fn new(policy: Policy, budget: Option<Budget>) -> anyhow::Result<Self> {
+        if !(0.0..=1.0).contains(&policy.jitter) {
+            anyhow::bail!("Jitter must be between 0 and 1, got {}", policy.jitter);
+        }
+        Ok(Self {
+            policy,
+            budget: budget.map(|v| Arc::new(Mutex::new(v))),
+        })
+    }
+
+    /// The attempts at `what`, starting with the first
+    pub fn attempts<W: Display>(&self, what: W) -> Attempts<W> {
+        if let Some(budget) = &self.budget {
+            budget.lock().unwrap().on_first_attempt(Instant::now());
+        }
+        Attempts {
+            what,
+            retries: self.clone(),
+            made: 1,
+        }
+    }
+}
+
+/// The attempts at one thing, e.g. writing a batch:
+///
+/// ```text
+/// let mut attempts = retries.attempts("Writing to Splunk");
+/// let response = loop {
+///     match send().await {
+///         Ok(v) => break v,
+///         Err(e) => attempts.failed(e).await?,
+///     }
+/// };
+/// ```
+pub struct Attempts<W> {
+    what: W,
+    retries: Retries,
+    made: u32,
+}
+
+impl<W: Display> Attempts<W> {
+    /// After the attempt failed with `e`, wait for the next one, or give `e` back if there are
+    /// no attempts left, by the policy or the budget
+    pub async fn failed(&mut self, e: anyhow::Error) -> anyhow::Result<()> {
+        let policy = &self.retries.policy;
+        if self.made >= policy.max_attempts {
+            return Err(e);
+        }
+        if let Some(budget) = &self.retries.budget {
+            if !budget.lock().unwrap().take(Instant::now()) {
+                OVER_BUDGET.fetch_add(1, Ordering::Relaxed);
+                log::warn!("{} failed, not retrying over the retry budget", self.what);
+                return Err(e);
+            }
+        }
+
+        let random = OsRng.next_u64() as f64 / u64::MAX as f64;
+        let delay = policy.delay(self.made, random);
+        log::warn!(
+            "{} failed, attempt {} of {}, retrying in {}: {e:#}",
+            self.what,
+            self.made,
+            policy.max_attempts,
+            humantime::format_duration(Duration::from_millis(delay.as_millis() as u64))
+        );
+        RETRIED.fetch_add(1, Ordering::Relaxed);
+        tokio::time::sleep(delay).await;
+        self.made += 1;
+        Ok(())
+    }
+}
+
+/// Tries writing to `inner` again by `retries`. Only writing is, not committing: once a commit
+/// fails, what it did commit isn't known.
+pub struct RetrySink {
+    pub inner: Box<dyn Sink>,
+    pub retries: Retries,
+}
+
+#[async_trait]
+impl Sink for RetrySink {
+    async fn write(&mut self, entries: &[Entry]) -> anyhow::Result<()> {
+        self.write_pending(entries).await?.await
+    }
+
+    async fn write_pending(&mut self, entries: &[Entry]) -> anyhow::Result<PendingCommit> {
+        let mut attempts = self
+            .retries
+            .attempts(format!("Writing {} entries", entries.len()));
+        loop {
+            match self.inner.write_pending(entries).await {
+                Ok(commit) => return Ok(commit),
+                Err(e) => attempts.failed(e).await?,
+            }
+        }
+    }
+
+    async fn close(&mut self) -> anyhow::Result<()> {
+        self.inner.close().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backs_off() {
+        let policy = Policy {
+            max_attempts: 5,
+            base: Duration::from_secs(1),
+            cap: Duration::from_secs(3),
+            jitter: 0.5,
+        };
+        let secs = Duration::from_secs_f64;
+        assert_eq!(secs(1.0), policy.delay(1, 0.0));
+        assert_eq!(secs(2.0), policy.delay(2, 0.0));
+        assert_eq!(secs(3.0), policy.delay(3, 0.0));
+        assert_eq!(secs(1.5), policy.delay(40, 1.0));
+        assert_eq!(secs(0.75), policy.delay(1, 0.5));
+    }
+
+    #[test]
+    fn spends_the_budget() {
+        let now = Instant::now();
+        let mut budget = Budget::new(0.5, 1.0);
+        budget.updated = now;
+        assert!(budget.take(now));
+        assert!(!budget.take(now));
+
+        budget.on_first_attempt(now);
+        assert!(!budget.take(now));
+        budget.on_first_attempt(now);
+        assert!(budget.take(now));
+
+        assert!(budget.take(now + Duration::from_secs(1)));
+        assert!(!budget.take(now + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn budgets_are_their_own() {
+        let policy = Policy {
+            max_attempts: 2,
+            ..Default::default()
+        };
+        let spent = Retries::new(policy, Some(Budget::new(0.0, 1.0))).unwrap();
+        let other = Retries::new(policy, Some(Budget::new(0.0, 1.0))).unwrap();
+        let now = Instant::now();
+        assert!(spent.budget.as_ref().unwrap().lock().unwrap().take(now));
+        assert!(!spent.clone().budget.unwrap().lock().unwrap().take(now));
+        assert!(other.budget.unwrap().lock().unwrap().take(now));
+
+        let jitter = Policy {
+            jitter: 2.0,
+            ..Default::default()
+        };
+        assert!(Retries::new(jitter, None).is_err());
+    }
+}
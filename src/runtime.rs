@@ -0,0 +1,78 @@
+use std::{
+    io,
+    num::NonZeroUsize,
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+};
+
+use tokio::runtime::{Builder, Runtime};
+
+#[derive(Debug, clap::Args)]
+pub struct RuntimeArgs {
+    /// Run everything on the main thread, e.g. on single-core machines, with only blocking work
+    /// on threads of its own
+    #[arg(long, conflicts_with = "tokio_workers")]
+    current_thread: bool,
+
+    /// Number of runtime worker threads. Defaults to the number of CPUs
+    #[arg(long)]
+    tokio_workers: Option<NonZeroUsize>,
+
+    /// Maximum number of threads for blocking work
+    #[arg(long)]
+    blocking_threads: Option<NonZeroUsize>,
+
+    /// Pin the worker threads receiving and handling messages to these CPUs, round-robin, e.g.
+    /// 2,3. Threads for blocking work are left to run anywhere
+    #[arg(long, value_delimiter = ',')]
+    pin_cpus: Vec<usize>,
+}
+
+impl RuntimeArgs {
+    pub fn build(&self) -> io::Result<Runtime> {
+        let mut builder = if self.current_thread {
+            Builder::new_current_thread()
+        } else {
+            Builder::new_multi_thread()
+        };
+        builder.enable_all();
+
+        if let Some(n) = self.tokio_workers {
+            builder.worker_threads(n.get());
+        }
+
+        if let Some(n) = self.blocking_threads {
+            builder.max_blocking_threads(n.get());
+        }
+
+        if !self.pin_cpus.is_empty() {
+            if self.current_thread {
+                // The main thread runs the tasks, every other thread is for blocking work
+                pin(self.pin_cpus[0]);
+            } else {
+                // The workers are the first threads started, as the runtime is built, before
+                // anything can be handed to a thread for blocking work
+                let workers = self
+                    .tokio_workers
+                    .or_else(|| thread::available_parallelism().ok())
+                    .map_or(1, NonZeroUsize::get);
+                let cpus = self.pin_cpus.clone();
+                let next = AtomicUsize::new(0);
+                builder.on_thread_start(move || {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    if i < workers {
+                        pin(cpus[i % cpus.len()]);
+                    }
+                });
+            }
+        }
+
+        builder.build()
+    }
+}
+
+fn pin(id: usize) {
+    if !core_affinity::set_for_current(core_affinity::CoreId { id }) {
+        log::warn!("Unable to pin thread to CPU {id}");
+    }
+}
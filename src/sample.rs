@@ -0,0 +1,78 @@
+use sha2::{Digest, Sha256};
+
+use crate::{
+    json,
+    pipeline::{Entry, Transform},
+};
+
+/// Keeps a fraction of the entries by the hash of a top-level field of JSON entries, so every
+/// entry of the same trace or request is either kept or dropped, by every collector alike.
+/// Entries without the field are kept.
+#[derive(Debug, Clone)]
+pub struct Sample {
+    field: String,
+    /// Hashes below this are kept
+    threshold: u64,
+}
+
+impl Sample {
+    /// Keep `rate` of the keys, 0 to 1
+    pub fn new(field: String, rate: f64) -> anyhow::Result<Self> {
+        if !(0.0..=1.0).contains(&rate) {
+            anyhow::bail!("Sample rate must be between 0 and 1, got {rate}");
+        }
+
+        Ok(Self {
+            field,
+            threshold: (rate * u64::MAX as f64) as u64,
+        })
+    }
+
+    fn keeps(&self, key: &str) -> bool {
+        let digest = Sha256::digest(key.as_bytes());
+        let hash = u64::from_be_bytes(digest[..8].try_into().unwrap());
+        self.threshold == u64::MAX || hash < self.threshold
+    }
+}
+
+impl Transform for Sample {
+    fn apply(&mut self, entry: Entry) -> Option<Entry> {
+        match json::get_string(&entry, &self.field) {
+            Some(key) if !self.keeps(&key) => None,
+            _ => Some(entry),
+        }
+    }
+
+    fn rejects(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_whole_keys() {
+        let mut sample = Sample::new("trace_id".to_string(), 0.25).unwrap();
+        let mut apply = |body: String| {
+            sample
+                .apply(Entry {
+                    body,
+                    ..Default::default()
+                })
+                .is_some()
+        };
+
+        let mut kept = 0;
+        for i in 0..1000 {
+            let first = apply(format!(r#"{{"trace_id":"t{i}","n":1}}"#));
+            assert_eq!(first, apply(format!(r#"{{"trace_id":"t{i}","n":2}}"#)));
+            kept += first as usize;
+        }
+        assert!((200..300).contains(&kept), "kept {kept}");
+
+        assert!(apply(r#"{"short_message":"no trace"}"#.to_string()));
+        assert!(Sample::new("trace_id".to_string(), 1.5).is_err());
+    }
+}
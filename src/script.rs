@@ -0,0 +1,102 @@
+//! SQL given in a file, rather than on the command line
+
+use std::path::Path;
+
+use anyhow::Context;
+
+/// Read the statements of a file, separated by semicolons
+pub fn read_statements(path: &Path) -> anyhow::Result<Vec<String>> {
+    let sql = std::fs::read_to_string(path)
+        .with_context(|| format!("Reading SQL from {}", path.display()))?;
+    let statements = split_statements(&sql);
+    if statements.is_empty() {
+        anyhow::bail!("No SQL in {}", path.display());
+    }
+    Ok(statements)
+}
+
+/// Split `sql` into statements at the semicolons outside of quotes, comments and Postgres
+/// dollar quotes, leaving out those with nothing but comments
+pub fn split_statements(sql: &str) -> Vec<String> {
+    let mut statements = Vec::new();
+    let mut start = 0;
+    let mut has_code = false;
+    let mut rest = sql;
+
+    while let Some(c) = rest.chars().next() {
+        let offset = sql.len() - rest.len();
+        let skip = match c {
+            ';' => {
+                if has_code {
+                    statements.push(sql[start..offset].trim().to_string());
+                }
+                start = offset + 1;
+                has_code = false;
+                1
+            }
+            '-' if rest.starts_with("--") => rest.find('\n').unwrap_or(rest.len()),
+            '/' if rest.starts_with("/*") => rest[2..].find("*/").map_or(rest.len(), |v| v + 4),
+            '\'' | '"' | '`' => {
+                has_code = true;
+                rest[1..].find(c).map_or(rest.len(), |v| v + 2)
+            }
+            '$' => {
+                has_code = true;
+                match dollar_tag(rest) {
+                    Some(tag) => rest[tag.len()..]
+                        .find(tag)
+                        .map_or(rest.len(), |v| v + tag.len() * 2),
+                    None => 1,
+                }
+            }
+            c => {
+                has_code |= !c.is_whitespace();
+                c.len_utf8()
+            }
+        };
+        rest = &rest[skip..];
+    }
+    if has_code {
+        statements.push(sql[start..].trim().to_string());
+    }
+    statements
+}
+
+/// The `$tag$` opening a dollar-quoted string, but not a `$1` parameter
+fn dollar_tag(sql: &str) -> Option<&str> {
+    let end = sql[1..].find(|c: char| !(c.is_alphanumeric() || c == '_'))? + 1;
+    let tag = &sql[..end + 1];
+    let starts_with_digit = tag[1..].starts_with(|c: char| c.is_ascii_digit());
+    (sql[end..].starts_with('$') && !starts_with_digit).then_some(tag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_at_semicolons() {
+        let sql = r#"
+            -- Keep every entry; the raw rows
+            INSERT INTO logs(body, sender)
+            VALUES ($1, $2);
+
+            /* The latest entry
+               of each host; */
+            INSERT INTO hosts(addr, last_entry) VALUES ($2, $1)
+            ON CONFLICT (addr) DO UPDATE SET last_entry = ';' || $1;
+            SELECT $fn$ a; b $fn$, 'it''s; fine';
+            -- Nothing but a comment;
+        "#;
+        assert_eq!(
+            vec![
+                "-- Keep every entry; the raw rows\n            INSERT INTO logs(body, sender)\n            VALUES ($1, $2)",
+                "/* The latest entry\n               of each host; */\n            INSERT INTO hosts(addr, last_entry) VALUES ($2, $1)\n            ON CONFLICT (addr) DO UPDATE SET last_entry = ';' || $1",
+                "SELECT $fn$ a; b $fn$, 'it''s; fine'",
+            ],
+            split_statements(sql)
+        );
+        assert_eq!(vec!["SELECT $$;$$"], split_statements("SELECT $$;$$"));
+        assert!(split_statements("-- nothing\n;\n").is_empty());
+    }
+}
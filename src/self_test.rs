@@ -0,0 +1,131 @@
+//! A marker entry through every stage at startup, so whatever is misconfigured fails before real
+//! traffic comes in
+
+use std::{borrow::Cow, io::Write, net::SocketAddr};
+
+use anyhow::{bail, Context};
+use bytes::Bytes;
+use flate2::{write::GzEncoder, Compression};
+use serde_json::json;
+
+use crate::{
+    compression,
+    gelf::{self, GELFState},
+    identity,
+    pipeline::{Dropped, Entry, Received},
+    service::ServiceBuilder,
+    writer::{BindField, Writer},
+};
+
+/// Set on the marker, to tell it apart, e.g. in a trigger
+pub const MARKER_FIELD: &str = "_sqlx_logger_self_test";
+
+/// What the marker is inserted with, and rolled back
+pub struct SqlTarget {
+    pub writer: Writer,
+    pub sql: Vec<String>,
+    pub binds: Vec<BindField>,
+}
+
+/// The marker as a GELF message
+pub fn marker() -> String {
+    let identity = identity::get();
+    json!({
+        "version": "1.1",
+        "host": identity.hostname,
+        "short_message": format!("sqlx_logger self-test of {}", identity.instance_id),
+        "level": 6,
+        MARKER_FIELD: true,
+    })
+    .to_string()
+}
+
+/// `payload` as the datagrams of a chunked GELF message, `n` of them
+fn chunked(payload: &[u8], n: usize) -> Vec<Bytes> {
+    let parts: Vec<_> = payload.chunks(payload.len().div_ceil(n).max(1)).collect();
+    parts
+        .iter()
+        .enumerate()
+        .map(|(seq, part)| {
+            let mut datagram = vec![0x1e, 0x0f];
+            datagram.extend_from_slice(b"selftest");
+            datagram.extend_from_slice(&[seq as u8, parts.len() as u8]);
+            datagram.extend_from_slice(part);
+            Bytes::from(datagram)
+        })
+        .collect()
+}
+
+/// Decode `message`, compressed and chunked, as if it came over UDP from localhost
+fn decode(message: &str) -> anyhow::Result<Entry> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(message.as_bytes())?;
+    let compressed = encoder.finish()?;
+
+    let sender = SocketAddr::from(([127, 0, 0, 1], 0));
+    let mut state = GELFState::default();
+    let mut payload = None;
+    for datagram in chunked(&compressed, 2) {
+        if let Some(v) = state.on_payload(sender, &datagram)? {
+            payload = Some(v.into_owned());
+        }
+    }
+    let payload = payload.context("Chunks weren't put back together")?;
+    let body = gelf::data_to_str(compression::decompress(
+        Cow::Owned(payload),
+        compression::MAX_DECOMPRESSED_LEN,
+    )?)?;
+
+    Ok(Entry {
+        sender: Some(sender),
+        received: Some(Received::now()),
+        body: body.into_owned(),
+        ..Default::default()
+    })
+}
+
+/// Decode the [`marker`], run it through the transforms of `service`, and insert it with `sql`,
+/// rolling back. Fails with the stage that went wrong.
+pub async fn run(service: &mut ServiceBuilder, sql: Option<SqlTarget>) -> anyhow::Result<()> {
+    let entry = decode(&marker()).context("Self-test: decoding")?;
+
+    let entry = match service.try_transforms(entry) {
+        Ok(v) => v,
+        Err(Dropped::Rejected) => {
+            bail!("Self-test: the filter or transforms rejected the marker entry")
+        }
+        Err(Dropped::LetGo) => {
+            log::info!(
+                "Self-test: the marker entry was let go, e.g. sampled out, not inserting it"
+            );
+            return Ok(());
+        }
+    };
+
+    if let Some(SqlTarget {
+        mut writer,
+        sql,
+        binds,
+    }) = sql
+    {
+        writer
+            .rehearse_batch(&sql, &binds, &[entry])
+            .await
+            .context("Self-test: inserting the marker entry")?;
+    }
+
+    log::info!("Self-test passed: decoded, transformed and inserted the marker entry, rolled back");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_marker() {
+        let entry = decode(&marker()).unwrap();
+        assert_eq!(Some(true), entry.json().unwrap()[MARKER_FIELD].as_bool());
+        assert_eq!(2, chunked(b"abc", 2).len());
+    }
+}
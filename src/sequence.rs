@@ -0,0 +1,232 @@
+//! Telling how many entries each sender lost on the way, by the sequence numbers they number their
+//! entries with, rather than guessing from what arrived
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    json,
+    pipeline::{Entry, Received, Transform},
+};
+
+/// How many entries were missing before this one, added by [`SequenceGaps`]
+pub const SEQUENCE_GAP: &str = "sequence-gap";
+
+/// Gaps found, however long
+pub static GAPS: AtomicU64 = AtomicU64::new(0);
+/// Entries missing in those gaps
+pub static MISSING: AtomicU64 = AtomicU64::new(0);
+/// Entries behind the latest one of their sender, arriving late or again
+pub static LATE: AtomicU64 = AtomicU64::new(0);
+
+/// A sequence number this far behind the latest one is taken as the sender starting over, e.g.
+/// after a restart, rather than as an entry arriving late
+const RESTART_BEHIND: u64 = 1000;
+/// Senders not heard from for this long are forgotten, and start anew when they are
+const FORGET_AFTER: Duration = Duration::from_secs(3600);
+/// At most this many senders are followed, forgetting the least recently heard from half beyond
+/// that, e.g. for spoofed addresses
+const MAX_SENDERS: usize = 10_000;
+
+/// Each sender address has a sequence of its own for every GELF `host` sending from it, as
+/// processes on one host may number their entries each
+type Sender = (IpAddr, String);
+
+#[derive(Debug, Clone, Copy)]
+struct Latest {
+    seq: u64,
+    heard: SystemTime,
+}
+
+/// What an entry's sequence number says, compared with the last one of its sender
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Step {
+    /// The first of the sender's, or the next one
+    Next,
+    /// After this many missing
+    Gap(u64),
+    /// Seen already, or behind the latest one
+    Late,
+    /// Starting over
+    Restart,
+}
+
+/// Follows the sequence numbers in a top-level field of JSON entries from each sender address and
+/// GELF `host`, counting the numbers skipped as missing and logging each gap. Entries after a gap get how many
+/// were missing before them as `sequence-gap`, to bind. Nothing is dropped, and entries without a
+/// sequence number, or without a sender, are left out.
+#[derive(Debug)]
+pub struct SequenceGaps {
+    field: String,
+    /// The highest sequence number of each sender
+    latest: HashMap<Sender, Latest>,
+}
+
+impl SequenceGaps {
+    pub fn new(field: String) -> Self {
+        Self {
+            field,
+            latest: Default::default(),
+        }
+    }
+
+    fn step(&mut self, sender: &Sender, seq: u64, now: SystemTime) -> Step {
+        let forgotten = |latest: &Latest| {
+            now.duration_since(latest.heard)
+                .is_ok_and(|v| v > FORGET_AFTER)
+        };
+        let latest = match self.latest.get_mut(sender) {
+            Some(latest) if !forgotten(latest) => latest,
+            _ => {
+                if self.latest.len() >= MAX_SENDERS {
+                    self.forget(now);
+                }
+                self.latest
+                    .insert(sender.clone(), Latest { seq, heard: now });
+                return Step::Next;
+            }
+        };
+        latest.heard = now;
+        let step = match seq.checked_sub(latest.seq) {
+            Some(1) => Step::Next,
+            Some(0) => return Step::Late,
+            Some(ahead) => Step::Gap(ahead - 1),
+            None if latest.seq - seq > RESTART_BEHIND => Step::Restart,
+            None => return Step::Late,
+        };
+        latest.seq = seq;
+        step
+    }
+
+    /// Make room for another sender, forgetting those not heard from for a while, or else the
+    /// least recently heard from half
+    fn forget(&mut self, now: SystemTime) {
+        self.latest.retain(|_, v| {
+            now.duration_since(v.heard)
+                .map_or(true, |v| v <= FORGET_AFTER)
+        });
+        if self.latest.len() < MAX_SENDERS {
+            return;
+        }
+        let mut heard: Vec<_> = self.latest.values().map(|v| v.heard).collect();
+        let (_, median, _) = heard.select_nth_unstable(MAX_SENDERS / 2);
+        let median = *median;
+        self.latest.retain(|_, v| v.heard > median);
+        log::warn!("Following sequences from over {MAX_SENDERS} senders, forgot half of them");
+    }
+}
+
+impl Transform for SequenceGaps {
+    fn apply(&mut self, mut entry: Entry) -> Option<Entry> {
+        let (Some(addr), Some(seq)) = (
+            entry.sender,
+            json::get_u64(&entry, &self.field)
+                .or_else(|| json::get_string(&entry, &self.field)?.trim().parse().ok()),
+        ) else {
+            return Some(entry);
+        };
+
+        let sender = (
+            addr.ip(),
+            json::get_string(&entry, "host").unwrap_or_default(),
+        );
+        let now = entry
+            .received
+            .as_ref()
+            .map_or_else(Received::clock, |v| v.at);
+        match self.step(&sender, seq, now) {
+            Step::Next => {}
+            Step::Gap(missing) => {
+                GAPS.fetch_add(1, Ordering::Relaxed);
+                MISSING.fetch_add(missing, Ordering::Relaxed);
+                log::warn!(
+                    "{missing} entries missing from {} at {} before {} {seq}",
+                    sender.1,
+                    sender.0,
+                    self.field
+                );
+                entry
+                    .fields
+                    .insert(SEQUENCE_GAP.to_string(), missing.to_string());
+            }
+            Step::Late => {
+                LATE.fetch_add(1, Ordering::Relaxed);
+            }
+            Step::Restart => {
+                log::info!(
+                    "{} at {} started its {} over at {seq}",
+                    sender.1,
+                    sender.0,
+                    self.field
+                );
+            }
+        }
+        Some(entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_gaps() {
+        let mut gaps = SequenceGaps::new("seq".to_string());
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let a = (ip, "web".to_string());
+        let b = ("10.0.0.2".parse().unwrap(), "web".to_string());
+        let now = Received::clock();
+
+        assert_eq!(Step::Next, gaps.step(&a, 1, now));
+        assert_eq!(Step::Next, gaps.step(&a, 2, now));
+        assert_eq!(Step::Next, gaps.step(&b, 5000, now));
+        assert_eq!(Step::Gap(2), gaps.step(&a, 5, now));
+        assert_eq!(Step::Late, gaps.step(&a, 4, now));
+        assert_eq!(Step::Late, gaps.step(&a, 5, now));
+        assert_eq!(Step::Next, gaps.step(&a, 6, now));
+        assert_eq!(Step::Restart, gaps.step(&b, 0, now));
+        assert_eq!(Step::Next, gaps.step(&b, 1, now));
+
+        // Another process on the same host, numbering its own
+        let worker = (ip, "worker".to_string());
+        assert_eq!(Step::Next, gaps.step(&worker, 100, now));
+        assert_eq!(Step::Next, gaps.step(&a, 7, now));
+
+        let entry = |body: &str| Entry {
+            sender: Some("10.0.0.1:12201".parse().unwrap()),
+            body: body.to_string(),
+            ..Default::default()
+        };
+        let gapped = gaps
+            .apply(entry(r#"{"host": "web", "seq": "10"}"#))
+            .unwrap();
+        assert_eq!(
+            Some("2"),
+            gapped.fields.get(SEQUENCE_GAP).map(String::as_str)
+        );
+        let next = gaps.apply(entry(r#"{"host": "web", "seq": 11}"#)).unwrap();
+        assert!(next.fields.is_empty());
+    }
+
+    #[test]
+    fn forgets_senders() {
+        let mut gaps = SequenceGaps::new("seq".to_string());
+        let sender = |i: usize| ("10.0.0.1".parse().unwrap(), format!("host-{i}"));
+        let start = Received::clock();
+
+        assert_eq!(Step::Next, gaps.step(&sender(0), 1, start));
+        let later = start + FORGET_AFTER + Duration::from_secs(1);
+        assert_eq!(Step::Next, gaps.step(&sender(0), 10, later));
+
+        for i in 0..MAX_SENDERS * 2 {
+            gaps.step(&sender(i), 1, later + Duration::from_millis(i as u64));
+        }
+        assert!(gaps.latest.len() <= MAX_SENDERS);
+        assert!(gaps.latest.contains_key(&sender(MAX_SENDERS * 2 - 1)));
+        assert!(!gaps.latest.contains_key(&sender(1)));
+    }
+}
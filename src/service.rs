@@ -0,0 +1,242 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::bail;
+use async_shutdown::Shutdown;
+use tokio::sync::watch;
+
+use crate::{
+    batch::AdaptiveBatchSize,
+    health::Health,
+    memory::{MemoryBudget, MemoryLimit},
+    overload::Overload,
+    pipeline::{self, Committed, Dropped, Entry, Pipeline, Sink, Source, Transform},
+    quarantine::Quarantine,
+    source_stats::SourceStats,
+};
+
+/// The whole daemon, for embedding into another application:
+///
+/// ```no_run
+/// # async fn example(sink: impl sqlx_logger::pipeline::Sink) -> anyhow::Result<()> {
+/// use sqlx_logger::{GelfSource, Service};
+///
+/// Service::builder()
+///     .source(GelfSource::bind("0.0.0.0:12201".parse()?).await?)
+///     .filter(|entry| entry.body.contains("\"level\""))
+///     .sink(sink)
+///     .run(async_shutdown::Shutdown::new())
+///     .await
+/// # }
+/// ```
+pub struct Service;
+
+impl Service {
+    pub fn builder() -> ServiceBuilder {
+        ServiceBuilder {
+            sources: Default::default(),
+            transforms: Default::default(),
+            sink: None,
+            batch_size: AdaptiveBatchSize::new(10, 10, Duration::from_secs(1)),
+            queue_size: 1024,
+            overload: Overload::Block,
+            max_in_flight: 1,
+            max_outstanding_commits: 0,
+            memory_budget: None,
+            memory_limit: None,
+            quarantine: Default::default(),
+            source_stats: Default::default(),
+            health: Default::default(),
+            committed: None,
+        }
+    }
+}
+
+pub struct ServiceBuilder {
+    sources: Vec<Box<dyn Source>>,
+    transforms: Vec<Box<dyn Transform>>,
+    sink: Option<Box<dyn Sink>>,
+    batch_size: AdaptiveBatchSize,
+    queue_size: usize,
+    overload: Overload,
+    max_in_flight: usize,
+    max_outstanding_commits: usize,
+    memory_budget: Option<Arc<MemoryBudget>>,
+    memory_limit: Option<Arc<MemoryLimit>>,
+    quarantine: Quarantine,
+    source_stats: SourceStats,
+    health: Health,
+    committed: Option<watch::Sender<Committed>>,
+}
+
+/// Keeps the entries a predicate accepts
+struct Filter<F>(F);
+
+impl<F: FnMut(&Entry) -> bool + Send> Transform for Filter<F> {
+    fn apply(&mut self, entry: Entry) -> Option<Entry> {
+        (self.0)(&entry).then_some(entry)
+    }
+}
+
+impl ServiceBuilder {
+    pub fn source(mut self, source: impl Source) -> Self {
+        self.sources.push(Box::new(source));
+        self
+    }
+
+    /// Transforms run in the order they are added
+    pub fn transform(mut self, transform: impl Transform + 'static) -> Self {
+        self.transforms.push(Box::new(transform));
+        self
+    }
+
+    /// Run `entry` through the transforms added so far, as the pipeline will, e.g. to try them out
+    /// before starting
+    pub fn try_transforms(&mut self, entry: Entry) -> Result<Entry, Dropped> {
+        pipeline::transform(&mut self.transforms, entry)
+    }
+
+    /// Run `entry` through the transforms, with what each one made of it, see
+    /// [`pipeline::transform_steps`]
+    pub fn transform_steps(&mut self, entry: Entry) -> Vec<(&'static str, Result<Entry, Dropped>)> {
+        pipeline::transform_steps(&mut self.transforms, entry)
+    }
+
+    /// Only keep the entries `predicate` returns true for
+    pub fn filter(self, predicate: impl FnMut(&Entry) -> bool + Send + 'static) -> Self {
+        self.transform(Filter(predicate))
+    }
+
+    pub fn sink(mut self, sink: impl Sink) -> Self {
+        self.sink = Some(Box::new(sink));
+        self
+    }
+
+    /// 10 entries per batch by default
+    pub fn batch_size(mut self, batch_size: AdaptiveBatchSize) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// 1024 entries by default
+    pub fn queue_size(mut self, queue_size: usize) -> Self {
+        self.queue_size = queue_size;
+        self
+    }
+
+    /// What to do with entries received while the queue is full, pushing back on the sources by
+    /// default
+    pub fn overload(mut self, overload: Overload) -> Self {
+        self.overload = overload;
+        self
+    }
+
+    /// How many full batches may wait while one is being written, 1 by default
+    pub fn max_in_flight(mut self, max_in_flight: usize) -> Self {
+        self.max_in_flight = max_in_flight;
+        self
+    }
+
+    /// How many written batches may still be committing while the next one is written, 0 by
+    /// default. Only makes a difference with a sink that leaves committing for later, such as
+    /// [`OverlappingSqlSink`](crate::writer::OverlappingSqlSink).
+    pub fn max_outstanding_commits(mut self, max_outstanding_commits: usize) -> Self {
+        self.max_outstanding_commits = max_outstanding_commits;
+        self
+    }
+
+    /// Tell the latest committed position of each durable source, so sources holding the
+    /// receiving end can acknowledge upstream, e.g. commit Kafka offsets, only once their entries
+    /// are in the database
+    pub fn committed(mut self, committed: watch::Sender<Committed>) -> Self {
+        self.committed = Some(committed);
+        self
+    }
+
+    /// Drop the least severe pending entries beyond the budget. Share the same budget with the
+    /// [`GelfSource`](crate::GelfSource), so it makes room by giving up on incomplete messages
+    /// first.
+    pub fn memory_budget(mut self, budget: Arc<MemoryBudget>) -> Self {
+        self.memory_budget = Some(budget);
+        self
+    }
+
+    /// Drop new entries while over `limit`. Share the same limit with the
+    /// [`GelfSource`](crate::GelfSource), so it gives up on incomplete messages too.
+    pub fn memory_limit(mut self, limit: Arc<MemoryLimit>) -> Self {
+        self.memory_limit = Some(limit);
+        self
+    }
+
+    /// Keep the entries the transforms drop in `quarantine`. Hand the same one to the sources, so
+    /// they keep what they can't decode there too.
+    pub fn quarantine(mut self, quarantine: Quarantine) -> Self {
+        self.quarantine = quarantine;
+        self
+    }
+
+    /// Count what each sender sent, and how much of it was dropped, into `stats`
+    pub fn source_stats(mut self, stats: SourceStats) -> Self {
+        self.source_stats = stats;
+        self
+    }
+
+    /// Count the entries dropped into `health`. Hand the same one to the sources and writers, and
+    /// to whatever watches the rates.
+    pub fn health(mut self, health: Health) -> Self {
+        self.health = health;
+        self
+    }
+
+    /// Run until `shutdown` is triggered, then write out whatever is still pending
+    pub async fn run(self, shutdown: Shutdown) -> anyhow::Result<()> {
+        self.build()?.run(shutdown).await
+    }
+
+    pub fn build(self) -> anyhow::Result<Pipeline> {
+        let Self {
+            sources,
+            transforms,
+            sink,
+            batch_size,
+            queue_size,
+            overload,
+            max_in_flight,
+            max_outstanding_commits,
+            memory_budget,
+            memory_limit,
+            quarantine,
+            source_stats,
+            health,
+            committed,
+        } = self;
+
+        let Some(sink) = sink else {
+            bail!("The service needs a sink");
+        };
+
+        if sources.is_empty() {
+            bail!("The service needs at least one source");
+        }
+
+        if queue_size == 0 {
+            bail!("The queue needs room for at least one entry");
+        }
+
+        Ok(Pipeline {
+            sources,
+            transforms,
+            sink,
+            batch_size,
+            queue_size,
+            overload,
+            max_in_flight,
+            max_outstanding_commits,
+            memory_budget,
+            memory_limit,
+            quarantine,
+            source_stats,
+            health,
+            committed,
+        })
+    }
+}
@@ -0,0 +1,107 @@
+use crate::{
+    json,
+    pipeline::{Entry, Transform},
+};
+
+/// The syslog keyword of the level, e.g. `warning`
+pub const SEVERITY: &str = "severity";
+/// The numeric syslog level, 0 to 7
+pub const SEVERITY_LEVEL: &str = "severity-level";
+
+/// Keywords as in RFC 5424, by level
+const KEYWORDS: [&str; 8] = [
+    "emergency",
+    "alert",
+    "critical",
+    "error",
+    "warning",
+    "notice",
+    "informational",
+    "debug",
+];
+
+/// The level of a keyword, accepting the usual spellings of other logging libraries
+pub fn parse_level(keyword: &str) -> Option<u64> {
+    let level = match keyword.to_ascii_lowercase().as_str() {
+        "emerg" | "emergency" | "panic" => 0,
+        "alert" => 1,
+        "crit" | "critical" | "fatal" => 2,
+        "err" | "error" => 3,
+        "warn" | "warning" => 4,
+        "notice" => 5,
+        "info" | "informational" | "information" => 6,
+        "debug" | "trace" => 7,
+        other => return other.parse().ok().filter(|v| *v < 8),
+    };
+    Some(level)
+}
+
+/// Adds both the keyword and the number of the level in a top-level field of JSON entries, which
+/// may have either
+#[derive(Debug, Clone)]
+pub struct Severity {
+    pub field: String,
+}
+
+impl Severity {
+    fn level(&self, entry: &Entry) -> Option<u64> {
+        match json::get_u64(entry, &self.field) {
+            Some(v) => Some(v).filter(|v| *v < 8),
+            None => parse_level(&json::get_string(entry, &self.field)?),
+        }
+    }
+}
+
+impl Transform for Severity {
+    fn apply(&mut self, mut entry: Entry) -> Option<Entry> {
+        if let Some(level) = self.level(&entry) {
+            entry
+                .fields
+                .insert(SEVERITY.to_string(), KEYWORDS[level as usize].to_string());
+            entry
+                .fields
+                .insert(SEVERITY_LEVEL.to_string(), level.to_string());
+        }
+        Some(entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn both_ways() {
+        let mut severity = Severity {
+            field: "level".to_string(),
+        };
+        let mut apply = |body: &str| {
+            let entry = severity
+                .apply(Entry {
+                    body: body.to_string(),
+                    ..Default::default()
+                })
+                .unwrap();
+            (
+                entry.fields.get(SEVERITY).cloned(),
+                entry.fields.get(SEVERITY_LEVEL).cloned(),
+            )
+        };
+
+        assert_eq!(
+            (Some("warning".into()), Some("4".into())),
+            apply(r#"{"level":4}"#)
+        );
+        assert_eq!(
+            (Some("error".into()), Some("3".into())),
+            apply(r#"{"level":"ERROR"}"#)
+        );
+        assert_eq!(
+            (Some("debug".into()), Some("7".into())),
+            apply(r#"{"level":"7"}"#)
+        );
+        assert_eq!((None, None), apply(r#"{"level":8}"#));
+        assert_eq!((None, None), apply(r#"{"level":"verbose"}"#));
+        assert_eq!((None, None), apply(r#"{"short_message":"hello"}"#));
+    }
+}
@@ -0,0 +1,216 @@
+//! Spreading entries over more than one database by the hash of a field, for more logs than one
+//! database can take
+
+use anyhow::{bail, Context};
+use async_trait::async_trait;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    json,
+    pipeline::{Entry, PendingCommit, Sink},
+};
+
+/// Which shard each entry goes to, out of `count`, by the hash of its `key`: `sender` for the
+/// sender's address, one of the [`Entry::fields`] added along the way, e.g. `tenant`, or else a
+/// top-level field of JSON entries, e.g. `host`. Entries without one are spread by the order they
+/// were received in.
+#[derive(Debug, Clone)]
+pub struct Shards {
+    key: String,
+    count: usize,
+}
+
+impl Shards {
+    pub fn new(key: String, count: usize) -> anyhow::Result<Self> {
+        if count == 0 {
+            bail!("Sharding needs at least one database");
+        }
+        Ok(Self { key, count })
+    }
+
+    fn key(&self, entry: &Entry) -> Option<String> {
+        if self.key == "sender" {
+            return entry.sender.map(|v| v.ip().to_string());
+        }
+        entry
+            .fields
+            .get(&self.key)
+            .cloned()
+            .or_else(|| json::get_string(entry, &self.key))
+    }
+
+    /// The shard of `entry`, counting from 0
+    pub fn of(&self, entry: &Entry) -> usize {
+        let key = self
+            .key(entry)
+            .unwrap_or_else(|| entry.received.map_or(0, |v| v.seq).to_string());
+        let digest = Sha256::digest(key.as_bytes());
+        let hash = u64::from_be_bytes(digest[..8].try_into().unwrap());
+        (hash % self.count as u64) as usize
+    }
+}
+
+/// Writes the entries of each shard to the sink of its own, all at once, as part of the same
+/// batch, which fails if any of them does. Each sink has to deal with its own failures, e.g. with
+/// a [`BreakerSink`](crate::breaker::BreakerSink) diverting to dead letters of its own, so one
+/// shard being down doesn't hold up the others.
+pub struct ShardSink {
+    shards: Shards,
+    sinks: Vec<Box<dyn Sink>>,
+}
+
+impl ShardSink {
+    pub fn new(key: String, sinks: Vec<Box<dyn Sink>>) -> anyhow::Result<Self> {
+        Ok(Self {
+            shards: Shards::new(key, sinks.len())?,
+            sinks,
+        })
+    }
+}
+
+#[async_trait]
+impl Sink for ShardSink {
+    async fn write(&mut self, entries: &[Entry]) -> anyhow::Result<()> {
+        self.write_pending(entries).await?.await
+    }
+
+    async fn write_pending(&mut self, entries: &[Entry]) -> anyhow::Result<PendingCommit> {
+        let mut parts = vec![Vec::new(); self.sinks.len()];
+        for entry in entries {
+            parts[self.shards.of(entry)].push(entry.clone());
+        }
+
+        let writes = self
+            .sinks
+            .iter_mut()
+            .zip(&parts)
+            .enumerate()
+            .filter(|(_, (_, part))| !part.is_empty())
+            .map(|(shard, (sink, part))| async move {
+                let commit = sink
+                    .write_pending(part)
+                    .await
+                    .with_context(|| format!("Writing to shard {shard}"))?;
+                anyhow::Ok(async move {
+                    commit
+                        .await
+                        .with_context(|| format!("Committing to shard {shard}"))
+                })
+            });
+        let pending = futures::future::try_join_all(writes).await?;
+        Ok(Box::pin(async move {
+            futures::future::try_join_all(pending).await?;
+            Ok(())
+        }))
+    }
+
+    async fn close(&mut self) -> anyhow::Result<()> {
+        for sink in &mut self.sinks {
+            sink.close().await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::breaker::{BreakerSink, CircuitBreaker};
+
+    #[derive(Clone, Default)]
+    struct Written(Arc<Mutex<Vec<String>>>);
+
+    #[async_trait]
+    impl Sink for Written {
+        async fn write(&mut self, entries: &[Entry]) -> anyhow::Result<()> {
+            let mut written = self.0.lock().unwrap();
+            written.extend(entries.iter().map(|v| v.body.clone()));
+            Ok(())
+        }
+    }
+
+    fn entry(body: &str) -> Entry {
+        Entry {
+            body: body.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn splits_by_key() {
+        let shards: Vec<_> = (0..3).map(|_| Written::default()).collect();
+        let mut sink = ShardSink::new(
+            "host".to_string(),
+            shards.iter().map(|v| Box::new(v.clone()) as _).collect(),
+        )
+        .unwrap();
+
+        let entries: Vec<_> = (0..30)
+            .map(|i| Entry {
+                body: format!(r#"{{"host":"web-{}","n":{i}}}"#, i % 6),
+                ..Default::default()
+            })
+            .collect();
+        sink.write(&entries).await.unwrap();
+
+        let mut written = 0;
+        for (i, shard) in shards.iter().enumerate() {
+            let bodies = shard.0.lock().unwrap();
+            written += bodies.len();
+            // So every entry of the same host is in the same shard
+            for body in bodies.iter() {
+                assert_eq!(i, sink.shards.of(&entry(body)), "{body}");
+            }
+        }
+        assert_eq!(30, written);
+        assert!(Shards::new("host".to_string(), 0).is_err());
+    }
+
+    struct Down;
+
+    #[async_trait]
+    impl Sink for Down {
+        async fn write(&mut self, _: &[Entry]) -> anyhow::Result<()> {
+            anyhow::bail!("Down")
+        }
+    }
+
+    #[tokio::test]
+    async fn one_shard_down_holds_up_no_other() {
+        let (up, dead_letters) = (Written::default(), Written::default());
+        let down = BreakerSink {
+            inner: Box::new(Down),
+            breaker: CircuitBreaker::new(2, Duration::from_secs(60), Duration::from_secs(60)),
+            divert: Some(Box::new(dead_letters.clone())),
+        };
+        let mut sink = ShardSink::new(
+            "host".to_string(),
+            vec![Box::new(up.clone()), Box::new(down)],
+        )
+        .unwrap();
+
+        let entries: Vec<_> = (0..20)
+            .map(|i| Entry {
+                body: format!(r#"{{"host":"web-{i}"}}"#),
+                ..Default::default()
+            })
+            .collect();
+        for batch in entries.chunks(5) {
+            tokio::time::timeout(Duration::from_secs(1), sink.write(batch))
+                .await
+                .expect("Held up by the shard that's down")
+                .unwrap();
+        }
+
+        let up = up.0.lock().unwrap();
+        let dead_letters = dead_letters.0.lock().unwrap();
+        assert!(!up.is_empty() && !dead_letters.is_empty());
+        assert_eq!(20, up.len() + dead_letters.len());
+        assert!(up.iter().all(|v| sink.shards.of(&entry(v)) == 0));
+    }
+}
@@ -0,0 +1,365 @@
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context as _;
+use async_shutdown::Shutdown;
+use async_trait::async_trait;
+use bytes::BytesMut;
+use futures::{Stream, StreamExt};
+use tokio::{
+    net::UdpSocket,
+    sync::mpsc,
+    time::{interval, Interval, MissedTickBehavior},
+};
+
+use crate::{
+    auth::DatagramAuth,
+    ban::BanList,
+    compression,
+    gelf::{data_to_str, ExpiredMessage, GELFState, GelfError, ReceiveBlock},
+    health::{Event, Health},
+    memory::{MemoryBudget, MemoryLimit},
+    pipeline::{Entry, Received, Source},
+    quarantine::Quarantine,
+};
+
+type ExpiredHandler = Box<dyn FnMut(Instant, &[ExpiredMessage]) + Send>;
+
+/// Large enough for any UDP datagram
+const MAX_DATAGRAM_SIZE: usize = 65536;
+
+/// Packets are received back to back into blocks of this size. A block is reused once every
+/// packet in it has been dropped, while chunks waiting for reassembly keep theirs alive, and
+/// charge the memory budget for all of it.
+const RECV_BLOCK_SIZE: usize = MAX_DATAGRAM_SIZE * 2;
+
+const DEFAULT_CLEAN_UP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A complete GELF message, reassembled and decompressed
+#[derive(Debug, Clone)]
+pub struct DecodedMessage {
+    pub sender: SocketAddr,
+    pub payload: String,
+}
+
+/// GELF messages received on a UDP socket.
+///
+/// Chunked messages are reassembled, and the ones that never complete are given up on every
+/// `clean_up_interval`. Receiving errors and undecodable packets come out as `Err` items without
+/// ending the stream.
+pub struct GelfSource {
+    socket: UdpSocket,
+    state: GELFState,
+    clean_up: Interval,
+    buf: BytesMut,
+    /// The block `buf` is in
+    block: ReceiveBlock,
+    num_expired: usize,
+    on_expired: Option<ExpiredHandler>,
+    memory_budget: Option<Arc<MemoryBudget>>,
+    memory_limit: Option<Arc<MemoryLimit>>,
+    auth: Option<DatagramAuth>,
+    num_unauthenticated: u64,
+    bans: Option<BanList>,
+    quarantine: Quarantine,
+    health: Health,
+    max_decompressed_len: usize,
+    acks: bool,
+}
+
+impl GelfSource {
+    pub fn new(socket: UdpSocket, state: GELFState, clean_up_interval: Duration) -> Self {
+        let mut clean_up = interval(clean_up_interval);
+        clean_up.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let buf = BytesMut::with_capacity(RECV_BLOCK_SIZE);
+
+        Self {
+            socket,
+            state,
+            clean_up,
+            block: ReceiveBlock::of(&buf),
+            buf,
+            num_expired: 0,
+            on_expired: None,
+            memory_budget: None,
+            memory_limit: None,
+            auth: None,
+            num_unauthenticated: 0,
+            bans: None,
+            quarantine: Default::default(),
+            health: Default::default(),
+            max_decompressed_len: compression::MAX_DECOMPRESSED_LEN,
+            acks: false,
+        }
+    }
+
+    /// Reply to every datagram but the banned ones with `OK` once it completes a message,
+    /// `CHUNK` when more chunks are to come, or `ERR` and why it couldn't be decoded. For
+    /// telling whether datagrams get through at all: anyone can make us send replies to anywhere.
+    pub fn with_acks(mut self) -> Self {
+        self.acks = true;
+        self
+    }
+
+    fn ack(&self, sender: SocketAddr, reply: &str) {
+        if self.acks {
+            if let Err(e) = self
+                .socket
+                .try_send_to(format!("{reply}\n").as_bytes(), sender)
+            {
+                log::debug!("Error acknowledging datagram from {sender}: {e}");
+            }
+        }
+    }
+
+    /// Only accept datagrams signed with `auth`, dropping the rest
+    pub fn with_auth(mut self, auth: DatagramAuth) -> Self {
+        self.auth = Some(auth);
+        self
+    }
+
+    /// Ignore senders for a while once they go over the thresholds of `bans`
+    pub fn with_bans(mut self, bans: BanList) -> Self {
+        self.bans = Some(bans);
+        self
+    }
+
+    /// Keep what can't be decoded in `quarantine`
+    pub fn with_quarantine(mut self, quarantine: Quarantine) -> Self {
+        self.quarantine = quarantine;
+        self
+    }
+
+    /// Count what can't be decoded into `health`
+    pub fn with_health(mut self, health: Health) -> Self {
+        self.health = health;
+        self
+    }
+
+    /// Refuse payloads decompressing to more than `max_length` bytes, instead of
+    /// [`compression::MAX_DECOMPRESSED_LEN`]
+    pub fn with_max_decompressed_len(mut self, max_length: usize) -> Self {
+        self.max_decompressed_len = max_length;
+        self
+    }
+
+    /// Give up on the oldest incomplete messages when they don't fit into `budget`
+    pub fn with_memory_budget(mut self, budget: Arc<MemoryBudget>) -> Self {
+        self.memory_budget = Some(budget);
+        self
+    }
+
+    /// Give up on every incomplete message while over `limit`
+    pub fn with_memory_limit(mut self, limit: Arc<MemoryLimit>) -> Self {
+        self.memory_limit = Some(limit);
+        self
+    }
+
+    /// Also hand the incomplete messages given up on to `handler`, e.g. to keep them somewhere
+    pub fn on_expired(
+        mut self,
+        handler: impl FnMut(Instant, &[ExpiredMessage]) + Send + 'static,
+    ) -> Self {
+        self.on_expired = Some(Box::new(handler));
+        self
+    }
+
+    /// Listen on `addr` with the default reassembly settings
+    pub async fn bind(addr: SocketAddr) -> anyhow::Result<Self> {
+        let socket = UdpSocket::bind(addr)
+            .await
+            .with_context(|| format!("Listening on udp://{addr}"))?;
+        Ok(Self::new(
+            socket,
+            Default::default(),
+            DEFAULT_CLEAN_UP_INTERVAL,
+        ))
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// The reassembly state, e.g. to look at how much is buffered
+    pub fn state(&self) -> &GELFState {
+        &self.state
+    }
+
+    /// The reassembly state, e.g. to give up on messages early when short of memory
+    pub fn state_mut(&mut self) -> &mut GELFState {
+        &mut self.state
+    }
+
+    /// How many incomplete messages have been given up on so far
+    pub fn num_expired(&self) -> usize {
+        self.num_expired
+    }
+
+    /// How many datagrams have been dropped for failing verification so far
+    pub fn num_unauthenticated(&self) -> u64 {
+        self.num_unauthenticated
+    }
+
+    pub fn bans(&self) -> Option<&BanList> {
+        self.bans.as_ref()
+    }
+
+    fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<SocketAddr>> {
+        loop {
+            ready!(self.socket.poll_recv_ready(cx))?;
+            match self.socket.try_recv_buf_from(&mut self.buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                rs => return Poll::Ready(rs.map(|(_, sender)| sender)),
+            }
+        }
+    }
+
+    fn decode(&mut self, sender: SocketAddr) -> anyhow::Result<Option<DecodedMessage>> {
+        let mut packet = self.buf.split().freeze();
+        if let Some(bans) = &mut self.bans {
+            if !bans.on_datagram(sender.ip(), Instant::now()) {
+                return Ok(None);
+            }
+        }
+
+        if let Some(auth) = &self.auth {
+            match auth.verify(&packet) {
+                Some(payload) => packet.truncate(payload.len()),
+                None => {
+                    self.num_unauthenticated += 1;
+                    self.ack(sender, "ERR Unauthenticated");
+                    return Err(GelfError::Unauthenticated {
+                        num_failed: self.num_unauthenticated,
+                    })
+                    .with_context(|| format!("Handling incoming data from {sender}"));
+                }
+            }
+        }
+
+        let rs = self.state.on_payload_in(sender, &packet, self.block);
+        if let Some(budget) = &self.memory_budget {
+            budget.shed_partials(&mut self.state);
+        }
+        if let Some(limit) = &self.memory_limit {
+            limit.shed(&mut self.state);
+        }
+
+        let payload = match rs {
+            Ok(Some(v)) => v,
+            Ok(None) => {
+                self.ack(sender, "CHUNK");
+                return Ok(None);
+            }
+            Err(e) => {
+                self.ack(sender, &format!("ERR {e}"));
+                self.quarantine.reject(&e, Some(sender), &packet);
+                return Err(e).with_context(|| format!("Handling incoming data from {sender}"));
+            }
+        };
+
+        let raw = self.quarantine.is_enabled().then(|| payload.to_vec());
+        let payload = compression::decompress(payload, self.max_decompressed_len)
+            .and_then(data_to_str)
+            .inspect_err(|e| {
+                self.ack(sender, &format!("ERR {e}"));
+                self.quarantine
+                    .reject(e, Some(sender), raw.as_deref().unwrap_or_default())
+            })
+            .with_context(|| format!("Decoding message from {sender}"))?;
+        self.ack(sender, "OK");
+
+        Ok(Some(DecodedMessage {
+            sender,
+            payload: payload.into_owned(),
+        }))
+    }
+}
+
+impl Stream for GelfSource {
+    type Item = anyhow::Result<DecodedMessage>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        while this.clean_up.poll_tick(cx).is_ready() {
+            let now = Instant::now();
+            if let Some(bans) = &mut this.bans {
+                bans.clean_up(now);
+            }
+
+            let expired = this.state.clean_up(now);
+            if !expired.is_empty() {
+                this.num_expired += expired.len();
+                log::warn!(
+                    "Gave up on {} incomplete messages ({} so far)",
+                    expired.len(),
+                    this.num_expired
+                );
+
+                if let Some(handler) = &mut this.on_expired {
+                    handler(now, &expired);
+                }
+            }
+        }
+
+        loop {
+            this.buf.reserve(MAX_DATAGRAM_SIZE);
+            if !this.block.holds(&this.buf) {
+                this.block = ReceiveBlock::of(&this.buf);
+            }
+            let sender = match ready!(this.poll_recv(cx)) {
+                Ok(v) => v,
+                Err(e) => return Poll::Ready(Some(Err(e).context("Receiving packet"))),
+            };
+
+            match this.decode(sender) {
+                Ok(None) => continue,
+                Err(e) => {
+                    if let Some(bans) = &mut this.bans {
+                        bans.on_error(sender.ip(), Instant::now());
+                    }
+                    return Poll::Ready(Some(Err(e)));
+                }
+                rs => return Poll::Ready(rs.transpose()),
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Source for GelfSource {
+    async fn run(
+        mut self: Box<Self>,
+        entries: mpsc::Sender<Entry>,
+        shutdown: Shutdown,
+    ) -> anyhow::Result<()> {
+        while let Some(Some(rs)) = shutdown.wrap_cancel(self.next()).await {
+            let message = match rs {
+                Ok(v) => v,
+                Err(e) => {
+                    log::error!("{e:#}");
+                    self.health.record(Event::DecodeError);
+                    continue;
+                }
+            };
+
+            let entry = Entry {
+                sender: Some(message.sender),
+                received: Some(Received::now()),
+                body: message.payload,
+                ..Default::default()
+            };
+            if entries.send(entry).await.is_err() {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,239 @@
+//! How much each sender has sent, to tell which hosts are actually shipping
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use tokio::time::{interval, MissedTickBehavior};
+
+use crate::{
+    pipeline::Entry,
+    writer::{Param, Writer},
+};
+
+/// What one sender sent within an interval
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Counts {
+    first_seen: SystemTime,
+    last_seen: SystemTime,
+    messages: u64,
+    bytes: u64,
+    dropped: u64,
+}
+
+impl Counts {
+    fn new(at: SystemTime) -> Self {
+        Self {
+            first_seen: at,
+            last_seen: at,
+            messages: 0,
+            bytes: 0,
+            dropped: 0,
+        }
+    }
+
+    fn merge(&mut self, other: &Self) {
+        self.first_seen = self.first_seen.min(other.first_seen);
+        self.last_seen = self.last_seen.max(other.last_seen);
+        self.messages += other.messages;
+        self.bytes += other.bytes;
+        self.dropped += other.dropped;
+    }
+
+    fn row(&self, sender: IpAddr) -> Vec<Param> {
+        let rfc3339 = |at| Some(humantime::format_rfc3339_micros(at).to_string());
+        vec![
+            Param::Text(Some(sender.to_string())),
+            Param::Text(rfc3339(self.first_seen)),
+            Param::Text(rfc3339(self.last_seen)),
+            Param::Int(Some(self.messages as i64)),
+            Param::Int(Some(self.bytes as i64)),
+            Param::Int(Some(self.dropped as i64)),
+        ]
+    }
+}
+
+/// The counts of each sender, or none when not counting. Clones count into the same counts, so
+/// hand the same one to the service and to what shows or writes them.
+#[derive(Debug, Clone, Default)]
+pub struct SourceStats {
+    /// Keyed by address without the port, which changes with every TCP connection
+    counts: Option<Arc<Mutex<HashMap<IpAddr, Counts>>>>,
+}
+
+impl SourceStats {
+    /// Counting, unlike the default
+    pub fn counting() -> Self {
+        Self {
+            counts: Some(Default::default()),
+        }
+    }
+
+    fn update(&self, sender: Option<SocketAddr>, at: SystemTime, f: impl FnOnce(&mut Counts)) {
+        let (Some(counts), Some(sender)) = (&self.counts, sender) else {
+            return;
+        };
+        let mut counts = counts.lock().unwrap();
+        let counts = counts.entry(sender.ip()).or_insert_with(|| Counts::new(at));
+        counts.last_seen = counts.last_seen.max(at);
+        f(counts);
+    }
+
+    /// Count an entry as received from its sender, if counting
+    pub fn received(&self, entry: &Entry) {
+        let at = entry.received.map_or_else(SystemTime::now, |v| v.at);
+        self.update(entry.sender, at, |counts| {
+            counts.messages += 1;
+            counts.bytes += entry.body.len() as u64;
+        });
+    }
+
+    /// Count an entry of `sender` as rejected, if counting
+    pub fn dropped(&self, sender: Option<SocketAddr>) {
+        self.update(sender, SystemTime::now(), |counts| counts.dropped += 1);
+    }
+
+    /// The `n` senders that sent the most messages, with how many they sent and how many of them
+    /// were dropped, since the counts were last written
+    pub fn top(&self, n: usize) -> Vec<(IpAddr, u64, u64)> {
+        let Some(counts) = &self.counts else {
+            return vec![];
+        };
+        let mut top: Vec<_> = counts
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(k, v)| (*k, v.messages, v.dropped))
+            .collect();
+        top.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        top.truncate(n);
+        top
+    }
+
+    fn take(&self) -> Vec<(IpAddr, Counts)> {
+        let Some(counts) = &self.counts else {
+            return vec![];
+        };
+        let mut taken: Vec<_> = counts.lock().unwrap().drain().collect();
+        taken.sort_by_key(|v| v.0);
+        taken
+    }
+
+    /// Put back what couldn't be written, to go with the next interval
+    fn restore(&self, taken: Vec<(IpAddr, Counts)>) {
+        let Some(counts) = &self.counts else {
+            return;
+        };
+        let mut counts = counts.lock().unwrap();
+        for (sender, taken) in taken {
+            counts
+                .entry(sender)
+                .and_modify(|v| v.merge(&taken))
+                .or_insert(taken);
+        }
+    }
+
+    /// What writes the counts of each interval of `every` with `sql`, given the address of the
+    /// sender, when it was first and last seen within the interval, and how many messages, bytes
+    /// and dropped entries there were. Meant to be spawned.
+    pub fn writer(
+        &self,
+        writer: Writer,
+        sql: String,
+        every: Duration,
+    ) -> anyhow::Result<impl Future<Output = ()>> {
+        if self.counts.is_none() {
+            anyhow::bail!("Not counting sources");
+        }
+        if every.is_zero() {
+            anyhow::bail!("Can't write the counts of sources every 0s");
+        }
+        Ok(run(self.clone(), writer, sql, every))
+    }
+}
+
+async fn run(stats: SourceStats, mut writer: Writer, sql: String, every: Duration) {
+    let mut ticks = interval(every);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    ticks.tick().await;
+
+    loop {
+        ticks.tick().await;
+        let taken = stats.take();
+        if taken.is_empty() {
+            continue;
+        }
+
+        let rows: Vec<_> = taken.iter().map(|(k, v)| v.row(*k)).collect();
+        match writer.write_rows(&sql, &rows).await {
+            Ok(_) => log::debug!("Wrote the counts of {} sources", rows.len()),
+            Err(e) => {
+                log::warn!("Error writing the counts of {} sources: {e:#}", rows.len());
+                stats.restore(taken);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::UNIX_EPOCH;
+
+    use super::*;
+
+    #[test]
+    fn merges_intervals() {
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        let mut stats = Counts {
+            messages: 2,
+            bytes: 100,
+            ..Counts::new(at(10))
+        };
+        stats.merge(&Counts {
+            last_seen: at(30),
+            messages: 1,
+            bytes: 20,
+            dropped: 1,
+            ..Counts::new(at(5))
+        });
+
+        assert_eq!(
+            Counts {
+                first_seen: at(5),
+                last_seen: at(30),
+                messages: 3,
+                bytes: 120,
+                dropped: 1,
+            },
+            stats
+        );
+        assert_eq!(
+            Param::Text(Some("1970-01-01T00:00:05.000000Z".to_string())),
+            stats.row("10.0.0.1".parse().unwrap())[1]
+        );
+    }
+
+    #[test]
+    fn counts_of_their_own() {
+        let entry = Entry {
+            sender: Some("10.0.0.1:12201".parse().unwrap()),
+            body: "hello".to_string(),
+            ..Default::default()
+        };
+        let stats = SourceStats::counting();
+        let other = SourceStats::counting();
+        stats.clone().received(&entry);
+        stats.dropped(entry.sender);
+        SourceStats::default().received(&entry);
+
+        let sender = entry.sender.unwrap().ip();
+        assert_eq!(vec![(sender, 1, 1)], stats.top(10));
+        assert!(other.top(10).is_empty());
+        assert_eq!(5, stats.take()[0].1.bytes);
+        assert!(stats.top(10).is_empty());
+    }
+}
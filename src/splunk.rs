@@ -0,0 +1,299 @@
+//! Writing entries to Splunk's HTTP Event Collector, waiting for the indexers to acknowledge them
+//! if asked to
+
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use anyhow::{bail, Context};
+use async_trait::async_trait;
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use serde_json::{json, Value};
+
+use crate::{
+    pipeline::{Entry, PendingCommit, Sink},
+    retry::Retries,
+    timestamp::TIMESTAMP,
+};
+
+/// How often to ask whether a batch has been indexed
+const ACK_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What every event is sent with, besides the entry
+#[derive(Debug, Clone, Default)]
+pub struct EventMetadata {
+    pub index: Option<String>,
+    pub source: Option<String>,
+    pub sourcetype: Option<String>,
+}
+
+impl EventMetadata {
+    /// The event of `entry`: the entry itself as JSON if it is, from its host or else the sender,
+    /// at its own timestamp or else when it was received
+    fn event(&self, entry: &Entry) -> Value {
+        let json = entry.json();
+        let mut event = json!({
+            "event": json.cloned().unwrap_or_else(|| entry.body.clone().into()),
+        });
+
+        let host = json
+            .and_then(|v| v["host"].as_str())
+            .map(str::to_string)
+            .or(entry.sender.map(|v| v.ip().to_string()));
+        let at = entry
+            .fields
+            .get(TIMESTAMP)
+            .and_then(|v| humantime::parse_rfc3339(v).ok())
+            .or(entry.received.map(|v| v.at))
+            .and_then(|v| v.duration_since(UNIX_EPOCH).ok());
+        let fields = [
+            ("host", host.map(Value::from)),
+            ("time", at.map(|v| json!(v.as_secs_f64()))),
+            ("index", self.index.clone().map(Value::from)),
+            ("source", self.source.clone().map(Value::from)),
+            ("sourcetype", self.sourcetype.clone().map(Value::from)),
+        ];
+        for (k, v) in fields {
+            if let Some(v) = v {
+                event[k] = v;
+            }
+        }
+        event
+    }
+
+    /// The events of `entries`, one JSON object after another, as the collector takes a batch
+    pub fn events(&self, entries: &[Entry]) -> String {
+        entries
+            .iter()
+            .map(|v| self.event(v).to_string())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// A random channel to ask for acknowledgements on, as a UUID
+fn new_channel() -> String {
+    let mut bytes = [0u8; 16];
+    OsRng.fill_bytes(&mut bytes);
+    // Version 4, variant 1
+    bytes[6] = bytes[6] & 0x0f | 0x40;
+    bytes[8] = bytes[8] & 0x3f | 0x80;
+    let hex: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// Whether `response` of the ack endpoint says `ack_id` was indexed
+fn is_acked(response: &Value, ack_id: u64) -> bool {
+    response["acks"][ack_id.to_string()]
+        .as_bool()
+        .unwrap_or(false)
+}
+
+/// Waits for the indexers to acknowledge the events sent as `ack_id`, failing after `timeout`
+#[derive(Clone)]
+struct AckPoller {
+    client: reqwest::Client,
+    url: String,
+    token: String,
+    channel: String,
+    timeout: Duration,
+}
+
+impl AckPoller {
+    async fn wait(self, ack_id: u64) -> anyhow::Result<()> {
+        let started = Instant::now();
+        loop {
+            tokio::time::sleep(ACK_POLL_INTERVAL).await;
+            let response: Value = self
+                .client
+                .post(&self.url)
+                .header(
+                    reqwest::header::AUTHORIZATION,
+                    format!("Splunk {}", self.token),
+                )
+                .header("X-Splunk-Request-Channel", &self.channel)
+                .json(&json!({ "acks": [ack_id] }))
+                .send()
+                .await
+                .and_then(|v| v.error_for_status())
+                .context("Polling Splunk for acknowledgements")?
+                .json()
+                .await
+                .context("Polling Splunk for acknowledgements")?;
+            if is_acked(&response, ack_id) {
+                return Ok(());
+            }
+            if started.elapsed() > self.timeout {
+                bail!(
+                    "Splunk didn't acknowledge the events within {:?}",
+                    self.timeout
+                );
+            }
+        }
+    }
+}
+
+/// Writes every batch to `inner`, if any, then as events to Splunk. With acknowledgements, a
+/// batch is committed once Splunk has indexed it as well.
+pub struct SplunkSink {
+    pub inner: Option<Box<dyn Sink>>,
+    client: reqwest::Client,
+    event_url: String,
+    token: String,
+    metadata: EventMetadata,
+    acks: Option<AckPoller>,
+    retries: Retries,
+}
+
+impl SplunkSink {
+    /// `ack_timeout` is how long to wait for the indexers to acknowledge each batch, which
+    /// needs indexer acknowledgement turned on for the token. Not waiting if none.
+    pub fn new(
+        inner: Option<Box<dyn Sink>>,
+        url: &str,
+        token: String,
+        metadata: EventMetadata,
+        ack_timeout: Option<Duration>,
+    ) -> Self {
+        let url = url.trim_end_matches('/');
+        let client = reqwest::Client::new();
+        let acks = ack_timeout.map(|timeout| AckPoller {
+            client: client.clone(),
+            url: format!("{url}/services/collector/ack"),
+            token: token.clone(),
+            channel: new_channel(),
+            timeout,
+        });
+
+        Self {
+            inner,
+            client,
+            event_url: format!("{url}/services/collector/event"),
+            token,
+            metadata,
+            acks,
+            retries: Default::default(),
+        }
+    }
+
+    /// Retry failed writes by `retries`
+    pub fn with_retries(mut self, retries: Retries) -> Self {
+        self.retries = retries;
+        self
+    }
+}
+
+#[async_trait]
+impl Sink for SplunkSink {
+    async fn write(&mut self, entries: &[Entry]) -> anyhow::Result<()> {
+        self.write_pending(entries).await?.await
+    }
+
+    async fn write_pending(&mut self, entries: &[Entry]) -> anyhow::Result<PendingCommit> {
+        let commit = match &mut self.inner {
+            Some(inner) => inner.write_pending(entries).await?,
+            None => Box::pin(async { Ok(()) }),
+        };
+
+        let events = self.metadata.events(entries);
+        let mut attempts = self.retries.attempts("Sending events to Splunk");
+        let response: Value = loop {
+            let mut request = self
+                .client
+                .post(&self.event_url)
+                .header(
+                    reqwest::header::AUTHORIZATION,
+                    format!("Splunk {}", self.token),
+                )
+                .body(events.clone());
+            if let Some(acks) = &self.acks {
+                request = request.header("X-Splunk-Request-Channel", &acks.channel);
+            }
+            let rs = async {
+                request
+                    .send()
+                    .await
+                    .and_then(|v| v.error_for_status())?
+                    .json()
+                    .await
+            };
+            match rs.await.context("Sending events to Splunk") {
+                Ok(v) => break v,
+                Err(e) => attempts.failed(e).await?,
+            }
+        };
+
+        let Some(acks) = self.acks.clone() else {
+            return Ok(commit);
+        };
+        let ack_id = response["ackId"]
+            .as_u64()
+            .context("No ackId from Splunk, is indexer acknowledgement on for the token?")?;
+        Ok(Box::pin(async move {
+            commit.await?;
+            acks.wait(ack_id).await
+        }))
+    }
+
+    async fn tick(&mut self) -> anyhow::Result<()> {
+        match &mut self.inner {
+            Some(inner) => inner.tick().await,
+            None => Ok(()),
+        }
+    }
+
+    async fn close(&mut self) -> anyhow::Result<()> {
+        match &mut self.inner {
+            Some(inner) => inner.close().await,
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pipeline::Received;
+
+    use super::*;
+
+    #[test]
+    fn builds_events() {
+        let metadata = EventMetadata {
+            index: Some("logs".to_string()),
+            sourcetype: Some("_json".to_string()),
+            ..Default::default()
+        };
+        let mut gelf = Entry {
+            sender: Some("10.0.0.1:1234".parse().unwrap()),
+            ..Default::default()
+        };
+        gelf.set_body(r#"{"host":"web","short_message":"hi"}"#.to_string());
+        let plain = Entry {
+            sender: Some("10.0.0.1:1234".parse().unwrap()),
+            received: Some(Received {
+                at: UNIX_EPOCH + Duration::from_millis(1500),
+                seq: 0,
+            }),
+            body: "not json".to_string(),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            concat!(
+                r#"{"event":{"host":"web","short_message":"hi"},"host":"web","index":"logs","sourcetype":"_json"}"#,
+                "\n",
+                r#"{"event":"not json","host":"10.0.0.1","index":"logs","sourcetype":"_json","time":1.5}"#
+            ),
+            metadata.events(&[gelf, plain])
+        );
+
+        assert!(is_acked(&json!({"acks": {"3": true}}), 3));
+        assert!(!is_acked(&json!({"acks": {"3": false}}), 3));
+        assert_eq!(36, new_channel().len());
+    }
+}
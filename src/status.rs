@@ -0,0 +1,167 @@
+//! A read-only status page, for a look at the daemon from a browser rather than at the metrics
+
+use std::{
+    fmt::Write,
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    health::{Event, Health},
+    metrics::{self, Metric, COMMIT_SECONDS},
+    quarantine::Quarantine,
+};
+
+/// How often the page reloads itself, in seconds
+const REFRESH_SECS: u64 = 5;
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '&' => escaped.push_str("&amp;"),
+            '"' => escaped.push_str("&quot;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn ago(elapsed: Option<Duration>) -> String {
+    match elapsed {
+        Some(v) => format!(
+            "{} ago",
+            humantime::format_duration(Duration::from_secs(v.as_secs()))
+        ),
+        None => "never".to_string(),
+    }
+}
+
+fn row(text: &mut String, cells: &[&str]) {
+    text.push_str("<tr>");
+    for cell in cells {
+        let _ = write!(text, "<td>{}</td>", escape(cell));
+    }
+    text.push_str("</tr>\n");
+}
+
+/// The page, showing `settings`, the name and value of each setting worth knowing, then the
+/// counters, how the database is doing and the latest rejected messages, if `quarantine` keeps them
+pub fn page(settings: &[(&str, String)], quarantine: &Quarantine, health: &Health) -> String {
+    let mut text = format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">\
+         <meta http-equiv=\"refresh\" content=\"{REFRESH_SECS}\">\
+         <title>sqlx_logger</title>\
+         <style>body{{font-family:sans-serif}}td{{padding:2px 12px;vertical-align:top}}\
+         pre{{margin:0;white-space:pre-wrap}}</style></head>\n<body>\n\
+         <h1>sqlx_logger {}</h1>\n",
+        env!("CARGO_PKG_VERSION")
+    );
+
+    text.push_str("<h2>Configuration</h2>\n<table>\n");
+    for (name, value) in settings {
+        let _ = writeln!(
+            text,
+            "<tr><td>{}</td><td><pre>{}</pre></td></tr>",
+            escape(name),
+            escape(value)
+        );
+    }
+    text.push_str("</table>\n");
+
+    let now = SystemTime::now();
+    let quantile = |q| match COMMIT_SECONDS.quantile(q) {
+        Some(v) => format!("{:.0} ms", v * 1000.0),
+        None => "-".to_string(),
+    };
+    text.push_str("<h2>Database</h2>\n<table>\n");
+    row(
+        &mut text,
+        &["Last commit", &ago(metrics::since_last_commit(now))],
+    );
+    row(
+        &mut text,
+        &[
+            "Oldest entry not committed",
+            &format!("{:.1}s waiting", metrics::lag(now).as_secs_f64()),
+        ],
+    );
+    row(
+        &mut text,
+        &[
+            "Commits, 50th and 99th percentile",
+            &format!("{}, {}", quantile(0.5), quantile(0.99)),
+        ],
+    );
+    row(
+        &mut text,
+        &["Failed writes", &health.count(Event::DbError).to_string()],
+    );
+    text.push_str("</table>\n");
+
+    text.push_str("<h2>Counters</h2>\n<table>\n");
+    for Metric {
+        name, help, value, ..
+    } in metrics::snapshot(health)
+    {
+        row(
+            &mut text,
+            &[
+                name.trim_start_matches("sqlx_logger_"),
+                &value.to_string(),
+                help,
+            ],
+        );
+    }
+    text.push_str("</table>\n");
+
+    text.push_str("<h2>Recently rejected</h2>\n");
+    let samples = quarantine.samples();
+    if samples.is_empty() {
+        text.push_str("<p>None</p>\n");
+    } else {
+        text.push_str("<table>\n");
+        for sample in samples {
+            let at = humantime::format_rfc3339_seconds(sample.at).to_string();
+            let sender = sample.sender.map(|v| v.to_string()).unwrap_or_default();
+            let _ = writeln!(
+                text,
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td><pre>{}</pre></td></tr>",
+                escape(&at),
+                escape(&sender),
+                escape(&sample.reason),
+                escape(&sample.payload)
+            );
+        }
+        text.push_str("</table>\n");
+    }
+    text.push_str("</body></html>\n");
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_settings() {
+        let quarantine = Quarantine::default().keep_samples();
+        quarantine.reject("Not JSON", None, b"<b>");
+        let page = page(
+            &[(
+                "Statements",
+                "INSERT INTO logs(body) SELECT $1 WHERE 1 < 2".to_string(),
+            )],
+            &quarantine,
+            &Health::default(),
+        );
+        assert!(page.contains(
+            "<tr><td>Statements</td><td><pre>INSERT INTO logs(body) SELECT $1 WHERE 1 &lt; 2</pre></td></tr>"
+        ));
+        assert!(page.contains("<tr><td>Last commit</td>"));
+        assert!(page.contains("<td>queue_depth</td>"));
+        assert!(page.contains("<td>Not JSON</td><td><pre>&lt;b&gt;</pre></td>"));
+        assert!(page.ends_with("</body></html>\n"));
+    }
+}
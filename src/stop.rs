@@ -0,0 +1,64 @@
+//! Stopping on our own, as if interrupted, for batch-style runs and soak tests
+
+use std::time::Duration;
+
+use async_shutdown::Shutdown;
+
+use crate::pipeline::{Entry, Transform};
+
+/// Shut down after `duration`, writing out what's pending as on an interrupt
+pub async fn after(duration: Duration, shutdown: Shutdown) {
+    tokio::time::sleep(duration).await;
+    log::info!("Ran for {duration:?}, shutting down");
+    shutdown.shutdown();
+}
+
+/// Lets the first `max` entries through, then shuts down, letting go of any coming in after them
+/// while the rest are written out
+pub struct StopAfter {
+    remaining: u64,
+    shutdown: Shutdown,
+}
+
+impl StopAfter {
+    pub fn new(max: u64, shutdown: Shutdown) -> Self {
+        Self {
+            remaining: max,
+            shutdown,
+        }
+    }
+}
+
+impl Transform for StopAfter {
+    fn apply(&mut self, entry: Entry) -> Option<Entry> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        if self.remaining == 0 {
+            log::info!("Received the last entry to stop after, shutting down");
+            self.shutdown.shutdown();
+        }
+        Some(entry)
+    }
+
+    fn rejects(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stops_after_entries() {
+        let shutdown = Shutdown::new();
+        let mut stop = StopAfter::new(2, shutdown.clone());
+        assert!(stop.apply(Entry::default()).is_some());
+        assert!(!shutdown.shutdown_started());
+        assert!(stop.apply(Entry::default()).is_some());
+        assert!(shutdown.shutdown_started());
+        assert!(stop.apply(Entry::default()).is_none());
+    }
+}
@@ -0,0 +1,43 @@
+use anyhow::bail;
+
+use crate::pipeline::{Entry, Transform};
+
+/// Adds the same fields to every entry, e.g. the environment or datacenter this instance runs in
+#[derive(Debug, Clone, Default)]
+pub struct Tags(pub Vec<(String, String)>);
+
+/// Parse a `key=value` tag
+pub fn parse_tag(s: &str) -> anyhow::Result<(String, String)> {
+    let Some((key, value)) = s.split_once('=') else {
+        bail!("Expecting key=value but got: {s}");
+    };
+    if key.is_empty() {
+        bail!("Empty tag name in: {s}");
+    }
+    Ok((key.to_string(), value.to_string()))
+}
+
+impl Transform for Tags {
+    fn apply(&mut self, mut entry: Entry) -> Option<Entry> {
+        for (key, value) in &self.0 {
+            entry.fields.insert(key.clone(), value.clone());
+        }
+        Some(entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tags() {
+        assert_eq!(
+            ("env".to_string(), "prod=1".to_string()),
+            parse_tag("env=prod=1").unwrap()
+        );
+        assert_eq!(("dc".to_string(), String::new()), parse_tag("dc=").unwrap());
+        assert!(parse_tag("env").is_err());
+        assert!(parse_tag("=prod").is_err());
+    }
+}
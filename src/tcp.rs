@@ -0,0 +1,180 @@
+use std::net::SocketAddr;
+
+use anyhow::Context;
+use async_shutdown::Shutdown;
+use async_trait::async_trait;
+use futures::StreamExt;
+use tokio::{
+    io::AsyncRead,
+    net::{TcpListener, TcpStream},
+    spawn,
+    sync::mpsc,
+};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::codec::FramedRead;
+
+use crate::{
+    gelf::GelfError,
+    health::{Event, Health},
+    pipeline::{Entry, Received, Source},
+    quarantine::Quarantine,
+    tls, GelfCodec,
+};
+
+/// Longest message accepted from a stream by default, before the connection is dropped
+const MAX_MESSAGE_SIZE: usize = 1 << 20;
+
+/// Accept GELF over TCP, where every message is terminated by a null byte.
+///
+/// Connections aren't read from while the pipeline's queue is full, so TCP flow control pushes
+/// back on the senders instead of us dropping messages.
+pub struct TcpSource {
+    listener: TcpListener,
+    tls: Option<TlsAcceptor>,
+    max_message_size: usize,
+    quarantine: Quarantine,
+    health: Health,
+}
+
+impl TcpSource {
+    pub fn new(listener: TcpListener) -> Self {
+        Self {
+            listener,
+            tls: None,
+            max_message_size: MAX_MESSAGE_SIZE,
+            quarantine: Default::default(),
+            health: Default::default(),
+        }
+    }
+
+    /// Accept messages up to this many bytes, rather than 1 MiB. Messages are buffered whole
+    /// until their null byte, so each connection may take up to this much memory.
+    pub fn with_max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = max_message_size;
+        self
+    }
+
+    /// Keep what can't be decoded in `quarantine`
+    pub fn with_quarantine(mut self, quarantine: Quarantine) -> Self {
+        self.quarantine = quarantine;
+        self
+    }
+
+    /// Count what can't be decoded into `health`
+    pub fn with_health(mut self, health: Health) -> Self {
+        self.health = health;
+        self
+    }
+
+    /// Expect TLS on every connection. When the acceptor verifies client certificates, the
+    /// common name of each client goes along with its entries.
+    pub fn with_tls(mut self, acceptor: TlsAcceptor) -> Self {
+        self.tls = Some(acceptor);
+        self
+    }
+}
+
+#[async_trait]
+impl Source for TcpSource {
+    async fn run(
+        self: Box<Self>,
+        entries: mpsc::Sender<Entry>,
+        shutdown: Shutdown,
+    ) -> anyhow::Result<()> {
+        let Self {
+            listener,
+            tls,
+            max_message_size,
+            quarantine,
+            health,
+        } = *self;
+        while let Some(rs) = shutdown.wrap_cancel(listener.accept()).await {
+            let (stream, addr) = rs.context("Accepting TCP connection")?;
+            log::debug!("Accepted connection from {addr}");
+
+            let entries = entries.clone();
+            let shutdown = shutdown.clone();
+            let tls = tls.clone();
+            let decoding = Decoding {
+                codec: GelfCodec::with_max_length(max_message_size),
+                quarantine: quarantine.clone(),
+                health: health.clone(),
+            };
+            spawn(async move {
+                let rs = match tls {
+                    Some(tls) => {
+                        serve_tls_connection(stream, addr, &tls, decoding, entries, shutdown).await
+                    }
+                    None => serve_connection(stream, addr, None, decoding, entries, shutdown).await,
+                };
+                log::debug!("Connection from {addr} closed: {rs:?}");
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// How the messages of a connection are decoded, and where what can't be goes
+struct Decoding {
+    codec: GelfCodec,
+    quarantine: Quarantine,
+    health: Health,
+}
+
+async fn serve_tls_connection(
+    stream: TcpStream,
+    addr: SocketAddr,
+    tls: &TlsAcceptor,
+    decoding: Decoding,
+    entries: mpsc::Sender<Entry>,
+    shutdown: Shutdown,
+) -> anyhow::Result<()> {
+    let stream = match shutdown.wrap_cancel(tls.accept(stream)).await {
+        Some(rs) => rs.context("TLS handshake")?,
+        None => return Ok(()),
+    };
+
+    let client_cn = tls::client_common_name(stream.get_ref().1);
+    log::debug!("TLS connection from {addr}, client CN: {client_cn:?}");
+    serve_connection(stream, addr, client_cn, decoding, entries, shutdown).await
+}
+
+async fn serve_connection(
+    stream: impl AsyncRead + Unpin,
+    addr: SocketAddr,
+    client_cn: Option<String>,
+    Decoding {
+        codec,
+        quarantine,
+        health,
+    }: Decoding,
+    entries: mpsc::Sender<Entry>,
+    shutdown: Shutdown,
+) -> anyhow::Result<()> {
+    let mut frames = FramedRead::new(stream, codec);
+
+    while let Some(Some(entry)) = shutdown.wrap_cancel(frames.next()).await {
+        let entry = Entry {
+            sender: Some(addr),
+            client_cn: client_cn.clone(),
+            received: Some(Received::now()),
+            body: entry
+                .inspect_err(|e| {
+                    health.record(Event::DecodeError);
+                    if !matches!(e, GelfError::Io(_)) {
+                        quarantine.reject(e, Some(addr), &[]);
+                    }
+                })
+                .context("Reading from connection")?,
+            ..Default::default()
+        };
+
+        // Waiting here is what stops us from reading more when the queue is full
+        if entries.send(entry).await.is_err() {
+            break;
+        }
+    }
+
+    Ok(())
+}
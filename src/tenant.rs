@@ -0,0 +1,191 @@
+//! Serving several teams from one daemon: each listener labels what it receives with a tenant,
+//! and each tenant's entries can go to statements of its own
+
+use std::{collections::BTreeMap, net::SocketAddr};
+
+use anyhow::Context;
+use async_shutdown::Shutdown;
+use async_trait::async_trait;
+use tokio::sync::mpsc;
+
+use crate::{
+    pipeline::{Entry, PendingCommit, Sink, Source},
+    tags,
+};
+
+/// The field holding the tenant of an entry, to bind by name
+pub const TENANT_FIELD: &str = "tenant";
+
+/// Parse a `name=address` listener of a tenant
+pub fn parse_listener(s: &str) -> anyhow::Result<(String, SocketAddr)> {
+    let (name, addr) = tags::parse_tag(s)?;
+    let addr = addr
+        .parse()
+        .with_context(|| format!("Parsing the address of tenant {name}: {addr}"))?;
+    Ok((name, addr))
+}
+
+/// Labels every entry of `inner` with the tenant it was received for
+pub struct TenantSource<S> {
+    inner: S,
+    tenant: String,
+}
+
+impl<S: Source> TenantSource<S> {
+    pub fn new(inner: S, tenant: String) -> Self {
+        Self { inner, tenant }
+    }
+}
+
+#[async_trait]
+impl<S: Source> Source for TenantSource<S> {
+    async fn run(
+        self: Box<Self>,
+        entries: mpsc::Sender<Entry>,
+        shutdown: Shutdown,
+    ) -> anyhow::Result<()> {
+        let Self { inner, tenant } = *self;
+        // Room for only one, so the queue filling up still stops the source
+        let (sender, mut labelled) = mpsc::channel(1);
+        let inner = tokio::spawn(Box::new(inner).run(sender, shutdown));
+
+        while let Some(mut entry) = labelled.recv().await {
+            entry
+                .fields
+                .insert(TENANT_FIELD.to_string(), tenant.clone());
+            if entries.send(entry).await.is_err() {
+                break;
+            }
+        }
+
+        drop(labelled);
+        inner.await?
+    }
+}
+
+/// Writes the entries of each tenant to a sink of its own, and the rest, of tenants without
+/// one or received by no tenant's listener, to the default. Each tenant's entries stay in order,
+/// and are written one sink after another.
+pub struct TenantSink {
+    tenants: BTreeMap<String, Box<dyn Sink>>,
+    default: Box<dyn Sink>,
+}
+
+impl TenantSink {
+    pub fn new(tenants: BTreeMap<String, Box<dyn Sink>>, default: Box<dyn Sink>) -> Self {
+        Self { tenants, default }
+    }
+
+    /// The entries for each tenant's sink, with `None` for the default
+    fn split<'a>(&self, entries: &'a [Entry]) -> BTreeMap<Option<&'a str>, Vec<Entry>> {
+        let mut parts: BTreeMap<_, Vec<_>> = BTreeMap::new();
+        for entry in entries {
+            let tenant = entry
+                .fields
+                .get(TENANT_FIELD)
+                .map(String::as_str)
+                .filter(|v| self.tenants.contains_key(*v));
+            parts.entry(tenant).or_default().push(entry.clone());
+        }
+        parts
+    }
+}
+
+#[async_trait]
+impl Sink for TenantSink {
+    async fn write(&mut self, entries: &[Entry]) -> anyhow::Result<()> {
+        self.write_pending(entries).await?.await
+    }
+
+    async fn write_pending(&mut self, entries: &[Entry]) -> anyhow::Result<PendingCommit> {
+        let mut pending = Vec::new();
+        for (tenant, part) in self.split(entries) {
+            pending.push(match tenant {
+                Some(tenant) => {
+                    let sink = self
+                        .tenants
+                        .get_mut(tenant)
+                        .expect("Split by tenants known");
+                    sink.write_pending(&part)
+                        .await
+                        .with_context(|| format!("Writing entries of tenant {tenant}"))?
+                }
+                None => self.default.write_pending(&part).await?,
+            });
+        }
+        Ok(Box::pin(async move {
+            futures::future::try_join_all(pending).await?;
+            Ok(())
+        }))
+    }
+
+    async fn close(&mut self) -> anyhow::Result<()> {
+        for sink in self.tenants.values_mut() {
+            sink.close().await?;
+        }
+        self.default.close().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Written(Arc<Mutex<Vec<String>>>);
+
+    #[async_trait]
+    impl Sink for Written {
+        async fn write(&mut self, entries: &[Entry]) -> anyhow::Result<()> {
+            let mut written = self.0.lock().unwrap();
+            written.extend(entries.iter().map(|v| v.body.clone()));
+            Ok(())
+        }
+    }
+
+    fn entry(tenant: Option<&str>, body: &str) -> Entry {
+        let mut entry = Entry {
+            body: body.to_string(),
+            ..Default::default()
+        };
+        if let Some(tenant) = tenant {
+            entry
+                .fields
+                .insert(TENANT_FIELD.to_string(), tenant.to_string());
+        }
+        entry
+    }
+
+    #[tokio::test]
+    async fn routes_by_tenant() {
+        let (a, default) = (Written::default(), Written::default());
+        let mut sink = TenantSink::new(
+            BTreeMap::from([("a".to_string(), Box::new(a.clone()) as _)]),
+            Box::new(default.clone()),
+        );
+
+        sink.write(&[
+            entry(Some("a"), "a1"),
+            entry(None, "none"),
+            entry(Some("b"), "b1"),
+            entry(Some("a"), "a2"),
+        ])
+        .await
+        .unwrap();
+
+        assert_eq!(vec!["a1", "a2"], *a.0.lock().unwrap());
+        assert_eq!(vec!["none", "b1"], *default.0.lock().unwrap());
+    }
+
+    #[test]
+    fn listeners() {
+        assert_eq!(
+            ("team-a".to_string(), "0.0.0.0:9001".parse().unwrap()),
+            parse_listener("team-a=0.0.0.0:9001").unwrap()
+        );
+        assert!(parse_listener("team-a").is_err());
+        assert!(parse_listener("team-a=9001").is_err());
+    }
+}
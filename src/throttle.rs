@@ -0,0 +1,78 @@
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use tokio::time::sleep_until;
+
+use crate::pipeline::{Entry, PendingCommit, Sink};
+
+/// Spaces out writes to a number of rows per second, without letting bursts through
+#[derive(Debug)]
+pub struct WriteRate {
+    per_row: Duration,
+    /// When the next write may start
+    next: Option<Instant>,
+}
+
+impl WriteRate {
+    pub fn new(rows_per_sec: f64) -> Self {
+        Self {
+            per_row: Duration::from_secs_f64(1.0 / rows_per_sec),
+            next: None,
+        }
+    }
+
+    /// When to write `num_rows`, if not right away at `now`
+    fn reserve(&mut self, num_rows: usize, now: Instant) -> Option<Instant> {
+        let start = self.next.filter(|next| *next > now);
+        self.next = Some(start.unwrap_or(now) + self.per_row * num_rows as u32);
+        start
+    }
+}
+
+/// Holds batches back to keep to a [`WriteRate`], so a burst of logs can't take over a database
+/// shared with other work. Sources are pushed back on meanwhile, as with a slow database.
+pub struct ThrottledSink {
+    pub inner: Box<dyn Sink>,
+    pub rate: WriteRate,
+}
+
+#[async_trait]
+impl Sink for ThrottledSink {
+    async fn write(&mut self, entries: &[Entry]) -> anyhow::Result<()> {
+        self.write_pending(entries).await?.await
+    }
+
+    async fn write_pending(&mut self, entries: &[Entry]) -> anyhow::Result<PendingCommit> {
+        if let Some(start) = self.rate.reserve(entries.len(), Instant::now()) {
+            log::debug!("Throttling writes for {:?}", start - Instant::now());
+            sleep_until(start.into()).await;
+        }
+        self.inner.write_pending(entries).await
+    }
+
+    async fn close(&mut self) -> anyhow::Result<()> {
+        self.inner.close().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spaces_out_writes() {
+        let mut rate = WriteRate::new(100.0);
+        let now = Instant::now();
+        assert_eq!(None, rate.reserve(50, now));
+        assert_eq!(
+            Some(now + Duration::from_millis(500)),
+            rate.reserve(10, now + Duration::from_millis(100))
+        );
+        // Idle time isn't saved up for later bursts
+        assert_eq!(None, rate.reserve(10, now + Duration::from_secs(10)));
+        assert_eq!(
+            Some(now + Duration::from_millis(10_100)),
+            rate.reserve(1, now + Duration::from_secs(10))
+        );
+    }
+}
@@ -0,0 +1,130 @@
+//! Setting up a TimescaleDB hypertable, and writing to it in time order
+
+use std::{
+    borrow::Cow,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use async_trait::async_trait;
+use sqlx::{AnyPool, Row};
+
+use crate::{
+    pipeline::{Entry, PendingCommit, Sink},
+    timestamp::TIMESTAMP,
+};
+
+/// Make `table` a hypertable partitioned by `time_column` into chunks of `chunk_interval`, unless
+/// it is one already, creating the extension first if need be. Existing rows are moved into
+/// chunks.
+pub async fn create_hypertable(
+    pool: &AnyPool,
+    table: &str,
+    time_column: &str,
+    chunk_interval: Duration,
+) -> anyhow::Result<()> {
+    sqlx::query("CREATE EXTENSION IF NOT EXISTS timescaledb")
+        .execute(pool)
+        .await
+        .context("Creating the timescaledb extension")?;
+
+    let created: bool = sqlx::query(
+        "SELECT created FROM create_hypertable($1::text::regclass, $2::text::name, \
+         chunk_time_interval => $3::text::interval, if_not_exists => TRUE, migrate_data => TRUE)",
+    )
+    .bind(table)
+    .bind(time_column)
+    .bind(format!("{} seconds", chunk_interval.as_secs().max(1)))
+    .fetch_one(pool)
+    .await
+    .with_context(|| format!("Creating hypertable {table}"))?
+    .try_get(0)?;
+
+    if created {
+        log::info!("Created hypertable {table} by {time_column}, in chunks of {chunk_interval:?}");
+    } else {
+        log::info!("{table} is a hypertable already");
+    }
+    Ok(())
+}
+
+/// When the entry happened, by its own timestamp if it has one, else when it was received
+fn time_of(entry: &Entry) -> Option<SystemTime> {
+    entry
+        .fields
+        .get(TIMESTAMP)
+        .and_then(|v| humantime::parse_rfc3339(v).ok())
+        .or(entry.received.map(|v| v.at))
+}
+
+/// The entries oldest first, keeping the order of those at the same time
+fn by_time(entries: &[Entry]) -> Cow<'_, [Entry]> {
+    if entries.is_sorted_by_key(time_of) {
+        return Cow::Borrowed(entries);
+    }
+
+    let mut sorted = entries.to_vec();
+    sorted.sort_by_key(time_of);
+    Cow::Owned(sorted)
+}
+
+/// Writes every batch to `inner` in time order, so rows land in as few chunks at a time as can
+/// be, appended in order within each
+pub struct TimeOrderedSink {
+    pub inner: Box<dyn Sink>,
+}
+
+#[async_trait]
+impl Sink for TimeOrderedSink {
+    async fn write(&mut self, entries: &[Entry]) -> anyhow::Result<()> {
+        self.inner.write(&by_time(entries)).await
+    }
+
+    async fn write_pending(&mut self, entries: &[Entry]) -> anyhow::Result<PendingCommit> {
+        self.inner.write_pending(&by_time(entries)).await
+    }
+
+    async fn close(&mut self) -> anyhow::Result<()> {
+        self.inner.close().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::UNIX_EPOCH;
+
+    use crate::pipeline::Received;
+
+    use super::*;
+
+    fn entry(body: &str, received: u64, timestamp: Option<&str>) -> Entry {
+        let mut entry = Entry {
+            received: Some(Received {
+                at: UNIX_EPOCH + Duration::from_secs(received),
+                seq: 0,
+            }),
+            body: body.to_string(),
+            ..Default::default()
+        };
+        if let Some(timestamp) = timestamp {
+            entry
+                .fields
+                .insert(TIMESTAMP.to_string(), timestamp.to_string());
+        }
+        entry
+    }
+
+    #[test]
+    fn orders_by_time() {
+        let entries = [entry("a", 10, None), entry("b", 20, None)];
+        assert!(matches!(by_time(&entries), Cow::Borrowed(_)));
+
+        let entries = [
+            entry("late", 30, None),
+            entry("early", 40, Some("1970-01-01T00:00:05Z")),
+            entry("same", 30, None),
+        ];
+        let bodies: Vec<_> = by_time(&entries).iter().map(|v| v.body.clone()).collect();
+        assert_eq!(vec!["early", "late", "same"], bodies);
+    }
+}
@@ -0,0 +1,178 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use clap::ValueEnum;
+
+use crate::{
+    json,
+    pipeline::{Entry, Transform},
+};
+
+/// The entry's own timestamp, in RFC 3339
+pub const TIMESTAMP: &str = "timestamp";
+
+/// Entries dropped by [`DropOlderThan`]
+pub static TOO_OLD: AtomicU64 = AtomicU64::new(0);
+
+/// What the numbers in a timestamp field count since the epoch
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum TimestampUnit {
+    /// Guess from the magnitude, which works for timestamps between 1973 and 5138
+    Auto,
+    #[value(name = "s")]
+    Seconds,
+    #[value(name = "ms")]
+    Millis,
+    #[value(name = "us")]
+    Micros,
+    #[value(name = "ns")]
+    Nanos,
+}
+
+impl TimestampUnit {
+    /// Seconds since the epoch
+    fn to_seconds(self, value: f64) -> f64 {
+        match self {
+            Self::Auto if value < 1e11 => value,
+            Self::Auto if value < 1e14 => value / 1e3,
+            Self::Auto if value < 1e17 => value / 1e6,
+            Self::Auto => value / 1e9,
+            Self::Seconds => value,
+            Self::Millis => value / 1e3,
+            Self::Micros => value / 1e6,
+            Self::Nanos => value / 1e9,
+        }
+    }
+}
+
+/// Adds the timestamp in a top-level field of JSON entries, scaled to the right unit.
+///
+/// GELF says fractional seconds, but not everyone sticks to it, and millis read as seconds land
+/// far in the future. Numbers in strings are taken too.
+#[derive(Debug, Clone)]
+pub struct NormalizeTimestamp {
+    pub field: String,
+    pub unit: TimestampUnit,
+}
+
+impl NormalizeTimestamp {
+    pub fn timestamp(&self, entry: &Entry) -> Option<SystemTime> {
+        let value = json::get_f64(entry, &self.field)
+            .or_else(|| json::get_string(entry, &self.field)?.trim().parse().ok())?;
+        let seconds = self.unit.to_seconds(value);
+        let since_epoch = Duration::try_from_secs_f64(seconds).ok()?;
+        UNIX_EPOCH.checked_add(since_epoch)
+    }
+}
+
+impl Transform for NormalizeTimestamp {
+    fn apply(&mut self, mut entry: Entry) -> Option<Entry> {
+        if let Some(timestamp) = self.timestamp(&entry) {
+            entry.fields.insert(
+                TIMESTAMP.to_string(),
+                humantime::format_rfc3339_micros(timestamp).to_string(),
+            );
+        }
+        Some(entry)
+    }
+}
+
+/// Drops entries whose own timestamp is older than `max_age`, e.g. the weeks of backlog a sender
+/// sends once it reconnects, counting them. Entries without one are kept.
+#[derive(Debug, Clone)]
+pub struct DropOlderThan {
+    pub timestamp: NormalizeTimestamp,
+    pub max_age: Duration,
+}
+
+impl DropOlderThan {
+    fn too_old(&self, entry: &Entry, now: SystemTime) -> bool {
+        self.timestamp
+            .timestamp(entry)
+            .and_then(|v| now.duration_since(v).ok())
+            .is_some_and(|age| age > self.max_age)
+    }
+}
+
+impl Transform for DropOlderThan {
+    fn apply(&mut self, entry: Entry) -> Option<Entry> {
+        if self.too_old(&entry, SystemTime::now()) {
+            TOO_OLD.fetch_add(1, Ordering::Relaxed);
+            log::debug!("Dropping an entry older than {:?}", self.max_age);
+            return None;
+        }
+        Some(entry)
+    }
+
+    fn rejects(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(body: &str) -> Entry {
+        Entry {
+            body: body.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn detects_units() {
+        let normalize = NormalizeTimestamp {
+            field: "timestamp".to_string(),
+            unit: TimestampUnit::Auto,
+        };
+        let expected = UNIX_EPOCH + Duration::from_millis(1_700_000_000_500);
+
+        for body in [
+            r#"{"timestamp": 1700000000.5}"#,
+            r#"{"timestamp": 1700000000500}"#,
+            r#"{"timestamp": 1700000000500000}"#,
+            r#"{"timestamp": 1700000000500000000}"#,
+            r#"{"timestamp": "1700000000.5"}"#,
+        ] {
+            assert_eq!(Some(expected), normalize.timestamp(&entry(body)), "{body}");
+        }
+
+        assert_eq!(None, normalize.timestamp(&entry(r#"{"timestamp": -1}"#)));
+        assert_eq!(
+            None,
+            normalize.timestamp(&entry(r#"{"timestamp": "yesterday"}"#))
+        );
+        assert_eq!(None, normalize.timestamp(&entry(r#"{"time": 1700000000}"#)));
+    }
+
+    #[test]
+    fn configured_unit() {
+        let normalize = NormalizeTimestamp {
+            field: "ts".to_string(),
+            unit: TimestampUnit::Millis,
+        };
+        assert_eq!(
+            Some(UNIX_EPOCH + Duration::from_secs(1)),
+            normalize.timestamp(&entry(r#"{"ts": 1000}"#))
+        );
+    }
+
+    #[test]
+    fn drops_old_entries() {
+        let drop = DropOlderThan {
+            timestamp: NormalizeTimestamp {
+                field: "timestamp".to_string(),
+                unit: TimestampUnit::Seconds,
+            },
+            max_age: Duration::from_secs(3600),
+        };
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        assert!(drop.too_old(&entry(r#"{"timestamp": 1699990000}"#), now));
+        assert!(!drop.too_old(&entry(r#"{"timestamp": 1699999000}"#), now));
+        assert!(!drop.too_old(&entry(r#"{"timestamp": 1800000000}"#), now));
+        assert!(!drop.too_old(&entry(r#"{"time": 1}"#), now));
+    }
+}
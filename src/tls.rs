@@ -0,0 +1,190 @@
+use std::{
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+
+use anyhow::{bail, Context};
+use clap::ValueEnum;
+use rustls_pemfile::Item;
+use sqlx::{any::AnyConnectOptions, mysql::MySqlSslMode, postgres::PgSslMode};
+use tokio_rustls::{
+    rustls::{
+        server::AllowAnyAuthenticatedClient, Certificate, PrivateKey, RootCertStore, ServerConfig,
+        ServerConnection,
+    },
+    TlsAcceptor,
+};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+/// Where to find the TLS certificate, its key and, to require client certificates, the CA bundle
+/// they have to be signed by. All in PEM.
+#[derive(Debug, Clone)]
+pub struct TlsOptions<'a> {
+    pub cert: &'a Path,
+    pub key: &'a Path,
+    pub client_ca: Option<&'a Path>,
+}
+
+impl TlsOptions<'_> {
+    pub fn acceptor(&self) -> anyhow::Result<TlsAcceptor> {
+        let certs = read_pem(self.cert)?
+            .into_iter()
+            .filter_map(|item| match item {
+                Item::X509Certificate(v) => Some(Certificate(v)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        if certs.is_empty() {
+            bail!("No certificate found in {}", self.cert.display());
+        }
+
+        let Some(key) = read_pem(self.key)?.into_iter().find_map(|item| match item {
+            Item::RSAKey(v) | Item::PKCS8Key(v) | Item::ECKey(v) => Some(PrivateKey(v)),
+            _ => None,
+        }) else {
+            bail!("No private key found in {}", self.key.display());
+        };
+
+        let builder = ServerConfig::builder().with_safe_defaults();
+        let builder = match self.client_ca {
+            Some(path) => {
+                let mut roots = RootCertStore::empty();
+                for item in read_pem(path)? {
+                    if let Item::X509Certificate(v) = item {
+                        roots
+                            .add(&Certificate(v))
+                            .with_context(|| format!("Adding CA from {}", path.display()))?;
+                    }
+                }
+                if roots.is_empty() {
+                    bail!("No CA certificate found in {}", path.display());
+                }
+                builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots))
+            }
+            None => builder.with_no_client_auth(),
+        };
+
+        let config = builder
+            .with_single_cert(certs, key)
+            .context("Setting up TLS certificate")?;
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+fn read_pem(path: &Path) -> anyhow::Result<Vec<Item>> {
+    let file = File::open(path).with_context(|| format!("Opening {}", path.display()))?;
+    rustls_pemfile::read_all(&mut BufReader::new(file))
+        .with_context(|| format!("Reading PEM from {}", path.display()))
+}
+
+/// The common name in the certificate the client authenticated with, if any
+pub fn client_common_name(conn: &ServerConnection) -> Option<String> {
+    let cert = conn.peer_certificates()?.first()?;
+    let (_, cert) = X509Certificate::from_der(&cert.0).ok()?;
+    let cn = cert.subject().iter_common_name().next()?;
+    cn.as_str().ok().map(str::to_string)
+}
+
+/// How much TLS to insist on with the database, named as Postgres' `sslmode` names them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SslMode {
+    /// Never
+    Disable,
+    /// If the server supports it
+    Prefer,
+    /// Always, without checking the server's certificate
+    Require,
+    /// Always, checking the server's certificate is signed by a trusted CA
+    VerifyCa,
+    /// Always, checking the certificate is also for the host connected to
+    VerifyFull,
+}
+
+/// TLS settings for connecting to the database, applied to the connect options of each driver
+/// rather than left to the parameters of its URL, which every driver names differently
+#[derive(Debug, Clone, Default)]
+pub struct DbTls {
+    pub mode: Option<SslMode>,
+    /// The CA bundle, in PEM, the server's certificate has to be signed by
+    pub root_cert: Option<PathBuf>,
+}
+
+impl DbTls {
+    /// The options to connect to `url` with, with these settings in place of any in the URL
+    pub fn options(&self, url: &str) -> anyhow::Result<AnyConnectOptions> {
+        let mut options = AnyConnectOptions::from_str(url).context("Parsing the database URL")?;
+        if self.mode.is_none() && self.root_cert.is_none() {
+            return Ok(options);
+        }
+
+        if let Some(pg) = options.as_postgres_mut() {
+            let mut with = pg.clone();
+            if let Some(mode) = self.mode {
+                with = with.ssl_mode(match mode {
+                    SslMode::Disable => PgSslMode::Disable,
+                    SslMode::Prefer => PgSslMode::Prefer,
+                    SslMode::Require => PgSslMode::Require,
+                    SslMode::VerifyCa => PgSslMode::VerifyCa,
+                    SslMode::VerifyFull => PgSslMode::VerifyFull,
+                });
+            }
+            if let Some(path) = &self.root_cert {
+                with = with.ssl_root_cert(path);
+            }
+            *pg = with;
+        } else if let Some(mysql) = options.as_mysql_mut() {
+            let mut with = mysql.clone();
+            if let Some(mode) = self.mode {
+                with = with.ssl_mode(match mode {
+                    SslMode::Disable => MySqlSslMode::Disabled,
+                    SslMode::Prefer => MySqlSslMode::Preferred,
+                    SslMode::Require => MySqlSslMode::Required,
+                    SslMode::VerifyCa => MySqlSslMode::VerifyCa,
+                    SslMode::VerifyFull => MySqlSslMode::VerifyIdentity,
+                });
+            }
+            if let Some(path) = &self.root_cert {
+                with = with.ssl_ca(path);
+            }
+            *mysql = with;
+        } else {
+            bail!(
+                "Database TLS options are for Postgres and MySQL, not {:?}",
+                options.kind()
+            );
+        }
+        Ok(options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::any::AnyKind;
+
+    use super::*;
+
+    #[test]
+    fn db_options() {
+        let tls = DbTls {
+            mode: Some(SslMode::VerifyFull),
+            root_cert: Some("/etc/ssl/db-ca.pem".into()),
+        };
+        let options = tls
+            .options("postgres://logger@db/logs?sslmode=disable")
+            .unwrap();
+        assert_eq!(AnyKind::Postgres, options.kind());
+        let pg = format!("{:?}", options.as_postgres().unwrap());
+        assert!(pg.contains("ssl_mode: VerifyFull"), "{pg}");
+        assert!(pg.contains("db-ca.pem"), "{pg}");
+
+        let options = tls.options("mysql://logger@db/logs").unwrap();
+        let mysql = format!("{:?}", options.as_mysql().unwrap());
+        assert!(mysql.contains("ssl_mode: VerifyIdentity"), "{mysql}");
+
+        assert!(tls.options("sqlite::memory:").is_err());
+        assert!(DbTls::default().options("sqlite::memory:").is_ok());
+    }
+}
@@ -0,0 +1,60 @@
+use std::{fs, io, path::PathBuf};
+
+use anyhow::Context;
+use clap::{Command, Subcommand};
+use clap_complete::Shell;
+
+// Things to do instead of running the daemon. Not a doc comment, which would become the about of
+// the daemon itself.
+#[derive(Debug, Subcommand)]
+pub enum Tool {
+    /// Print the shell completions, or write them into a directory
+    Completions {
+        shell: Shell,
+
+        #[arg(long)]
+        output_dir: Option<PathBuf>,
+    },
+
+    /// Print the man page, or write it into a directory
+    Man {
+        #[arg(long)]
+        output_dir: Option<PathBuf>,
+    },
+}
+
+impl Tool {
+    pub fn run(self, mut command: Command) -> anyhow::Result<()> {
+        let name = command.get_name().to_string();
+        match self {
+            Self::Completions {
+                shell,
+                output_dir: None,
+            } => clap_complete::generate(shell, &mut command, name, &mut io::stdout()),
+
+            Self::Completions {
+                shell,
+                output_dir: Some(dir),
+            } => {
+                fs::create_dir_all(&dir).with_context(|| format!("Creating {}", dir.display()))?;
+                let path = clap_complete::generate_to(shell, &mut command, name, &dir)
+                    .with_context(|| format!("Writing completions into {}", dir.display()))?;
+                log::info!("Wrote {}", path.display());
+            }
+
+            Self::Man { output_dir: None } => clap_mangen::Man::new(command)
+                .render(&mut io::stdout())
+                .context("Writing man page")?,
+
+            Self::Man {
+                output_dir: Some(dir),
+            } => {
+                fs::create_dir_all(&dir).with_context(|| format!("Creating {}", dir.display()))?;
+                clap_mangen::generate_to(command, &dir)
+                    .with_context(|| format!("Writing man pages into {}", dir.display()))?;
+                log::info!("Wrote man pages into {}", dir.display());
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,290 @@
+use std::{
+    cell::RefCell,
+    future::Future,
+    sync::OnceLock,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use serde_json::{json, Value};
+use tokio::{sync::mpsc, time::interval};
+
+use crate::identity;
+
+/// How many traces may wait to be exported before new ones are dropped
+const QUEUE_SIZE: usize = 1024;
+/// How often waiting traces are exported
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AttrValue {
+    Int(i64),
+    Str(String),
+}
+
+impl From<i64> for AttrValue {
+    fn from(v: i64) -> Self {
+        Self::Int(v)
+    }
+}
+
+impl From<usize> for AttrValue {
+    fn from(v: usize) -> Self {
+        Self::Int(v as i64)
+    }
+}
+
+impl From<&str> for AttrValue {
+    fn from(v: &str) -> Self {
+        Self::Str(v.to_string())
+    }
+}
+
+/// One stage of handling a batch
+#[derive(Debug, Clone, PartialEq)]
+pub struct Span {
+    pub name: &'static str,
+    pub start: SystemTime,
+    pub end: SystemTime,
+    /// Index of the parent within the same trace, or none for the root
+    pub parent: Option<usize>,
+    pub attributes: Vec<(&'static str, AttrValue)>,
+}
+
+impl Span {
+    pub fn new(name: &'static str, start: SystemTime, end: SystemTime) -> Self {
+        Self {
+            name,
+            start,
+            end,
+            parent: None,
+            attributes: vec![],
+        }
+    }
+
+    pub fn child_of(mut self, parent: usize) -> Self {
+        self.parent = Some(parent);
+        self
+    }
+
+    pub fn attr(mut self, key: &'static str, value: impl Into<AttrValue>) -> Self {
+        self.attributes.push((key, value.into()));
+        self
+    }
+
+    fn attribute(&self, key: &str) -> Option<&AttrValue> {
+        self.attributes.iter().find(|v| v.0 == key).map(|v| &v.1)
+    }
+}
+
+tokio::task_local! {
+    static RECORDED: RefCell<Vec<Span>>;
+}
+
+/// Run `f`, collecting what it [`record`]s
+pub async fn scope<T>(f: impl Future<Output = T>) -> (T, Vec<Span>) {
+    RECORDED
+        .scope(RefCell::new(vec![]), async move {
+            let rs = f.await;
+            (rs, RECORDED.with(|v| v.take()))
+        })
+        .await
+}
+
+/// Record a span for the current [`scope`], if any. Consecutive spans of the same name and
+/// statement are merged, counting how many there were, so executing the same statement for each
+/// entry of a batch doesn't make a span each.
+pub fn record(span: Span) {
+    let _ = RECORDED.try_with(|recorded| {
+        let mut recorded = recorded.borrow_mut();
+        let statement = span.attribute("db.statement");
+        if let Some(last) = recorded.last_mut() {
+            if statement.is_some()
+                && last.name == span.name
+                && last.attribute("db.statement") == statement
+            {
+                last.end = span.end;
+                if let Some((_, AttrValue::Int(n))) =
+                    last.attributes.iter_mut().find(|v| v.0 == "executions")
+                {
+                    *n += 1;
+                }
+                return;
+            }
+        }
+        match statement {
+            Some(_) => recorded.push(span.attr("executions", 1i64)),
+            None => recorded.push(span),
+        }
+    });
+}
+
+static EXPORTER: OnceLock<mpsc::Sender<Vec<Span>>> = OnceLock::new();
+
+pub fn is_enabled() -> bool {
+    EXPORTER.get().is_some()
+}
+
+/// Hand a trace to the exporter, if there is one
+pub fn export(trace: Vec<Span>) {
+    if let Some(exporter) = EXPORTER.get() {
+        if exporter.try_send(trace).is_err() {
+            log::debug!("Too many traces waiting to be exported, dropping one");
+        }
+    }
+}
+
+/// Start tracing batches, returning what exports them to `endpoint`, an OTLP/HTTP traces URL
+/// such as `http://localhost:4318/v1/traces`. Meant to be spawned.
+pub fn exporter(endpoint: String) -> anyhow::Result<impl Future<Output = ()>> {
+    let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+    if EXPORTER.set(sender).is_err() {
+        anyhow::bail!("Traces are already being exported");
+    }
+    Ok(run_exporter(endpoint, receiver))
+}
+
+async fn run_exporter(endpoint: String, mut receiver: mpsc::Receiver<Vec<Span>>) {
+    let client = reqwest::Client::new();
+    let mut ticks = interval(EXPORT_INTERVAL);
+    let mut traces = Vec::new();
+    loop {
+        ticks.tick().await;
+        while let Ok(trace) = receiver.try_recv() {
+            traces.push(trace);
+        }
+        if traces.is_empty() {
+            continue;
+        }
+
+        let rs = client
+            .post(&endpoint)
+            .json(&to_otlp(&traces))
+            .send()
+            .await
+            .and_then(|v| v.error_for_status());
+        match rs {
+            Ok(_) => log::debug!("Exported {} traces", traces.len()),
+            Err(e) => log::warn!("Error exporting {} traces: {e}", traces.len()),
+        }
+        traces.clear();
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn random_id<const N: usize>() -> [u8; N] {
+    let mut id = [0u8; N];
+    OsRng.fill_bytes(&mut id);
+    id
+}
+
+fn unix_nanos(at: SystemTime) -> String {
+    at.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+/// The OTLP/JSON request exporting `traces`
+fn to_otlp(traces: &[Vec<Span>]) -> Value {
+    let identity = identity::get();
+    let mut spans = Vec::new();
+    for trace in traces {
+        let trace_id = hex(&random_id::<16>());
+        let span_ids: Vec<String> = trace.iter().map(|_| hex(&random_id::<8>())).collect();
+        for (span, span_id) in trace.iter().zip(&span_ids) {
+            let attributes: Vec<_> = span
+                .attributes
+                .iter()
+                .map(|(key, value)| {
+                    let value = match value {
+                        AttrValue::Int(v) => json!({ "intValue": v.to_string() }),
+                        AttrValue::Str(v) => json!({ "stringValue": v }),
+                    };
+                    json!({ "key": key, "value": value })
+                })
+                .collect();
+
+            let mut span_json = json!({
+                "traceId": trace_id,
+                "spanId": span_id,
+                "name": span.name,
+                "kind": 1,
+                "startTimeUnixNano": unix_nanos(span.start),
+                "endTimeUnixNano": unix_nanos(span.end),
+                "attributes": attributes,
+            });
+            if let Some(parent) = span.parent.and_then(|i| span_ids.get(i)) {
+                span_json["parentSpanId"] = parent.as_str().into();
+            }
+            spans.push(span_json);
+        }
+    }
+
+    let resource = json!({ "attributes": [
+        { "key": "service.name", "value": { "stringValue": "sqlx_logger" } },
+        { "key": "service.version", "value": { "stringValue": identity.version } },
+        { "key": "service.instance.id", "value": { "stringValue": identity.instance_id } },
+        { "key": "host.name", "value": { "stringValue": identity.hostname } },
+    ]});
+    json!({ "resourceSpans": [{
+        "resource": resource,
+        "scopeSpans": [{ "scope": { "name": "sqlx_logger" }, "spans": spans }],
+    }]})
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn merges_repeated_statements() {
+        let at = |millis| UNIX_EPOCH + Duration::from_millis(millis);
+        let ((), spans) = scope(async {
+            for i in 0..3 {
+                record(Span::new("execute", at(i), at(i + 1)).attr("db.statement", "INSERT 1"));
+            }
+            record(Span::new("execute", at(3), at(4)).attr("db.statement", "INSERT 2"));
+            record(Span::new("commit", at(4), at(6)));
+        })
+        .await;
+
+        assert_eq!(
+            vec![
+                Span::new("execute", at(0), at(3))
+                    .attr("db.statement", "INSERT 1")
+                    .attr("executions", 3i64),
+                Span::new("execute", at(3), at(4))
+                    .attr("db.statement", "INSERT 2")
+                    .attr("executions", 1i64),
+                Span::new("commit", at(4), at(6)),
+            ],
+            spans
+        );
+
+        // Outside of a scope, nothing is recorded
+        record(Span::new("commit", at(4), at(6)));
+    }
+
+    #[test]
+    fn otlp_parents() {
+        let at = |millis| UNIX_EPOCH + Duration::from_millis(millis);
+        let trace = vec![
+            Span::new("batch", at(0), at(10)).attr("entries", 5usize),
+            Span::new("write", at(5), at(10)).child_of(0),
+        ];
+        let otlp = to_otlp(&[trace]);
+        let spans = &otlp["resourceSpans"][0]["scopeSpans"][0]["spans"];
+        assert_eq!(spans[0]["spanId"], spans[1]["parentSpanId"]);
+        assert_eq!(spans[0]["traceId"], spans[1]["traceId"]);
+        assert!(spans[0].get("parentSpanId").is_none());
+        assert_eq!("5000000", spans[1]["startTimeUnixNano"]);
+        assert_eq!(
+            json!([{ "key": "entries", "value": { "intValue": "5" } }]),
+            spans[0]["attributes"]
+        );
+    }
+}
@@ -0,0 +1,1127 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::Infallible,
+    hash::{Hash, Hasher},
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context};
+use async_trait::async_trait;
+use sqlx::{
+    any::{AnyArguments, AnyConnectionKind, AnyKind, AnyRow, AnyStatement, AnyTypeInfo},
+    pool::PoolConnection,
+    query::Query,
+    Any, AnyConnection, AnyPool, Column, Connection, Either, Executor, Row, Statement, Transaction,
+    TypeInfo,
+};
+
+use tokio::{
+    spawn,
+    sync::{mpsc, oneshot, watch, Mutex, MutexGuard, RwLock, RwLockReadGuard},
+};
+
+use crate::{
+    health::{Event, Health},
+    identity, metrics,
+    pipeline::{Entry, PendingCommit, Position, Received, Sink},
+    placeholders,
+    trace::{self, Span},
+};
+
+/// How a batch of entries is handed to the database.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BatchMode {
+    /// Execute the SQL once per entry, binding the entry as the only parameter.
+    PerEntry,
+
+    /// Execute the SQL once per batch, binding all entries as a single `text[]` parameter.
+    /// Postgres only; meant for `INSERT ... SELECT * FROM UNNEST($1::text[])`.
+    PgUnnest,
+
+    /// Execute the SQL once per batch, binding all entries as a single JSON array, JSON entries
+    /// as they are and others as strings, and each field as an array of its own. Meant for
+    /// stored procedures, e.g. `CALL ingest_logs($1::text::jsonb)`, keeping the inserting on the
+    /// database side.
+    JsonArray,
+}
+
+/// What else about an entry can be bound, as parameters following the entry itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BindField {
+    /// The sender's address, e.g. `10.0.0.1:51234`
+    Sender,
+    /// The common name of the certificate the sender authenticated with over TLS
+    ClientCn,
+    /// `@received_at`: when the entry was received, in RFC 3339
+    ReceivedAt,
+    /// `@seq`: the order the entry was received in
+    Seq,
+    /// `@hostname` of the host we run on
+    Hostname,
+    /// `@instance_id` of this process, see [`identity`](crate::identity)
+    InstanceId,
+    /// `@version` of this crate
+    Version,
+    /// `@body`: the entry itself, for statements not given it first
+    Body,
+    /// `returned.<column>`: what an earlier statement for the same entry returned in that
+    /// column, e.g. with `RETURNING id`, from its first row. NULL until one has.
+    Returned(String),
+    /// One of the [`Entry::fields`] added along the way, e.g. `geo-country`. NULL if the entry
+    /// doesn't have it.
+    Field(String),
+    /// A top-level field of JSON entries, as text unless it's a string already. NULL if the
+    /// entry has no such field or isn't JSON, see [`placeholders`](crate::placeholders)
+    Json(String),
+}
+
+impl FromStr for BindField {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "sender" => Self::Sender,
+            "client-cn" => Self::ClientCn,
+            "@received_at" => Self::ReceivedAt,
+            "@seq" => Self::Seq,
+            "@hostname" => Self::Hostname,
+            "@instance_id" => Self::InstanceId,
+            "@version" => Self::Version,
+            "@body" => Self::Body,
+            name if name.starts_with(RETURNED) => Self::Returned(name.to_string()),
+            name => Self::Field(name.to_string()),
+        })
+    }
+}
+
+impl BindField {
+    /// As given to [`FromStr`]
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Sender => "sender",
+            Self::ClientCn => "client-cn",
+            Self::ReceivedAt => "@received_at",
+            Self::Seq => "@seq",
+            Self::Hostname => "@hostname",
+            Self::InstanceId => "@instance_id",
+            Self::Version => "@version",
+            Self::Body => "@body",
+            Self::Returned(name) | Self::Field(name) | Self::Json(name) => name,
+        }
+    }
+
+    pub fn value(&self, entry: &Entry) -> Option<String> {
+        match self {
+            Self::Sender => entry.sender.map(|v| v.to_string()),
+            Self::ClientCn => entry.client_cn.clone(),
+            Self::ReceivedAt => entry
+                .received
+                .map(|v| humantime::format_rfc3339_micros(v.at).to_string()),
+            Self::Seq => entry.received.map(|v| v.seq.to_string()),
+            Self::Hostname => Some(identity::get().hostname.clone()),
+            Self::InstanceId => Some(identity::get().instance_id.clone()),
+            Self::Version => Some(identity::get().version.to_string()),
+            Self::Body => Some(entry.body.clone()),
+            Self::Returned(_) => None,
+            Self::Field(name) => entry.fields.get(name).cloned(),
+            Self::Json(key) => match entry.json()?.get(key)? {
+                serde_json::Value::String(v) => Some(v.clone()),
+                serde_json::Value::Null => None,
+                v => Some(v.to_string()),
+            },
+        }
+    }
+
+    /// As [`BindField::value`], with what statements run before for the entry returned
+    fn value_after(
+        &self,
+        entry: &Entry,
+        returned: &BTreeMap<String, Option<String>>,
+    ) -> Option<String> {
+        match self {
+            Self::Returned(name) => returned.get(&name[RETURNED.len()..]).cloned().flatten(),
+            field => field.value(entry),
+        }
+    }
+}
+
+/// What [`BindField::Returned`] names start with
+const RETURNED: &str = "returned.";
+
+/// A returned column as text, whatever its type
+fn column_text(row: &AnyRow, i: usize) -> Option<String> {
+    if let Ok(v) = row.try_get::<Option<String>, _>(i) {
+        return v;
+    }
+    if let Ok(v) = row.try_get::<Option<i64>, _>(i) {
+        return v.map(|v| v.to_string());
+    }
+    if let Ok(v) = row.try_get::<Option<f64>, _>(i) {
+        return v.map(|v| v.to_string());
+    }
+    if let Ok(v) = row.try_get::<Option<bool>, _>(i) {
+        return v.map(|v| v.to_string());
+    }
+    row.try_get::<Option<i32>, _>(i)
+        .ok()
+        .flatten()
+        .map(|v| v.to_string())
+}
+
+/// A parameter of [`Writer::write_rows`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Param {
+    Text(Option<String>),
+    /// Bound as whatever number the statement takes there, as Postgres won't convert
+    Int(Option<i64>),
+}
+
+/// A database writer owning one connection from the pool.
+///
+/// Prepared statements only live on the connection they were prepared on, so each writer
+/// keeps its own cache keyed by SQL. Statements are prepared lazily on first use and again
+/// after the connection is replaced or the server tells us they have been invalidated.
+pub struct Writer {
+    pool: AnyPool,
+    mode: BatchMode,
+    conn: Option<PoolConnection<Any>>,
+    statements: HashMap<String, Prepared>,
+    slow: Option<Duration>,
+    /// Whether the entry itself is bound first, before the fields
+    bind_entry: bool,
+    /// New pools to switch to, e.g. when credentials rotate
+    pools: Option<watch::Receiver<AnyPool>>,
+    /// Held for reading while a transaction is open, see [`Writer::run_between_transactions`]
+    transactions: Option<Arc<RwLock<()>>>,
+    /// Held for every transaction on SQLite, see [`Writer::with_sqlite_lock`]
+    sqlite_writer: Arc<Mutex<()>>,
+    /// Where failed writes are counted
+    health: Health,
+}
+
+impl Writer {
+    pub fn new(pool: AnyPool, mode: BatchMode) -> anyhow::Result<Self> {
+        if mode == BatchMode::PgUnnest && pool.any_kind() != AnyKind::Postgres {
+            bail!("UNNEST batching is only supported on Postgres");
+        }
+
+        Ok(Self {
+            pool,
+            mode,
+            conn: None,
+            statements: Default::default(),
+            slow: None,
+            bind_entry: true,
+            pools: None,
+            transactions: None,
+            sqlite_writer: Default::default(),
+            health: Default::default(),
+        })
+    }
+
+    /// Warn about statements and commits taking longer than `threshold`
+    pub fn with_slow_threshold(mut self, threshold: Option<Duration>) -> Self {
+        self.slow = threshold;
+        self
+    }
+
+    /// Switch to the pools sent to `pools` as they come, dropping the connection on the old one
+    pub fn with_pool_updates(mut self, pools: watch::Receiver<AnyPool>) -> Self {
+        self.pools = Some(pools);
+        self
+    }
+
+    /// Share `lock` with the other writers, so that what one runs with
+    /// [`Writer::run_between_transactions`] never overlaps with a transaction of another
+    pub fn with_transaction_lock(mut self, lock: Arc<RwLock<()>>) -> Self {
+        self.transactions = Some(lock);
+        self
+    }
+
+    /// Share `lock` with the other writers to the same SQLite database, which only ever lets one
+    /// connection write at a time: writers waiting their turn on it, rather than on the database,
+    /// don't get `SQLITE_BUSY` once its busy timeout runs out. Writers made by
+    /// [`Writer::another`] share it already.
+    pub fn with_sqlite_lock(mut self, lock: Arc<Mutex<()>>) -> Self {
+        self.sqlite_writer = lock;
+        self
+    }
+
+    /// Count failed writes into `health`. Writers made by [`Writer::another`] do too.
+    pub fn with_health(mut self, health: Health) -> Self {
+        self.health = health;
+        self
+    }
+
+    fn update_pool(&mut self) {
+        let Some(pools) = &mut self.pools else {
+            return;
+        };
+        if pools.has_changed().unwrap_or(false) {
+            self.pool = pools.borrow_and_update().clone();
+            self.conn = None;
+            self.statements.clear();
+        }
+    }
+
+    /// Another writer like this one, on a connection of its own
+    pub fn another(&self) -> Self {
+        Self {
+            pool: self.pool.clone(),
+            mode: self.mode,
+            conn: None,
+            statements: Default::default(),
+            slow: self.slow,
+            bind_entry: self.bind_entry,
+            pools: self.pools.clone(),
+            transactions: self.transactions.clone(),
+            sqlite_writer: self.sqlite_writer.clone(),
+            health: self.health.clone(),
+        }
+    }
+
+    /// Bind only the fields, leaving the entry itself out
+    pub fn without_entry(mut self) -> Self {
+        self.bind_entry = false;
+        self
+    }
+
+    /// Prepare the SQL on this writer's connection ahead of time, mostly to validate it, along
+    /// with it taking no more parameters than it's `given`, named by what is bound as each.
+    /// Returns how many it takes, if the driver can tell.
+    pub async fn prepare(&mut self, sql: &str, given: &[&str]) -> anyhow::Result<Option<usize>> {
+        self.update_pool();
+        let Self {
+            pool,
+            conn,
+            statements,
+            ..
+        } = self;
+
+        let conn = acquire(pool, conn).await?;
+        let Some(takes) = cached_statement(statements, conn, sql).await?.takes() else {
+            return Ok(None);
+        };
+
+        if takes > given.len() {
+            let given: Vec<_> = given
+                .iter()
+                .enumerate()
+                .map(|(i, name)| format!(":{} {name}", i + 1))
+                .collect();
+            bail!(
+                "Takes {takes} parameters, but is only given {}: {}",
+                given.len(),
+                if given.is_empty() {
+                    "none".to_string()
+                } else {
+                    given.join(", ")
+                }
+            );
+        }
+        Ok(Some(takes))
+    }
+
+    /// Write all entries within a single transaction, running every statement in turn for each
+    /// entry, or for the whole batch with [`BatchMode::PgUnnest`] and [`BatchMode::JsonArray`].
+    ///
+    /// Statements are given the entry and then the `binds`, as many as they take, so later ones
+    /// may leave some out.
+    ///
+    /// With `position_sql`, the latest [`Position`] of each source in the batch is recorded in the
+    /// same transaction, given the source and the offset, so a source resuming from there neither
+    /// loses nor repeats entries.
+    pub async fn write_batch(
+        &mut self,
+        sql: &[String],
+        binds: &[BindField],
+        position_sql: Option<&str>,
+        entries: &[Entry],
+    ) -> anyhow::Result<()> {
+        self.write_batch_then(sql, binds, position_sql, entries, Then::Commit)
+            .await
+    }
+
+    /// Run every statement for the entries as [`Writer::write_batch`] does, but roll back rather
+    /// than commit, to try out the SQL and binds on real entries without keeping them
+    pub async fn rehearse_batch(
+        &mut self,
+        sql: &[String],
+        binds: &[BindField],
+        entries: &[Entry],
+    ) -> anyhow::Result<()> {
+        self.write_batch_then(sql, binds, None, entries, Then::RollBack)
+            .await
+    }
+
+    async fn write_batch_then(
+        &mut self,
+        sql: &[String],
+        binds: &[BindField],
+        position_sql: Option<&str>,
+        entries: &[Entry],
+        mut then: Then,
+    ) -> anyhow::Result<()> {
+        self.update_pool();
+        let transactions = self.transactions.clone();
+        let _open = lock_for_reading(&transactions).await;
+        let sqlite_writer = self.sqlite_writer.clone();
+        let _writing = lock_sqlite_writer(&self.pool, &sqlite_writer).await;
+        let rs = match self
+            .try_write_batch(sql, binds, position_sql, entries, &mut then)
+            .await
+        {
+            Err(e) if is_statement_invalidated(&e) => {
+                self.forget_statements(&e).await?;
+                self.try_write_batch(sql, binds, position_sql, entries, &mut then)
+                    .await
+            }
+            rs => rs,
+        };
+
+        if rs.is_err() {
+            self.health.record(Event::DbError);
+        }
+        self.check_connection(&rs);
+        rs
+    }
+
+    /// Run `sql` once for each row of parameters, within a single transaction, returning how many
+    /// rows they affected
+    pub async fn write_rows(&mut self, sql: &str, rows: &[Vec<Param>]) -> anyhow::Result<u64> {
+        self.update_pool();
+        let transactions = self.transactions.clone();
+        let _open = lock_for_reading(&transactions).await;
+        let sqlite_writer = self.sqlite_writer.clone();
+        let _writing = lock_sqlite_writer(&self.pool, &sqlite_writer).await;
+        let rs = match self.try_write_rows(sql, rows).await {
+            Err(e) if is_statement_invalidated(&e) => {
+                self.forget_statements(&e).await?;
+                self.try_write_rows(sql, rows).await
+            }
+            rs => rs,
+        };
+
+        if rs.is_err() {
+            self.health.record(Event::DbError);
+        }
+        self.check_connection(&rs);
+        rs
+    }
+
+    /// Run `sql` as is, outside of any transaction, e.g. `VACUUM`, once the transactions of every
+    /// writer sharing the lock of [`Writer::with_transaction_lock`] are done, holding off new ones
+    /// meanwhile
+    pub async fn run_between_transactions(&mut self, sql: &str) -> anyhow::Result<()> {
+        self.update_pool();
+        let transactions = self.transactions.clone();
+        let _exclusive = match &transactions {
+            Some(lock) => Some(lock.write().await),
+            None => None,
+        };
+
+        let rs = async {
+            acquire(&self.pool, &mut self.conn)
+                .await?
+                .execute(sql)
+                .await
+                .with_context(|| format!("Executing SQL: {sql}"))?;
+            Ok(())
+        }
+        .await;
+        self.check_connection(&rs);
+        rs
+    }
+
+    async fn forget_statements(&mut self, e: &anyhow::Error) -> anyhow::Result<()> {
+        log::info!("Prepared statements invalidated, preparing again: {e}");
+        self.statements.clear();
+        if let Some(conn) = self.conn.as_mut() {
+            conn.clear_cached_statements()
+                .await
+                .context("Clearing cached statements")?;
+        }
+        Ok(())
+    }
+
+    fn check_connection<T>(&mut self, rs: &anyhow::Result<T>) {
+        if matches!(rs, Err(e) if is_connection_broken(e)) {
+            log::info!("Discarding broken connection");
+            self.conn = None;
+            self.statements.clear();
+        }
+    }
+
+    async fn try_write_rows(&mut self, sql: &str, rows: &[Vec<Param>]) -> anyhow::Result<u64> {
+        let Self {
+            pool,
+            conn,
+            statements,
+            slow,
+            ..
+        } = self;
+
+        let mut tx = Tx::begin(pool, conn).await?;
+
+        // Rolled back right away when anything fails, rather than on the next use of the
+        // connection, so it isn't held open while waiting for the next batch
+        let mut affected = 0;
+        let rs: anyhow::Result<()> = async {
+            for row in rows {
+                let st = cached_statement(statements, &mut tx, sql).await?;
+                let mut query = st.statement.query();
+                for (i, n) in st.placeholders(row.len()).enumerate() {
+                    query = match row.get(n) {
+                        Some(Param::Text(v)) => query.bind(v.as_deref()),
+                        Some(Param::Int(v)) => bind_int(query, *v, st.type_of(i)),
+                        None => bail!("Takes parameter ${}, but is given {}", n + 1, row.len()),
+                    };
+                }
+
+                let started = Instant::now();
+                affected += query
+                    .execute(&mut *tx)
+                    .await
+                    .with_context(|| format!("Executing SQL: {sql}"))?
+                    .rows_affected();
+                observe_statement(started, *slow, sql, rows.len());
+            }
+            Ok(())
+        }
+        .await;
+        finish(tx, rs, *slow, rows.len()).await?;
+        Ok(affected)
+    }
+
+    async fn try_write_batch(
+        &mut self,
+        sql: &[String],
+        binds: &[BindField],
+        position_sql: Option<&str>,
+        entries: &[Entry],
+        then: &mut Then,
+    ) -> anyhow::Result<()> {
+        let Self {
+            pool,
+            mode,
+            conn,
+            statements,
+            slow,
+            bind_entry,
+            ..
+        } = self;
+        let num_entry_params = *bind_entry as usize;
+
+        let mut tx = Tx::begin(pool, conn).await?;
+
+        // Rolled back right away when anything fails, rather than on the next use of the
+        // connection, so it isn't held open while waiting for the next batch
+        let rs: anyhow::Result<()> = async {
+            match mode {
+                BatchMode::PerEntry => {
+                    for entry in entries {
+                        // What statements before returned, for the ones after
+                        let mut returned = BTreeMap::new();
+                        for sql in sql {
+                            let st = cached_statement(statements, &mut tx, sql).await?;
+                            let num_params = st.takes().unwrap_or(binds.len() + num_entry_params);
+                            let mut query = st.statement.query();
+                            for n in st.placeholders(num_params) {
+                                query = match n.checked_sub(num_entry_params) {
+                                    None => query.bind(entry.body.as_str()),
+                                    Some(n) => query.bind(
+                                        binds.get(n).and_then(|v| v.value_after(entry, &returned)),
+                                    ),
+                                };
+                            }
+
+                            let started = Instant::now();
+                            if st.statement.columns().is_empty() {
+                                let r = query
+                                    .execute(&mut *tx)
+                                    .await
+                                    .with_context(|| format!("Executing SQL: {sql}"))?;
+                                observe_statement(started, *slow, sql, entries.len());
+                                log::debug!("Inserted {} rows", r.rows_affected());
+                            } else {
+                                let rows = query
+                                    .fetch_all(&mut *tx)
+                                    .await
+                                    .with_context(|| format!("Executing SQL: {sql}"))?;
+                                observe_statement(started, *slow, sql, entries.len());
+                                if let Some(row) = rows.first() {
+                                    for column in row.columns() {
+                                        let value = column_text(row, column.ordinal());
+                                        returned.insert(column.name().to_string(), value);
+                                    }
+                                }
+                                if log::log_enabled!(log::Level::Debug) {
+                                    let values: Vec<_> = returned
+                                        .iter()
+                                        .map(|(k, v)| {
+                                            format!("{k}={}", v.as_deref().unwrap_or("NULL"))
+                                        })
+                                        .collect();
+                                    log::debug!(
+                                        "Inserted {} rows, returning {}",
+                                        rows.len(),
+                                        values.join(", ")
+                                    );
+                                }
+                            }
+                        }
+                    }
+                }
+
+                BatchMode::PgUnnest => {
+                    for sql in sql {
+                        let num_params = cached_statement(statements, &mut tx, sql)
+                            .await?
+                            .takes()
+                            .unwrap_or(binds.len() + num_entry_params);
+
+                        // Arrays can't be bound through the Any driver, so we have to reach for
+                        // the underlying Postgres connection. The Postgres driver keeps its own
+                        // statement cache. Every bound field becomes an array of its own, in the
+                        // same order as the entries.
+                        let mut query = sqlx::query(sql);
+                        if num_params > 0 && *bind_entry {
+                            let bodies: Vec<&str> =
+                                entries.iter().map(|entry| entry.body.as_str()).collect();
+                            query = query.bind(bodies);
+                        }
+                        for field in binds
+                            .iter()
+                            .take(num_params.saturating_sub(num_entry_params))
+                        {
+                            let values: Vec<Option<String>> =
+                                entries.iter().map(|entry| field.value(entry)).collect();
+                            query = query.bind(values);
+                        }
+
+                        let started = Instant::now();
+                        let r = match tx.private_get_mut() {
+                            AnyConnectionKind::Postgres(conn) => query
+                                .execute(conn)
+                                .await
+                                .with_context(|| format!("Executing SQL: {sql}"))?,
+                            _ => bail!("UNNEST batching is only supported on Postgres"),
+                        };
+                        observe_statement(started, *slow, sql, entries.len());
+                        log::debug!("Inserted {} rows", r.rows_affected());
+                    }
+                }
+
+                BatchMode::JsonArray => {
+                    let bodies = serde_json::Value::Array(
+                        entries
+                            .iter()
+                            .map(|entry| match entry.json() {
+                                Some(v) => v.clone(),
+                                None => serde_json::Value::String(entry.body.clone()),
+                            })
+                            .collect(),
+                    )
+                    .to_string();
+                    let fields: Vec<String> = binds
+                        .iter()
+                        .map(|field| {
+                            let values: Vec<_> = entries.iter().map(|v| field.value(v)).collect();
+                            serde_json::to_string(&values).unwrap_or_default()
+                        })
+                        .collect();
+
+                    for sql in sql {
+                        let st = cached_statement(statements, &mut tx, sql).await?;
+                        let num_params = st.takes().unwrap_or(binds.len() + num_entry_params);
+                        let mut query = st.statement.query();
+                        for n in st.placeholders(num_params) {
+                            query = match n.checked_sub(num_entry_params) {
+                                None => query.bind(bodies.as_str()),
+                                Some(n) => query.bind(fields.get(n).map(String::as_str)),
+                            };
+                        }
+
+                        let started = Instant::now();
+                        let r = query
+                            .execute(&mut *tx)
+                            .await
+                            .with_context(|| format!("Executing SQL: {sql}"))?;
+                        observe_statement(started, *slow, sql, entries.len());
+                        log::debug!(
+                            "Handed {} entries over, {} rows affected",
+                            entries.len(),
+                            r.rows_affected()
+                        );
+                    }
+                }
+            }
+
+            if let Some(sql) = position_sql {
+                for (source, offset) in Position::latest(entries) {
+                    let st = cached_statement(statements, &mut tx, sql).await?;
+                    let mut query = st.statement.query();
+                    for (i, n) in st.placeholders(2).enumerate() {
+                        query = match n {
+                            0 => query.bind(source),
+                            _ => bind_int(query, Some(offset), st.type_of(i)),
+                        };
+                    }
+                    let started = Instant::now();
+                    query
+                        .execute(&mut *tx)
+                        .await
+                        .with_context(|| format!("Executing SQL: {sql}"))?;
+                    observe_statement(started, *slow, sql, entries.len());
+                }
+            }
+            Ok(())
+        }
+        .await;
+
+        if rs.is_ok() {
+            match then {
+                Then::Commit => {}
+                Then::Tell(executed) => {
+                    if let Some(executed) = executed.take() {
+                        let _ = executed.send(());
+                    }
+                }
+                Then::RollBack => return tx.rollback().await.context("Rolling back"),
+            }
+        }
+        finish(tx, rs, *slow, entries.len()).await
+    }
+}
+
+/// What to do once every statement of a batch has run
+enum Then {
+    Commit,
+    /// Tell the sender, then commit
+    Tell(Option<oneshot::Sender<()>>),
+    RollBack,
+}
+
+/// Runs the SQL for every batch through a [`Writer`]
+pub struct SqlSink {
+    pub writer: Writer,
+    /// Run in this order, within the same transaction
+    pub sql: Vec<String>,
+    /// Bound as further parameters after the entry
+    pub binds: Vec<BindField>,
+    /// Records the positions of durable sources, see [`Writer::write_batch`]
+    pub position_sql: Option<String>,
+}
+
+#[async_trait]
+impl Sink for SqlSink {
+    async fn write(&mut self, entries: &[Entry]) -> anyhow::Result<()> {
+        self.writer
+            .write_batch(
+                &self.sql,
+                &self.binds,
+                self.position_sql.as_deref(),
+                entries,
+            )
+            .await
+    }
+}
+
+/// A batch for a writer of an [`OverlappingSqlSink`], with who to tell once it's executed and
+/// once it's committed
+type Overlapped = (
+    Vec<Entry>,
+    oneshot::Sender<()>,
+    oneshot::Sender<anyhow::Result<()>>,
+);
+
+/// Runs the SQL for every batch like a [`SqlSink`], but takes turns between several writers, each
+/// with a connection of its own, so the next batch is written while the ones before are still
+/// committing. Only one batch is written at a time, so rows are still inserted in order, but
+/// commits may finish out of order.
+pub struct OverlappingSqlSink {
+    writers: Vec<mpsc::Sender<Overlapped>>,
+    next: usize,
+}
+
+impl OverlappingSqlSink {
+    /// Enough writers for `max_outstanding_commits` batches to commit while one more is written,
+    /// the same as given to [`ServiceBuilder::max_outstanding_commits`](crate::service::ServiceBuilder::max_outstanding_commits)
+    pub fn new(sink: SqlSink, max_outstanding_commits: usize) -> Self {
+        let writers = (0..=max_outstanding_commits)
+            .map(|_| {
+                let SqlSink {
+                    writer,
+                    sql,
+                    binds,
+                    position_sql,
+                } = &sink;
+                let (sender, mut receiver) = mpsc::channel::<Overlapped>(1);
+                let mut writer = writer.another();
+                let (sql, binds, position_sql) = (sql.clone(), binds.clone(), position_sql.clone());
+                spawn(async move {
+                    while let Some((entries, executed, committed)) = receiver.recv().await {
+                        let rs = writer
+                            .write_batch_then(
+                                &sql,
+                                &binds,
+                                position_sql.as_deref(),
+                                &entries,
+                                Then::Tell(Some(executed)),
+                            )
+                            .await;
+                        let _ = committed.send(rs);
+                    }
+                });
+                sender
+            })
+            .collect();
+
+        Self { writers, next: 0 }
+    }
+}
+
+#[async_trait]
+impl Sink for OverlappingSqlSink {
+    async fn write(&mut self, entries: &[Entry]) -> anyhow::Result<()> {
+        self.write_pending(entries).await?.await
+    }
+
+    async fn write_pending(&mut self, entries: &[Entry]) -> anyhow::Result<PendingCommit> {
+        let stopped = || anyhow!("Writer has stopped");
+        let writer = &self.writers[self.next];
+        self.next = (self.next + 1) % self.writers.len();
+
+        let (executed_sender, executed) = oneshot::channel();
+        let (committed_sender, committed) = oneshot::channel();
+        writer
+            .send((entries.to_vec(), executed_sender, committed_sender))
+            .await
+            .map_err(|_| stopped())?;
+
+        // Not told it's executed when it failed, so it's told why instead
+        if executed.await.is_err() {
+            return match committed.await {
+                Ok(Err(e)) => Err(e),
+                _ => Err(stopped()),
+            };
+        }
+        Ok(Box::pin(async move {
+            committed.await.unwrap_or_else(|_| Err(stopped()))
+        }))
+    }
+}
+
+/// Writes every batch like a [`SqlSink`], but split across several writers at once, each with a
+/// connection and a transaction of its own. Entries of the same sender host always go to the same
+/// writer, in the order they came in, so lines of one host are never inserted out of order, while
+/// hosts are written side by side.
+///
+/// A batch only counts as written once every part is committed, but a part failing leaves the
+/// others committed, so retrying the batch may write those again.
+pub struct ParallelSqlSink {
+    writers: Vec<SqlSink>,
+}
+
+impl ParallelSqlSink {
+    /// Can't record positions, which have to be committed together with every entry before them
+    pub fn new(sink: SqlSink, writers: usize) -> anyhow::Result<Self> {
+        if sink.position_sql.is_some() {
+            bail!("Positions can't be recorded across parallel writers");
+        }
+        let writers = (0..writers.max(1))
+            .map(|_| SqlSink {
+                writer: sink.writer.another(),
+                sql: sink.sql.clone(),
+                binds: sink.binds.clone(),
+                position_sql: None,
+            })
+            .collect();
+        Ok(Self { writers })
+    }
+
+    /// The entries of each writer, in the order they came in
+    fn split(&self, entries: &[Entry]) -> Vec<Vec<Entry>> {
+        let mut parts = vec![Vec::new(); self.writers.len()];
+        for entry in entries {
+            let mut hasher = std::collections::hash_map::DefaultHasher::new();
+            entry.sender.map(|v| v.ip()).hash(&mut hasher);
+            let writer = hasher.finish() % self.writers.len() as u64;
+            parts[writer as usize].push(entry.clone());
+        }
+        parts
+    }
+}
+
+#[async_trait]
+impl Sink for ParallelSqlSink {
+    async fn write(&mut self, entries: &[Entry]) -> anyhow::Result<()> {
+        let parts = self.split(entries);
+        let writes = self
+            .writers
+            .iter_mut()
+            .zip(&parts)
+            .filter(|(_, part)| !part.is_empty())
+            .map(|(writer, part)| writer.write(part));
+        futures::future::try_join_all(writes).await?;
+        Ok(())
+    }
+}
+
+fn observe_statement(started: Instant, slow: Option<Duration>, sql: &str, batch_size: usize) {
+    let elapsed = started.elapsed();
+    metrics::STATEMENT_SECONDS.observe(elapsed);
+    if trace::is_enabled() {
+        let end = Received::clock();
+        trace::record(
+            Span::new("execute", end - elapsed, end)
+                .attr("db.statement", sql)
+                .attr("entries", batch_size),
+        );
+    }
+    if slow.is_some_and(|v| elapsed > v) {
+        log::warn!("Slow statement took {elapsed:?} for a batch of {batch_size}: {sql}");
+    }
+}
+
+/// Commit if everything in the transaction went fine, or else roll it back
+async fn finish(
+    tx: Tx<'_>,
+    rs: anyhow::Result<()>,
+    slow: Option<Duration>,
+    batch_size: usize,
+) -> anyhow::Result<()> {
+    if let Err(e) = rs {
+        if let Err(rollback) = tx.rollback().await {
+            log::debug!("Error rolling back: {rollback}");
+        }
+        return Err(e);
+    }
+
+    let started = Instant::now();
+    tx.commit().await.context("Committing transactions")?;
+
+    let elapsed = started.elapsed();
+    metrics::COMMIT_SECONDS.observe(elapsed);
+    if trace::is_enabled() {
+        let end = Received::clock();
+        trace::record(Span::new("commit", end - elapsed, end).attr("entries", batch_size));
+    }
+    if slow.is_some_and(|v| elapsed > v) {
+        log::warn!("Slow commit took {elapsed:?} for a batch of {batch_size}");
+    }
+    Ok(())
+}
+
+fn bind_int<'q>(
+    query: Query<'q, Any, AnyArguments<'q>>,
+    value: Option<i64>,
+    ty: Option<&AnyTypeInfo>,
+) -> Query<'q, Any, AnyArguments<'q>> {
+    match ty.map(|v| v.name().to_ascii_uppercase()).as_deref() {
+        Some("INT2" | "SMALLINT") => query.bind(value.map(|v| v as i16)),
+        Some("INT4" | "INT" | "INTEGER") => query.bind(value.map(|v| v as i32)),
+        Some("FLOAT4" | "REAL") => query.bind(value.map(|v| v as f32)),
+        Some("FLOAT8" | "DOUBLE" | "DOUBLE PRECISION" | "FLOAT") => {
+            query.bind(value.map(|v| v as f64))
+        }
+        _ => query.bind(value),
+    }
+}
+
+/// A statement prepared from SQL with `$n` placeholders, as the database takes them, see
+/// [`placeholders::translate`]
+struct Prepared {
+    statement: AnyStatement<'static>,
+    /// Which of the parameters given each placeholder takes, when it isn't simply the first as
+    /// many as the statement takes
+    order: Option<Vec<usize>>,
+}
+
+impl Prepared {
+    /// How many of the parameters given it takes, if the driver can tell
+    fn takes(&self) -> Option<usize> {
+        if let Some(order) = &self.order {
+            return Some(order.iter().max().map_or(0, |v| v + 1));
+        }
+        match self.statement.parameters()? {
+            Either::Left(types) => Some(types.len()),
+            Either::Right(count) => Some(count),
+        }
+    }
+
+    /// Which of the first `takes` parameters given to bind, in order
+    fn placeholders(&self, takes: usize) -> Box<dyn Iterator<Item = usize> + '_> {
+        match &self.order {
+            Some(order) => Box::new(order.iter().copied()),
+            None => Box::new(0..takes),
+        }
+    }
+
+    /// The type of the `i`th placeholder, if the driver can tell
+    fn type_of(&self, i: usize) -> Option<&AnyTypeInfo> {
+        match self.statement.parameters()? {
+            Either::Left(types) => types.get(i),
+            Either::Right(_) => None,
+        }
+    }
+}
+
+/// Taken for every transaction on SQLite
+async fn lock_sqlite_writer<'a>(pool: &AnyPool, lock: &'a Mutex<()>) -> Option<MutexGuard<'a, ()>> {
+    match pool.any_kind() {
+        AnyKind::Sqlite => Some(lock.lock().await),
+        _ => None,
+    }
+}
+
+/// A transaction on a writer's connection. On SQLite it's begun with `BEGIN IMMEDIATE`, taking
+/// the write lock up front rather than at the first write, where failing to get it would leave
+/// half a batch to roll back.
+enum Tx<'c> {
+    Any(Box<Transaction<'c, Any>>),
+    Immediate(Immediate<'c>),
+}
+
+/// A transaction begun with SQL of our own, as [`Transaction`] can't begin one `IMMEDIATE`
+struct Immediate<'c> {
+    conn: &'c mut Option<PoolConnection<Any>>,
+    done: bool,
+}
+
+impl Tx<'_> {
+    async fn begin<'c>(
+        pool: &AnyPool,
+        conn: &'c mut Option<PoolConnection<Any>>,
+    ) -> anyhow::Result<Tx<'c>> {
+        let c = acquire(pool, conn).await?;
+        if c.kind() != AnyKind::Sqlite {
+            let tx = conn.as_mut().unwrap().begin();
+            return Ok(Tx::Any(Box::new(tx.await.context("Begin transaction")?)));
+        }
+
+        c.execute("BEGIN IMMEDIATE")
+            .await
+            .context("Begin transaction")?;
+        Ok(Tx::Immediate(Immediate { conn, done: false }))
+    }
+
+    async fn commit(self) -> sqlx::Result<()> {
+        match self {
+            Tx::Any(tx) => tx.commit().await,
+            Tx::Immediate(mut tx) => {
+                tx.execute("COMMIT").await?;
+                tx.done = true;
+                Ok(())
+            }
+        }
+    }
+
+    async fn rollback(self) -> sqlx::Result<()> {
+        match self {
+            Tx::Any(tx) => tx.rollback().await,
+            Tx::Immediate(mut tx) => {
+                tx.execute("ROLLBACK").await?;
+                tx.done = true;
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Immediate<'_> {
+    async fn execute(&mut self, sql: &str) -> sqlx::Result<()> {
+        let conn = self.conn.as_mut().expect("Open on a connection");
+        conn.execute(sql).await?;
+        Ok(())
+    }
+}
+
+impl Drop for Immediate<'_> {
+    /// Left open, e.g. when a commit fails, the connection is closed rather than put back in
+    /// the pool halfway through a transaction, which SQLite rolls back
+    fn drop(&mut self) {
+        if !self.done {
+            log::debug!("Closing connection left in a transaction");
+            drop(self.conn.take().map(PoolConnection::detach));
+        }
+    }
+}
+
+impl std::ops::Deref for Tx<'_> {
+    type Target = AnyConnection;
+
+    fn deref(&self) -> &AnyConnection {
+        match self {
+            Tx::Any(tx) => tx,
+            Tx::Immediate(tx) => tx.conn.as_ref().expect("Open on a connection"),
+        }
+    }
+}
+
+impl std::ops::DerefMut for Tx<'_> {
+    fn deref_mut(&mut self) -> &mut AnyConnection {
+        match self {
+            Tx::Any(tx) => tx,
+            Tx::Immediate(tx) => tx.conn.as_mut().expect("Open on a connection"),
+        }
+    }
+}
+
+async fn lock_for_reading(lock: &Option<Arc<RwLock<()>>>) -> Option<RwLockReadGuard<'_, ()>> {
+    match lock {
+        Some(lock) => Some(lock.read().await),
+        None => None,
+    }
+}
+
+async fn acquire<'a>(
+    pool: &AnyPool,
+    conn: &'a mut Option<PoolConnection<Any>>,
+) -> anyhow::Result<&'a mut PoolConnection<Any>> {
+    if conn.is_none() {
+        conn.replace(pool.acquire().await.context("Acquiring connection")?);
+    }
+
+    Ok(conn.as_mut().unwrap())
+}
+
+async fn cached_statement<'s>(
+    statements: &'s mut HashMap<String, Prepared>,
+    conn: &mut AnyConnection,
+    sql: &str,
+) -> anyhow::Result<&'s Prepared> {
+    if !statements.contains_key(sql) {
+        let (native, order) = placeholders::translate(sql, conn.kind());
+        let statement = conn
+            .prepare(&native)
+            .await
+            .with_context(|| format!("Preparing SQL: {sql}"))?
+            .to_owned();
+        statements.insert(sql.to_string(), Prepared { statement, order });
+    }
+
+    Ok(&statements[sql])
+}
+
+fn is_statement_invalidated(err: &anyhow::Error) -> bool {
+    match err.downcast_ref::<sqlx::Error>() {
+        Some(sqlx::Error::Database(e)) => match e.code().as_deref() {
+            // Postgres: "prepared statement does not exist" / "cached plan must not change result type"
+            Some("26000") => true,
+            Some("0A000") => e.message().contains("cached plan"),
+            // MySQL reports ER_NEED_REPREPARE under the generic HY000 state
+            _ => e.message().contains("re-prepared"),
+        },
+        _ => false,
+    }
+}
+
+fn is_connection_broken(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<sqlx::Error>(),
+        Some(sqlx::Error::Io(_) | sqlx::Error::Protocol(_) | sqlx::Error::Tls(_))
+    )
+}
@@ -0,0 +1,124 @@
+use std::process::{Command, Output};
+
+use crate::fixtures::databases;
+
+/// Run `subcommand` on the database at `url`, with `args` after that
+fn sqlx_logger(subcommand: &str, url: &str, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_sqlx_logger"))
+        .args([subcommand, "--db-url", url])
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn checks_sql_against_the_database() {
+    for db in databases() {
+        let pool = db.connect().await;
+        let table = db.create_table(&pool, "check", "body TEXT").await;
+
+        let sql = format!("INSERT INTO {table}(body) VALUES ({})", db.param(1));
+        let output = sqlx_logger("check", &db.url, &[&sql]);
+        assert!(
+            output.status.success(),
+            "on {}: {}",
+            db.name,
+            String::from_utf8_lossy(&output.stderr)
+        );
+
+        let sql = format!(
+            "INSERT INTO {table}(body) VALUES ({}), ({})",
+            db.param(1),
+            db.param(2)
+        );
+        let output = sqlx_logger("check", &db.url, &[&sql]);
+        assert!(!output.status.success(), "on {}", db.name);
+    }
+}
+
+#[test]
+fn refuses_conflicting_credentials() {
+    let url = "sqlite::memory:";
+    let sql = "INSERT INTO logs(body) VALUES ($1)";
+    for args in [
+        // Refreshing Vault credentials would leave the failover databases behind
+        &[
+            "--vault-path",
+            "secret/data/sqlx_logger",
+            "--vault-addr",
+            "http://127.0.0.1:8200",
+            "--circuit-failures",
+            "3",
+            "--failover-db-url",
+            "sqlite::memory:",
+        ][..],
+        &[
+            "--rds-iam",
+            "--circuit-failures",
+            "3",
+            "--failover-db-url",
+            "sqlite::memory:",
+        ],
+        // Nor would they reach the shards
+        &[
+            "--rds-iam",
+            "--shard-key",
+            "host",
+            "--shard-db-url",
+            "sqlite::memory:",
+        ],
+        &[
+            "--vault-path",
+            "secret/data/sqlx_logger",
+            "--vault-addr",
+            "http://127.0.0.1:8200",
+            "--shard-key",
+            "host",
+            "--shard-db-url",
+            "sqlite::memory:",
+        ],
+    ] {
+        let output = sqlx_logger("check", url, &[args, &[sql]].concat());
+        assert!(!output.status.success(), "{args:?}");
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("cannot be used with"), "{args:?}: {stderr}");
+    }
+}
+
+#[cfg(feature = "keyring")]
+#[test]
+fn refuses_more_than_one_credential_provider() {
+    let args = [
+        "--keyring-service",
+        "sqlx_logger",
+        "--keyring-user",
+        "logger",
+        "--rds-iam",
+        "INSERT INTO logs(body) VALUES ($1)",
+    ];
+    let output = sqlx_logger("check", "sqlite::memory:", &args);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("cannot be used with"), "{stderr}");
+}
+
+#[test]
+fn refuses_zero() {
+    let sql = "INSERT INTO logs(body) VALUES ($1)";
+    for args in [
+        ["--tokio-workers", "0"],
+        ["--blocking-threads", "0"],
+        ["--queue-size", "0"],
+        ["--source-stats-interval", "0s"],
+        ["--stats-interval", "0s"],
+        ["--maintenance-interval", "0s"],
+        ["--clean-up-interval", "0s"],
+        ["--heartbeat-interval", "0s"],
+        ["--alert-rate-window", "0s"],
+    ] {
+        let output = sqlx_logger("check", "sqlite::memory:", &[&args[..], &[sql]].concat());
+        assert!(!output.status.success(), "{args:?}");
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("invalid value"), "{args:?}: {stderr}");
+    }
+}
@@ -0,0 +1,212 @@
+//! What the end-to-end tests run the pipeline with: a sink keeping entries in memory, the
+//! databases to write to, and a running service to send GELF messages to over UDP
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use async_shutdown::Shutdown;
+use async_trait::async_trait;
+use sqlx::AnyPool;
+use sqlx_logger::{
+    batch::AdaptiveBatchSize,
+    pipeline::{Entry, Sink},
+    service::ServiceBuilder,
+    GelfSource,
+};
+use tokio::{net::UdpSocket, task::JoinHandle};
+
+/// How long to wait for entries to come through before giving up
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Keeps every entry written to it
+#[derive(Clone, Default)]
+pub struct MemorySink {
+    pub entries: Arc<Mutex<Vec<Entry>>>,
+}
+
+impl MemorySink {
+    pub fn bodies(&self) -> Vec<String> {
+        let entries = self.entries.lock().unwrap();
+        entries.iter().map(|v| v.body.clone()).collect()
+    }
+
+    /// Wait for at least `n` entries to be written
+    pub async fn wait_for(&self, n: usize) {
+        let started = Instant::now();
+        while self.entries.lock().unwrap().len() < n {
+            assert!(
+                started.elapsed() < TIMEOUT,
+                "Only {} entries of {n} written",
+                self.entries.lock().unwrap().len()
+            );
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+}
+
+#[async_trait]
+impl Sink for MemorySink {
+    async fn write(&mut self, entries: &[Entry]) -> anyhow::Result<()> {
+        self.entries.lock().unwrap().extend_from_slice(entries);
+        Ok(())
+    }
+}
+
+/// A service listening on a port of its own, until stopped
+pub struct Running {
+    pub addr: SocketAddr,
+    socket: UdpSocket,
+    shutdown: Shutdown,
+    task: JoinHandle<anyhow::Result<()>>,
+}
+
+impl Running {
+    /// Start `service`, batching up to 10 entries at a time, flushed after 100ms at most
+    pub async fn start(service: ServiceBuilder) -> Self {
+        let source = GelfSource::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let addr = source.local_addr().unwrap();
+        let shutdown = Shutdown::new();
+        let task = tokio::spawn(
+            service
+                .source(source)
+                .batch_size(AdaptiveBatchSize::new(1, 10, Duration::from_millis(100)))
+                .run(shutdown.clone()),
+        );
+
+        Self {
+            addr,
+            socket: UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+            shutdown,
+            task,
+        }
+    }
+
+    pub async fn send(&self, packet: &[u8]) {
+        self.socket.send_to(packet, self.addr).await.unwrap();
+    }
+
+    /// Send `packet` from another host on the loopback network, e.g. `127.0.0.2`
+    pub async fn send_from(&self, host: IpAddr, packet: &[u8]) {
+        let socket = UdpSocket::bind((host, 0)).await.unwrap();
+        socket.send_to(packet, self.addr).await.unwrap();
+    }
+
+    /// Where the packets come from, as entries are sent by
+    pub fn sender(&self) -> SocketAddr {
+        self.socket.local_addr().unwrap()
+    }
+
+    /// Shut down, writing out what's still pending
+    pub async fn stop(self) -> anyhow::Result<()> {
+        self.shutdown.shutdown();
+        self.task.await?
+    }
+}
+
+/// A GELF message from `host`
+pub fn gelf(host: &str, short_message: &str) -> String {
+    serde_json::json!({
+        "version": "1.1",
+        "host": host,
+        "short_message": short_message,
+    })
+    .to_string()
+}
+
+/// `message` as GELF chunks of `size` bytes at most, the last first
+pub fn chunks(id: u64, message: &[u8], size: usize) -> Vec<Vec<u8>> {
+    let parts: Vec<_> = message.chunks(size).collect();
+    let mut chunks: Vec<_> = parts
+        .iter()
+        .enumerate()
+        .map(|(seq, part)| {
+            let mut chunk = vec![0x1e, 0x0f];
+            chunk.extend_from_slice(&id.to_be_bytes());
+            chunk.extend_from_slice(&[seq as u8, parts.len() as u8]);
+            chunk.extend_from_slice(part);
+            chunk
+        })
+        .collect();
+    chunks.reverse();
+    chunks
+}
+
+/// A database to write to, with a table of its own for each test
+pub struct Database {
+    pub name: &'static str,
+    pub url: String,
+}
+
+/// SQLite in a temporary file, always. Postgres and MySQL too when their URLs are in
+/// `SQLX_LOGGER_TEST_POSTGRES_URL` and `SQLX_LOGGER_TEST_MYSQL_URL`, e.g. for the databases of
+/// `tests/docker-compose.yml`.
+pub fn databases() -> Vec<Database> {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let path = std::env::temp_dir().join(format!(
+        "sqlx_logger-test-{}-{}.db",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    let mut databases = vec![Database {
+        name: "sqlite",
+        url: format!("sqlite://{}?mode=rwc", path.display()),
+    }];
+    for (name, var) in [
+        ("postgres", "SQLX_LOGGER_TEST_POSTGRES_URL"),
+        ("mysql", "SQLX_LOGGER_TEST_MYSQL_URL"),
+    ] {
+        match std::env::var(var) {
+            Ok(url) => databases.push(Database { name, url }),
+            Err(_) => eprintln!("{var} not set, not testing against {name}"),
+        }
+    }
+    databases
+}
+
+impl Database {
+    pub async fn connect(&self) -> AnyPool {
+        AnyPool::connect(&self.url)
+            .await
+            .unwrap_or_else(|e| panic!("Connecting to {}: {e}", self.name))
+    }
+
+    /// The `n`th placeholder, as the database writes it
+    pub fn param(&self, n: usize) -> String {
+        match self.name {
+            "postgres" => format!("${n}"),
+            _ => "?".to_string(),
+        }
+    }
+
+    /// A column numbering rows in the order they're inserted
+    pub fn id_column(&self) -> &'static str {
+        match self.name {
+            "postgres" => "id SERIAL PRIMARY KEY",
+            "mysql" => "id INTEGER PRIMARY KEY AUTO_INCREMENT",
+            _ => "id INTEGER PRIMARY KEY AUTOINCREMENT",
+        }
+    }
+
+    /// Create a table of `columns` for `test`, dropping what an earlier run left behind
+    pub async fn create_table(&self, pool: &AnyPool, test: &str, columns: &str) -> String {
+        let table = format!("sqlx_logger_test_{test}");
+        sqlx::query(&format!("DROP TABLE IF EXISTS {table}"))
+            .execute(pool)
+            .await
+            .unwrap();
+        sqlx::query(&format!("CREATE TABLE {table} ({columns})"))
+            .execute(pool)
+            .await
+            .unwrap_or_else(|e| panic!("Creating {table} on {}: {e}", self.name));
+        table
+    }
+}
@@ -0,0 +1,65 @@
+use std::io::Write;
+
+use flate2::write::GzEncoder;
+use sqlx_logger::Service;
+
+use crate::fixtures::{chunks, gelf, MemorySink, Running};
+
+#[tokio::test]
+async fn receives_messages() {
+    let sink = MemorySink::default();
+    let running = Running::start(Service::builder().sink(sink.clone())).await;
+
+    let messages: Vec<_> = (0..25).map(|i| gelf("web", &format!("{i}"))).collect();
+    for message in &messages {
+        running.send(message.as_bytes()).await;
+    }
+    sink.wait_for(messages.len()).await;
+    let sender = running.sender();
+    running.stop().await.unwrap();
+
+    assert_eq!(messages, sink.bodies());
+    let entries = sink.entries.lock().unwrap();
+    assert!(entries.iter().all(|v| v.sender == Some(sender)));
+    assert!(entries.iter().all(|v| v.received.is_some()));
+}
+
+#[tokio::test]
+async fn reassembles_chunks_and_decompresses() {
+    let sink = MemorySink::default();
+    let running = Running::start(Service::builder().sink(sink.clone())).await;
+
+    let chunked = gelf("web", &"long ".repeat(100));
+    for chunk in chunks(1, chunked.as_bytes(), 100) {
+        running.send(&chunk).await;
+    }
+    sink.wait_for(1).await;
+
+    let compressed = gelf("web", "compressed");
+    let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(compressed.as_bytes()).unwrap();
+    running.send(&encoder.finish().unwrap()).await;
+    sink.wait_for(2).await;
+    running.stop().await.unwrap();
+
+    assert_eq!(vec![chunked, compressed], sink.bodies());
+}
+
+#[tokio::test]
+async fn writes_pending_entries_on_shutdown() {
+    let sink = MemorySink::default();
+    let running = Running::start(
+        Service::builder()
+            .filter(|entry| !entry.body.contains("debug"))
+            .sink(sink.clone()),
+    )
+    .await;
+
+    running.send(gelf("web", "debug").as_bytes()).await;
+    running.send(gelf("web", "kept").as_bytes()).await;
+    // Less than a batch, and before it's due
+    tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    running.stop().await.unwrap();
+
+    assert_eq!(vec![gelf("web", "kept")], sink.bodies());
+}
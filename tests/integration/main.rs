@@ -0,0 +1,7 @@
+//! End-to-end tests, sending GELF messages over UDP through a running pipeline
+
+mod cli;
+mod fixtures;
+mod gelf;
+mod parquet;
+mod sql;
@@ -0,0 +1,303 @@
+//! Reading archives back the way a Parquet reader does, going by the format's spec rather than by
+//! how they're written, with every codec
+
+use std::{
+    collections::BTreeMap,
+    io::Read,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use flate2::read::GzDecoder;
+use sqlx_logger::{
+    parquet::{self, Codec},
+    pipeline::{Entry, Received},
+};
+
+/// A value in Thrift's compact protocol, which Parquet's metadata is written in
+#[derive(Debug)]
+enum Thrift {
+    Int(i64),
+    Binary(Vec<u8>),
+    List(Vec<Thrift>),
+    Struct(BTreeMap<i16, Thrift>),
+}
+
+impl Thrift {
+    fn field(&self, id: i16) -> &Thrift {
+        match self {
+            Self::Struct(fields) => fields
+                .get(&id)
+                .unwrap_or_else(|| panic!("No field {id} in {self:?}")),
+            _ => panic!("Not a struct: {self:?}"),
+        }
+    }
+
+    fn has(&self, id: i16) -> bool {
+        matches!(self, Self::Struct(fields) if fields.contains_key(&id))
+    }
+
+    fn int(&self) -> i64 {
+        match self {
+            Self::Int(v) => *v,
+            _ => panic!("Not an integer: {self:?}"),
+        }
+    }
+
+    fn str(&self) -> &str {
+        match self {
+            Self::Binary(v) => std::str::from_utf8(v).unwrap(),
+            _ => panic!("Not a string: {self:?}"),
+        }
+    }
+
+    fn list(&self) -> &[Thrift] {
+        match self {
+            Self::List(v) => v,
+            _ => panic!("Not a list: {self:?}"),
+        }
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8], pos: usize) -> Self {
+        Self { data, pos }
+    }
+
+    fn bytes(&mut self, len: usize) -> &'a [u8] {
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        bytes
+    }
+
+    fn byte(&mut self) -> u8 {
+        self.bytes(1)[0]
+    }
+
+    fn u32(&mut self) -> u32 {
+        u32::from_le_bytes(self.bytes(4).try_into().unwrap())
+    }
+
+    fn varint(&mut self) -> u64 {
+        let mut v = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte();
+            v |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        v
+    }
+
+    fn zigzag(&mut self) -> i64 {
+        let v = self.varint();
+        (v >> 1) as i64 ^ -((v & 1) as i64)
+    }
+
+    fn value(&mut self, ty: u8) -> Thrift {
+        match ty {
+            1 => Thrift::Int(1),
+            2 => Thrift::Int(0),
+            3 => Thrift::Int(self.byte() as i8 as i64),
+            4..=6 => Thrift::Int(self.zigzag()),
+            7 => Thrift::Binary(self.bytes(8).to_vec()),
+            8 => {
+                let len = self.varint() as usize;
+                Thrift::Binary(self.bytes(len).to_vec())
+            }
+            9 | 10 => {
+                let header = self.byte();
+                let len = match header >> 4 {
+                    15 => self.varint() as usize,
+                    len => len as usize,
+                };
+                Thrift::List((0..len).map(|_| self.value(header & 0x0f)).collect())
+            }
+            12 => self.structure(),
+            _ => panic!("Unexpected Thrift type {ty} at {}", self.pos),
+        }
+    }
+
+    fn structure(&mut self) -> Thrift {
+        let mut fields = BTreeMap::new();
+        let mut last = 0;
+        loop {
+            let header = self.byte();
+            if header == 0 {
+                return Thrift::Struct(fields);
+            }
+            let id = match header >> 4 {
+                0 => self.zigzag() as i16,
+                delta => last + delta as i16,
+            };
+            last = id;
+            fields.insert(id, self.value(header & 0x0f));
+        }
+    }
+
+    /// Definition levels of a bit each, in the RLE/bit-packing hybrid
+    fn levels(&mut self, num_values: usize) -> Vec<bool> {
+        let mut levels = Vec::with_capacity(num_values);
+        while levels.len() < num_values {
+            let header = self.varint();
+            let run = (header >> 1) as usize;
+            if header & 1 == 0 {
+                let level = self.byte() != 0;
+                levels.resize(levels.len() + run, level);
+            } else {
+                for byte in self.bytes(run) {
+                    levels.extend((0..8).map(|i| byte >> i & 1 != 0));
+                }
+            }
+        }
+        levels.truncate(num_values);
+        levels
+    }
+}
+
+struct Column {
+    name: String,
+    converted_type: Option<i64>,
+    /// Raw little-endian bytes for integers
+    values: Vec<Option<Vec<u8>>>,
+}
+
+/// The file's metadata and columns
+fn read(file: &[u8]) -> (Thrift, Vec<Column>) {
+    assert_eq!(b"PAR1", &file[..4]);
+    assert_eq!(b"PAR1", &file[file.len() - 4..]);
+    let footer_len = Reader::new(file, file.len() - 8).u32() as usize;
+    let metadata = Reader::new(file, file.len() - 8 - footer_len).structure();
+
+    let schema = metadata.field(2).list();
+    assert_eq!(schema.len() as i64 - 1, schema[0].field(5).int());
+    let row_groups = metadata.field(4).list();
+    assert_eq!(1, row_groups.len());
+    let chunks = row_groups[0].field(1).list();
+    assert_eq!(schema.len() - 1, chunks.len());
+
+    let mut columns = Vec::new();
+    for (element, chunk) in schema.iter().skip(1).zip(chunks) {
+        let meta = chunk.field(3);
+        assert_eq!(element.field(4).str(), meta.field(3).list()[0].str());
+        assert_eq!(element.field(1).int(), meta.field(1).int());
+
+        let start = meta.field(9).int() as usize;
+        let mut reader = Reader::new(file, start);
+        let header = reader.structure();
+        assert_eq!(0, header.field(1).int(), "A data page");
+        let compressed = reader.bytes(header.field(3).int() as usize);
+        assert_eq!(meta.field(7).int() as usize, reader.pos - start);
+        let page = match meta.field(4).int() {
+            0 => compressed.to_vec(),
+            2 => {
+                let mut page = Vec::new();
+                GzDecoder::new(compressed).read_to_end(&mut page).unwrap();
+                page
+            }
+            6 => zstd::decode_all(compressed).unwrap(),
+            codec => panic!("Unexpected codec {codec}"),
+        };
+        assert_eq!(header.field(2).int() as usize, page.len());
+
+        let data_page = header.field(5);
+        let num_values = data_page.field(1).int() as usize;
+        assert_eq!(meta.field(5).int() as usize, num_values);
+        assert_eq!(0, data_page.field(2).int(), "Plain encoded");
+        let mut page = Reader::new(&page, 0);
+        let defined = match element.field(3).int() {
+            0 => vec![true; num_values],
+            1 => {
+                assert_eq!(3, data_page.field(3).int(), "RLE levels");
+                let len = page.u32() as usize;
+                let mut levels = Reader::new(page.bytes(len), 0);
+                levels.levels(num_values)
+            }
+            repetition => panic!("Unexpected repetition {repetition}"),
+        };
+        let values = defined
+            .into_iter()
+            .map(|defined| {
+                defined.then(|| match element.field(1).int() {
+                    2 => page.bytes(8).to_vec(),
+                    6 => {
+                        let len = page.u32() as usize;
+                        page.bytes(len).to_vec()
+                    }
+                    ty => panic!("Unexpected type {ty}"),
+                })
+            })
+            .collect();
+        assert_eq!(page.data.len(), page.pos, "Values left over");
+        columns.push(Column {
+            name: element.field(4).str().to_string(),
+            converted_type: element.has(6).then(|| element.field(6).int()),
+            values,
+        });
+    }
+    (metadata, columns)
+}
+
+#[test]
+fn reads_back_with_every_codec() {
+    let entries: Vec<_> = (0..40u64)
+        .map(|i| Entry {
+            sender: (i % 3 != 0).then(|| format!("10.0.0.{i}:12201").parse().unwrap()),
+            received: (i % 5 != 0).then(|| Received {
+                at: UNIX_EPOCH + Duration::from_micros(1_700_000_000_000_000 + i),
+                seq: i,
+            }),
+            body: format!(r#"{{"short_message":"message {i}"}}"#),
+            ..Default::default()
+        })
+        .collect();
+
+    for codec in [Codec::None, Codec::Gzip, Codec::Zstd] {
+        let file = parquet::encode(&entries, codec).unwrap();
+        let (metadata, columns) = read(&file);
+        assert_eq!(entries.len() as i64, metadata.field(3).int(), "{codec:?}");
+        assert!(metadata.field(6).str().starts_with("sqlx_logger"));
+
+        let names: Vec<_> = columns.iter().map(|v| v.name.as_str()).collect();
+        assert_eq!(vec!["received_at", "sender", "body"], names);
+        let converted: Vec<_> = columns.iter().map(|v| v.converted_type).collect();
+        // TIMESTAMP_MICROS, then UTF8
+        assert_eq!(vec![Some(10), Some(0), Some(0)], converted);
+
+        let received_at: Vec<_> = columns[0]
+            .values
+            .iter()
+            .map(|v| {
+                v.as_ref()
+                    .map(|v| i64::from_le_bytes(v.as_slice().try_into().unwrap()))
+            })
+            .collect();
+        let expected: Vec<_> = entries
+            .iter()
+            .map(|v| {
+                let at = v.received?.at.duration_since(UNIX_EPOCH).ok()?;
+                Some(at.as_micros() as i64)
+            })
+            .collect();
+        assert_eq!(expected, received_at, "{codec:?}");
+
+        let text = |values: &[Option<Vec<u8>>]| -> Vec<Option<String>> {
+            values
+                .iter()
+                .map(|v| v.clone().map(|v| String::from_utf8(v).unwrap()))
+                .collect()
+        };
+        let senders: Vec<_> = entries
+            .iter()
+            .map(|v| v.sender.map(|v| v.to_string()))
+            .collect();
+        assert_eq!(senders, text(&columns[1].values), "{codec:?}");
+        let bodies: Vec<_> = entries.iter().map(|v| Some(v.body.clone())).collect();
+        assert_eq!(bodies, text(&columns[2].values), "{codec:?}");
+    }
+}
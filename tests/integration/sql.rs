@@ -0,0 +1,520 @@
+use std::net::IpAddr;
+
+use sqlx::{AnyPool, Row};
+use sqlx_logger::{
+    aggregate::{AggregateSink, Aggregator},
+    lease::Lease,
+    pipeline::{Entry, Received, Sink},
+    placeholders, poison,
+    writer::{BatchMode, BindField, OverlappingSqlSink, ParallelSqlSink, SqlSink, Writer},
+    Service,
+};
+
+use crate::fixtures::{databases, gelf, Running};
+
+fn sql_sink(pool: &AnyPool, sql: Vec<String>, binds: Vec<BindField>) -> SqlSink {
+    SqlSink {
+        writer: Writer::new(pool.clone(), BatchMode::PerEntry).unwrap(),
+        sql,
+        binds,
+        position_sql: None,
+    }
+}
+
+async fn rows(pool: &AnyPool, sql: &str) -> Vec<(String, String)> {
+    let mut rows: Vec<(String, String)> = sqlx::query(sql)
+        .fetch_all(pool)
+        .await
+        .unwrap()
+        .iter()
+        .map(|v| (v.get(0), v.get(1)))
+        .collect();
+    rows.sort();
+    rows
+}
+
+#[tokio::test]
+async fn inserts_every_entry() {
+    for db in databases() {
+        let pool = db.connect().await;
+        let table = db.create_table(&pool, "inserts", "body TEXT").await;
+        let sql = format!("INSERT INTO {table}(body) VALUES ({})", db.param(1));
+        let running =
+            Running::start(Service::builder().sink(sql_sink(&pool, vec![sql], vec![]))).await;
+
+        let messages: Vec<_> = (0..25).map(|i| gelf("web", &format!("{i:02}"))).collect();
+        for message in &messages {
+            running.send(message.as_bytes()).await;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+        running.stop().await.unwrap();
+
+        let bodies: Vec<_> = rows(&pool, &format!("SELECT body, body FROM {table}"))
+            .await
+            .into_iter()
+            .map(|v| v.0)
+            .collect();
+        assert_eq!(messages, bodies, "on {}", db.name);
+    }
+}
+
+#[tokio::test]
+async fn binds_fields_across_statements() {
+    for db in databases() {
+        let pool = db.connect().await;
+        let logs = db
+            .create_table(&pool, "binds_logs", "body TEXT, sender TEXT")
+            .await;
+        let senders = db
+            .create_table(&pool, "binds_senders", "sender TEXT, body TEXT")
+            .await;
+        let sql = vec![
+            format!(
+                "INSERT INTO {logs}(body, sender) VALUES ({}, {})",
+                db.param(1),
+                db.param(2)
+            ),
+            format!(
+                "INSERT INTO {senders}(body, sender) VALUES ({}, {})",
+                db.param(1),
+                db.param(2)
+            ),
+        ];
+        let sink = OverlappingSqlSink::new(sql_sink(&pool, sql, vec![BindField::Sender]), 1);
+        let running =
+            Running::start(Service::builder().sink(sink).max_outstanding_commits(1)).await;
+
+        let message = gelf("web", "hi");
+        running.send(message.as_bytes()).await;
+        let sender = running.sender().to_string();
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        running.stop().await.unwrap();
+
+        let expected = vec![(message.clone(), sender.clone())];
+        for table in [&logs, &senders] {
+            assert_eq!(
+                expected,
+                rows(&pool, &format!("SELECT body, sender FROM {table}")).await,
+                "{table} on {}",
+                db.name
+            );
+        }
+    }
+}
+
+#[tokio::test]
+async fn keeps_the_order_of_each_host_across_writers() {
+    for db in databases() {
+        let pool = db.connect().await;
+        let columns = format!("{}, body TEXT, sender TEXT", db.id_column());
+        let table = db.create_table(&pool, "parallel", &columns).await;
+        let sql = format!(
+            "INSERT INTO {table}(body, sender) VALUES ({}, {})",
+            db.param(1),
+            db.param(2)
+        );
+        let sink = sql_sink(&pool, vec![sql], vec![BindField::Sender]);
+        let running =
+            Running::start(Service::builder().sink(ParallelSqlSink::new(sink, 3).unwrap())).await;
+
+        let hosts: [IpAddr; 3] = [[127, 0, 0, 1], [127, 0, 0, 2], [127, 0, 0, 3]].map(Into::into);
+        for i in 0..30 {
+            for host in hosts {
+                running
+                    .send_from(host, gelf(&host.to_string(), &format!("{i:02}")).as_bytes())
+                    .await;
+            }
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+        running.stop().await.unwrap();
+
+        let rows = sqlx::query(&format!("SELECT body, sender FROM {table} ORDER BY id"))
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(90, rows.len(), "on {}", db.name);
+        for host in hosts {
+            let bodies: Vec<String> = rows
+                .iter()
+                .filter(|v| v.get::<String, _>(1).starts_with(&format!("{host}:")))
+                .map(|v| v.get(0))
+                .collect();
+            let sent: Vec<_> = (0..30)
+                .map(|i| gelf(&host.to_string(), &format!("{i:02}")))
+                .collect();
+            assert_eq!(sent, bodies, "{host} on {}", db.name);
+        }
+    }
+}
+
+#[tokio::test]
+async fn writes_to_sqlite_one_transaction_at_a_time() {
+    let db = databases().remove(0);
+    let pool = db.connect().await;
+    let table = db.create_table(&pool, "one_writer", "body TEXT").await;
+    // Reading first, two transactions would each wait for the other to finish to write
+    let sql = vec![
+        format!("SELECT COUNT(*) AS n FROM {table}"),
+        format!("INSERT INTO {table}(body) VALUES ($1)"),
+    ];
+    let sink = sql_sink(&pool, sql, vec![]);
+    let running =
+        Running::start(Service::builder().sink(ParallelSqlSink::new(sink, 4).unwrap())).await;
+
+    let hosts: [IpAddr; 4] = [1, 2, 3, 4].map(|v| [127, 0, 0, v].into());
+    for i in 0..20 {
+        for host in hosts {
+            running
+                .send_from(host, gelf(&host.to_string(), &format!("{i:02}")).as_bytes())
+                .await;
+        }
+    }
+    tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+    running.stop().await.unwrap();
+
+    let written = rows(&pool, &format!("SELECT body, body FROM {table}")).await;
+    assert_eq!(80, written.len());
+}
+
+#[tokio::test]
+async fn translates_placeholders_for_each_database() {
+    for db in databases() {
+        let pool = db.connect().await;
+        let table = db
+            .create_table(&pool, "translated", "sender TEXT, body TEXT, again TEXT")
+            .await;
+        let sql = format!("INSERT INTO {table}(sender, body, again) VALUES ($2, $1, $2)");
+        let sink = sql_sink(&pool, vec![sql], vec![BindField::Sender]);
+        let running = Running::start(Service::builder().sink(sink)).await;
+
+        let message = gelf("web", "hi");
+        running.send(message.as_bytes()).await;
+        let sender = running.sender().to_string();
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        running.stop().await.unwrap();
+
+        assert_eq!(
+            vec![(sender.clone(), message)],
+            rows(&pool, &format!("SELECT sender, body FROM {table}")).await,
+            "on {}",
+            db.name
+        );
+        assert_eq!(
+            vec![(sender.clone(), sender)],
+            rows(&pool, &format!("SELECT sender, again FROM {table}")).await,
+            "on {}",
+            db.name
+        );
+    }
+}
+
+#[tokio::test]
+async fn binds_named_placeholders() {
+    for db in databases() {
+        let pool = db.connect().await;
+        let table = db
+            .create_table(&pool, "named", "host TEXT, msg TEXT, again TEXT")
+            .await;
+        let sql =
+            format!("INSERT INTO {table}(host, msg, again) VALUES (:host, :short_message, :host)");
+        let (sql, binds) = placeholders::rewrite(&[sql]).unwrap().unwrap();
+        let sink = SqlSink {
+            writer: Writer::new(pool.clone(), BatchMode::PerEntry)
+                .unwrap()
+                .without_entry(),
+            sql,
+            binds,
+            position_sql: None,
+        };
+        let running = Running::start(Service::builder().sink(sink)).await;
+
+        running.send(gelf("web", "hi").as_bytes()).await;
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        running.stop().await.unwrap();
+
+        let rows = sqlx::query(&format!("SELECT host, msg, again FROM {table}"))
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        let rows: Vec<(String, String, String)> = rows
+            .iter()
+            .map(|v| (v.get(0), v.get(1), v.get(2)))
+            .collect();
+        let expected = ("web".to_string(), "hi".to_string(), "web".to_string());
+        assert_eq!(vec![expected], rows, "on {}", db.name);
+    }
+}
+
+#[tokio::test]
+async fn binds_what_statements_return() {
+    // MySQL has no RETURNING
+    for db in databases().into_iter().filter(|v| v.name != "mysql") {
+        let pool = db.connect().await;
+        let columns = format!("{}, body TEXT", db.id_column());
+        let logs = db.create_table(&pool, "returning_logs", &columns).await;
+        let refs = db
+            .create_table(&pool, "returning_refs", "log_id TEXT, body TEXT")
+            .await;
+        let sql = vec![
+            format!("INSERT INTO {logs}(body) VALUES ($1) RETURNING id"),
+            format!("INSERT INTO {refs}(log_id, body) VALUES ($2, $1)"),
+        ];
+        let binds = vec!["returned.id".parse().unwrap()];
+        let running = Running::start(Service::builder().sink(sql_sink(&pool, sql, binds))).await;
+
+        for i in 0..3 {
+            running.send(gelf("web", &format!("{i}")).as_bytes()).await;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+        running.stop().await.unwrap();
+
+        let expected: Vec<_> = (0..3)
+            .map(|i| ((i + 1).to_string(), gelf("web", &format!("{i}"))))
+            .collect();
+        assert_eq!(
+            expected,
+            rows(&pool, &format!("SELECT log_id, body FROM {refs}")).await,
+            "on {}",
+            db.name
+        );
+    }
+}
+
+#[tokio::test]
+async fn fails_on_sql_the_database_rejects() {
+    for db in databases() {
+        let pool = db.connect().await;
+        let sql = "INSERT INTO sqlx_logger_missing_table(body) VALUES (1)".to_string();
+        let running =
+            Running::start(Service::builder().sink(sql_sink(&pool, vec![sql], vec![]))).await;
+
+        running.send(gelf("web", "hi").as_bytes()).await;
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(running.stop().await.is_err(), "on {}", db.name);
+    }
+}
+
+#[tokio::test]
+async fn hands_batches_over_as_json() {
+    for db in databases().into_iter().filter(|v| v.name != "mysql") {
+        let pool = db.connect().await;
+        let table = db
+            .create_table(&pool, "json_batch", "host TEXT, msg TEXT")
+            .await;
+        let sql = match db.name {
+            "postgres" => format!(
+                "INSERT INTO {table}(host, msg) SELECT e->>'host', e->>'short_message' FROM jsonb_array_elements($1::text::jsonb) e"
+            ),
+            _ => format!(
+                "INSERT INTO {table}(host, msg) SELECT json_extract(value, '$.host'), json_extract(value, '$.short_message') FROM json_each(?)"
+            ),
+        };
+        let sink = SqlSink {
+            writer: Writer::new(pool.clone(), BatchMode::JsonArray).unwrap(),
+            sql: vec![sql],
+            binds: vec![],
+            position_sql: None,
+        };
+        let running = Running::start(Service::builder().sink(sink)).await;
+
+        for i in 0..3 {
+            running.send(gelf("web", &format!("{i}")).as_bytes()).await;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        running.stop().await.unwrap();
+
+        let expected: Vec<_> = (0..3).map(|i| ("web".to_string(), i.to_string())).collect();
+        assert_eq!(
+            expected,
+            rows(&pool, &format!("SELECT host, msg FROM {table}")).await,
+            "on {}",
+            db.name
+        );
+    }
+}
+
+#[tokio::test]
+async fn hands_leases_over() {
+    for db in databases() {
+        let pool = db.connect().await;
+        let table = db
+            .create_table(
+                &pool,
+                "leases",
+                "name VARCHAR(64) PRIMARY KEY, holder VARCHAR(64), expires_at BIGINT",
+            )
+            .await;
+        let kind = pool.any_kind();
+        let lease = |holder: &str| Lease {
+            table: table.clone(),
+            name: "collector".to_string(),
+            holder: holder.to_string(),
+            ttl: std::time::Duration::from_millis(500),
+        };
+        let (a, b) = (lease("a"), lease("b"));
+        let mut writer = Writer::new(pool.clone(), BatchMode::PerEntry).unwrap();
+        a.prepare(&mut writer, kind).await.unwrap();
+
+        assert!(
+            a.try_renew(&mut writer, kind).await.unwrap(),
+            "on {}",
+            db.name
+        );
+        assert!(!b.try_renew(&mut writer, kind).await.unwrap());
+        assert!(a.try_renew(&mut writer, kind).await.unwrap());
+
+        tokio::time::sleep(std::time::Duration::from_millis(600)).await;
+        assert!(
+            b.try_renew(&mut writer, kind).await.unwrap(),
+            "on {}",
+            db.name
+        );
+        assert!(!a.try_renew(&mut writer, kind).await.unwrap());
+    }
+}
+
+#[tokio::test]
+async fn upserts_aggregates_while_idle() {
+    for db in databases() {
+        let pool = db.connect().await;
+        let table = db
+            .create_table(
+                &pool,
+                "rates",
+                "window_start BIGINT, host TEXT, level BIGINT, num BIGINT",
+            )
+            .await;
+        let sql = format!(
+            "INSERT INTO {table}(window_start, host, level, num) VALUES ({}, {}, {}, {})",
+            db.param(1),
+            db.param(2),
+            db.param(3),
+            db.param(4)
+        );
+        let sink = AggregateSink {
+            inner: None,
+            aggregator: Aggregator::new(std::time::Duration::from_secs(1)),
+            writer: Writer::new(pool.clone(), BatchMode::PerEntry).unwrap(),
+            sql,
+        };
+        let running = Running::start(Service::builder().sink(sink)).await;
+        running.send(gelf("web", "hello").as_bytes()).await;
+        running.send(gelf("web", "again").as_bytes()).await;
+
+        // Nothing else comes in to write the window out with
+        tokio::time::sleep(std::time::Duration::from_millis(2500)).await;
+        let counts: Vec<(String, i64)> = sqlx::query(&format!("SELECT host, num FROM {table}"))
+            .fetch_all(&pool)
+            .await
+            .unwrap()
+            .iter()
+            .map(|v| (v.get(0), v.get(1)))
+            .collect();
+        running.stop().await.unwrap();
+        let total: i64 = counts.iter().map(|v| v.1).sum();
+        assert_eq!(2, total, "on {}: {counts:?}", db.name);
+    }
+}
+
+#[tokio::test]
+async fn keeps_aggregates_until_they_are_written() {
+    for db in databases() {
+        let pool = db.connect().await;
+        let raw = db.create_table(&pool, "raw", "body TEXT").await;
+        let table = "sqlx_logger_test_late_rates";
+        sqlx::query(&format!("DROP TABLE IF EXISTS {table}"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        let mut sink = AggregateSink {
+            inner: Some(Box::new(sql_sink(
+                &pool,
+                vec![format!("INSERT INTO {raw}(body) VALUES ({})", db.param(1))],
+                vec![],
+            ))),
+            aggregator: Aggregator::new(std::time::Duration::from_secs(1)),
+            writer: Writer::new(pool.clone(), BatchMode::PerEntry).unwrap(),
+            sql: format!(
+                "INSERT INTO {table}(window_start, host, level, num) VALUES ({}, {}, {}, {})",
+                db.param(1),
+                db.param(2),
+                db.param(3),
+                db.param(4)
+            ),
+        };
+        let entry = Entry {
+            body: "hello".to_string(),
+            received: Some(Received {
+                at: std::time::UNIX_EPOCH,
+                seq: 0,
+            }),
+            ..Default::default()
+        };
+
+        // The raw row is in, so the entry mustn't be written again for the counts
+        sink.write(&[entry]).await.unwrap();
+        sqlx::query(&format!(
+            "CREATE TABLE {table} (window_start BIGINT, host TEXT, level BIGINT, num BIGINT)"
+        ))
+        .execute(&pool)
+        .await
+        .unwrap();
+        sink.tick().await.unwrap();
+
+        let counts: Vec<i64> = sqlx::query(&format!("SELECT num FROM {table}"))
+            .fetch_all(&pool)
+            .await
+            .unwrap()
+            .iter()
+            .map(|v| v.get(0))
+            .collect();
+        assert_eq!(vec![1], counts, "on {}", db.name);
+        let raw: i64 = sqlx::query(&format!("SELECT COUNT(*) FROM {raw}"))
+            .fetch_one(&pool)
+            .await
+            .unwrap()
+            .get(0);
+        assert_eq!(1, raw, "on {}", db.name);
+    }
+}
+
+#[tokio::test]
+async fn tells_refused_entries_from_failed_statements() {
+    for db in databases() {
+        let pool = db.connect().await;
+        let table = db
+            .create_table(
+                &pool,
+                "refused",
+                "body VARCHAR(20) CHECK (body <> 'poison')",
+            )
+            .await;
+        let mut sink = sql_sink(
+            &pool,
+            vec![format!(
+                "INSERT INTO {table}(body) VALUES ({})",
+                db.param(1)
+            )],
+            vec![],
+        );
+        let entry = |body: &str| Entry {
+            body: body.to_string(),
+            ..Default::default()
+        };
+
+        let e = sink
+            .write(&[entry("ok"), entry("poison")])
+            .await
+            .unwrap_err();
+        assert!(poison::is_refused(&e), "on {}: {e:#}", db.name);
+
+        // Nothing to do with the entries
+        let mut sink = sql_sink(
+            &pool,
+            vec!["INSERT INTO sqlx_logger_missing_table(body) VALUES (1)".to_string()],
+            vec![],
+        );
+        let e = sink.write(&[entry("ok")]).await.unwrap_err();
+        assert!(!poison::is_refused(&e), "on {}: {e:#}", db.name);
+    }
+}